@@ -0,0 +1,349 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Authenticates the admin requests of indexer-service and tap-agent, by
+//! bearer token or request signature
+//!
+//! A signed request carries the unix time it was signed at in
+//! [ADMIN_TIMESTAMP], a value it is the only one to use in [ADMIN_NONCE]
+//! and, in [ADMIN_SIGNATURE], the EIP-191 signature of
+//! [admin_request_message]. The method, path and query are signed along with
+//! the body, so a signed request can't be used against another route, and
+//! each nonce is only accepted once, so it can't be replayed either.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{primitives::Bytes, signers::Signature};
+use anyhow::{anyhow, ensure};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{OriginalUri, Request, State},
+    http::{header::AUTHORIZATION, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexer_config::AdminSignatureConfig;
+use tracing::warn;
+
+/// Header with the unix time in seconds the request was signed at
+pub const ADMIN_TIMESTAMP: &str = "x-admin-timestamp";
+/// Header with a value unique to the request, such as a random hex string
+pub const ADMIN_NONCE: &str = "x-admin-nonce";
+/// Header with the hex signature of the request
+pub const ADMIN_SIGNATURE: &str = "x-admin-signature";
+
+/// Admin request bodies are small, larger ones aren't buffered to be verified
+pub const MAX_SIGNED_BODY_BYTES: usize = 1 << 20;
+
+/// Longest nonce accepted, for the ones seen to be kept in memory
+const MAX_NONCE_LEN: usize = 128;
+
+/// What admin requests are signed over
+///
+/// `path` is the full path of the request with its query, if any, such as
+/// `/admin/appraisals/0x..?sender=0x..`.
+pub fn admin_request_message(
+    timestamp: u64,
+    nonce: &str,
+    method: &Method,
+    path: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut message = format!("{timestamp}\n{nonce}\n{method}\n{path}\n").into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// State to be used by the admin auth middleware
+#[derive(Clone)]
+pub struct AdminAuthState {
+    /// `Authorization` header value of the token
+    authorization: Option<Arc<String>>,
+    signature: Option<Arc<AdminSignatureConfig>>,
+    /// nonces of the signed requests accepted, with the time they were
+    /// signed at. They are forgotten once their requests are too old anyway
+    nonces: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl AdminAuthState {
+    pub fn new(token: Option<&str>, signature: Option<AdminSignatureConfig>) -> Self {
+        Self {
+            authorization: token.map(|token| Arc::new(format!("Bearer {token}"))),
+            signature: signature.map(Arc::new),
+            nonces: Default::default(),
+        }
+    }
+
+    fn verify_signature(
+        &self,
+        config: &AdminSignatureConfig,
+        timestamp: &str,
+        nonce: &str,
+        signature: &str,
+        message: impl FnOnce(u64) -> Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let timestamp: u64 = timestamp
+            .parse()
+            .map_err(|_| anyhow!("Invalid timestamp `{timestamp}`"))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        ensure!(
+            timestamp <= now,
+            "Request signed at {timestamp} is in the future"
+        );
+        ensure!(
+            now - timestamp <= config.max_age_secs.as_secs(),
+            "Request signed at {timestamp} is stale"
+        );
+        ensure!(
+            !nonce.is_empty() && nonce.len() <= MAX_NONCE_LEN,
+            "Invalid nonce"
+        );
+        let signature = Bytes::from_str(signature).map_err(|_| anyhow!("Invalid signature"))?;
+        let signer = Signature::try_from(signature.as_ref())?
+            .recover_address_from_msg(message(timestamp))?;
+        ensure!(
+            signer == config.signer,
+            "Request is signed by {signer}, which is not the admin signer"
+        );
+
+        // only requests signed by the admin get here, for others not to fill
+        // the nonces up
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, signed_at| now - *signed_at <= config.max_age_secs.as_secs());
+        ensure!(
+            !nonces.contains_key(nonce),
+            "Request with nonce `{nonce}` was already accepted"
+        );
+        nonces.insert(nonce.to_string(), timestamp);
+        Ok(())
+    }
+}
+
+/// Lets admin requests through with the token or, when signatures are
+/// configured, a valid signature. Other requests are answered with a `401`
+pub async fn admin_auth_middleware(
+    State(state): State<AdminAuthState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    if state.authorization.as_ref().is_some_and(|authorization| {
+        headers
+            .get(AUTHORIZATION)
+            .is_some_and(|value| value.as_bytes() == authorization.as_bytes())
+    }) {
+        return next.run(request).await;
+    }
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(config), Some(timestamp), Some(nonce), Some(signature)) = (
+        state.signature.clone(),
+        header(ADMIN_TIMESTAMP),
+        header(ADMIN_NONCE),
+        header(ADMIN_SIGNATURE),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let (timestamp, nonce, signature) = (
+        timestamp.to_string(),
+        nonce.to_string(),
+        signature.to_string(),
+    );
+
+    // nested routes see their path without the prefix
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or(request.uri(), |OriginalUri(uri)| uri);
+    let path = uri
+        .path_and_query()
+        .map_or(uri.path(), |path| path.as_str())
+        .to_string();
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_SIGNED_BODY_BYTES).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    if let Err(e) = state.verify_signature(&config, &timestamp, &nonce, &signature, |timestamp| {
+        admin_request_message(timestamp, &nonce, &parts.method, &path, &body)
+    }) {
+        warn!(error = %e, "Refusing admin request");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use alloy::{
+        hex::ToHexExt,
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use indexer_config::AdminSignatureConfig;
+    use tower::ServiceExt;
+
+    use super::{
+        admin_auth_middleware, admin_request_message, AdminAuthState, ADMIN_NONCE, ADMIN_SIGNATURE,
+        ADMIN_TIMESTAMP,
+    };
+
+    const BODY: &str = r#"{"enabled": true}"#;
+    const PATH: &str = "/admin/safe-mode?reason=maintenance";
+
+    fn app(admin: &PrivateKeySigner) -> Router {
+        let state = AdminAuthState::new(
+            Some("token"),
+            Some(AdminSignatureConfig {
+                signer: admin.address(),
+                max_age_secs: Duration::from_secs(30),
+            }),
+        );
+        let admin_routes = Router::new()
+            .route("/safe-mode", post(|body: String| async move { body }))
+            .layer(from_fn_with_state(state, admin_auth_middleware));
+        Router::new().nest("/admin", admin_routes)
+    }
+
+    /// Request signed at `now + offset` seconds, for `signed_path`
+    fn signed_request(
+        signer: &PrivateKeySigner,
+        offset: i64,
+        nonce: &str,
+        signed_path: &str,
+    ) -> Request<Body> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let timestamp = now.checked_add_signed(offset).unwrap();
+        let message = admin_request_message(
+            timestamp,
+            nonce,
+            &Method::POST,
+            signed_path,
+            BODY.as_bytes(),
+        );
+        let signature = signer.sign_message_sync(&message).unwrap();
+        Request::builder()
+            .method(Method::POST)
+            .uri(PATH)
+            .header(ADMIN_TIMESTAMP, timestamp)
+            .header(ADMIN_NONCE, nonce)
+            .header(
+                ADMIN_SIGNATURE,
+                signature.as_bytes().encode_hex_with_prefix(),
+            )
+            .body(Body::from(BODY))
+            .unwrap()
+    }
+
+    async fn status(app: Router, request: Request<Body>) -> StatusCode {
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_valid_signature() {
+        let admin = PrivateKeySigner::random();
+        let app = app(&admin);
+        let res = app
+            .oneshot(signed_request(&admin, 0, "1", PATH))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, BODY);
+    }
+
+    #[tokio::test]
+    async fn test_stale_or_future_signature() {
+        let admin = PrivateKeySigner::random();
+        assert_eq!(
+            status(app(&admin), signed_request(&admin, -60, "1", PATH)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(app(&admin), signed_request(&admin, 10, "2", PATH)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key() {
+        let admin = PrivateKeySigner::random();
+        let other = PrivateKeySigner::random();
+        assert_eq!(
+            status(app(&admin), signed_request(&other, 0, "1", PATH)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replayed_request() {
+        let admin = PrivateKeySigner::random();
+        let app = app(&admin);
+        assert_eq!(
+            status(app.clone(), signed_request(&admin, 0, "1", PATH)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(app.clone(), signed_request(&admin, 0, "1", PATH)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(app, signed_request(&admin, 0, "2", PATH)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_or_tampered_request() {
+        let admin = PrivateKeySigner::random();
+        let app = app(&admin);
+
+        let unsigned = || Request::builder().method(Method::POST).uri(PATH);
+        assert_eq!(
+            status(app.clone(), unsigned().body(Body::from(BODY)).unwrap()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(
+                app.clone(),
+                unsigned()
+                    .header("authorization", "Bearer token")
+                    .body(Body::from(BODY))
+                    .unwrap()
+            )
+            .await,
+            StatusCode::OK
+        );
+
+        // the signature doesn't hold for another body
+        let (parts, _) = signed_request(&admin, 0, "1", PATH).into_parts();
+        let tampered = Request::from_parts(parts, Body::from(r#"{"enabled": false}"#));
+        assert_eq!(
+            status(app.clone(), tampered).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // nor for another query
+        assert_eq!(
+            status(app, signed_request(&admin, 0, "2", "/admin/safe-mode")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
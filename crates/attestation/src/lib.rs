@@ -3,12 +3,17 @@
 
 use alloy::{
     dyn_abi::Eip712Domain,
+    primitives::{keccak256, B256},
     signers::{
         k256,
         local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
+        Signature, SignerSync,
     },
 };
-use thegraph_core::{attestation, Address, Attestation, ChainId, DeploymentId};
+use thegraph_core::{
+    alloy_sol_types::{sol, SolStruct},
+    attestation, Address, Attestation, ChainId, DeploymentId,
+};
 
 use indexer_allocation::Allocation;
 
@@ -37,6 +42,60 @@ pub fn derive_key_pair(
         .build()?)
 }
 
+sol! {
+    /// EIP-712 message signed by an attestation
+    struct Receipt {
+        bytes32 requestCID;
+        bytes32 responseCID;
+        bytes32 subgraphDeploymentID;
+    }
+}
+
+/// What an attestation is made of before it is signed, for signing the hash
+/// with a key held outside of the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationPayload {
+    pub request_cid: B256,
+    pub response_cid: B256,
+    pub deployment: B256,
+    /// EIP-712 hash the allocation key signs
+    pub signing_hash: B256,
+}
+
+impl AttestationPayload {
+    pub fn new(
+        domain: &Eip712Domain,
+        deployment: &DeploymentId,
+        request: &str,
+        response: impl AsRef<[u8]>,
+    ) -> Self {
+        let receipt = Receipt {
+            requestCID: keccak256(request),
+            responseCID: keccak256(response),
+            subgraphDeploymentID: B256::from(*deployment),
+        };
+        Self {
+            signing_hash: receipt.eip712_signing_hash(domain),
+            request_cid: receipt.requestCID,
+            response_cid: receipt.responseCID,
+            deployment: receipt.subgraphDeploymentID,
+        }
+    }
+
+    /// The attestation, given the signature of [Self::signing_hash] by the
+    /// allocation key
+    pub fn into_attestation(self, signature: &Signature) -> Attestation {
+        Attestation {
+            request_cid: self.request_cid,
+            response_cid: self.response_cid,
+            deployment: self.deployment,
+            r: B256::from(signature.r()),
+            s: B256::from(signature.s()),
+            v: 27 + signature.v().y_parity() as u8,
+        }
+    }
+}
+
 /// An attestation signer tied to a specific allocation via its signer key
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AttestationSigner {
@@ -62,11 +121,32 @@ impl AttestationSigner {
         })
     }
 
+    /// Signs the EIP-712 `Receipt` the gateway and dispute manager verify:
+    ///
+    /// ```text
+    /// Receipt(bytes32 requestCID,bytes32 responseCID,bytes32 subgraphDeploymentID)
+    /// ```
+    ///
+    /// where `requestCID` and `responseCID` are the keccak256 hashes of the
+    /// request body as it was received and of the response body, so an
+    /// attestation can't be replayed against a different query.
     pub fn create_attestation(&self, request: &str, response: &str) -> Attestation {
         let wallet = PrivateKeySigner::from_signing_key(self.signer.clone());
         attestation::create(&self.domain, &wallet, &self.deployment, request, response)
     }
 
+    /// Signs a payload computed over the domain and deployment of this signer
+    pub fn sign(&self, payload: &AttestationPayload) -> Signature {
+        PrivateKeySigner::from_signing_key(self.signer.clone())
+            .sign_hash_sync(&payload.signing_hash)
+            .expect("signing a hash with a local key can't fail")
+    }
+
+    /// Payload of the attestation of this response, to be signed by [Self::sign]
+    pub fn payload(&self, request: &str, response: &str) -> AttestationPayload {
+        AttestationPayload::new(&self.domain, &self.deployment, request, response)
+    }
+
     pub fn verify(
         &self,
         attestation: &Attestation,
@@ -117,7 +197,7 @@ fn wallet_for_allocation(
 
 #[cfg(test)]
 mod tests {
-    use alloy::primitives::U256;
+    use alloy::primitives::{keccak256, U256};
     use std::str::FromStr;
     use test_log::test;
 
@@ -241,4 +321,95 @@ mod tests {
         )
         .is_err());
     }
+
+    #[test]
+    fn test_attestation_bound_to_request() {
+        let allocation = Allocation {
+            id: Address::from_str("0xa171cd12c3dde7eb8fe7717a0bcd06f3ffa65658").unwrap(),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::ZERO,
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            closed_at_epoch: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        let request = r#"{"query": "{ pairs { id } }"}"#;
+        let response = r#"{"data":{"pairs":[]}}"#;
+        let attestation = signer.create_attestation(request, response);
+
+        assert_eq!(attestation.request_cid, keccak256(request));
+        assert_eq!(attestation.response_cid, keccak256(response));
+        assert!(signer
+            .verify(&attestation, request, response, &allocation.id)
+            .is_ok());
+
+        // the same response can't be passed off as the answer to another query
+        let other_request = r#"{"query": "{ tokens { id } }"}"#;
+        assert!(signer
+            .verify(&attestation, other_request, response, &allocation.id)
+            .is_err());
+    }
+
+    #[test]
+    fn test_signed_payload_matches_attestation() {
+        let allocation = Allocation {
+            id: Address::from_str("0xa171cd12c3dde7eb8fe7717a0bcd06f3ffa65658").unwrap(),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::ZERO,
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            closed_at_epoch: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            1,
+            *DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        let request = r#"{"query": "{ pairs { id } }"}"#;
+        let response = r#"{"data":{"pairs":[]}}"#;
+        let payload = signer.payload(request, response);
+        let attestation = payload.clone().into_attestation(&signer.sign(&payload));
+
+        // signing the hash elsewhere gives the same attestation
+        assert_eq!(attestation, signer.create_attestation(request, response));
+        assert!(signer
+            .verify(&attestation, request, response, &allocation.id)
+            .is_ok());
+    }
 }
@@ -3,13 +3,14 @@
 
 use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
 use figment::{
-    providers::{Env, Format, Toml},
+    providers::{Env, Format, Serialized, Toml},
+    value::Dict,
     Figment,
 };
 use serde_repr::Deserialize_repr;
 use serde_with::DurationSecondsWithFrac;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
     str::FromStr,
@@ -20,7 +21,7 @@ use tracing::warn;
 use alloy::primitives::Address;
 use bip39::Mnemonic;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::env;
 use thegraph_core::DeploymentId;
@@ -29,6 +30,10 @@ use url::Url;
 use crate::NonZeroGRT;
 
 const SHARED_PREFIX: &str = "INDEXER_";
+/// Environment variable used to select a profile when none is given explicitly
+const PROFILE_ENV: &str = "INDEXER_PROFILE";
+/// Table of the config file holding the named profiles
+const PROFILES_KEY: &str = "profiles";
 
 #[derive(Debug, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
@@ -80,6 +85,23 @@ impl ConfigPrefix {
 
 impl Config {
     pub fn parse(prefix: ConfigPrefix, filename: Option<&PathBuf>) -> Result<Self, String> {
+        Self::parse_with_profile(prefix, filename, None)
+    }
+
+    /// Same as [Config::parse], merging the `[profiles.<profile>]` table of the
+    /// config file on top of the rest of the file.
+    ///
+    /// Nested sections are merged key by key, so a profile only has to list the
+    /// values that differ from the base config. If `profile` is `None`, the
+    /// `INDEXER_PROFILE` environment variable is used, if set.
+    pub fn parse_with_profile(
+        prefix: ConfigPrefix,
+        filename: Option<&PathBuf>,
+        profile: Option<&str>,
+    ) -> Result<Self, String> {
+        let env_profile = env::var(PROFILE_ENV).ok().filter(|p| !p.is_empty());
+        let profile = profile.or(env_profile.as_deref());
+
         let config_defaults = include_str!("../default_values.toml");
 
         let mut figment_config = Figment::new().merge(Toml::string(config_defaults));
@@ -88,12 +110,38 @@ impl Config {
             let mut config_content = std::fs::read_to_string(path)
                 .map_err(|e| format!("Failed to read config file: {}", e))?;
             config_content = Self::substitute_env_vars(config_content)?;
-            figment_config = figment_config.merge(Toml::string(&config_content));
+
+            let mut base: Dict = Figment::from(Toml::string(&config_content))
+                .extract()
+                .map_err(|e| e.to_string())?;
+            let mut profiles = match base.remove(PROFILES_KEY) {
+                Some(profiles) => profiles
+                    .into_dict()
+                    .ok_or_else(|| format!("`{}` must be a table", PROFILES_KEY))?,
+                None => Dict::new(),
+            };
+            figment_config = figment_config.merge(Serialized::defaults(base));
+
+            if let Some(profile) = profile {
+                let overrides = profiles.remove(profile).ok_or_else(|| {
+                    format!(
+                        "Unknown configuration profile `{}`, available profiles: [{}]",
+                        profile,
+                        profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                    )
+                })?;
+                figment_config = figment_config.merge(Serialized::defaults(overrides));
+            }
+        } else if let Some(profile) = profile {
+            return Err(format!(
+                "Configuration profile `{}` selected but no configuration file was provided",
+                profile
+            ));
         }
 
         let config: ConfigWrapper = figment_config
             .merge(Self::from_env_ignore_empty(prefix.get_prefix()))
-            .merge(Self::from_env_ignore_empty(SHARED_PREFIX))
+            .merge(Self::from_env_ignore_empty(SHARED_PREFIX).ignore(&["profile"]))
             .extract()
             .map_err(|e| e.to_string())?;
 
@@ -186,6 +234,29 @@ impl Config {
             )
         }
 
+        if self.tap.max_query_appraisals == 0 {
+            return Err("tap.max_query_appraisals must be greater than 0".to_string());
+        }
+
+        if self.tap.max_appraisal_batch_size == 0 {
+            return Err("tap.max_appraisal_batch_size must be greater than 0".to_string());
+        }
+
+        if self.tap.check_appraised_values
+            && self.tap.admin_auth_token.is_none()
+            && self.tap.admin_signature.is_none()
+        {
+            return Err(
+                "tap.check_appraised_values requires tap.admin_auth_token or \
+                tap.admin_signature to set the appraisals"
+                    .to_string(),
+            );
+        }
+
+        if self.tap.rav_request.max_retry_attempts == 0 {
+            return Err("tap.rav_request.max_retry_attempts must be greater than 0".to_string());
+        }
+
         let ten: BigDecimal = 10.into();
         let usual_grt_price = BigDecimal::from_str("0.0001").unwrap() * ten;
         if self.tap.max_amount_willing_to_lose_grt.get_value() < usual_grt_price.to_u128().unwrap()
@@ -217,6 +288,63 @@ impl Config {
             );
         }
 
+        if self.service.max_concurrent_signings == Some(0) {
+            return Err("service.max_concurrent_signings must be greater than 0".to_string());
+        }
+
+        if self.subgraphs.max_concurrent_queries == Some(0) {
+            return Err("subgraphs.max_concurrent_queries must be greater than 0".to_string());
+        }
+
+        if let AttestationFailurePolicy::Retry {
+            retry_interval_secs,
+            ..
+        } = self.service.attestation_failure_policy
+        {
+            if retry_interval_secs.is_zero() {
+                return Err(
+                    "service.attestation_failure_policy.retry_interval_secs must be greater than 0"
+                        .to_string(),
+                );
+            }
+        }
+
+        // Postgres takes the timeout in milliseconds, 0 disabling it
+        if self
+            .service
+            .database_statement_timeout_secs
+            .is_some_and(|timeout| timeout < Duration::from_millis(1))
+        {
+            return Err("service.database_statement_timeout_secs must be at least 1ms".to_string());
+        }
+
+        if self.indexer.operator_mnemonic.is_none() && self.service.remote_signer.is_none() {
+            return Err(
+                "indexer.operator_mnemonic is required unless service.remote_signer is set"
+                    .to_string(),
+            );
+        }
+
+        if !(0.0..=1.0).contains(&self.service.request_log_sample_rate) {
+            return Err("request_log_sample_rate must be between 0 and 1".to_string());
+        }
+
+        if let Some(receipt_archive) = &self.service.tap.receipt_archive {
+            if !(0.0..=1.0).contains(&receipt_archive.sample_rate) {
+                return Err("receipt_archive.sample_rate must be between 0 and 1".to_string());
+            }
+        }
+
+        if self.service.tap.sender_allow_list.is_some()
+            && self.service.tap.enable_deny_list == Some(true)
+        {
+            return Err(
+                "service.tap.sender_allow_list and service.tap.enable_deny_list can't be \
+                used together, the allow-list replaces the deny-list"
+                    .to_string(),
+            );
+        }
+
         if self.tap.rav_request.timestamp_buffer_secs < Duration::from_secs(10) {
             warn!(
                 "Your `tap.rav_request.timestamp_buffer_secs` value it too low. \
@@ -233,7 +361,16 @@ impl Config {
 #[cfg_attr(test, derive(PartialEq))]
 pub struct IndexerConfig {
     pub indexer_address: Address,
-    pub operator_mnemonic: Mnemonic,
+    /// required unless attestations are signed by `service.remote_signer`
+    #[serde(default)]
+    pub operator_mnemonic: Option<Mnemonic>,
+    /// mnemonics of previous operators with allocations still open, newest first
+    #[serde(default)]
+    pub previous_operator_mnemonics: Vec<Mnemonic>,
+    /// operators whose attestation signer is preferred when several mnemonics
+    /// match an allocation, instead of the newest
+    #[serde(default)]
+    pub operator_priority: Vec<Address>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -302,6 +439,8 @@ impl MetricsConfig {
 pub struct SubgraphsConfig {
     pub network: NetworkSubgraphConfig,
     pub escrow: EscrowSubgraphConfig,
+    /// queries in flight at once over both subgraphs, the others wait
+    pub max_concurrent_queries: Option<usize>,
 }
 
 #[serde_as]
@@ -315,11 +454,59 @@ pub struct NetworkSubgraphConfig {
     pub recently_closed_allocation_buffer_secs: Duration,
 }
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct EscrowSubgraphConfig {
     #[serde(flatten)]
     pub config: SubgraphConfig,
+    /// how far apart, in blocks, the escrow and network subgraphs may be
+    pub max_block_gap_to_network: Option<u64>,
+    /// what to do once past `max_block_gap_to_network`
+    pub block_gap_action: BlockGapAction,
+    /// what to do with paid queries while the escrow accounts can't be read
+    pub escrow_unavailable_policy: EscrowUnavailablePolicy,
+    /// how long the last escrow accounts read are used with `serve_stale`,
+    /// forever when unset
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub max_escrow_staleness_secs: Option<Duration>,
+    /// where the escrow accounts are read from, the subgraph by default
+    #[serde(default)]
+    pub accounts_source: EscrowSourceConfig,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum EscrowSourceConfig {
+    /// the escrow subgraph
+    #[default]
+    Subgraph,
+    /// an endpoint serving the accounts as JSON, such as an indexer of the
+    /// escrow events run by the operator
+    Http { url: Url },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EscrowUnavailablePolicy {
+    /// keep checking receipts against the last escrow accounts read, up to
+    /// `max_escrow_staleness_secs`, then refuse paid queries
+    ServeStale,
+    /// refuse paid queries with 503 right away, reporting the service as not
+    /// ready
+    RejectPaid,
+    /// refuse paid queries with 503, staying ready to serve free queries
+    FreeOnly,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockGapAction {
+    /// log the gap, but keep serving
+    Warn,
+    /// refuse paid queries with 503 until the subgraphs catch up
+    Refuse,
 }
 
 #[serde_as]
@@ -353,6 +540,7 @@ pub struct BlockchainConfig {
     pub receipts_verifier_address: Address,
 }
 
+#[serde_as]
 #[derive(Debug, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct ServiceConfig {
@@ -360,9 +548,361 @@ pub struct ServiceConfig {
     pub serve_escrow_subgraph: bool,
     pub serve_auth_token: Option<String>,
     pub host_and_port: SocketAddr,
+    /// addresses to listen on besides `host_and_port`, which always serves public routes
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+    /// serve queries over gRPC too, with the `grpc` feature
+    pub grpc: Option<GrpcConfig>,
+    /// pending connections queued by the kernel before it starts dropping them
+    pub listen_backlog: u32,
+    /// set `SO_REUSEADDR`, so a restart can bind while old connections are in `TIME_WAIT`
+    pub reuse_address: bool,
+    /// set `SO_REUSEPORT`, letting several processes share the addresses (unix only)
+    pub reuse_port: bool,
     pub url_prefix: String,
     pub tap: ServiceTapConfig,
     pub free_query_auth_token: Option<String>,
+    /// attest error responses that graph-node marked as attestable, not only
+    /// successful ones
+    pub attest_error_responses: bool,
+    /// serve free queries unattested when the allocation has no signer
+    pub unattested_free_queries: bool,
+    /// reuse the signature of identical attestations of the same allocation,
+    /// for signing backends whose signatures aren't bound to a nonce
+    pub cache_attestations: bool,
+    /// part of the graph-node response the attestation is computed over
+    pub attestation_scope: ResponseAttestationScope,
+    /// sign attestations with this signing service instead of keys derived
+    /// from the operator mnemonic
+    pub remote_signer: Option<RemoteSignerConfig>,
+    /// what to do with a query when the attestation backend fails to sign
+    #[serde(default)]
+    pub attestation_failure_policy: AttestationFailurePolicy,
+    /// attestations signed at once, the others wait in the signing queue
+    pub max_concurrent_signings: Option<usize>,
+    /// warn once more attestations than this wait to be signed
+    pub signing_queue_warning_threshold: Option<usize>,
+    /// receipts waiting to be stored before paid queries are shed on a saturated database
+    pub load_shedding_receipt_queue_threshold: usize,
+    /// refuse all paid queries on startup, can be toggled at runtime through `/admin/safe-mode`
+    pub safe_mode: bool,
+    /// compare the host clock with a reference, the receipt timestamps are
+    /// checked against it
+    pub clock_drift: Option<ClockDriftConfig>,
+    /// wait on startup for the escrow and network subgraphs to catch up with
+    /// the chain before serving
+    pub wait_for_sync: Option<WaitForSyncConfig>,
+    /// explain in error responses why a request body could not be parsed
+    pub verbose_errors: bool,
+    /// content types accepted for queries, which are always parsed as JSON
+    pub accepted_content_types: Vec<String>,
+    /// what to do when handling a query panics
+    pub request_panic_action: RequestPanicAction,
+    /// how addresses are written in responses and error bodies
+    pub address_format: AddressFormat,
+    /// how deployment ids are written in responses and error bodies
+    pub deployment_id_format: DeploymentIdFormat,
+    /// restricts the GraphQL operations accepted for a deployment, by operation
+    /// name or keccak256 hash of the normalized query
+    #[serde(default)]
+    pub allowed_operations: HashMap<DeploymentId, Vec<String>>,
+    /// rejects GraphQL queries nested deeper than this
+    pub max_query_depth: Option<usize>,
+    /// rejects GraphQL queries selecting more fields than this, fragments being
+    /// counted every time they are spread
+    pub max_query_fields: Option<usize>,
+    /// overrides `max_query_depth` and `max_query_fields` for specific deployments
+    #[serde(default)]
+    pub query_limits_per_deployment: HashMap<DeploymentId, QueryLimitsConfig>,
+    /// refuse graph-node responses larger than this instead of forwarding them
+    pub max_response_body_bytes: Option<usize>,
+    /// have graph-node answer identical queries arriving together only once
+    #[serde(default)]
+    pub coalesce_identical_queries: bool,
+    /// tell in paid responses what the query was charged and appraised at
+    #[serde(default)]
+    pub cost_metadata: CostMetadata,
+    /// names that can be used instead of the deployment id in query paths
+    #[serde(default)]
+    pub deployment_aliases: HashMap<String, DeploymentId>,
+    /// fraction of successful requests to log, failed requests are always logged
+    pub request_log_sample_rate: f64,
+    /// distinct deployments labelled in the query metrics, the others are
+    /// counted together as `other`
+    pub max_deployment_metric_labels: usize,
+    /// how requests are identified in responses, logs and downstream calls
+    pub request_id: RequestIdConfig,
+    /// version headers advertised in responses and read from gateways
+    pub version_headers: VersionHeadersConfig,
+    /// experimental handling clients may enable for their own requests
+    pub request_features: RequestFeaturesConfig,
+    pub response_compression: ResponseCompressionConfig,
+    /// only build attestation signers up front for these allocations, other
+    /// allocations get theirs on their first query
+    #[serde(default)]
+    pub monitored_allocations: Vec<Address>,
+    /// build attestation signers up front for every allocation of these deployments
+    #[serde(default)]
+    pub monitored_deployments: Vec<DeploymentId>,
+    /// only serve these deployments, allocations of any other deployment are
+    /// left out and queries to them answered with `404`. Empty serves all
+    #[serde(default)]
+    pub served_deployments: Vec<DeploymentId>,
+    /// refuse queries to these deployments with `403`, can be replaced at
+    /// runtime through `/admin/denied-deployments`
+    #[serde(default)]
+    pub denied_deployments: Vec<DeploymentId>,
+    /// cap the requests and receipt value accepted for specific allocations,
+    /// can be replaced at runtime through `/admin/allocation-quotas`
+    #[serde(default)]
+    pub allocation_quotas: HashMap<Address, AllocationQuotaConfig>,
+    /// keep the signers of at most this many recently queried allocations
+    /// outside the monitored ones
+    pub max_lazy_signers: Option<usize>,
+    /// build the signers of these allocations ahead of their first query,
+    /// keeping them whatever `max_lazy_signers`
+    #[serde(default)]
+    pub warm_allocations: Vec<Address>,
+    /// queries handled at once, further ones wait in the request queue
+    pub max_concurrent_requests: Option<usize>,
+    /// queries waiting for a slot before new ones are refused
+    pub request_queue_length: usize,
+    /// how long a query waits for a slot before it is refused
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub request_queue_max_wait_secs: Duration,
+    /// overall time a query has, from its arrival to graph-node's response
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub request_timeout_secs: Option<Duration>,
+    /// how long in-flight requests are given to complete on shutdown
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub shutdown_grace_period_secs: Duration,
+    /// close connections that haven't read or written anything for this long
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub connection_idle_timeout_secs: Option<Duration>,
+    /// Postgres cancels the statements of the service running longer than this
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub database_statement_timeout_secs: Option<Duration>,
+    /// enables the `/admin` routes, protected by this token
+    pub admin_auth_token: Option<String>,
+    /// enables the `/admin` routes for requests signed by this key, on top of
+    /// the token if both are set
+    pub admin_signature: Option<AdminSignatureConfig>,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AllocationQuotaConfig {
+    pub max_requests_per_second: Option<u32>,
+    /// total value of the receipts accepted per `window_secs`
+    pub max_value_grt: Option<NonZeroGRT>,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub window_secs: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RemoteSignerConfig {
+    pub url: Url,
+    /// operator reported by `/info`, holding the allocation keys
+    pub operator_address: Address,
+    /// how long signing may take before the query is answered with a `504`
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub timeout_secs: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum AttestationFailurePolicy {
+    /// answer with the error of the backend
+    #[default]
+    Fail,
+    /// serve the response without an attestation
+    ServeUnattested,
+    /// sign again every `retry_interval_secs` until `deadline_secs` is over
+    Retry {
+        #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+        deadline_secs: Duration,
+        #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+        retry_interval_secs: Duration,
+    },
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ClockDriftConfig {
+    pub reference: ClockReferenceConfig,
+    /// drift of the host clock from the reference tolerated
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub max_drift_secs: Duration,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub check_interval_secs: Duration,
+    /// what to do once past `max_drift_secs`
+    pub action: ClockDriftAction,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct AdminSignatureConfig {
+    /// address of the operator key admin requests are signed with
+    pub signer: Address,
+    /// requests signed longer ago than this, or in the future, are rejected
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub max_age_secs: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct WaitForSyncConfig {
+    /// blocks behind the chain head the subgraphs may be for serving to start
+    pub min_block_lag: u64,
+    /// serve anyway once waited this long
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub timeout_secs: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ClockReferenceConfig {
+    /// an NTP server, as `host:port`
+    Ntp { server: String },
+    /// the timestamp of the latest block indexed by the network subgraph,
+    /// which lags behind by the indexing delay
+    NetworkSubgraph,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockDriftAction {
+    /// log the drift, but keep serving
+    Warn,
+    /// refuse paid queries with 503 until the clock is back in sync
+    Refuse,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct RequestIdConfig {
+    /// header the request id is read from and echoed back in
+    pub header: String,
+    /// keep the request id sent by the client instead of generating a UUID
+    pub honor_inbound: bool,
+    /// send the request id along to graph-node and the served subgraphs
+    pub propagate: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct VersionHeadersConfig {
+    /// send the version of the service in `Graph-Indexer-Version`
+    pub advertise_version: bool,
+    /// value of the `Server` header, left out if not set
+    pub server: Option<String>,
+    /// refuse queries of gateways with an older `Graph-Gateway-Version`
+    pub min_gateway_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct RequestFeaturesConfig {
+    /// header listing the features of the request, separated by commas
+    pub header: String,
+    /// features clients may enable, the others are never enabled
+    pub allowed: Vec<String>,
+    pub unknown_action: UnknownFeatureAction,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseCompressionConfig {
+    /// gzip query responses for clients accepting it, the attestation still
+    /// covering the response as graph-node returned it
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CostMetadata {
+    #[default]
+    Disabled,
+    /// in response headers, which are never attested
+    Headers,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownFeatureAction {
+    /// serve the request without the features that aren't allowed
+    Ignore,
+    /// refuse the request with a 400
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestPanicAction {
+    /// log the panic and answer the query with a 500
+    Respond,
+    /// abort the process, leaving the restart to the supervisor
+    Abort,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseAttestationScope {
+    /// the whole response body
+    #[default]
+    FullBody,
+    /// the `data` member of the response, leaving out `errors` and `extensions`
+    DataOnly,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFormat {
+    /// mixed-case EIP-55 checksum
+    Checksummed,
+    #[default]
+    Lowercase,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeploymentIdFormat {
+    /// IPFS hash, `Qm...`
+    #[default]
+    Base58,
+    /// 0x-prefixed bytes32
+    Hex,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct QueryLimitsConfig {
+    pub max_depth: Option<usize>,
+    pub max_fields: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ListenConfig {
+    pub address: SocketAddr,
+    pub role: ListenRole,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct GrpcConfig {
+    pub host_and_port: SocketAddr,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenRole {
+    /// query, cost, status and informational routes
+    Public,
+    /// `/admin` routes. Once an admin address is configured, admin routes are
+    /// no longer served on the public ones
+    Admin,
 }
 
 #[serde_as]
@@ -371,6 +911,288 @@ pub struct ServiceConfig {
 pub struct ServiceTapConfig {
     /// what's the maximum value we accept in a receipt
     pub max_receipt_value_grt: NonZeroGRT,
+    /// minimum value accepted in a receipt for deployments without their own minimum
+    pub min_price_grt: Option<NonZeroGRT>,
+    /// minimum value accepted in a receipt for specific deployments
+    #[serde(default)]
+    pub min_price_per_deployment_grt: HashMap<DeploymentId, NonZeroGRT>,
+    /// what the value check does with queries no cost model appraises, any
+    /// receipt of at least 1 GRT wei is accepted for them without it
+    pub no_appraisal_policy: Option<NoAppraisalPolicy>,
+    /// write the numbers of the query variables the same way before appraising
+    /// the query, so `1e2` and `100` are appraised alike
+    #[serde(default)]
+    pub normalize_variable_numbers: bool,
+    /// refuse receipts whose fields are missing, unexpected or out of range
+    /// with a 400 naming the field, instead of treating them as absent
+    #[serde(default)]
+    pub strict_receipt_validation: bool,
+    /// only accept receipts from these senders, instead of checking them
+    /// against the deny-list. Updated at runtime through the admin routes
+    pub sender_allow_list: Option<HashSet<Address>>,
+    /// check senders against the deny-list kept in the database, by default
+    /// unless `sender_allow_list` is set. Can't be enabled with it
+    pub enable_deny_list: Option<bool>,
+    /// maximum value of receipts not yet covered by a RAV that we hold for a single sender
+    pub max_pending_value_per_sender_grt: Option<NonZeroGRT>,
+    /// refuse receipts that would raise the value collected on an allocation
+    /// above its allocated tokens
+    #[serde(default)]
+    pub enforce_allocation_cap: bool,
+    /// warn about senders whose escrow balance falls below this, receipts are still accepted
+    pub low_escrow_balance_grt: Option<NonZeroGRT>,
+    /// accept some receipts from senders whose escrow doesn't cover them,
+    /// betting on a top-up the escrow subgraph doesn't show yet
+    pub escrow_top_up_grace: Option<EscrowTopUpGraceConfig>,
+    /// refuse the lower priority queries of senders whose escrow is running
+    /// out, keeping what's left for their higher priority ones
+    pub escrow_admission: Option<EscrowAdmissionConfig>,
+    /// refresh the escrow accounts out of cycle for receipts of unknown
+    /// signers before refusing them, at most once per debounce interval
+    #[serde(default)]
+    pub refresh_escrow_on_unknown_sender: bool,
+    /// token escrow balances and cost models are denominated in, assumed for
+    /// receipts that don't state theirs. Receipts in any other token are rejected
+    pub token_address: Option<Address>,
+    /// flag receipts whose timestamp jumps further than this ahead of the sender's history
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub max_timestamp_gap_secs: Option<Duration>,
+    /// what to do with receipts flagged by `max_timestamp_gap_secs`
+    pub timestamp_gap_action: TimestampGapAction,
+    /// what to do with receipts for allocations missing from the network
+    /// subgraph, which may not have synced them yet
+    pub unknown_allocation_policy: UnknownAllocationPolicy,
+    /// how long the allocation of such receipts is given to show up
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub unknown_allocation_grace_secs: Duration,
+    /// how long a receipt check may take before the request is refused as retryable
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub check_timeout_secs: Duration,
+    /// overrides `check_timeout_secs` for specific checks, by name
+    #[serde_as(as = "HashMap<_, DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub check_timeouts_secs: HashMap<CheckName, Duration>,
+    /// order the checks run in and what happens once one of them fails
+    pub check_policy: CheckPolicy,
+    /// file rejected receipts are written to
+    pub receipt_log: Option<ReceiptLogConfig>,
+    /// throttle the logging of identical check failures, every failure is logged without it
+    pub check_failure_logging: Option<CheckFailureLoggingConfig>,
+    /// only accept receipts worth the price of the query in a signed price list
+    pub price_list: Option<PriceListConfig>,
+    /// what to do with a receipt while the receipts waiting to be written to
+    /// the database fill the queue
+    #[serde(default)]
+    pub receipt_queue_overflow: ReceiptQueueOverflowPolicy,
+    /// record the receipts that passed the checks for queries that can never
+    /// be served, for the operator to look into
+    #[serde(default)]
+    pub dead_letter_receipts: bool,
+    /// keep a sample of the paid queries served, receipt, request, response
+    /// and attestation, apart from the receipts that are collected
+    pub receipt_archive: Option<ReceiptArchiveConfig>,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ReceiptArchiveConfig {
+    /// fraction of the paid queries archived, between 0 and 1
+    pub sample_rate: f64,
+    /// how long archived queries are kept
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub retention_secs: Duration,
+    pub sink: ReceiptArchiveSink,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReceiptArchiveSink {
+    /// the `scalar_tap_receipt_archive` table
+    Database,
+    /// one file of JSON lines a day in this directory
+    Directory { path: PathBuf },
+}
+
+/// Queued receipts are owed fees, so they are never dropped to make room
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum ReceiptQueueOverflowPolicy {
+    /// hold the request until there is room, refusing it after `max_block_secs`
+    Backpressure {
+        #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+        max_block_secs: Duration,
+    },
+    /// refuse the request right away
+    Reject,
+}
+
+impl Default for ReceiptQueueOverflowPolicy {
+    fn default() -> Self {
+        Self::Backpressure {
+            max_block_secs: Duration::from_secs(1),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct PriceListConfig {
+    /// where the signed price list is fetched from
+    pub url: Url,
+    /// keys the price list may be signed by
+    pub authorized_signers: HashSet<Address>,
+    /// how often the price list is fetched again
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub refresh_interval_secs: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CheckFailureLoggingConfig {
+    /// failures of a sender in a check logged per window, the rest are only counted
+    pub max_failures: usize,
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub window_secs: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ReceiptLogConfig {
+    pub path: PathBuf,
+    pub format: ReceiptLogFormat,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptLogFormat {
+    /// one pretty printed JSON object per receipt
+    Json,
+    /// one JSON object per line
+    Ndjson,
+    /// bincode records, each prefixed with its length as a little endian u32
+    Binary,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct EscrowTopUpGraceConfig {
+    /// total value of the receipts accepted from a sender without sufficient balance
+    pub max_value_grt: NonZeroGRT,
+    /// how long after running out a sender's receipts are accepted
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub duration_secs: Duration,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct EscrowAdmissionConfig {
+    /// header the priority of a query is read from
+    pub priority_header: String,
+    /// priority of queries without a valid header, for deployments without their own
+    pub default_priority: QueryPriority,
+    /// priority of the queries to specific deployments without a valid header
+    #[serde(default)]
+    pub deployment_priorities: HashMap<DeploymentId, QueryPriority>,
+    /// fraction of the escrow balance under which low priority queries are refused
+    pub low_priority_min_headroom: f64,
+    /// fraction of the escrow balance under which normal priority queries are refused
+    pub normal_priority_min_headroom: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryPriority {
+    Low,
+    #[default]
+    Normal,
+    /// only refused once the escrow is exhausted, by the balance check
+    High,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "policy", content = "value_grt", rename_all = "snake_case")]
+pub enum NoAppraisalPolicy {
+    Reject,
+    /// accept receipts worth at least this much
+    AcceptAboveFloor(NonZeroGRT),
+    /// appraise the query at this value
+    UseDefault(NonZeroGRT),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownAllocationPolicy {
+    /// refuse the receipt for good
+    Reject,
+    /// refuse the receipt with a retryable 503 until the grace period is over
+    RetryableUnavailable,
+    /// accept the receipt and wait for the signer of the allocation to attest
+    /// the response, until the grace period is over. Receipts are refused for
+    /// good afterwards
+    AcceptAndDeferSigner,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampGapAction {
+    /// log and count the receipt, but accept it
+    Warn,
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct CheckPolicy {
+    /// checks to run first, in this order. Checks left out run afterwards in
+    /// their default order
+    #[serde(default)]
+    pub order: Vec<CheckName>,
+    pub mode: CheckMode,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckName {
+    AllocationEligible,
+    SenderBalance,
+    Timestamp,
+    DenyList,
+    SenderAllowList,
+    ReceiptMaxValue,
+    MinimumPrice,
+    MinimumValue,
+    PendingValue,
+    AllocationCap,
+    TimestampGap,
+    PriceList,
+}
+
+impl CheckName {
+    /// Name used for the check in logs, errors and `check_timeouts_secs`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckName::AllocationEligible => "allocation_eligible",
+            CheckName::SenderBalance => "sender_balance",
+            CheckName::Timestamp => "timestamp",
+            CheckName::DenyList => "deny_list",
+            CheckName::SenderAllowList => "sender_allow_list",
+            CheckName::ReceiptMaxValue => "receipt_max_value",
+            CheckName::MinimumPrice => "minimum_price",
+            CheckName::MinimumValue => "minimum_value",
+            CheckName::PendingValue => "pending_value",
+            CheckName::AllocationCap => "allocation_cap",
+            CheckName::TimestampGap => "timestamp_gap",
+            CheckName::PriceList => "price_list",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckMode {
+    /// refuse the receipt as soon as a check fails
+    FirstFailure,
+    /// run every check and report all of the failures together
+    CollectAll,
 }
 
 #[derive(Debug, Deserialize)]
@@ -378,9 +1200,36 @@ pub struct ServiceTapConfig {
 pub struct TapConfig {
     /// what is the maximum amount the indexer is willing to lose in grt
     pub max_amount_willing_to_lose_grt: NonZeroGRT,
+    /// appraisals held in memory, the least recently used are evicted past it
+    pub max_query_appraisals: usize,
+    /// appraisals set at once by `POST /admin/appraisals`, larger batches are refused
+    pub max_appraisal_batch_size: usize,
+    /// check receipts against the appraisals set through the admin routes
+    pub check_appraised_values: bool,
     pub rav_request: RavRequestConfig,
+    pub rav_redemption: RavRedemptionConfig,
+    pub receipt_pruning: ReceiptPruningConfig,
 
     pub sender_aggregator_endpoints: HashMap<Address, Url>,
+    /// enables the `/admin` routes of tap-agent on the metrics port, protected by this token
+    pub admin_auth_token: Option<String>,
+    /// enables the `/admin` routes of tap-agent for requests signed by this key
+    pub admin_signature: Option<AdminSignatureConfig>,
+    /// whether indexer-service stores the time it received receipts, next to
+    /// the signed `timestamp_ns` RAV requests are windowed by
+    #[serde(default)]
+    pub receipt_time_source: ReceiptTimeSource,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptTimeSource {
+    /// the `timestamp_ns` signed by the sender
+    #[default]
+    ReceiptField,
+    /// also the time the service received the receipt, stored next to the signed
+    /// one to audit receipts stamped far from when they arrived
+    ServerReceive,
 }
 
 #[derive(Debug, Deserialize)]
@@ -415,6 +1264,38 @@ pub struct RavRequestConfig {
     pub request_timeout_secs: Duration,
     /// how many receipts are sent in a single rav requests
     pub max_receipts_per_request: u64,
+    /// record rav requests in the database before sending them, so a restart
+    /// resumes an interrupted request instead of aggregating its receipts again
+    pub persist_requests: bool,
+    /// how many times a rav request failing to reach the aggregator is sent in
+    /// total before giving up and alerting
+    pub max_retry_attempts: u32,
+    /// backoff before the first retry, doubled on every failed attempt
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub retry_backoff_secs: Duration,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct RavRedemptionConfig {
+    /// how often the escrow subgraph is polled for redeemed RAVs
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub polling_interval_secs: Duration,
+    /// how many blocks a redemption must be buried under before it's considered final
+    pub confirmation_depth: u64,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ReceiptPruningConfig {
+    /// how often receipts covered by redeemed RAVs are pruned
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub interval_secs: Duration,
+    /// how long receipts are kept, even once covered by a redeemed RAV
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub retention_secs: Duration,
 }
 
 #[cfg(test)]
@@ -549,6 +1430,111 @@ mod tests {
         );
     }
 
+    const PROFILES_CONFIG: &str = r#"
+        [database]
+        host = "postgres"
+        port = 5432
+        user = "indexer"
+        database = "indexer"
+
+        [service]
+        host_and_port = "0.0.0.0:7600"
+        url_prefix = "/"
+
+        [service.tap]
+        max_receipt_value_grt = "0.001"
+
+        [profiles.staging.database]
+        host = "postgres-staging"
+
+        [profiles.staging.service]
+        url_prefix = "/staging"
+
+        [profiles.staging.service.tap]
+        max_receipt_value_grt = "0.01"
+
+        [profiles.prod.database]
+        host = "postgres-prod"
+    "#;
+
+    /// Writes the minimal config with [PROFILES_CONFIG] on top of it
+    fn profiles_config_file() -> tempfile::NamedTempFile {
+        let mut config: toml::Value = toml::from_str(
+            fs::read_to_string("minimal-config-example.toml")
+                .unwrap()
+                .as_str(),
+        )
+        .unwrap();
+        let profiles: toml::Value = toml::from_str(PROFILES_CONFIG).unwrap();
+        let table = config.as_table_mut().unwrap();
+        for (key, value) in profiles.as_table().unwrap() {
+            table.insert(key.clone(), value.clone());
+        }
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), toml::to_string(&config).unwrap()).unwrap();
+        file
+    }
+
+    #[sealed_test(files = ["minimal-config-example.toml"])]
+    fn test_profile_deep_merge() {
+        let file = profiles_config_file();
+        let path = PathBuf::from(file.path());
+
+        let base = Config::parse(ConfigPrefix::Service, Some(&path)).unwrap();
+        assert_eq!(
+            base.database.get_formated_postgres_url().as_str(),
+            "postgres://indexer@postgres:5432/indexer"
+        );
+
+        let staging =
+            Config::parse_with_profile(ConfigPrefix::Service, Some(&path), Some("staging"))
+                .unwrap();
+        // only the overridden values change, the rest of each section is kept
+        assert_eq!(
+            staging.database.get_formated_postgres_url().as_str(),
+            "postgres://indexer@postgres-staging:5432/indexer"
+        );
+        assert_eq!(staging.service.url_prefix, "/staging");
+        assert_eq!(staging.service.host_and_port, base.service.host_and_port);
+        assert_eq!(
+            staging.service.tap.max_receipt_value_grt.get_value(),
+            10_000_000_000_000_000
+        );
+        assert_eq!(staging.indexer, base.indexer);
+        assert_eq!(staging.tap, base.tap);
+    }
+
+    #[sealed_test(files = ["minimal-config-example.toml"])]
+    fn test_profile_from_env() {
+        let file = profiles_config_file();
+        env::set_var("INDEXER_PROFILE", "prod");
+
+        let config = Config::parse(
+            ConfigPrefix::Service,
+            Some(PathBuf::from(file.path())).as_ref(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.database.get_formated_postgres_url().as_str(),
+            "postgres://indexer@postgres-prod:5432/indexer"
+        );
+        assert_eq!(config.service.url_prefix, "/");
+    }
+
+    #[sealed_test(files = ["minimal-config-example.toml"])]
+    fn test_unknown_profile() {
+        let file = profiles_config_file();
+
+        let err = Config::parse_with_profile(
+            ConfigPrefix::Service,
+            Some(PathBuf::from(file.path())).as_ref(),
+            Some("dev"),
+        )
+        .unwrap_err();
+        assert!(err.contains("Unknown configuration profile `dev`"));
+    }
+
     #[test]
     fn test_ignore_empty_values() {
         env::set_var("INDEXER_TEST1", "123");
@@ -0,0 +1,155 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Peak memory of fetching a large page of allocations, read whole with
+//! `SubgraphClient::query` or one allocation at a time with
+//! `SubgraphClient::query_stream`, both keeping the converted allocations.
+//!
+//! Run with `cargo bench -p indexer-monitor --bench query_stream_memory`.
+//! The mock subgraph serves from the same process, the copy of the body it
+//! sends is counted in both peaks alike.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloy::primitives::Address;
+use indexer_allocation::Allocation;
+use indexer_monitor::{DeploymentDetails, SubgraphClient};
+use indexer_query::allocations_query::{self, AllocationsQuery};
+use serde_json::json;
+use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+const ALLOCATIONS: usize = 100_000;
+
+/// System allocator keeping track of the bytes allocated, and their peak
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// Bytes allocated at the peak of `future`, above what was allocated before
+async fn peak_of<F: Future>(future: F) -> (F::Output, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let output = future.await;
+    (output, PEAK.load(Ordering::SeqCst) - before)
+}
+
+fn variables() -> allocations_query::Variables {
+    allocations_query::Variables {
+        indexer: Address::ZERO.to_string(),
+        closed_at_threshold: 0,
+        first: ALLOCATIONS as i64,
+        last: String::new(),
+        block: None,
+    }
+}
+
+fn mib(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let allocations = (0..ALLOCATIONS)
+        .map(|i| {
+            json!({
+                "id": format!("0x{i:040x}"),
+                "indexer": { "id": Address::ZERO.to_string() },
+                "allocatedTokens": "1000000000000000000000",
+                "createdAtBlockHash": format!("0x{i:064x}"),
+                "createdAtEpoch": 1,
+                "closedAtEpoch": null,
+                "subgraphDeployment": { "id": format!("0x{i:064x}"), "deniedAt": 0 }
+            })
+        })
+        .collect::<Vec<_>>();
+    let body = serde_json::to_vec(&json!({
+        "data": {
+            "meta": { "block": { "number": 1, "hash": null, "timestamp": null } },
+            "allocations": allocations
+        }
+    }))
+    .unwrap();
+    drop(allocations);
+    let body_len = body.len();
+
+    let mock_server = MockServer::start().await;
+    mock_server
+        .register(
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json")),
+        )
+        .await;
+    let client = SubgraphClient::new(
+        reqwest::Client::new(),
+        None,
+        DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+    )
+    .await;
+
+    let (buffered, buffered_peak) = peak_of(async {
+        let data = client
+            .query::<AllocationsQuery, _>(variables())
+            .await
+            .unwrap()
+            .unwrap();
+        data.allocations
+            .into_iter()
+            .map(|allocation| Allocation::try_from(allocation).unwrap())
+            .map(|allocation| (allocation.id, allocation))
+            .collect::<HashMap<_, _>>()
+    })
+    .await;
+    assert_eq!(buffered.len(), ALLOCATIONS);
+    drop(buffered);
+
+    let (streamed, streamed_peak) = peak_of(async {
+        let mut stream = client
+            .query_stream::<AllocationsQuery, allocations_query::AllocationFragment, _>(
+                variables(),
+                "allocations",
+            )
+            .await
+            .unwrap();
+        let mut allocations = HashMap::new();
+        while let Some(allocation) = stream.next().await {
+            let allocation = Allocation::try_from(allocation.unwrap()).unwrap();
+            allocations.insert(allocation.id, allocation);
+        }
+        stream.finish().await.unwrap();
+        allocations
+    })
+    .await;
+    assert_eq!(streamed.len(), ALLOCATIONS);
+
+    println!(
+        "{ALLOCATIONS} allocations, {:.1} MiB response body",
+        mib(body_len)
+    );
+    println!("query        peak {:>8.1} MiB", mib(buffered_peak));
+    println!("query_stream peak {:>8.1} MiB", mib(streamed_peak));
+}
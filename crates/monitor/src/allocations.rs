@@ -46,44 +46,46 @@ pub async fn get_allocations(
 
     let mut hash: Option<TxHash> = None;
     let mut last: Option<String> = None;
-    let mut responses = vec![];
-    let page_size = 200;
+    let mut allocations = HashMap::new();
+    // the largest page the network subgraph serves, allocations are converted
+    // as they arrive so the raw page is never held at once
+    let page_size = 1000;
     loop {
-        let result = network_subgraph
-            .query::<AllocationsQuery, _>(allocations_query::Variables {
-                indexer: indexer_address.to_string().to_ascii_lowercase(),
-                closed_at_threshold: closed_at_threshold.as_secs() as i64,
-                first: page_size,
-                last: last.unwrap_or_default(),
-                block: hash.map(|hash| allocations_query::Block_height {
-                    hash: Some(hash),
-                    number: None,
-                    number_gte: None,
-                }),
-            })
+        let mut stream = network_subgraph
+            .query_stream::<AllocationsQuery, allocations_query::AllocationFragment, _>(
+                allocations_query::Variables {
+                    indexer: indexer_address.to_string().to_ascii_lowercase(),
+                    closed_at_threshold: closed_at_threshold.as_secs() as i64,
+                    first: page_size,
+                    last: last.clone().unwrap_or_default(),
+                    block: hash.map(|hash| allocations_query::Block_height {
+                        hash: Some(hash),
+                        number: None,
+                        number_gte: None,
+                    }),
+                },
+                "allocations",
+            )
             .await
             .map_err(|e| anyhow::anyhow!(e.to_string()))?;
 
-        let mut data = result?;
-        let page_len = data.allocations.len();
+        let mut page_len = 0;
+        while let Some(allocation) = stream.next().await {
+            let allocation = allocation?;
+            page_len += 1;
+            last = Some(allocation.id.clone());
+            let allocation: Allocation = allocation.try_into()?;
+            allocations.insert(allocation.id, allocation);
+        }
 
+        let data = stream.finish().await?;
         hash = data.meta.and_then(|meta| meta.block.hash);
-        last = data.allocations.last().map(|entry| entry.id.to_string());
-
-        responses.append(&mut data.allocations);
-        if (page_len as i64) < page_size {
+        if page_len < page_size {
             break;
         }
     }
-    let responses = responses
-        .into_iter()
-        .map(|allocation| allocation.try_into())
-        .collect::<Result<Vec<Allocation>, _>>()?;
-
-    Ok(responses
-        .into_iter()
-        .map(|allocation| (allocation.id, allocation))
-        .collect())
+
+    Ok(allocations)
 }
 
 #[cfg(test)]
@@ -92,6 +94,10 @@ mod test {
         "https://api.thegraph.com/subgraphs/name/graphprotocol/graph-network-arbitrum";
     use std::str::FromStr;
 
+    use alloy::primitives::U256;
+    use serde_json::json;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
     use crate::client::{DeploymentDetails, SubgraphClient};
 
     use super::*;
@@ -119,6 +125,49 @@ mod test {
         assert!(result.unwrap().len() > 2000)
     }
 
+    #[tokio::test]
+    async fn test_allocations_streamed() {
+        let indexer = Address::repeat_byte(0x11);
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": {
+                        "meta": { "block": { "number": 1, "hash": null, "timestamp": null } },
+                        "allocations": (1..=3u8).map(|i| json!({
+                            "id": Address::repeat_byte(i).to_string(),
+                            "indexer": { "id": indexer.to_string() },
+                            "allocatedTokens": "1000",
+                            "createdAtBlockHash": format!("0x{}", "00".repeat(32)),
+                            "createdAtEpoch": 1,
+                            "closedAtEpoch": null,
+                            "subgraphDeployment": {
+                                "id": format!("0x{}", "ab".repeat(32)),
+                                "deniedAt": 0
+                            }
+                        })).collect::<Vec<_>>()
+                    }
+                })),
+            ))
+            .await;
+        let client = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+
+        let allocations = get_allocations(client, indexer, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(allocations.len(), 3);
+        let allocation = &allocations[&Address::repeat_byte(2)];
+        assert_eq!(allocation.indexer, indexer);
+        assert_eq!(allocation.allocated_tokens, U256::from(1000));
+    }
+
     #[tokio::test]
     #[ignore = "depends on the defunct hosted-service"]
     async fn test_network_query_empty_response() {
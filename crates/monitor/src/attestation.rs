@@ -1,39 +1,147 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use alloy::signers::local::{coins_bip39::English, MnemonicBuilder};
 use bip39::Mnemonic;
 use indexer_allocation::Allocation;
 use indexer_attestation::AttestationSigner;
 use indexer_watcher::join_and_map_watcher;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{collections::HashMap, sync::Mutex};
+use std::time::Instant;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
 use thegraph_core::{Address, ChainId};
 use tokio::sync::watch::Receiver;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::{AllocationWatcher, DisputeManagerWatcher};
 
+lazy_static! {
+    static ref WARM_ATTESTATION_SIGNERS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_warm_attestation_signers",
+        "Set to 1 for the warm allocations with a signer built ahead of their first query",
+        &["allocation"]
+    )
+    .unwrap();
+}
+
 /// Receiver for Map of allocation id and attestation signer
 pub type AttestationWatcher = Receiver<HashMap<Address, AttestationSigner>>;
 
+/// Mnemonics the attestation signers are derived from
+///
+/// While rotating operator keys, allocations opened with the previous mnemonic
+/// stay open for a while, so signers are looked for in every mnemonic. Should
+/// more than one of them match an allocation, the signer of the preferred
+/// operator is used, so the choice doesn't change from one build to the next.
+#[derive(Clone)]
+pub struct OperatorMnemonics {
+    /// operator address and phrase, newest first
+    mnemonics: Arc<Vec<(Address, String)>>,
+    /// operators to prefer over the newest one
+    priority: Arc<Vec<Address>>,
+}
+
+impl OperatorMnemonics {
+    /// `mnemonics` are listed newest first, `priority` lists the addresses of
+    /// the operators to prefer, the ones left out coming after in that order
+    pub fn new(mnemonics: Vec<Mnemonic>, priority: Vec<Address>) -> Self {
+        let mnemonics = mnemonics
+            .into_iter()
+            .map(|mnemonic| {
+                let phrase = mnemonic.to_string();
+                let operator = MnemonicBuilder::<English>::default()
+                    .phrase(phrase.as_str())
+                    .build()
+                    .expect("Valid mnemonic")
+                    .address();
+                (operator, phrase)
+            })
+            .collect();
+        Self {
+            mnemonics: Arc::new(mnemonics),
+            priority: Arc::new(priority),
+        }
+    }
+
+    fn signer(
+        &self,
+        allocation: &Allocation,
+        chain_id: ChainId,
+        dispute_manager: Address,
+    ) -> Result<AttestationSigner, anyhow::Error> {
+        let mut candidates = Vec::new();
+        let mut last_error = None;
+        for (operator, phrase) in self.mnemonics.iter() {
+            match AttestationSigner::new(phrase, allocation, chain_id, dispute_manager) {
+                Ok(signer) => candidates.push((*operator, signer)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match self.select(candidates) {
+            Some((operator, signer)) => {
+                debug!(
+                    allocation = %allocation.id,
+                    %operator,
+                    "Selected attestation signer"
+                );
+                Ok(signer)
+            }
+            None => {
+                Err(last_error
+                    .unwrap_or_else(|| anyhow::anyhow!("No operator mnemonic configured")))
+            }
+        }
+    }
+
+    /// Preferred signer among the ones matching an allocation, given newest first
+    fn select(
+        &self,
+        candidates: Vec<(Address, AttestationSigner)>,
+    ) -> Option<(Address, AttestationSigner)> {
+        candidates
+            .into_iter()
+            .enumerate()
+            .min_by_key(|(newest, (operator, _))| {
+                let priority = self
+                    .priority
+                    .iter()
+                    .position(|preferred| preferred == operator);
+                (priority.unwrap_or(usize::MAX), *newest)
+            })
+            .map(|(_, candidate)| candidate)
+    }
+}
+
+impl From<Mnemonic> for OperatorMnemonics {
+    fn from(mnemonic: Mnemonic) -> Self {
+        Self::new(vec![mnemonic], vec![])
+    }
+}
+
 /// An always up-to-date list of attestation signers, one for each of the indexer's allocations.
 pub fn attestation_signers(
     indexer_allocations_rx: AllocationWatcher,
-    indexer_mnemonic: Mnemonic,
+    indexer_mnemonics: impl Into<OperatorMnemonics>,
     chain_id: ChainId,
     dispute_manager_rx: DisputeManagerWatcher,
 ) -> AttestationWatcher {
     let attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>> =
         Box::leak(Box::new(Mutex::new(HashMap::new())));
-    let indexer_mnemonic = Arc::new(indexer_mnemonic.to_string());
+    let indexer_mnemonics = indexer_mnemonics.into();
 
     join_and_map_watcher(
         indexer_allocations_rx,
         dispute_manager_rx,
         move |(allocation, dispute)| {
-            let indexer_mnemonic = indexer_mnemonic.clone();
             modify_sigers(
-                &indexer_mnemonic,
+                &indexer_mnemonics,
                 chain_id,
                 attestation_signers_map,
                 &allocation,
@@ -43,7 +151,7 @@ pub fn attestation_signers(
     )
 }
 fn modify_sigers(
-    indexer_mnemonic: &str,
+    indexer_mnemonics: &OperatorMnemonics,
     chain_id: ChainId,
     attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>>,
     allocations: &HashMap<Address, Allocation>,
@@ -56,8 +164,7 @@ fn modify_sigers(
     // Create signers for new allocations
     for (id, allocation) in allocations.iter() {
         if !signers.contains_key(id) {
-            let signer =
-                AttestationSigner::new(indexer_mnemonic, allocation, chain_id, *dispute_manager);
+            let signer = indexer_mnemonics.signer(allocation, chain_id, *dispute_manager);
             match signer {
                 Ok(signer) => {
                     signers.insert(*id, signer);
@@ -76,8 +183,217 @@ fn modify_sigers(
     signers.clone()
 }
 
+/// Attestation signers built on the first query for an allocation.
+///
+/// Meant for allocations left out of [attestation_signers], so indexers with
+/// many allocations only pay for the ones receiving traffic. Finding the key
+/// of an allocation means deriving up to a few hundred keys from the mnemonic,
+/// which delays the first query of every allocation served this way.
+///
+/// With a capacity, only the signers of the most recently queried allocations
+/// are kept and the others are built again when queried. Signers of the
+/// allocations given to [LazyAttestationSigners::warm_up] are built ahead of
+/// their first query instead, and kept whatever the capacity.
+pub struct LazyAttestationSigners {
+    indexer_mnemonics: OperatorMnemonics,
+    chain_id: ChainId,
+    allocations: AllocationWatcher,
+    dispute_manager: DisputeManagerWatcher,
+    capacity: Option<usize>,
+    signers: Mutex<HashMap<Address, CachedSigner>>,
+    /// signers built so far
+    builds: AtomicUsize,
+}
+
+struct CachedSigner {
+    signer: AttestationSigner,
+    dispute_manager: Address,
+    last_used: Instant,
+    warm: bool,
+}
+
+impl LazyAttestationSigners {
+    pub fn new(
+        indexer_allocations_rx: AllocationWatcher,
+        indexer_mnemonics: impl Into<OperatorMnemonics>,
+        chain_id: ChainId,
+        dispute_manager_rx: DisputeManagerWatcher,
+        capacity: Option<usize>,
+    ) -> Self {
+        Self {
+            indexer_mnemonics: indexer_mnemonics.into(),
+            chain_id,
+            allocations: indexer_allocations_rx,
+            dispute_manager: dispute_manager_rx,
+            capacity,
+            signers: Mutex::new(HashMap::new()),
+            builds: AtomicUsize::new(0),
+        }
+    }
+
+    /// Keeps the signers of these allocations built, from now on and as the
+    /// allocations and the dispute manager change, in a background task
+    /// running as long as the signers are used
+    pub fn warm_up(self: &Arc<Self>, warm_allocations: HashSet<Address>) {
+        if warm_allocations.is_empty() {
+            return;
+        }
+        let signers = Arc::downgrade(self);
+        let mut allocations = self.allocations.clone();
+        let mut dispute_manager = self.dispute_manager.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(signers) = signers.upgrade() else {
+                    break;
+                };
+                signers.refresh_warm(&warm_allocations).await;
+                drop(signers);
+
+                let changed = tokio::select! {
+                    changed = allocations.changed() => changed,
+                    changed = dispute_manager.changed() => changed,
+                };
+                if changed.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Builds the signers missing among the warm allocations, and forgets the
+    /// ones of allocations that are gone
+    async fn refresh_warm(&self, warm_allocations: &HashSet<Address>) {
+        let allocations: Vec<Allocation> = self
+            .allocations
+            .borrow()
+            .iter()
+            .filter(|(id, _)| warm_allocations.contains(id))
+            .map(|(_, allocation)| allocation.clone())
+            .collect();
+        let dispute_manager = *self.dispute_manager.borrow();
+
+        for allocation in allocations {
+            if let Some(cached) = self.signers.lock().unwrap().get_mut(&allocation.id) {
+                if cached.dispute_manager == dispute_manager {
+                    cached.warm = true;
+                    continue;
+                }
+            }
+            let id = allocation.id;
+            if let Some(signer) = self.build(allocation, dispute_manager).await {
+                self.signers.lock().unwrap().insert(
+                    id,
+                    CachedSigner {
+                        signer,
+                        dispute_manager,
+                        last_used: Instant::now(),
+                        warm: true,
+                    },
+                );
+            }
+        }
+
+        let mut signers = self.signers.lock().unwrap();
+        {
+            let allocations = self.allocations.borrow();
+            signers.retain(|id, _| allocations.contains_key(id));
+        }
+        for id in warm_allocations {
+            let warm = signers.get(id).is_some_and(|cached| cached.warm);
+            if warm {
+                WARM_ATTESTATION_SIGNERS
+                    .with_label_values(&[&id.to_string()])
+                    .set(1);
+            } else {
+                let _ = WARM_ATTESTATION_SIGNERS.remove_label_values(&[&id.to_string()]);
+            }
+        }
+    }
+
+    async fn build(
+        &self,
+        allocation: Allocation,
+        dispute_manager: Address,
+    ) -> Option<AttestationSigner> {
+        self.builds.fetch_add(1, Ordering::Relaxed);
+        let indexer_mnemonics = self.indexer_mnemonics.clone();
+        let chain_id = self.chain_id;
+        let signer = tokio::task::spawn_blocking(move || {
+            indexer_mnemonics
+                .signer(&allocation, chain_id, dispute_manager)
+                .map_err(|e| (allocation, e))
+        })
+        .await
+        .ok()?;
+        match signer {
+            Ok(signer) => Some(signer),
+            Err((allocation, e)) => {
+                warn!(
+                    "Failed to establish signer for allocation {}, deployment {}, createdAtEpoch {}: {}",
+                    allocation.id, allocation.subgraph_deployment.id,
+                    allocation.created_at_epoch, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Signer of one of the indexer's allocations, building it if needed
+    pub async fn get(&self, allocation_id: &Address) -> Option<AttestationSigner> {
+        let allocation = self.allocations.borrow().get(allocation_id).cloned()?;
+        let dispute_manager = *self.dispute_manager.borrow();
+
+        if let Some(cached) = self.signers.lock().unwrap().get_mut(allocation_id) {
+            if cached.dispute_manager == dispute_manager {
+                cached.last_used = Instant::now();
+                return Some(cached.signer.clone());
+            }
+        }
+
+        let signer = self.build(allocation, dispute_manager).await?;
+
+        let mut signers = self.signers.lock().unwrap();
+        {
+            let allocations = self.allocations.borrow();
+            signers.retain(|id, _| allocations.contains_key(id));
+        }
+        // a warm signer built again after the dispute manager changed stays warm
+        let warm = signers.get(allocation_id).is_some_and(|cached| cached.warm);
+        signers.insert(
+            *allocation_id,
+            CachedSigner {
+                signer: signer.clone(),
+                dispute_manager,
+                last_used: Instant::now(),
+                warm,
+            },
+        );
+        if let Some(capacity) = self.capacity {
+            while signers.values().filter(|cached| !cached.warm).count() > capacity {
+                let Some(least_recent) = signers
+                    .iter()
+                    .filter(|(_, cached)| !cached.warm)
+                    .min_by_key(|(_, cached)| cached.last_used)
+                    .map(|(id, _)| *id)
+                else {
+                    break;
+                };
+                signers.remove(&least_recent);
+            }
+        }
+        Some(signer)
+    }
+
+    /// Allocations with a signer currently built
+    pub fn cached(&self) -> Vec<Address> {
+        self.signers.lock().unwrap().keys().copied().collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{str::FromStr, time::Duration};
+
     use tokio::sync::watch;
 
     use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
@@ -113,4 +429,154 @@ mod tests {
                 .any(|allocation_id| signer_allocation_id == allocation_id));
         }
     }
+
+    #[tokio::test]
+    async fn test_lazy_attestation_signers() {
+        let (_, allocations_rx) = watch::channel((*INDEXER_ALLOCATIONS).clone());
+        let (_, dispute_manager_rx) = watch::channel(*DISPUTE_MANAGER_ADDRESS);
+        let eager_signers = attestation_signers(
+            allocations_rx.clone(),
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx.clone(),
+        );
+        let lazy_signers = LazyAttestationSigners::new(
+            allocations_rx,
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx,
+            Some(1),
+        );
+
+        let mut allocation_ids = INDEXER_ALLOCATIONS.keys();
+        let first = *allocation_ids.next().unwrap();
+        let second = *allocation_ids.next().unwrap();
+
+        // nothing is built before the first query
+        assert!(lazy_signers.cached().is_empty());
+        assert_eq!(
+            lazy_signers.get(&first).await.as_ref(),
+            eager_signers.borrow().get(&first)
+        );
+        assert_eq!(lazy_signers.cached(), vec![first]);
+
+        // only the most recently queried signer is kept
+        assert!(lazy_signers.get(&second).await.is_some());
+        assert_eq!(lazy_signers.cached(), vec![second]);
+
+        // allocations that aren't the indexer's get no signer
+        assert!(lazy_signers.get(&Address::ZERO).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_warm_attestation_signers() {
+        let (allocations_tx, allocations_rx) = watch::channel((*INDEXER_ALLOCATIONS).clone());
+        let (_, dispute_manager_rx) = watch::channel(*DISPUTE_MANAGER_ADDRESS);
+        let lazy_signers = Arc::new(LazyAttestationSigners::new(
+            allocations_rx,
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx,
+            Some(1),
+        ));
+
+        let mut allocation_ids = INDEXER_ALLOCATIONS.keys();
+        let warm = *allocation_ids.next().unwrap();
+        let first = *allocation_ids.next().unwrap();
+        let second = *allocation_ids.next().unwrap();
+        let builds = || lazy_signers.builds.load(Ordering::Relaxed);
+        let wait_for = |cached: bool| {
+            let lazy_signers = lazy_signers.clone();
+            async move {
+                for _ in 0..100 {
+                    if lazy_signers.cached().contains(&warm) == cached {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+
+        // the warm signer is built without being queried
+        lazy_signers.warm_up(HashSet::from([warm]));
+        wait_for(true).await;
+        assert_eq!(lazy_signers.cached(), vec![warm]);
+        assert_eq!(builds(), 1);
+        assert_eq!(
+            WARM_ATTESTATION_SIGNERS
+                .with_label_values(&[&warm.to_string()])
+                .get(),
+            1
+        );
+
+        // its first query skips the build, others are still built on demand
+        assert!(lazy_signers.get(&warm).await.is_some());
+        assert_eq!(builds(), 1);
+        assert!(lazy_signers.get(&first).await.is_some());
+        assert_eq!(builds(), 2);
+
+        // the capacity is left for the signers built on demand
+        assert!(lazy_signers.get(&second).await.is_some());
+        let mut cached = lazy_signers.cached();
+        cached.sort();
+        let mut expected = vec![warm, second];
+        expected.sort();
+        assert_eq!(cached, expected);
+
+        // the signer is dropped once the allocation is gone
+        let mut allocations = (*INDEXER_ALLOCATIONS).clone();
+        allocations.remove(&warm);
+        allocations_tx.send(allocations).unwrap();
+        wait_for(false).await;
+        assert!(!lazy_signers.cached().contains(&warm));
+        assert!(WARM_ATTESTATION_SIGNERS
+            .get_metric_with_label_values(&[&warm.to_string()])
+            .is_ok_and(|gauge| gauge.get() == 0));
+    }
+
+    #[tokio::test]
+    async fn test_preferred_signer_wins() {
+        let mut allocations = INDEXER_ALLOCATIONS.values();
+        let first = allocations.next().unwrap();
+        let second = allocations.next().unwrap();
+        let newest_operator = Address::repeat_byte(1);
+        let previous_operator = Address::repeat_byte(2);
+
+        // two signers matching the same allocation, the newest coming first
+        let candidates = || {
+            vec![
+                (
+                    newest_operator,
+                    AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), first, 1, Address::ZERO)
+                        .unwrap(),
+                ),
+                (
+                    previous_operator,
+                    AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), second, 1, Address::ZERO)
+                        .unwrap(),
+                ),
+            ]
+        };
+
+        let mnemonics = OperatorMnemonics::from(INDEXER_MNEMONIC.clone());
+        let (operator, _) = mnemonics.select(candidates()).unwrap();
+        assert_eq!(operator, newest_operator);
+
+        let mnemonics =
+            OperatorMnemonics::new(vec![INDEXER_MNEMONIC.clone()], vec![previous_operator]);
+        let (operator, signer) = mnemonics.select(candidates()).unwrap();
+        assert_eq!(operator, previous_operator);
+        assert_eq!(signer, candidates()[1].1);
+
+        // the signer is found whichever mnemonic comes first
+        let other = Mnemonic::from_str(
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        )
+        .unwrap();
+        let mnemonics = OperatorMnemonics::new(vec![other, INDEXER_MNEMONIC.clone()], vec![]);
+        assert_eq!(
+            mnemonics.signer(first, 1, Address::ZERO).unwrap(),
+            candidates()[0].1
+        );
+    }
 }
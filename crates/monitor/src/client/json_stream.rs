@@ -0,0 +1,300 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Incremental splitting of a JSON array out of a document read in chunks
+//!
+//! [ArrayElements] is fed the chunks of a JSON document as they arrive and
+//! hands out the raw bytes of each element of the array found at a path of
+//! object keys, e.g. `data.allocations` of a GraphQL response, once the
+//! element is complete. Only the element being read is held, plus the rest of
+//! the document with the array left empty, which is small for query results.
+//!
+//! It only tracks the structure of the document, the elements and the rest
+//! are parsed by serde afterwards. Elements must be objects or arrays.
+
+use std::{collections::VecDeque, mem};
+
+use anyhow::{anyhow, bail};
+
+/// Container opened in the document, with the key it sits under in its
+/// parent object
+struct Container {
+    object: bool,
+    key: Option<String>,
+}
+
+/// Splits the elements of the array at `path` out of a JSON document
+pub(crate) struct ArrayElements {
+    /// object keys leading to the array
+    path: Vec<String>,
+    containers: Vec<Container>,
+    /// number of containers open inside the array, once found
+    array_depth: Option<usize>,
+    found: bool,
+    in_string: bool,
+    escaped: bool,
+    /// the next string of the current object is a key
+    expect_key: bool,
+    /// key being read, as long as the array isn't found
+    key_buffer: Option<Vec<u8>>,
+    /// key of the next value of the current object
+    key: Option<String>,
+    element: Vec<u8>,
+    rest: Vec<u8>,
+}
+
+impl ArrayElements {
+    pub(crate) fn new(path: &[&str]) -> Self {
+        Self {
+            path: path.iter().map(|key| key.to_string()).collect(),
+            containers: Vec::new(),
+            array_depth: None,
+            found: false,
+            in_string: false,
+            escaped: false,
+            expect_key: false,
+            key_buffer: None,
+            key: None,
+            element: Vec::new(),
+            rest: Vec::new(),
+        }
+    }
+
+    /// Reads `chunk`, pushing the elements completed by it to `elements`
+    pub(crate) fn feed(
+        &mut self,
+        chunk: &[u8],
+        elements: &mut VecDeque<Vec<u8>>,
+    ) -> Result<(), anyhow::Error> {
+        for &byte in chunk {
+            self.read(byte, elements)?;
+        }
+        Ok(())
+    }
+
+    /// The document without the array elements once it is read entirely, and
+    /// whether the array was found
+    pub(crate) fn finish(self) -> Result<(Vec<u8>, bool), anyhow::Error> {
+        if !self.containers.is_empty() || self.in_string {
+            bail!("JSON document ended unexpectedly");
+        }
+        Ok((self.rest, self.found))
+    }
+
+    fn in_element(&self) -> bool {
+        self.array_depth
+            .is_some_and(|depth| self.containers.len() > depth)
+    }
+
+    fn write(&mut self, byte: u8) {
+        if self.in_element() {
+            self.element.push(byte);
+        } else {
+            self.rest.push(byte);
+        }
+    }
+
+    /// Whether a container opened now, under `key`, is the array
+    fn is_array_path(&self, key: Option<&str>) -> bool {
+        !self.found
+            && self.containers.len() == self.path.len()
+            && self
+                .containers
+                .iter()
+                .skip(1)
+                .map(|container| container.key.as_deref())
+                .chain([key])
+                .eq(self.path.iter().map(|key| Some(key.as_str())))
+    }
+
+    fn read(&mut self, byte: u8, elements: &mut VecDeque<Vec<u8>>) -> Result<(), anyhow::Error> {
+        if self.in_string {
+            self.write(byte);
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                if let Some(key) = self.key_buffer.take() {
+                    self.key = Some(String::from_utf8(key)?);
+                }
+                return Ok(());
+            }
+            if let Some(key) = &mut self.key_buffer {
+                key.push(byte);
+            }
+            return Ok(());
+        }
+
+        let directly_in_array = self.array_depth == Some(self.containers.len());
+        match byte {
+            b' ' | b'\n' | b'\r' | b'\t' => {}
+            // separates the elements, which are handed out on their own
+            b',' if directly_in_array => {}
+            b']' if directly_in_array => {
+                self.containers.pop();
+                self.array_depth = None;
+                self.rest.push(byte);
+            }
+            b'{' | b'[' => {
+                let key = self.key.take();
+                if byte == b'[' && self.is_array_path(key.as_deref()) {
+                    self.rest.push(byte);
+                    self.containers.push(Container { object: false, key });
+                    self.array_depth = Some(self.containers.len());
+                    self.found = true;
+                    return Ok(());
+                }
+                self.containers.push(Container {
+                    object: byte == b'{',
+                    key,
+                });
+                self.expect_key = byte == b'{';
+                self.write(byte);
+            }
+            b'}' | b']' => {
+                self.write(byte);
+                self.containers.pop();
+                self.key = None;
+                // closing an element
+                if self.array_depth == Some(self.containers.len()) {
+                    elements.push_back(mem::take(&mut self.element));
+                }
+            }
+            _ if directly_in_array => {
+                bail!(
+                    "Expected objects or arrays in the array, got `{}`",
+                    byte as char
+                )
+            }
+            b'"' => {
+                self.in_string = true;
+                if self.expect_key {
+                    self.expect_key = false;
+                    if self.array_depth.is_none() {
+                        self.key_buffer = Some(Vec::new());
+                    }
+                }
+                self.write(byte);
+            }
+            b',' => {
+                self.expect_key = self
+                    .containers
+                    .last()
+                    .is_some_and(|container| container.object);
+                self.key = None;
+                self.write(byte);
+            }
+            _ => {
+                if self.containers.is_empty() {
+                    return Err(anyhow!("Expected a JSON object or array"));
+                }
+                self.write(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use serde_json::{json, Value};
+
+    use super::ArrayElements;
+
+    /// Elements and rest of `document`, fed `chunk_size` bytes at a time
+    fn split(document: &str, chunk_size: usize) -> (Vec<Value>, Value) {
+        let mut splitter = ArrayElements::new(&["data", "items"]);
+        let mut elements = VecDeque::new();
+        for chunk in document.as_bytes().chunks(chunk_size) {
+            splitter.feed(chunk, &mut elements).unwrap();
+        }
+        let (rest, found) = splitter.finish().unwrap();
+        assert!(found);
+        let elements = elements
+            .iter()
+            .map(|element| serde_json::from_slice(element).unwrap())
+            .collect();
+        (elements, serde_json::from_slice(&rest).unwrap())
+    }
+
+    #[test]
+    fn test_array_elements() {
+        let document = json!({
+            "data": {
+                "meta": { "block": { "number": 1, "items": [1, 2] } },
+                "items": [
+                    { "id": "a", "tags": ["]", "{"], "nested": { "items": [] } },
+                    { "id": "b\"}],", "empty": {} },
+                    [{ "id": "c" }]
+                ],
+                "after": [true, null]
+            },
+            "errors": null
+        })
+        .to_string();
+
+        // split at every position
+        for chunk_size in [1, 2, 7, document.len()] {
+            let (elements, rest) = split(&document, chunk_size);
+            assert_eq!(
+                elements,
+                vec![
+                    json!({ "id": "a", "tags": ["]", "{"], "nested": { "items": [] } }),
+                    json!({ "id": "b\"}],", "empty": {} }),
+                    json!([{ "id": "c" }]),
+                ]
+            );
+            assert_eq!(
+                rest,
+                json!({
+                    "data": {
+                        "meta": { "block": { "number": 1, "items": [1, 2] } },
+                        "items": [],
+                        "after": [true, null]
+                    },
+                    "errors": null
+                })
+            );
+        }
+
+        // whitespace is of no consequence
+        let pretty =
+            serde_json::to_string_pretty(&serde_json::from_str::<Value>(&document).unwrap())
+                .unwrap();
+        assert_eq!(split(&pretty, 3).0.len(), 3);
+    }
+
+    #[test]
+    fn test_array_missing_or_malformed() {
+        let mut splitter = ArrayElements::new(&["data", "items"]);
+        let mut elements = VecDeque::new();
+        splitter
+            .feed(
+                br#"{"data":null,"errors":[{"message":"failed"}]}"#,
+                &mut elements,
+            )
+            .unwrap();
+        let (rest, found) = splitter.finish().unwrap();
+        assert!(!found);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&rest).unwrap(),
+            json!({ "data": null, "errors": [{ "message": "failed" }] })
+        );
+
+        let mut splitter = ArrayElements::new(&["data", "items"]);
+        assert!(splitter
+            .feed(br#"{"data":{"items":[1]}}"#, &mut elements)
+            .is_err());
+
+        let mut splitter = ArrayElements::new(&["data", "items"]);
+        splitter
+            .feed(br#"{"data":{"items":[{"id":"#, &mut elements)
+            .unwrap();
+        assert!(splitter.finish().is_err());
+        assert!(elements.is_empty());
+    }
+}
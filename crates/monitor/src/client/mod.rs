@@ -1,7 +1,11 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod json_stream;
 mod monitor;
 mod subgraph_client;
 
-pub use subgraph_client::{DeploymentDetails, SubgraphClient};
+pub use subgraph_client::{
+    DeploymentDetails, QueryStream, SubgraphClient, SubgraphQueryLimit, SubgraphQueryTimeout,
+    DEFAULT_REQUEST_TIMEOUT,
+};
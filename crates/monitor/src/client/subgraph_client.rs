@@ -1,17 +1,149 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::monitor::{monitor_deployment_status, DeploymentStatus};
+use std::{collections::VecDeque, marker::PhantomData, pin::Pin, sync::Arc, time::Duration};
+
+use super::{
+    json_stream::ArrayElements,
+    monitor::{monitor_deployment_status, DeploymentStatus},
+};
 use anyhow::anyhow;
 use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
 use graphql_client::GraphQLQuery;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
 use reqwest::{header, Url};
 use thegraph_core::DeploymentId;
-use tokio::sync::watch::Receiver;
+use thiserror::Error;
+use tokio::{
+    sync::{watch::Receiver, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
 use tracing::warn;
 
+lazy_static! {
+    static ref SUBGRAPH_QUERIES_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "indexer_subgraph_queries_in_flight",
+        "Subgraph queries sent and not answered yet"
+    )
+    .unwrap();
+}
+
 pub type ResponseResult<T> = Result<T, anyhow::Error>;
 
+/// How long a single subgraph request may take unless the client is given
+/// its own timeout
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A subgraph request that didn't complete within the request timeout.
+///
+/// The endpoint may just be slow, so the query is worth retrying
+#[derive(Error, Debug)]
+#[error("Query to `{url}` timed out after {timeout:?}")]
+pub struct SubgraphQueryTimeout {
+    pub url: Url,
+    pub timeout: Duration,
+}
+
+/// Limits the queries in flight over every subgraph client sharing it, the
+/// others wait for a slot within their request timeout
+#[derive(Debug, Clone)]
+pub struct SubgraphQueryLimit(Arc<Semaphore>);
+
+impl SubgraphQueryLimit {
+    pub fn new(max_concurrent_queries: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_concurrent_queries)))
+    }
+}
+
+/// Counts a query in `indexer_subgraph_queries_in_flight` until dropped, and
+/// holds its slot of the [SubgraphQueryLimit]
+struct InFlightQuery(Option<OwnedSemaphorePermit>);
+
+impl InFlightQuery {
+    fn new(permit: Option<OwnedSemaphorePermit>) -> Self {
+        SUBGRAPH_QUERIES_IN_FLIGHT.inc();
+        Self(permit)
+    }
+}
+
+impl Drop for InFlightQuery {
+    fn drop(&mut self) {
+        SUBGRAPH_QUERIES_IN_FLIGHT.dec();
+    }
+}
+
+/// Items of a list field of a query result, deserialized one at a time as the
+/// response body is read, so the whole result is never held at once.
+///
+/// The query keeps its slot of the [SubgraphQueryLimit] until the stream is
+/// finished or dropped.
+pub struct QueryStream<Q: GraphQLQuery, T> {
+    body: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>,
+    elements: ArrayElements,
+    ready: VecDeque<Vec<u8>>,
+    ended: bool,
+    query_url: Url,
+    request_timeout: Duration,
+    _in_flight: InFlightQuery,
+    _response: PhantomData<fn() -> (Q, T)>,
+}
+
+impl<Q, T> QueryStream<Q, T>
+where
+    Q: GraphQLQuery,
+    T: serde::de::DeserializeOwned,
+{
+    /// Next item of the list, `None` once the response is read entirely
+    pub async fn next(&mut self) -> Option<Result<T, anyhow::Error>> {
+        loop {
+            if let Some(element) = self.ready.pop_front() {
+                return Some(serde_json::from_slice(&element).map_err(Into::into));
+            }
+            if self.ended {
+                return None;
+            }
+            let read = match self.body.next().await {
+                Some(Ok(chunk)) => self.elements.feed(&chunk, &mut self.ready),
+                Some(Err(error)) if error.is_timeout() => Err(SubgraphQueryTimeout {
+                    url: self.query_url.clone(),
+                    timeout: self.request_timeout,
+                }
+                .into()),
+                Some(Err(error)) => Err(error.into()),
+                None => {
+                    self.ended = true;
+                    Ok(())
+                }
+            };
+            if let Err(error) = read {
+                self.ended = true;
+                return Some(Err(error));
+            }
+        }
+    }
+
+    /// The rest of the response data, with the list left empty. Items not
+    /// taken with [QueryStream::next] yet are read and dropped
+    pub async fn finish(mut self) -> ResponseResult<Q::ResponseData> {
+        while let Some(item) = self.next().await {
+            item?;
+        }
+        let (rest, _) = self.elements.finish()?;
+        let response: graphql_client::Response<Q::ResponseData> = serde_json::from_slice(&rest)?;
+        match (response.data, response.errors) {
+            (Some(data), None) => Ok(data),
+            (None, Some(errors)) => Err(anyhow!("{errors:?}")),
+            (Some(_data), Some(err)) => Err(anyhow!("Unsupported partial results. Error: {err:?}")),
+            (None, None) => Err(anyhow!(
+                "No data or error returned for query. Endpoint: {}",
+                self.query_url.as_str()
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DeploymentDetails {
     deployment: Option<DeploymentId>,
@@ -72,6 +204,8 @@ struct DeploymentClient {
     pub status: Option<Receiver<DeploymentStatus>>,
     pub query_url: Url,
     pub query_auth_token: Option<String>,
+    pub request_timeout: Duration,
+    pub query_limit: Option<SubgraphQueryLimit>,
 }
 
 impl DeploymentClient {
@@ -93,6 +227,40 @@ impl DeploymentClient {
             },
             query_url: details.query_url,
             query_auth_token: details.query_auth_token,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            query_limit: None,
+        }
+    }
+
+    /// Waits for a slot of the query limit, if any, within the request
+    /// timeout. Returns how much of the timeout is left for the query
+    async fn start_query(&self) -> Result<(InFlightQuery, Duration), anyhow::Error> {
+        let Some(SubgraphQueryLimit(permits)) = &self.query_limit else {
+            return Ok((InFlightQuery::new(None), self.request_timeout));
+        };
+        let start = Instant::now();
+        let permit = tokio::time::timeout(self.request_timeout, permits.clone().acquire_owned())
+            .await
+            .map_err(|_| SubgraphQueryTimeout {
+                url: self.query_url.clone(),
+                timeout: self.request_timeout,
+            })?
+            .expect("query permits are never closed");
+        Ok((
+            InFlightQuery::new(Some(permit)),
+            self.request_timeout.saturating_sub(start.elapsed()),
+        ))
+    }
+
+    fn timeout_error(&self, error: reqwest::Error) -> anyhow::Error {
+        if error.is_timeout() {
+            SubgraphQueryTimeout {
+                url: self.query_url.clone(),
+                timeout: self.request_timeout,
+            }
+            .into()
+        } else {
+            error.into()
         }
     }
 
@@ -112,18 +280,23 @@ impl DeploymentClient {
         }
 
         let body = T::build_query(variables);
+        let (_in_flight, timeout) = self.start_query().await?;
         let mut req = self
             .http_client
             .post(self.query_url.as_ref())
             .header(header::USER_AGENT, "indexer-common")
+            .timeout(timeout)
             .json(&body);
 
         if let Some(token) = self.query_auth_token.as_ref() {
             req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
         }
 
-        let reqwest_response = req.send().await?;
-        let response: graphql_client::Response<T::ResponseData> = reqwest_response.json().await?;
+        let reqwest_response = req.send().await.map_err(|e| self.timeout_error(e))?;
+        let response: graphql_client::Response<T::ResponseData> = reqwest_response
+            .json()
+            .await
+            .map_err(|e| self.timeout_error(e))?;
 
         // TODO handle partial responses
         Ok(match (response.data, response.errors) {
@@ -140,7 +313,11 @@ impl DeploymentClient {
         })
     }
 
-    pub async fn query_raw(&self, body: Bytes) -> Result<reqwest::Response, anyhow::Error> {
+    pub async fn query_stream<Q: GraphQLQuery, T>(
+        &self,
+        variables: Q::Variables,
+        field: &str,
+    ) -> Result<QueryStream<Q, T>, anyhow::Error> {
         if let Some(ref status) = self.status {
             let deployment_status = status.borrow();
 
@@ -152,18 +329,65 @@ impl DeploymentClient {
             }
         }
 
+        let body = Q::build_query(variables);
+        let (in_flight, timeout) = self.start_query().await?;
+        let mut req = self
+            .http_client
+            .post(self.query_url.as_ref())
+            .header(header::USER_AGENT, "indexer-common")
+            .timeout(timeout)
+            .json(&body);
+
+        if let Some(token) = self.query_auth_token.as_ref() {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let reqwest_response = req.send().await.map_err(|e| self.timeout_error(e))?;
+        Ok(QueryStream {
+            body: Box::pin(reqwest_response.bytes_stream()),
+            elements: ArrayElements::new(&["data", field]),
+            ready: VecDeque::new(),
+            ended: false,
+            query_url: self.query_url.clone(),
+            request_timeout: self.request_timeout,
+            _in_flight: in_flight,
+            _response: PhantomData,
+        })
+    }
+
+    pub async fn query_raw(
+        &self,
+        body: Bytes,
+        headers: header::HeaderMap,
+    ) -> Result<reqwest::Response, anyhow::Error> {
+        if let Some(ref status) = self.status {
+            let deployment_status = status.borrow();
+
+            if !deployment_status.synced || &deployment_status.health != "healthy" {
+                return Err(anyhow!(
+                    "Deployment `{}` is not ready or healthy to be queried",
+                    self.query_url
+                ));
+            }
+        }
+
+        // the slot is given back once the response head is received, the
+        // caller reads the body
+        let (_in_flight, timeout) = self.start_query().await?;
         let mut req = self
             .http_client
             .post(self.query_url.as_ref())
             .header(header::USER_AGENT, "indexer-common")
             .header(header::CONTENT_TYPE, "application/json")
+            .headers(headers)
+            .timeout(timeout)
             .body(body);
 
         if let Some(token) = self.query_auth_token.as_ref() {
             req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
         }
 
-        Ok(req.send().await?)
+        req.send().await.map_err(|e| self.timeout_error(e))
     }
 }
 
@@ -188,6 +412,27 @@ impl SubgraphClient {
         }
     }
 
+    /// Bounds each request to the local and remote deployments, separately
+    /// from however often a failed query is retried. Defaults to
+    /// [DEFAULT_REQUEST_TIMEOUT]
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        if let Some(ref mut local_client) = self.local_client {
+            local_client.request_timeout = request_timeout;
+        }
+        self.remote_client.request_timeout = request_timeout;
+        self
+    }
+
+    /// Shares `query_limit` with the other clients given the same limit, e.g.
+    /// those querying the same gateway
+    pub fn with_query_limit(mut self, query_limit: SubgraphQueryLimit) -> Self {
+        if let Some(ref mut local_client) = self.local_client {
+            local_client.query_limit = Some(query_limit.clone());
+        }
+        self.remote_client.query_limit = Some(query_limit);
+        self
+    }
+
     pub async fn query<Q, V>(
         &self,
         variables: Q::Variables,
@@ -222,11 +467,61 @@ impl SubgraphClient {
             })
     }
 
+    /// Like [SubgraphClient::query], yielding the items of the list `field` of
+    /// the result one at a time as they arrive instead of reading the whole
+    /// response first. Falls back to the remote deployment if the local one
+    /// can't be queried, not once the response started coming in
+    pub async fn query_stream<Q, T, V>(
+        &self,
+        variables: Q::Variables,
+        field: &str,
+    ) -> Result<QueryStream<Q, T>, anyhow::Error>
+    where
+        Q: GraphQLQuery<Variables = V>,
+        V: Clone,
+    {
+        if let Some(ref local_client) = self.local_client {
+            match local_client
+                .query_stream::<Q, T>(variables.clone(), field)
+                .await
+            {
+                Ok(stream) => return Ok(stream),
+                Err(err) => warn!(
+                    "Failed to query local subgraph deployment `{}`, trying remote deployment next: {}",
+                    local_client.query_url, err
+                ),
+            }
+        }
+
+        self.remote_client
+            .query_stream::<Q, T>(variables, field)
+            .await
+            .map_err(|err| {
+                warn!(
+                    "Failed to query remote subgraph deployment `{}`: {}",
+                    self.remote_client.query_url, err
+                );
+
+                err
+            })
+    }
+
     pub async fn query_raw(&self, query: Bytes) -> Result<reqwest::Response, anyhow::Error> {
+        self.query_raw_with_headers(query, header::HeaderMap::new())
+            .await
+    }
+
+    /// Sends `headers` along with the query, e.g. to propagate the id of the
+    /// request the query is made for
+    pub async fn query_raw_with_headers(
+        &self,
+        query: Bytes,
+        headers: header::HeaderMap,
+    ) -> Result<reqwest::Response, anyhow::Error> {
         // Try the local client first; if that fails, log the error and move on
         // to the remote client
         if let Some(ref local_client) = self.local_client {
-            match local_client.query_raw(query.clone()).await {
+            match local_client.query_raw(query.clone(), headers.clone()).await {
                 Ok(response) => return Ok(response),
                 Err(err) => warn!(
                     "Failed to query local subgraph deployment `{}`, trying remote deployment next: {}",
@@ -236,14 +531,17 @@ impl SubgraphClient {
         }
 
         // Try the remote client
-        self.remote_client.query_raw(query).await.map_err(|err| {
-            warn!(
-                "Failed to query remote subgraph deployment `{}`: {}",
-                self.remote_client.query_url, err
-            );
+        self.remote_client
+            .query_raw(query, headers)
+            .await
+            .map_err(|err| {
+                warn!(
+                    "Failed to query remote subgraph deployment `{}`: {}",
+                    self.remote_client.query_url, err
+                );
 
-            err
-        })
+                err
+            })
     }
 }
 
@@ -309,6 +607,73 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_query_stream() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(path("/streamed"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        // any list of the data is streamed, in the query or not
+                        "data": {
+                            "user": { "name": "[{\"name\": \"no\"}]" },
+                            "users": [{ "name": "a" }, { "name": "b" }]
+                        }
+                    }))),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(path("/failed"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "data": null,
+                        "errors": [{ "message": "failed" }]
+                    }))),
+            )
+            .await;
+        let client = |route: &str| {
+            let url = format!("{}/{route}", mock_server.uri());
+            async move {
+                SubgraphClient::new(
+                    reqwest::Client::new(),
+                    None,
+                    DeploymentDetails::for_query_url(&url).unwrap(),
+                )
+                .await
+            }
+        };
+
+        let client_streamed = client("streamed").await;
+        let mut stream = client_streamed
+            .query_stream::<UserQuery, user_query::UserQueryUser, _>(
+                user_query::Variables {},
+                "users",
+            )
+            .await
+            .unwrap();
+        let mut names = Vec::new();
+        while let Some(user) = stream.next().await {
+            names.push(user.unwrap().name);
+        }
+        assert_eq!(names, vec!["a", "b"]);
+        // the rest of the data is there, the streamed list left out
+        let data = stream.finish().await.unwrap();
+        assert_eq!(data.user.name, "[{\"name\": \"no\"}]");
+
+        let client_failed = client("failed").await;
+        let mut stream = client_failed
+            .query_stream::<UserQuery, user_query::UserQueryUser, _>(
+                user_query::Variables {},
+                "users",
+            )
+            .await
+            .unwrap();
+        assert!(stream.next().await.is_none());
+        assert!(stream.finish().await.is_err());
+    }
+
     #[tokio::test]
     async fn test_uses_local_deployment_if_healthy_and_synced() {
         let deployment =
@@ -551,4 +916,97 @@ mod test {
 
         assert_eq!(data.user.name, "remote".to_string());
     }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!({ "data": { "user": { "name": "slow" } } }))
+                        .set_delay(Duration::from_secs(5)),
+                ),
+            )
+            .await;
+
+        let client = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .await
+        .with_request_timeout(Duration::from_millis(100));
+
+        let error = client
+            .query::<UserQuery, _>(user_query::Variables {})
+            .await
+            .expect_err("Query should time out");
+        let timeout = error
+            .downcast_ref::<SubgraphQueryTimeout>()
+            .expect("Should be a timeout error");
+        assert_eq!(timeout.timeout, Duration::from_millis(100));
+
+        let error = client
+            .query_raw(Bytes::from_static(b"{}"))
+            .await
+            .expect_err("Raw query should time out");
+        assert!(error.is::<SubgraphQueryTimeout>());
+    }
+
+    #[tokio::test]
+    async fn test_query_limit() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!({ "data": { "user": { "name": "slow" } } }))
+                        .set_delay(Duration::from_millis(100)),
+                ),
+            )
+            .await;
+        let client = |limit: &SubgraphQueryLimit, timeout| {
+            let limit = limit.clone();
+            let url = mock_server.uri();
+            async move {
+                SubgraphClient::new(
+                    reqwest::Client::new(),
+                    None,
+                    DeploymentDetails::for_query_url(&url).unwrap(),
+                )
+                .await
+                .with_request_timeout(timeout)
+                .with_query_limit(limit)
+            }
+        };
+
+        // both clients share a single slot, their queries run one after the other
+        let limit = SubgraphQueryLimit::new(1);
+        let first = client(&limit, Duration::from_secs(5)).await;
+        let second = client(&limit, Duration::from_secs(5)).await;
+        let start = std::time::Instant::now();
+        let (a, b, c) = tokio::join!(
+            first.query::<UserQuery, _>(user_query::Variables {}),
+            first.query::<UserQuery, _>(user_query::Variables {}),
+            second.query::<UserQuery, _>(user_query::Variables {}),
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // waiting for a slot counts towards the request timeout
+        let slow = client(&limit, Duration::from_secs(5)).await;
+        let impatient = client(&limit, Duration::from_millis(50)).await;
+        let (slow, impatient) = tokio::join!(
+            slow.query::<UserQuery, _>(user_query::Variables {}),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                impatient
+                    .query::<UserQuery, _>(user_query::Variables {})
+                    .await
+            },
+        );
+        assert!(slow.is_ok());
+        assert!(impatient.unwrap_err().is::<SubgraphQueryTimeout>());
+    }
 }
@@ -4,18 +4,31 @@
 use std::{
     collections::{HashMap, HashSet},
     str::FromStr,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use alloy::primitives::{Address, U256};
 use anyhow::{anyhow, Result};
 use indexer_query::escrow_account::{self, EscrowAccountQuery};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, Gauge};
+use reqwest::Url;
+use serde::Deserialize;
 use thiserror::Error;
-use tokio::sync::watch::Receiver;
+use tokio::sync::{watch::Receiver, Notify};
 use tracing::{error, warn};
 
 use crate::client::SubgraphClient;
 
+lazy_static! {
+    static ref ESCROW_STALENESS_SECONDS: Gauge = register_gauge!(
+        "indexer_escrow_staleness_seconds",
+        "Seconds since the escrow accounts were last read, while reading them fails"
+    )
+    .unwrap();
+}
+
 #[derive(Error, Debug)]
 pub enum EscrowAccountsError {
     #[error("No signer found for sender {sender}")]
@@ -89,18 +102,244 @@ impl EscrowAccounts {
 
 pub type EscrowAccountsWatcher = Receiver<EscrowAccounts>;
 
+/// Where the escrow accounts of the indexer are read from
+///
+/// The balances and signers of the senders are taken from the escrow subgraph
+/// by default, operators indexing the escrow events themselves can provide
+/// their own source.
+#[async_trait::async_trait]
+pub trait EscrowSource: Send + Sync {
+    /// Current balance and signers of every sender with an escrow for the indexer
+    async fn escrow_accounts(&self) -> Result<EscrowAccounts>;
+}
+
+/// Reads the escrow accounts from the escrow subgraph
+pub struct SubgraphEscrowSource {
+    escrow_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    reject_thawing_signers: bool,
+}
+
+impl SubgraphEscrowSource {
+    pub fn new(
+        escrow_subgraph: &'static SubgraphClient,
+        indexer_address: Address,
+        reject_thawing_signers: bool,
+    ) -> Self {
+        Self {
+            escrow_subgraph,
+            indexer_address,
+            reject_thawing_signers,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EscrowSource for SubgraphEscrowSource {
+    async fn escrow_accounts(&self) -> Result<EscrowAccounts> {
+        get_escrow_accounts(
+            self.escrow_subgraph,
+            self.indexer_address,
+            self.reject_thawing_signers,
+        )
+        .await
+    }
+}
+
+/// Reads the escrow accounts from an HTTP endpoint, e.g. an indexer of the
+/// escrow events run by the operator
+///
+/// The endpoint answers GET requests with the accounts of the indexer, as
+/// `[{"sender": "0x…", "balance": "1000", "signers": ["0x…"]}]`. The balance is
+/// what is left to spend, thawing amounts taken out.
+pub struct HttpEscrowSource {
+    http_client: reqwest::Client,
+    url: Url,
+}
+
+impl HttpEscrowSource {
+    pub fn new(http_client: reqwest::Client, url: Url) -> Self {
+        Self { http_client, url }
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpEscrowAccount {
+    sender: Address,
+    balance: U256,
+    signers: Vec<Address>,
+}
+
+#[async_trait::async_trait]
+impl EscrowSource for HttpEscrowSource {
+    async fn escrow_accounts(&self) -> Result<EscrowAccounts> {
+        let accounts: Vec<HttpEscrowAccount> = self
+            .http_client
+            .get(self.url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let senders_balances = accounts
+            .iter()
+            .map(|account| (account.sender, account.balance))
+            .collect();
+        let senders_to_signers = accounts
+            .into_iter()
+            .map(|account| (account.sender, account.signers))
+            .collect();
+        Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
+    }
+}
+
+/// How fresh the escrow accounts read through a [FreshnessTrackingSource] are
+#[derive(Clone)]
+pub struct EscrowFreshness(Arc<Mutex<FreshnessState>>);
+
+struct FreshnessState {
+    last_success: Instant,
+    failing: bool,
+}
+
+impl EscrowFreshness {
+    /// How long ago the accounts were last read while reading them fails, zero
+    /// while they are read fine
+    pub fn staleness(&self) -> Duration {
+        let state = self.0.lock().unwrap();
+        if state.failing {
+            state.last_success.elapsed()
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Exported as a metric on every read, which the escrow accounts are
+    /// retried at whether queries come in or not
+    fn record(&self, success: bool) {
+        let mut state = self.0.lock().unwrap();
+        if success {
+            state.last_success = Instant::now();
+        }
+        state.failing = !success;
+        let staleness = if success {
+            Duration::ZERO
+        } else {
+            state.last_success.elapsed()
+        };
+        ESCROW_STALENESS_SECONDS.set(staleness.as_secs_f64());
+    }
+}
+
+/// Records in an [EscrowFreshness] whether the accounts of the source it wraps
+/// could be read, and since when they couldn't
+pub struct FreshnessTrackingSource {
+    source: Arc<dyn EscrowSource>,
+    freshness: EscrowFreshness,
+}
+
+impl FreshnessTrackingSource {
+    pub fn new(source: Arc<dyn EscrowSource>) -> (Self, EscrowFreshness) {
+        let freshness = EscrowFreshness(Arc::new(Mutex::new(FreshnessState {
+            last_success: Instant::now(),
+            failing: false,
+        })));
+        (
+            Self {
+                source,
+                freshness: freshness.clone(),
+            },
+            freshness,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl EscrowSource for FreshnessTrackingSource {
+    async fn escrow_accounts(&self) -> Result<EscrowAccounts> {
+        let accounts = self.source.escrow_accounts().await;
+        self.freshness.record(accounts.is_ok());
+        accounts
+    }
+}
+
 pub async fn escrow_accounts(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     reject_thawing_signers: bool,
+) -> Result<EscrowAccountsWatcher, anyhow::Error> {
+    escrow_accounts_from_source(
+        Arc::new(SubgraphEscrowSource::new(
+            escrow_subgraph,
+            indexer_address,
+            reject_thawing_signers,
+        )),
+        interval,
+    )
+    .await
+}
+
+/// Same as [escrow_accounts], reading the accounts from any source
+pub async fn escrow_accounts_from_source(
+    source: Arc<dyn EscrowSource>,
+    interval: Duration,
 ) -> Result<EscrowAccountsWatcher, anyhow::Error> {
     indexer_watcher::new_watcher(interval, move || {
-        get_escrow_accounts(escrow_subgraph, indexer_address, reject_thawing_signers)
+        let source = source.clone();
+        async move { source.escrow_accounts().await }
     })
     .await
 }
 
+/// Fetches the escrow accounts out of cycle, at most once per `debounce`
+/// however often it's asked to
+#[derive(Clone)]
+pub struct EscrowAccountsRefresh {
+    trigger: Arc<Notify>,
+    debounce: Duration,
+    last_refresh: Arc<Mutex<Option<Instant>>>,
+}
+
+impl EscrowAccountsRefresh {
+    pub fn new(trigger: Arc<Notify>, debounce: Duration) -> Self {
+        Self {
+            trigger,
+            debounce,
+            last_refresh: Default::default(),
+        }
+    }
+
+    /// Requests a refresh, unless one was requested less than `debounce` ago.
+    /// Returns whether it was requested
+    pub fn request(&self) -> bool {
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+        if last_refresh.is_some_and(|last_refresh| last_refresh.elapsed() < self.debounce) {
+            return false;
+        }
+        *last_refresh = Some(Instant::now());
+        self.trigger.notify_one();
+        true
+    }
+}
+
+/// Same as [escrow_accounts_from_source], the accounts can also be refreshed
+/// on demand
+pub async fn escrow_accounts_with_refresh(
+    source: Arc<dyn EscrowSource>,
+    interval: Duration,
+    debounce: Duration,
+) -> Result<(EscrowAccountsWatcher, EscrowAccountsRefresh), anyhow::Error> {
+    let trigger = Arc::new(Notify::new());
+    let accounts =
+        indexer_watcher::new_watcher_with_trigger(interval, trigger.clone(), move || {
+            let source = source.clone();
+            async move { source.escrow_accounts().await }
+        })
+        .await?;
+    Ok((accounts, EscrowAccountsRefresh::new(trigger, debounce)))
+}
+
 async fn get_escrow_accounts(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
@@ -237,4 +476,144 @@ mod tests {
             )
         );
     }
+
+    #[test(tokio::test)]
+    async fn test_refresh_accounts() {
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&format!(
+                    "{}/subgraphs/id/{}",
+                    &mock_server.uri(),
+                    *test_assets::ESCROW_SUBGRAPH_DEPLOYMENT
+                ))
+                .unwrap(),
+            )
+            .await,
+        ));
+
+        // the sender only creates its escrow after the first two syncs
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .respond_with(
+                        ResponseTemplate::new(200).set_body_raw(
+                            r#"{"data": {"escrowAccounts": []}}"#,
+                            "application/json",
+                        ),
+                    )
+                    .up_to_n_times(2)
+                    .with_priority(1),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_raw(test_assets::ESCROW_QUERY_RESPONSE, "application/json"),
+                ),
+            )
+            .await;
+
+        let (mut accounts, refresh) = escrow_accounts_with_refresh(
+            Arc::new(SubgraphEscrowSource::new(
+                escrow_subgraph,
+                *test_assets::INDEXER_ADDRESS,
+                true,
+            )),
+            Duration::from_secs(600),
+            Duration::from_secs(600),
+        )
+        .await
+        .unwrap();
+        accounts.changed().await.unwrap();
+        assert_eq!(accounts.borrow().clone(), EscrowAccounts::default());
+
+        assert!(refresh.request());
+        accounts.changed().await.unwrap();
+        assert_eq!(
+            accounts.borrow().clone(),
+            EscrowAccounts::new(
+                ESCROW_ACCOUNTS_BALANCES.to_owned(),
+                ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+            )
+        );
+
+        // debounced
+        assert!(!refresh.request());
+    }
+
+    /// Serves the accounts it is given, as an indexer of the escrow events would
+    struct MockEscrowSource(Mutex<EscrowAccounts>);
+
+    #[async_trait::async_trait]
+    impl EscrowSource for MockEscrowSource {
+        async fn escrow_accounts(&self) -> Result<EscrowAccounts> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_http_source() {
+        let mock_server = MockServer::start().await;
+        let (sender, signers) = ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.iter().next().unwrap();
+        let balance = ESCROW_ACCOUNTS_BALANCES[sender];
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .and(path("/escrow-accounts"))
+                    .respond_with(
+                        ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                            "sender": sender,
+                            "balance": balance.to_string(),
+                            "signers": signers,
+                        }])),
+                    ),
+            )
+            .await;
+
+        let source = HttpEscrowSource::new(
+            reqwest::Client::new(),
+            format!("{}/escrow-accounts", mock_server.uri())
+                .parse()
+                .unwrap(),
+        );
+        let accounts = source.escrow_accounts().await.unwrap();
+        assert_eq!(accounts.get_balance_for_sender(sender).unwrap(), balance);
+        assert_eq!(accounts.get_signers_for_sender(sender), *signers);
+        assert_eq!(
+            accounts.get_sender_for_signer(&signers[0]).unwrap(),
+            *sender
+        );
+
+        // a failing endpoint fails the read
+        let failing = HttpEscrowSource::new(
+            reqwest::Client::new(),
+            format!("{}/missing", mock_server.uri()).parse().unwrap(),
+        );
+        assert!(failing.escrow_accounts().await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_accounts_from_source() {
+        let source = Arc::new(MockEscrowSource(Mutex::new(EscrowAccounts::default())));
+        let (mut accounts, refresh) =
+            escrow_accounts_with_refresh(source.clone(), Duration::from_secs(600), Duration::ZERO)
+                .await
+                .unwrap();
+        // the first tick of the interval
+        accounts.changed().await.unwrap();
+        assert_eq!(accounts.borrow().clone(), EscrowAccounts::default());
+
+        let expected = EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        *source.0.lock().unwrap() = expected.clone();
+        assert!(refresh.request());
+        accounts.changed().await.unwrap();
+        assert_eq!(accounts.borrow().clone(), expected);
+    }
 }
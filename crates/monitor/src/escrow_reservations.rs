@@ -0,0 +1,170 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::{Address, U256};
+
+/// Reservations held for a sender at once, the one expiring first is dropped
+/// for a new one past it
+const MAX_RESERVATIONS_PER_SENDER: usize = 100;
+
+/// Escrow value held for senders between a quote and the receipt paying it.
+///
+/// A reservation is made when a query is quoted, and is fulfilled by the first
+/// receipt of the sender for that exact value. Reservations that aren't
+/// fulfilled within their ttl expire and stop holding the sender's balance.
+#[derive(Clone, Default)]
+pub struct EscrowReservations {
+    reservations: Arc<Mutex<HashMap<Address, Vec<Reservation>>>>,
+}
+
+struct Reservation {
+    value: U256,
+    expires_at: Instant,
+}
+
+impl EscrowReservations {
+    /// Holds `value` of the sender's escrow for up to `ttl`
+    pub fn reserve(&self, sender: Address, value: U256, ttl: Duration) {
+        let mut reservations = self.reservations.lock().unwrap();
+        let sender_reservations = reservations.entry(sender).or_default();
+        prune(sender_reservations);
+        if sender_reservations.len() >= MAX_RESERVATIONS_PER_SENDER {
+            if let Some(index) = sender_reservations
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, reservation)| reservation.expires_at)
+                .map(|(index, _)| index)
+            {
+                sender_reservations.swap_remove(index);
+            }
+        }
+        sender_reservations.push(Reservation {
+            value,
+            expires_at: Instant::now() + ttl,
+        });
+    }
+
+    /// Whether a receipt of this value would pay for a reservation
+    pub fn holds(&self, sender: &Address, value: U256) -> bool {
+        let now = Instant::now();
+        self.reservations
+            .lock()
+            .unwrap()
+            .get(sender)
+            .is_some_and(|reservations| {
+                reservations
+                    .iter()
+                    .any(|reservation| reservation.value == value && reservation.expires_at > now)
+            })
+    }
+
+    /// Releases the reservation a receipt of this value pays for, the one
+    /// expiring first if there are several. Returns whether there was one
+    pub fn fulfill(&self, sender: &Address, value: U256) -> bool {
+        let mut reservations = self.reservations.lock().unwrap();
+        let Some(sender_reservations) = reservations.get_mut(sender) else {
+            return false;
+        };
+        prune(sender_reservations);
+        let fulfilled = sender_reservations
+            .iter()
+            .enumerate()
+            .filter(|(_, reservation)| reservation.value == value)
+            .min_by_key(|(_, reservation)| reservation.expires_at)
+            .map(|(index, _)| index);
+        if let Some(index) = fulfilled {
+            sender_reservations.swap_remove(index);
+        }
+        if sender_reservations.is_empty() {
+            reservations.remove(sender);
+        }
+        fulfilled.is_some()
+    }
+
+    /// Value of the sender's escrow currently held by reservations
+    pub fn reserved(&self, sender: &Address) -> U256 {
+        let mut reservations = self.reservations.lock().unwrap();
+        let Some(sender_reservations) = reservations.get_mut(sender) else {
+            return U256::ZERO;
+        };
+        prune(sender_reservations);
+        let reserved = sender_reservations
+            .iter()
+            .fold(U256::ZERO, |total, reservation| {
+                total.saturating_add(reservation.value)
+            });
+        if sender_reservations.is_empty() {
+            reservations.remove(sender);
+        }
+        reserved
+    }
+}
+
+fn prune(reservations: &mut Vec<Reservation>) {
+    let now = Instant::now();
+    reservations.retain(|reservation| reservation.expires_at > now);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use alloy::primitives::{Address, U256};
+
+    use super::{EscrowReservations, MAX_RESERVATIONS_PER_SENDER};
+
+    const SENDER: Address = Address::repeat_byte(1);
+
+    #[test]
+    fn test_reserve_and_fulfill() {
+        let reservations = EscrowReservations::default();
+        reservations.reserve(SENDER, U256::from(10), Duration::from_secs(60));
+        reservations.reserve(SENDER, U256::from(5), Duration::from_secs(60));
+        assert_eq!(reservations.reserved(&SENDER), U256::from(15));
+        assert_eq!(reservations.reserved(&Address::ZERO), U256::ZERO);
+
+        // only a receipt of the quoted value pays for the reservation
+        assert!(reservations.holds(&SENDER, U256::from(10)));
+        assert!(!reservations.holds(&SENDER, U256::from(7)));
+        assert!(!reservations.fulfill(&SENDER, U256::from(7)));
+        assert!(reservations.fulfill(&SENDER, U256::from(10)));
+        assert_eq!(reservations.reserved(&SENDER), U256::from(5));
+        assert!(!reservations.fulfill(&SENDER, U256::from(10)));
+    }
+
+    #[test]
+    fn test_reservations_per_sender_are_capped() {
+        let reservations = EscrowReservations::default();
+        reservations.reserve(SENDER, U256::from(1), Duration::from_secs(10));
+        for _ in 1..MAX_RESERVATIONS_PER_SENDER {
+            reservations.reserve(SENDER, U256::from(2), Duration::from_secs(60));
+        }
+        reservations.reserve(SENDER, U256::from(3), Duration::from_secs(60));
+
+        // the reservation expiring first made room for the last one
+        assert!(!reservations.holds(&SENDER, U256::from(1)));
+        assert!(reservations.holds(&SENDER, U256::from(3)));
+        assert_eq!(
+            reservations.reserved(&SENDER),
+            U256::from(2 * (MAX_RESERVATIONS_PER_SENDER - 1) + 3)
+        );
+    }
+
+    #[test]
+    fn test_reservation_expires() {
+        let reservations = EscrowReservations::default();
+        reservations.reserve(SENDER, U256::from(10), Duration::from_millis(50));
+        reservations.reserve(SENDER, U256::from(5), Duration::from_secs(60));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(reservations.reserved(&SENDER), U256::from(5));
+        // a late receipt no longer finds its reservation
+        assert!(!reservations.fulfill(&SENDER, U256::from(10)));
+    }
+}
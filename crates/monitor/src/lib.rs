@@ -7,14 +7,23 @@ mod client;
 mod deployment_to_allocation;
 mod dispute_manager;
 mod escrow_accounts;
+mod escrow_reservations;
 
 pub use crate::{
     allocations::{indexer_allocations, AllocationWatcher},
-    attestation::{attestation_signers, AttestationWatcher},
-    client::{DeploymentDetails, SubgraphClient},
+    attestation::{
+        attestation_signers, AttestationWatcher, LazyAttestationSigners, OperatorMnemonics,
+    },
+    client::{
+        DeploymentDetails, QueryStream, SubgraphClient, SubgraphQueryLimit, SubgraphQueryTimeout,
+        DEFAULT_REQUEST_TIMEOUT,
+    },
     deployment_to_allocation::{deployment_to_allocation, DeploymentToAllocationWatcher},
     dispute_manager::{dispute_manager, DisputeManagerWatcher},
     escrow_accounts::{
-        escrow_accounts, EscrowAccounts, EscrowAccountsError, EscrowAccountsWatcher,
+        escrow_accounts, escrow_accounts_from_source, escrow_accounts_with_refresh, EscrowAccounts,
+        EscrowAccountsError, EscrowAccountsRefresh, EscrowAccountsWatcher, EscrowFreshness,
+        EscrowSource, FreshnessTrackingSource, HttpEscrowSource, SubgraphEscrowSource,
     },
+    escrow_reservations::EscrowReservations,
 };
@@ -99,6 +99,22 @@ pub struct DeploymentStatusQuery;
 )]
 pub struct UnfinalizedTransactions;
 
+pub mod rav_redemptions {
+    use graphql_client::GraphQLQuery;
+
+    type Bytes = String;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/tap.schema.graphql",
+        query_path = "graphql/rav_redemptions.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct RavRedemptions;
+    pub use rav_redemptions::*;
+}
+
 pub mod closed_allocations {
     use graphql_client::GraphQLQuery;
 
@@ -5,4 +5,12 @@ use build_info_build::DependencyDepth;
 
 fn main() {
     build_info_build::build_script().collect_dependencies(DependencyDepth::Depth(1));
+
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc found");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/query.proto")
+            .expect("Failed to compile the gRPC service");
+    }
 }
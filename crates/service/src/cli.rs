@@ -12,4 +12,8 @@ pub struct Cli {
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/service for examples.
     #[arg(long, value_name = "FILE", verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+    /// Name of the `[profiles.<name>]` table of the configuration file to merge on top of it.
+    /// Defaults to the `INDEXER_PROFILE` environment variable.
+    #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+    pub profile: Option<String>,
 }
@@ -0,0 +1,210 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measures the drift of the host clock from a reference.
+//!
+//! The timestamp checks of the receipts and the RAV windows compare against
+//! the host clock, a host clock off by more than their tolerance makes them
+//! refuse good receipts or accept stale ones without any error. The drift is
+//! exported as a metric, and past the configured threshold the service either
+//! warns or refuses paid queries until the clock is back in sync.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, ensure};
+use axum::body::Bytes;
+use indexer_config::ClockDriftAction;
+use indexer_monitor::SubgraphClient;
+use serde_json::Value;
+use tokio::{net::UdpSocket, sync::watch};
+use tracing::{info, warn};
+
+use crate::metrics::CLOCK_DRIFT_SECONDS;
+
+const LATEST_BLOCK_TIMESTAMP_QUERY: &str = r#"{"query": "{ _meta { block { timestamp } } }"}"#;
+
+/// Seconds between the NTP era, 1900, and the unix epoch
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A time the host clock is compared with
+#[async_trait::async_trait]
+pub trait ClockReference: Send + Sync {
+    async fn now(&self) -> anyhow::Result<SystemTime>;
+}
+
+/// Asks an NTP server for the time, with a single SNTP request
+pub struct NtpReference {
+    server: String,
+}
+
+impl NtpReference {
+    pub fn new(server: String) -> Self {
+        Self { server }
+    }
+}
+
+#[async_trait::async_trait]
+impl ClockReference for NtpReference {
+    async fn now(&self) -> anyhow::Result<SystemTime> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&self.server).await?;
+        // version 3, client mode
+        let mut packet = [0u8; 48];
+        packet[0] = 0x1b;
+        let sent_at = Instant::now();
+        socket.send(&packet).await?;
+        let len = tokio::time::timeout(NTP_TIMEOUT, socket.recv(&mut packet))
+            .await
+            .map_err(|_| anyhow!("NTP server `{}` did not answer", self.server))??;
+        ensure!(len >= 48, "NTP response of {len} bytes is too short");
+        let round_trip = sent_at.elapsed();
+
+        // transmit timestamp, the time the server answered
+        let secs = u32::from_be_bytes(packet[40..44].try_into()?) as u64;
+        let fraction = u32::from_be_bytes(packet[44..48].try_into()?) as u64;
+        let secs = secs
+            .checked_sub(NTP_UNIX_OFFSET_SECS)
+            .ok_or_else(|| anyhow!("NTP server `{}` sent an invalid time", self.server))?;
+        let nanos = (fraction * 1_000_000_000) >> 32;
+        // the answer took about half of the round trip to arrive
+        Ok(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_nanos(nanos) + round_trip / 2)
+    }
+}
+
+/// Takes the timestamp of the latest block indexed by a subgraph
+pub struct SubgraphReference(pub &'static SubgraphClient);
+
+#[async_trait::async_trait]
+impl ClockReference for SubgraphReference {
+    async fn now(&self) -> anyhow::Result<SystemTime> {
+        let response = self
+            .0
+            .query_raw(Bytes::from_static(LATEST_BLOCK_TIMESTAMP_QUERY.as_bytes()))
+            .await?;
+        let body: Value = response.json().await?;
+        let timestamp = body["data"]["_meta"]["block"]["timestamp"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("No block timestamp in the `_meta` response: {body}"))?;
+        Ok(UNIX_EPOCH + Duration::from_secs(timestamp))
+    }
+}
+
+/// Seconds `local` is ahead of `reference`, negative when behind
+fn drift_secs(local: SystemTime, reference: SystemTime) -> f64 {
+    match local.duration_since(reference) {
+        Ok(ahead) => ahead.as_secs_f64(),
+        Err(behind) => -behind.duration().as_secs_f64(),
+    }
+}
+
+/// Measures the drift of `clock` right away and then on every interval. The
+/// returned receiver is `true` while paid queries should be refused
+pub fn spawn_clock_drift_monitor(
+    reference: Arc<dyn ClockReference>,
+    clock: fn() -> SystemTime,
+    interval: Duration,
+    max_drift: Duration,
+    action: ClockDriftAction,
+) -> watch::Receiver<bool> {
+    let (drifted_tx, drifted_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut drifted = false;
+        loop {
+            interval.tick().await;
+            let reference_now = match reference.now().await {
+                Ok(reference_now) => reference_now,
+                Err(e) => {
+                    // keep the last verdict instead of guessing
+                    warn!(error = %e, "Failed to read the reference clock");
+                    continue;
+                }
+            };
+            let drift = drift_secs(clock(), reference_now);
+            CLOCK_DRIFT_SECONDS.set(drift);
+
+            let exceeded = drift.abs() > max_drift.as_secs_f64();
+            match (exceeded, drifted) {
+                (true, false) => warn!(
+                    drift_secs = drift,
+                    max_drift_secs = max_drift.as_secs_f64(),
+                    "HOST CLOCK DRIFT: receipt timestamp checks can't be trusted"
+                ),
+                (false, true) => info!(drift_secs = drift, "Host clock is back in sync"),
+                _ => {}
+            }
+            drifted = exceeded;
+            drifted_tx.send_if_modified(|refused| {
+                let refuse = drifted && action == ClockDriftAction::Refuse;
+                std::mem::replace(refused, refuse) != refuse
+            });
+        }
+    });
+    drifted_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use indexer_config::ClockDriftAction;
+
+    use super::{spawn_clock_drift_monitor, ClockReference};
+    use crate::metrics::CLOCK_DRIFT_SECONDS;
+
+    const NOW: Duration = Duration::from_secs(1_700_000_000);
+
+    fn clock() -> SystemTime {
+        UNIX_EPOCH + NOW
+    }
+
+    /// Reference a given number of seconds behind the host clock
+    struct Behind(u64);
+
+    #[async_trait::async_trait]
+    impl ClockReference for Behind {
+        async fn now(&self) -> anyhow::Result<SystemTime> {
+            Ok(UNIX_EPOCH + NOW - Duration::from_secs(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refuses_when_clock_drifts() {
+        let spawn = |behind: u64, action: ClockDriftAction| {
+            spawn_clock_drift_monitor(
+                Arc::new(Behind(behind)),
+                clock,
+                Duration::from_secs(60),
+                Duration::from_secs(5),
+                action,
+            )
+        };
+
+        // within the tolerance, nothing to refuse
+        let mut in_sync = spawn(2, ClockDriftAction::Refuse);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!*in_sync.borrow_and_update());
+        assert_eq!(CLOCK_DRIFT_SECONDS.get(), 2.0);
+
+        let mut drifted = spawn(30, ClockDriftAction::Refuse);
+        tokio::time::timeout(Duration::from_secs(5), drifted.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(*drifted.borrow());
+        assert_eq!(CLOCK_DRIFT_SECONDS.get(), 30.0);
+
+        // only a warning
+        let mut warned = spawn(30, ClockDriftAction::Warn);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!*warned.borrow_and_update());
+    }
+}
@@ -6,19 +6,77 @@ pub mod dips;
 
 use std::time::Duration;
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sqlx::{postgres::PgPoolOptions, Executor, PgPool};
 use tracing::debug;
 
 const DATABASE_TIMEOUT: Duration = Duration::from_secs(30);
-const DATABASE_MAX_CONNECTIONS: u32 = 50;
+pub const DATABASE_MAX_CONNECTIONS: u32 = 50;
 
-pub async fn connect(url: &str) -> PgPool {
+/// SQLSTATE of statements cancelled by Postgres, `query_canceled`
+const QUERY_CANCELED: &str = "57014";
+
+pub async fn connect(url: &str, statement_timeout: Option<Duration>) -> PgPool {
     debug!("Connecting to database");
 
-    PgPoolOptions::new()
-        .max_connections(DATABASE_MAX_CONNECTIONS)
-        .acquire_timeout(DATABASE_TIMEOUT)
+    pool_options(statement_timeout)
         .connect(url)
         .await
         .expect("Should be able to connect to the database")
 }
+
+/// Pool options, having Postgres cancel the statements of every connection
+/// running longer than `statement_timeout`
+fn pool_options(statement_timeout: Option<Duration>) -> PgPoolOptions {
+    let options = PgPoolOptions::new()
+        .max_connections(DATABASE_MAX_CONNECTIONS)
+        .acquire_timeout(DATABASE_TIMEOUT);
+    let Some(statement_timeout) = statement_timeout else {
+        return options;
+    };
+    let statement = format!("SET statement_timeout = {}", statement_timeout.as_millis());
+    options.after_connect(move |conn, _| {
+        let statement = statement.clone();
+        Box::pin(async move {
+            conn.execute(statement.as_str()).await?;
+            Ok(())
+        })
+    })
+}
+
+/// Whether the statement was cancelled by Postgres, which is what happens to
+/// statements running past the statement timeout
+pub fn is_statement_timeout(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .and_then(|error| error.code())
+        .is_some_and(|code| code == QUERY_CANCELED)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use super::{is_statement_timeout, pool_options};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_statement_timeout(pgpool: PgPool) {
+        let pool = pool_options(Some(Duration::from_millis(100)))
+            .connect_with((*pgpool.connect_options()).clone())
+            .await
+            .unwrap();
+
+        sqlx::query("SELECT pg_sleep(0.01)")
+            .execute(&pool)
+            .await
+            .expect("fast statements should complete");
+        let error = sqlx::query("SELECT pg_sleep(5)")
+            .execute(&pool)
+            .await
+            .unwrap_err();
+        assert!(is_statement_timeout(&error));
+        // the connection stays usable
+        sqlx::query("SELECT 1").execute(&pool).await.unwrap();
+    }
+}
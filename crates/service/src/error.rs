@@ -1,7 +1,7 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::convert::Infallible;
+use std::{convert::Infallible, time::Duration};
 
 use anyhow::Error;
 use axum::{
@@ -9,17 +9,25 @@ use axum::{
     Json,
 };
 use indexer_monitor::EscrowAccountsError;
-use reqwest::StatusCode;
+use reqwest::{
+    header::{HeaderValue, RETRY_AFTER},
+    StatusCode,
+};
 use serde::Serialize;
 use tap_core::receipt::ReceiptError;
 use tap_core::Error as TapError;
-use thegraph_core::DeploymentId;
+use thegraph_core::{Address, DeploymentId};
 use thiserror::Error;
+use tracing::warn;
+
+use crate::{middleware::PermanentFailure, service::ReceiptFieldError, tap::AdapterError};
 
 #[derive(Debug, Error)]
 pub enum IndexerServiceError {
     #[error("No Tap receipt was found in the request")]
     ReceiptNotFound,
+    #[error("Request was rejected by the authenticator")]
+    Unauthorized,
     #[error("Could not find deployment id")]
     DeploymentIdNotFound,
     #[error(transparent)]
@@ -29,9 +37,97 @@ pub enum IndexerServiceError {
     SerializationError(#[from] serde_json::Error),
 
     #[error("Issues with provided receipt: {0}")]
-    TapCoreError(#[from] tap_core::Error),
+    TapCoreError(tap_core::Error),
     #[error("There was an error while accessing escrow account: {0}")]
     EscrowAccount(#[from] EscrowAccountsError),
+    #[error("Service is overloaded, please retry later")]
+    ServiceNotReady,
+    #[error("Paid queries are temporarily disabled, please retry later")]
+    SafeMode,
+    #[error("Invalid request body")]
+    InvalidRequest(Option<InvalidRequestDetails>),
+    #[error("Invalid request body: empty query")]
+    EmptyQuery,
+    #[error("Gateway version `{0}` is not supported")]
+    GatewayVersionUnsupported(String),
+    #[error("Unsupported content type: `{}`", .0.as_deref().unwrap_or("none"))]
+    UnsupportedContentType(Option<String>),
+    #[error("Feature `{0}` can't be enabled")]
+    UnsupportedFeature(String),
+    #[error("Invalid deployment id: `{0}`")]
+    InvalidDeploymentId(String),
+    #[error("Invalid receipt token: `{0}`")]
+    InvalidReceiptToken(String),
+    #[error("Malformed receipt: {0}")]
+    MalformedReceipt(ReceiptFieldError),
+    #[error("Deployment `{0}` is not served")]
+    DeploymentNotServed(DeploymentId),
+    #[error("Deployment `{0}` is denied by the indexer")]
+    DeploymentDenied(DeploymentId),
+    #[error("Database is temporarily unavailable, please retry later")]
+    DatabaseUnavailable,
+    #[error("Escrow and network data are out of sync, please retry later")]
+    SubgraphsOutOfSync,
+    #[error("The indexer clock is out of sync, please retry later")]
+    ClockOutOfSync,
+    #[error("Escrow accounts are unavailable, please retry later")]
+    EscrowUnavailable,
+    #[error(
+        "Escrow of sender {0} is running out, what's left is kept for higher priority queries"
+    )]
+    EscrowHeadroomReserved(Address),
+    #[error("Allocation {0} is over its quota of {1} requests per second")]
+    AllocationRateQuotaExceeded(Address, u32),
+    #[error("Allocation {0} is over its quota of {1} GRT wei of receipts per {2:?}")]
+    AllocationValueQuotaExceeded(Address, u128, Duration),
+    #[error("The query could not be answered in time")]
+    DeadlineExceeded,
+}
+
+/// Seconds clients are asked to wait before retrying while the database is unavailable
+const DATABASE_UNAVAILABLE_RETRY_AFTER: &str = "5";
+/// Seconds clients are asked to wait before retrying a receipt that couldn't be checked yet
+const SERVICE_NOT_READY_RETRY_AFTER: &str = "1";
+
+impl From<TapError> for IndexerServiceError {
+    fn from(error: TapError) -> Self {
+        match &error {
+            TapError::AdapterError { source_error }
+                if matches!(
+                    source_error.downcast_ref::<AdapterError>(),
+                    Some(AdapterError::DatabaseUnavailable(_) | AdapterError::DatabaseDown)
+                ) =>
+            {
+                IndexerServiceError::DatabaseUnavailable
+            }
+            // the database writes can't keep up, don't let the queue grow
+            TapError::AdapterError { source_error }
+                if matches!(
+                    source_error.downcast_ref::<AdapterError>(),
+                    Some(AdapterError::ReceiptQueueFull)
+                ) =>
+            {
+                IndexerServiceError::ServiceNotReady
+            }
+            // the receipt may well be valid, the check just couldn't tell in time
+            TapError::ReceiptError(ReceiptError::RetryableCheck(reason)) => {
+                warn!(%reason, "Receipt check could not complete");
+                IndexerServiceError::ServiceNotReady
+            }
+            _ => IndexerServiceError::TapCoreError(error),
+        }
+    }
+}
+
+/// Where the request body failed to deserialize, only sent with `verbose_errors`
+#[derive(Debug, Serialize)]
+pub struct InvalidRequestDetails {
+    /// path of the offending field, e.g. `variables.first`
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    /// what serde expected to find
+    pub reason: String,
 }
 
 impl StatusCodeExt for IndexerServiceError {
@@ -44,8 +140,29 @@ impl StatusCodeExt for IndexerServiceError {
                 _ => StatusCode::INTERNAL_SERVER_ERROR,
             },
             E::EscrowAccount(_) | E::ReceiptNotFound => StatusCode::PAYMENT_REQUIRED,
+            E::Unauthorized => StatusCode::UNAUTHORIZED,
             E::DeploymentIdNotFound => StatusCode::INTERNAL_SERVER_ERROR,
             E::AxumError(_) | E::SerializationError(_) => StatusCode::BAD_GATEWAY,
+            E::ServiceNotReady
+            | E::SafeMode
+            | E::DatabaseUnavailable
+            | E::SubgraphsOutOfSync
+            | E::ClockOutOfSync
+            | E::EscrowUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            E::InvalidRequest(_)
+            | E::EmptyQuery
+            | E::InvalidDeploymentId(_)
+            | E::InvalidReceiptToken(_)
+            | E::MalformedReceipt(_)
+            | E::UnsupportedFeature(_)
+            | E::GatewayVersionUnsupported(_) => StatusCode::BAD_REQUEST,
+            E::UnsupportedContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            E::DeploymentNotServed(_) => StatusCode::NOT_FOUND,
+            E::DeploymentDenied(_) => StatusCode::FORBIDDEN,
+            E::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            E::AllocationRateQuotaExceeded(..)
+            | E::AllocationValueQuotaExceeded(..)
+            | E::EscrowHeadroomReserved(_) => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
@@ -53,18 +170,44 @@ impl StatusCodeExt for IndexerServiceError {
 impl IntoResponse for IndexerServiceError {
     fn into_response(self) -> Response {
         #[derive(Serialize)]
-        struct ErrorResponse {
+        struct ErrorResponse<'a> {
             message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            details: Option<&'a InvalidRequestDetails>,
         }
 
-        tracing::error!(%self, "An IndexerServiceError occoured.");
-        (
+        // check failures are logged, possibly throttled, by the receipt middleware
+        if !matches!(
+            self,
+            IndexerServiceError::TapCoreError(TapError::ReceiptError(ReceiptError::CheckFailure(
+                _
+            )))
+        ) {
+            tracing::error!(%self, "An IndexerServiceError occoured.");
+        }
+        let details = match &self {
+            IndexerServiceError::InvalidRequest(details) => details.as_ref(),
+            _ => None,
+        };
+        let mut response = (
             self.status_code(),
             Json(ErrorResponse {
                 message: self.to_string(),
+                details,
             }),
         )
-            .into_response()
+            .into_response();
+        let retry_after = match self {
+            IndexerServiceError::DatabaseUnavailable => Some(DATABASE_UNAVAILABLE_RETRY_AFTER),
+            IndexerServiceError::ServiceNotReady => Some(SERVICE_NOT_READY_RETRY_AFTER),
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static(retry_after));
+        }
+        response
     }
 }
 
@@ -77,27 +220,61 @@ pub enum SubgraphServiceError {
     #[error("Internal server error: {0}")]
     StatusQueryError(Error),
     #[error("Invalid deployment: {0}")]
-    InvalidDeployment(DeploymentId),
+    InvalidDeployment(String),
     #[error("Failed to process query: {0}")]
     QueryForwardingError(reqwest::Error),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Operation `{0}` is not allowed for this deployment")]
+    OperationNotAllowed(String),
+    #[error("Query is too complex: {0}")]
+    QueryTooComplex(String),
+    #[error("Response too large: graph-node returned more than {0} bytes")]
+    ResponseTooLarge(usize),
+    #[error("The query ran out of time")]
+    DeadlineExceeded,
+    #[error("Failed to provide a response")]
+    FailedToProvideResponse,
 }
 
 impl StatusCodeExt for SubgraphServiceError {
     fn status_code(&self) -> StatusCode {
         use SubgraphServiceError::*;
         match self {
-            InvalidStatusQuery(_) | UnsupportedStatusQueryFields(_) => StatusCode::BAD_REQUEST,
-            InvalidDeployment(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            StatusQueryError(_) => StatusCode::BAD_GATEWAY,
+            InvalidStatusQuery(_)
+            | UnsupportedStatusQueryFields(_)
+            | InvalidQuery(_)
+            | QueryTooComplex(_) => StatusCode::BAD_REQUEST,
+            OperationNotAllowed(_) => StatusCode::FORBIDDEN,
+            InvalidDeployment(_) | FailedToProvideResponse => StatusCode::INTERNAL_SERVER_ERROR,
+            StatusQueryError(_) | ResponseTooLarge(_) => StatusCode::BAD_GATEWAY,
             QueryForwardingError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
         }
     }
 }
 
+impl SubgraphServiceError {
+    /// Failures the same query would run into again however often it is retried
+    pub fn is_permanent(&self) -> bool {
+        use SubgraphServiceError::*;
+        matches!(
+            self,
+            InvalidDeployment(_) | InvalidQuery(_) | OperationNotAllowed(_) | QueryTooComplex(_)
+        )
+    }
+}
+
 // Tell axum how to convert `SubgraphServiceError` into a response.
 impl IntoResponse for SubgraphServiceError {
     fn into_response(self) -> Response {
-        (self.status_code(), self.to_string()).into_response()
+        let mut response = (self.status_code(), self.to_string()).into_response();
+        if self.is_permanent() {
+            response
+                .extensions_mut()
+                .insert(PermanentFailure(self.to_string()));
+        }
+        response
     }
 }
 
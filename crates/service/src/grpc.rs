@@ -0,0 +1,154 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serves queries over gRPC, following `proto/query.proto`
+//!
+//! Every call is answered by running the query through the regular query
+//! route, so the receipt goes through the same TAP checks and the response is
+//! attested the same way as over HTTP. Errors of the query route are mapped to
+//! the closest gRPC status, with the same message.
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Method, Request, StatusCode,
+    },
+    Router,
+};
+use axum_extra::headers::Header;
+use serde::Deserialize;
+use thegraph_core::Attestation;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Code, Status,
+};
+
+use crate::{
+    routes::{InProcessQueries, InProcessQueryError},
+    service::TapReceipt,
+};
+
+pub mod proto {
+    tonic::include_proto!("graphprotocol.indexer.query.v1");
+}
+
+use proto::{
+    query_server::{Query, QueryServer},
+    QueryRequest, QueryResponse,
+};
+
+#[derive(Deserialize)]
+struct IndexerResponsePayload {
+    #[serde(rename = "graphQLResponse")]
+    graphql_response: String,
+    attestation: Option<Attestation>,
+}
+
+impl From<Attestation> for proto::Attestation {
+    fn from(attestation: Attestation) -> Self {
+        Self {
+            request_cid: attestation.request_cid.to_vec(),
+            response_cid: attestation.response_cid.to_vec(),
+            deployment: attestation.deployment.to_vec(),
+            r: attestation.r.to_vec(),
+            s: attestation.s.to_vec(),
+            v: attestation.v.into(),
+        }
+    }
+}
+
+pub struct QueryService {
+    /// serves the queries as they would be over HTTP
+    queries: InProcessQueries,
+    /// prefix of the query route, `service.url_prefix`
+    url_prefix: String,
+}
+
+impl QueryService {
+    pub fn new(routes: Router, url_prefix: &str, max_response_body_bytes: Option<usize>) -> Self {
+        Self {
+            queries: InProcessQueries::new(routes, max_response_body_bytes),
+            url_prefix: url_prefix.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Query for QueryService {
+    async fn query(
+        &self,
+        request: tonic::Request<QueryRequest>,
+    ) -> Result<tonic::Response<QueryResponse>, Status> {
+        let authorization = request.metadata().get(AUTHORIZATION.as_str()).cloned();
+        let QueryRequest {
+            deployment_id,
+            request,
+            receipt,
+        } = request.into_inner();
+
+        let mut http_request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/subgraphs/id/{deployment_id}", self.url_prefix))
+            .header(CONTENT_TYPE, "application/json");
+        if !receipt.is_empty() {
+            http_request = http_request.header(TapReceipt::name(), receipt);
+        }
+        if let Some(authorization) = authorization {
+            let authorization = authorization
+                .to_str()
+                .map_err(|_| Status::invalid_argument("Invalid authorization metadata"))?;
+            http_request = http_request.header(AUTHORIZATION, authorization);
+        }
+        let http_request = http_request
+            .body(Body::from(request))
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let payload: IndexerResponsePayload =
+            self.queries.run(http_request).await.map_err(|e| match e {
+                InProcessQueryError::Refused(status, message) => Status::new(code(status), message),
+                InProcessQueryError::InvalidResponse(message) => Status::internal(message),
+            })?;
+        Ok(tonic::Response::new(QueryResponse {
+            graphql_response: payload.graphql_response,
+            attestation: payload.attestation.map(Into::into),
+        }))
+    }
+}
+
+/// gRPC status of the HTTP status of the query route
+fn code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNSUPPORTED_MEDIA_TYPE => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::PAYMENT_REQUIRED | StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    }
+}
+
+/// Serves the [Query] service on `listener` until `shutdown` is cancelled
+pub async fn serve_grpc(
+    listener: TcpListener,
+    routes: Router,
+    url_prefix: &str,
+    max_response_body_bytes: Option<usize>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow!(e))?;
+    Server::builder()
+        .add_service(QueryServer::new(QueryService::new(
+            routes,
+            url_prefix,
+            max_response_body_bytes,
+        )))
+        .serve_with_incoming_shutdown(incoming, shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
@@ -2,13 +2,28 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod cli;
+mod clock_drift;
 mod database;
 mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 mod metrics;
 mod middleware;
+mod response_format;
 mod routes;
 pub mod service;
+mod subgraph_consistency;
 mod tap;
+mod wait_for_sync;
 mod wallet;
 
-pub use middleware::QueryBody;
+pub use indexer_admin_auth::{
+    admin_request_message, ADMIN_NONCE, ADMIN_SIGNATURE, ADMIN_TIMESTAMP,
+};
+pub use indexer_attestation::AttestationPayload;
+pub use middleware::{
+    auth::{AuthOutcome, Authenticator},
+    AttestationBackend, AttestationBackendError, AttestedContext, Deadline, PostAttestationHook,
+    QueryBody,
+};
+pub use routes::ResponseTransformer;
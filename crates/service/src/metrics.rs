@@ -6,7 +6,10 @@ use std::net::SocketAddr;
 use axum::{routing::get, serve, Router};
 use lazy_static::lazy_static;
 use prometheus::{
-    register_counter_vec, register_histogram_vec, CounterVec, HistogramVec, TextEncoder,
+    register_counter_vec, register_gauge, register_gauge_vec, register_histogram,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, CounterVec, Gauge, GaugeVec, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
 use reqwest::StatusCode;
 use tokio::net::TcpListener;
@@ -34,6 +37,157 @@ lazy_static! {
     )
     .unwrap();
 
+    /// Metric registered in global registry for
+    /// Receipts whose timestamp jumped ahead of the sender's history
+    ///
+    /// Labels: "sender"
+    pub static ref RECEIPT_TIMESTAMP_GAP: CounterVec = register_counter_vec!(
+        "indexer_receipt_timestamp_gap_total",
+        "Receipts whose timestamp jumped ahead of the sender's previous receipts",
+        &["sender"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Escrow balance of the sender, in GRT wei
+    ///
+    /// Labels: "sender"
+    pub static ref ESCROW_BALANCE: GaugeVec = register_gauge_vec!(
+        "indexer_escrow_balance",
+        "Escrow balance of the sender in GRT wei",
+        &["sender"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Escrow balance committed by receipts not yet aggregated into a RAV, in GRT wei
+    ///
+    /// Labels: "sender"
+    pub static ref ESCROW_COMMITTED: GaugeVec = register_gauge_vec!(
+        "indexer_escrow_committed",
+        "Escrow balance of the sender committed by unaggregated receipts in GRT wei",
+        &["sender"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Senders whose escrow balance is below `low_escrow_balance_grt`, set to 1
+    ///
+    /// Labels: "sender"
+    pub static ref ESCROW_LOW_BALANCE: GaugeVec = register_gauge_vec!(
+        "indexer_escrow_low_balance",
+        "Set to 1 for senders whose escrow balance is below the warning threshold",
+        &["sender"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Latest block indexed by the network and escrow subgraphs
+    ///
+    /// Labels: "subgraph"
+    pub static ref SUBGRAPH_LATEST_BLOCK: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_latest_block",
+        "Latest block indexed by the network or escrow subgraph",
+        &["subgraph"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Drift of the host clock from the configured reference
+    pub static ref CLOCK_DRIFT_SECONDS: Gauge = register_gauge!(
+        "indexer_clock_drift_seconds",
+        "Seconds the host clock is ahead of the reference, negative when behind"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Fraction of the database pool connections in use
+    pub static ref DATABASE_POOL_SATURATION: Gauge = register_gauge!(
+        "indexer_database_pool_saturation_ratio",
+        "Fraction of database pool connections in use"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Paid queries refused because the database is saturated
+    pub static ref LOAD_SHED_REQUESTS: IntCounter = register_int_counter!(
+        "indexer_load_shed_requests_total",
+        "Receipt-bearing requests refused while the database pool is saturated"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Receipts waiting to be written to the database
+    pub static ref RECEIPT_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "indexer_receipt_queue_depth",
+        "Receipts waiting to be written to the database"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Receipts that found the write queue full, by what became of them
+    pub static ref RECEIPT_QUEUE_BACKPRESSURE: IntCounterVec = register_int_counter_vec!(
+        "indexer_receipt_queue_backpressure_total",
+        "Receipts that found the write queue full, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Requests currently being handled
+    pub static ref INFLIGHT_REQUESTS: IntGauge = register_int_gauge!(
+        "indexer_inflight_requests",
+        "Requests currently being handled"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Requests waiting for a free slot under `max_concurrent_requests`
+    pub static ref REQUEST_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "indexer_request_queue_depth",
+        "Requests waiting for a free slot before being handled"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// How long queued requests waited for a free slot
+    pub static ref REQUEST_QUEUE_WAIT: Histogram = register_histogram!(
+        "indexer_request_queue_wait_seconds",
+        "Time queued requests waited for a free slot, including those that gave up"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Attestations being signed by the attestation backend
+    pub static ref ATTESTATION_SIGNING_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "indexer_attestation_signing_in_flight",
+        "Attestations being signed by the attestation backend"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Attestations waiting for a slot under `max_concurrent_signings`
+    pub static ref ATTESTATION_SIGNING_QUEUED: IntGauge = register_int_gauge!(
+        "indexer_attestation_signing_queued",
+        "Attestations waiting for a free slot before being signed"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Connections currently open on the listen addresses
+    pub static ref OPEN_CONNECTIONS: IntGauge = register_int_gauge!(
+        "indexer_open_connections",
+        "Connections currently open on the listen addresses"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Shutdown phase: 0 serving, 1 draining, 2 drained
+    pub static ref SHUTDOWN_PHASE: IntGauge = register_int_gauge!(
+        "indexer_shutdown_phase",
+        "Shutdown phase: 0 serving, 1 draining in-flight requests, 2 drained"
+    )
+    .unwrap();
 }
 
 pub fn serve_metrics(host_and_port: SocketAddr) {
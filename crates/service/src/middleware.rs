@@ -2,22 +2,77 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod allocation;
+mod allocation_quota;
 mod attestation;
+mod attestation_backend;
 mod attestation_signer;
 pub mod auth;
+mod catch_panic;
+mod clock_drift;
+mod content_type;
+mod cost_metadata;
+mod dead_letter;
+mod deadline;
 mod deployment;
+mod escrow_admission;
+mod escrow_freshness;
+mod features;
+mod inflight;
 mod labels;
+mod load_shedding;
+mod post_attestation;
 mod prometheus_metrics;
+mod query_check;
+mod receipt_archive;
+mod request_id;
+mod request_log;
+mod request_queue;
+mod safe_mode;
 mod sender;
+mod subgraph_sync;
 mod tap_context;
 mod tap_receipt;
+mod version_headers;
 
 pub use allocation::{allocation_middleware, Allocation, AllocationState};
-pub use attestation::{attestation_middleware, AttestationInput};
-pub use attestation_signer::{signer_middleware, AttestationState};
-pub use deployment::deployment_middleware;
-pub use labels::labels_middleware;
+pub use allocation_quota::{allocation_quota_middleware, AllocationQuota, AllocationQuotaState};
+pub use attestation::{
+    attestation_middleware, AttestationInput, AttestationScope, ATTESTATION_FAILURE,
+    GRAPH_ATTESTABLE,
+};
+pub use attestation_backend::{
+    AttestationBackend, AttestationBackendError, AttestationBackendState, CachingBackend,
+    DeferredSigner, MeteredBackend, RemoteSigner,
+};
+pub use attestation_signer::AttestationState;
+pub use catch_panic::{catch_panic_middleware, CatchPanicState};
+pub use clock_drift::{clock_drift_middleware, ClockDriftState};
+pub use content_type::{content_type_middleware, ContentTypeState};
+pub use cost_metadata::cost_metadata_middleware;
+pub use dead_letter::{dead_letter_middleware, PermanentFailure};
+pub use deadline::{deadline_middleware, Deadline, DeadlineState};
+pub use deployment::{deployment_middleware, DeploymentState};
+pub use escrow_admission::{escrow_admission_middleware, EscrowAdmissionState};
+pub use escrow_freshness::{escrow_freshness_middleware, EscrowFreshnessState};
+pub use features::{features_middleware, FeaturesState, RequestFeatures};
+pub use inflight::inflight_middleware;
+pub use labels::{labels_middleware, LabelsState};
+pub use load_shedding::{load_shedding_middleware, LoadSheddingState};
+pub use post_attestation::{
+    post_attestation_middleware, AttestedContext, PostAttestationHook, PostAttestationState,
+};
 pub use prometheus_metrics::PrometheusMetricsMiddlewareLayer;
+pub use query_check::{query_check_middleware, QueryCheckState};
+pub use receipt_archive::receipt_archive_middleware;
+pub use request_id::{request_id_middleware, RequestId, RequestIdState};
+pub use request_log::{request_log_middleware, RequestLogState};
+pub use request_queue::{request_queue_middleware, RequestQueueState};
+pub use safe_mode::{safe_mode_middleware, SafeModeState};
 pub use sender::{sender_middleware, Sender, SenderState};
-pub use tap_context::{context_middleware, QueryBody};
-pub use tap_receipt::receipt_middleware;
+pub use subgraph_sync::{subgraph_sync_middleware, SubgraphSyncState};
+pub use tap_context::{context_middleware, ContextState, QueryBody, ReceiptToken};
+pub use tap_receipt::{receipt_middleware, ReceiptState};
+pub use version_headers::{
+    version_headers_middleware, GatewayVersion, VersionHeadersState, GRAPH_GATEWAY_VERSION,
+    GRAPH_INDEXER_VERSION, INDEXER_VERSION,
+};
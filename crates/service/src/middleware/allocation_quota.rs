@@ -0,0 +1,283 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexer_config::AllocationQuotaConfig;
+use serde::Serialize;
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::Address;
+use tokio::sync::watch;
+
+use super::sender::Sender;
+use crate::error::IndexerServiceError;
+
+const SECOND: Duration = Duration::from_secs(1);
+
+/// Requests and receipt value accepted for an allocation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AllocationQuota {
+    pub max_requests_per_second: Option<u32>,
+    /// in GRT wei
+    #[serde(serialize_with = "serialize_wei")]
+    pub max_value: Option<u128>,
+    #[serde(rename = "window_secs", serialize_with = "serialize_secs")]
+    pub window: Duration,
+}
+
+impl From<&AllocationQuotaConfig> for AllocationQuota {
+    fn from(config: &AllocationQuotaConfig) -> Self {
+        Self {
+            max_requests_per_second: config.max_requests_per_second,
+            max_value: config.max_value_grt.as_ref().map(|value| value.get_value()),
+            window: config.window_secs,
+        }
+    }
+}
+
+/// Values in GRT wei don't fit in JSON numbers
+fn serialize_wei<S: serde::Serializer>(
+    value: &Option<u128>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value.map(|value| value.to_string()).serialize(serializer)
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    window: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(window.as_secs_f64())
+}
+
+/// What an allocation used of its quota in the current windows
+struct Usage {
+    second_start: Instant,
+    requests: u32,
+    window_start: Instant,
+    value: u128,
+}
+
+/// State to be used by allocation quota middleware
+#[derive(Clone)]
+pub struct AllocationQuotaState {
+    pub quotas: watch::Receiver<HashMap<Address, AllocationQuota>>,
+    usage: Arc<Mutex<HashMap<Address, Usage>>>,
+}
+
+impl AllocationQuotaState {
+    pub fn new(quotas: watch::Receiver<HashMap<Address, AllocationQuota>>) -> Self {
+        Self {
+            quotas,
+            usage: Default::default(),
+        }
+    }
+
+    /// Counts the request against the quota of the allocation, returning the
+    /// starts of the request and value windows it was counted in
+    fn admit(
+        &self,
+        allocation: Address,
+        quota: &AllocationQuota,
+        value: u128,
+        now: Instant,
+    ) -> Result<(Instant, Instant), IndexerServiceError> {
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        let usage = usage.entry(allocation).or_insert(Usage {
+            second_start: now,
+            requests: 0,
+            window_start: now,
+            value: 0,
+        });
+        if now - usage.second_start >= SECOND {
+            usage.second_start = now;
+            usage.requests = 0;
+        }
+        if now - usage.window_start >= quota.window {
+            usage.window_start = now;
+            usage.value = 0;
+        }
+
+        if let Some(max_requests) = quota.max_requests_per_second {
+            if usage.requests >= max_requests {
+                return Err(IndexerServiceError::AllocationRateQuotaExceeded(
+                    allocation,
+                    max_requests,
+                ));
+            }
+        }
+        if let Some(max_value) = quota.max_value {
+            if usage.value.saturating_add(value) > max_value {
+                return Err(IndexerServiceError::AllocationValueQuotaExceeded(
+                    allocation,
+                    max_value,
+                    quota.window,
+                ));
+            }
+        }
+        usage.requests += 1;
+        usage.value = usage.value.saturating_add(value);
+        Ok((usage.second_start, usage.window_start))
+    }
+
+    /// Gives back the request and value of a receipt that wasn't accepted,
+    /// to the windows that are still the ones they were counted in
+    fn refund(
+        &self,
+        allocation: Address,
+        value: u128,
+        (second_start, window_start): (Instant, Instant),
+    ) {
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(usage) = usage.get_mut(&allocation) {
+            if usage.second_start == second_start {
+                usage.requests = usage.requests.saturating_sub(1);
+            }
+            if usage.window_start == window_start {
+                usage.value = usage.value.saturating_sub(value);
+            }
+        }
+    }
+}
+
+/// Refuses paid queries once the allocation of their receipt used up its quota
+///
+/// Only receipts of a known sender are counted, for receipts anyone can sign
+/// not to use up the quota of an allocation. The requests and value of
+/// receipts that end up refused don't count against the quota.
+///
+/// Requires signed receipt and Sender Extensions to be added
+pub async fn allocation_quota_middleware(
+    State(state): State<AllocationQuotaState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (Some(receipt), Some(_)) = (
+        request.extensions().get::<SignedReceipt>(),
+        request.extensions().get::<Sender>(),
+    ) else {
+        return next.run(request).await;
+    };
+    let allocation = receipt.message.allocation_id;
+    let value = receipt.message.value;
+    let quota = state.quotas.borrow().get(&allocation).cloned();
+    let Some(quota) = quota else {
+        return next.run(request).await;
+    };
+
+    let windows = match state.admit(allocation, &quota, value, Instant::now()) {
+        Ok(windows) => windows,
+        Err(e) => return e.into_response(),
+    };
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        state.refund(allocation, value, windows);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, ALLOCATION_ID_0};
+    use thegraph_core::Address;
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{allocation_quota_middleware, AllocationQuota, AllocationQuotaState};
+    use crate::middleware::Sender;
+
+    fn quota(max_requests_per_second: Option<u32>, max_value: Option<u128>) -> AllocationQuota {
+        AllocationQuota {
+            max_requests_per_second,
+            max_value,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_rate_quota() {
+        let allocation = Address::repeat_byte(1);
+        let quota = quota(Some(2), None);
+        let state = AllocationQuotaState::new(watch::channel(HashMap::new()).1);
+        let now = Instant::now();
+
+        assert!(state.admit(allocation, &quota, 1, now).is_ok());
+        assert!(state.admit(allocation, &quota, 1, now).is_ok());
+        assert!(state.admit(allocation, &quota, 1, now).is_err());
+        // other allocations have their own quota
+        assert!(state.admit(Address::ZERO, &quota, 1, now).is_ok());
+        // a refused request gives its slot back
+        state.refund(
+            Address::ZERO,
+            1,
+            state.admit(Address::ZERO, &quota, 1, now).unwrap(),
+        );
+        assert!(state.admit(Address::ZERO, &quota, 1, now).is_ok());
+        // the next second starts over
+        assert!(state
+            .admit(allocation, &quota, 1, now + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_value_quota() {
+        let (quotas_tx, quotas) =
+            watch::channel(HashMap::from([(*ALLOCATION_ID_0, quota(None, Some(150)))]));
+        let state = AllocationQuotaState::new(quotas);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route("/refused", get(|| async { StatusCode::BAD_REQUEST }))
+            .layer(from_fn_with_state(state, allocation_quota_middleware));
+
+        let send = |uri: &'static str, value: u128, sender: bool| {
+            let app = app.clone();
+            async move {
+                let receipt = create_signed_receipt(
+                    SignedReceiptRequest::builder()
+                        .allocation_id(*ALLOCATION_ID_0)
+                        .value(value)
+                        .build(),
+                )
+                .await;
+                let mut request = Request::builder().uri(uri).extension(receipt);
+                if sender {
+                    request = request.extension(Sender(Address::ZERO));
+                }
+                app.oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+                    .status()
+            }
+        };
+
+        assert_eq!(send("/", 100, true).await, StatusCode::OK);
+        // refused receipts don't use the quota up
+        assert_eq!(send("/refused", 50, true).await, StatusCode::BAD_REQUEST);
+        assert_eq!(send("/", 50, true).await, StatusCode::OK);
+        assert_eq!(send("/", 1, true).await, StatusCode::TOO_MANY_REQUESTS);
+        // receipts without a known sender are left to the sender middleware
+        assert_eq!(send("/", 1000, false).await, StatusCode::OK);
+
+        // quotas are replaced at runtime
+        quotas_tx.send_replace(HashMap::from([(*ALLOCATION_ID_0, quota(None, Some(1000)))]));
+        assert_eq!(send("/", 1, true).await, StatusCode::OK);
+        quotas_tx.send_replace(HashMap::new());
+        assert_eq!(send("/", 1000, true).await, StatusCode::OK);
+    }
+}
@@ -3,19 +3,37 @@
 
 use std::string::FromUtf8Error;
 
+use alloy::{primitives::Address, signers::Signature};
 use axum::{
-    body::to_bytes,
-    extract::Request,
+    body::{to_bytes, Bytes},
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, response::Parts, HeaderValue},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use indexer_config::{AttestationFailurePolicy, ResponseAttestationScope};
 use reqwest::StatusCode;
-use serde::Serialize;
-use thegraph_core::Attestation;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use thegraph_core::{Attestation, DeploymentId};
+use tracing::warn;
 
-use indexer_attestation::AttestationSigner;
+use indexer_attestation::AttestationPayload;
 
-use crate::error::StatusCodeExt;
+use super::{
+    attestation_backend::{AttestationBackendError, AttestationBackendState},
+    auth::AuthOutcome,
+    Allocation,
+};
+use crate::error::{IndexerServiceError, StatusCodeExt};
+
+/// Header used by graph-node to signal if a response can be attested.
+/// The same header is set on the indexer response, telling whether it
+/// carries an attestation.
+pub const GRAPH_ATTESTABLE: &str = "graph-attestable";
+/// Header set on responses served unattested because the attestation backend
+/// failed, to why it did
+pub const ATTESTATION_FAILURE: &str = "attestation-failure";
 
 #[derive(Clone)]
 pub enum AttestationInput {
@@ -23,51 +41,233 @@ pub enum AttestationInput {
     NotAttestable,
 }
 
-#[derive(Debug, Serialize)]
-#[cfg_attr(test, derive(serde::Deserialize))]
+/// What the attestation of a response is computed over, read from the
+/// response extensions. Responses without one are attested in full.
+///
+/// The `responseCID` of the attestation is the hash of these bytes, so a
+/// verifier has to hash the same part of `graphQLResponse`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AttestationScope {
+    #[default]
+    FullBody,
+    /// the `data` member exactly as it appears in the response. Responses
+    /// without `data` are attested in full
+    DataOnly,
+    /// bytes chosen by the handler
+    Custom(String),
+}
+
+impl From<ResponseAttestationScope> for AttestationScope {
+    fn from(scope: ResponseAttestationScope) -> Self {
+        match scope {
+            ResponseAttestationScope::FullBody => AttestationScope::FullBody,
+            ResponseAttestationScope::DataOnly => AttestationScope::DataOnly,
+        }
+    }
+}
+
+impl AttestationScope {
+    fn attested<'a>(&'a self, response: &'a str) -> &'a str {
+        #[derive(Deserialize)]
+        struct Data<'a> {
+            #[serde(borrow)]
+            data: Option<&'a RawValue>,
+        }
+
+        match self {
+            AttestationScope::FullBody => response,
+            AttestationScope::DataOnly => serde_json::from_str::<Data>(response)
+                .ok()
+                .and_then(|body| body.data)
+                .map_or(response, RawValue::get),
+            AttestationScope::Custom(bytes) => bytes,
+        }
+    }
+}
+
+/// Signs the payload, signing again while the backend is out with
+/// [AttestationFailurePolicy::Retry]
+async fn sign(
+    state: &AttestationBackendState,
+    payload: &AttestationPayload,
+    allocation: &Address,
+) -> Result<Signature, AttestationBackendError> {
+    let AttestationFailurePolicy::Retry {
+        deadline_secs,
+        retry_interval_secs,
+    } = state.failure_policy
+    else {
+        return state.backend.sign(payload, allocation).await;
+    };
+    let deadline = tokio::time::Instant::now() + deadline_secs;
+    loop {
+        match state.backend.sign(payload, allocation).await {
+            Err(e)
+                if e.is_outage()
+                    && tokio::time::Instant::now() + retry_interval_secs < deadline =>
+            {
+                warn!(error = %e, %allocation, "Attestation signing failed, retrying");
+                tokio::time::sleep(retry_interval_secs).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct IndexerResponsePayload {
     #[serde(rename = "graphQLResponse")]
-    graphql_response: String,
-    attestation: Option<Attestation>,
+    pub(super) graphql_response: String,
+    pub(super) attestation: Option<Attestation>,
 }
 
 /// Check if the query is attestable and generates attestation
 ///
 /// Executes query -> return subgraph response: (string, attestable (bool))
+/// A response is attestable only if the handler says so and the response
+/// is not marked with `graph-attestable: false`.
 /// if attestable && allocation id:
-///     - look for signer
-///     - create attestation
+///     - compute the attestation payload
+///     - have the backend sign it with the allocation key
 ///     - return response with attestation
 /// else:
 ///     - return with no attestation
 ///
-/// Requires AttestationSigner
+/// The part of the response attested follows its [AttestationScope]. The
+/// attestation is computed before any response compression, over the bytes
+/// graph-node returned.
+///
+/// When the backend is out, the [AttestationFailurePolicy] decides whether the
+/// query fails, is served unattested with [ATTESTATION_FAILURE] set or waits
+/// for the backend to sign.
+///
+/// Requires Allocation and DeploymentId Extensions. With
+/// `unattested_free_queries`, free queries are served unattested when these
+/// are missing or the allocation has no signer, paid queries fail either way.
 pub async fn attestation_middleware(
+    State(state): State<AttestationBackendState>,
     request: Request,
     next: Next,
 ) -> Result<Response, AttestationError> {
-    let signer = request
-        .extensions()
-        .get::<AttestationSigner>()
-        .cloned()
-        .ok_or(AttestationError::CouldNotFindSigner)?;
+    let unattested_fallback = state.unattested_free_queries
+        && request.extensions().get::<AuthOutcome>() == Some(&AuthOutcome::FreeQuery);
+    let signing_target = match (
+        request.extensions().get::<Allocation>().cloned(),
+        request.extensions().get::<DeploymentId>().cloned(),
+    ) {
+        (Some(Allocation(allocation)), Some(deployment)) => Some((allocation, deployment)),
+        _ if unattested_fallback => None,
+        _ => return Err(AttestationError::CouldNotFindSigner),
+    };
 
     let (parts, graphql_response) = next.run(request).await.into_parts();
     let attestation_response = parts.extensions.get::<AttestationInput>();
+    let scope = parts
+        .extensions
+        .get::<AttestationScope>()
+        .cloned()
+        .unwrap_or_default();
     let bytes = to_bytes(graphql_response, usize::MAX).await?;
     let res = String::from_utf8(bytes.into())?;
 
-    let attestation = match attestation_response {
-        Some(AttestationInput::Attestable { req }) => Some(signer.create_attestation(req, &res)),
+    let marked_not_attestable = parts
+        .headers
+        .get(GRAPH_ATTESTABLE)
+        .is_some_and(|value| value != "true");
+
+    let mut failure = None;
+    let attestation = match (attestation_response, signing_target) {
+        (Some(AttestationInput::Attestable { req }), Some((allocation, deployment)))
+            if !marked_not_attestable =>
+        {
+            let domain = state.domain.borrow().clone();
+            let payload = AttestationPayload::new(&domain, &deployment, req, scope.attested(&res));
+            match sign(&state, &payload, &allocation).await {
+                Ok(signature) => Some(payload.into_attestation(&signature)),
+                Err(AttestationBackendError::NoSigner(_)) if unattested_fallback => None,
+                Err(e)
+                    if e.is_outage()
+                        && state.failure_policy == AttestationFailurePolicy::ServeUnattested =>
+                {
+                    warn!(error = %e, %allocation, "Serving a response unattested");
+                    failure = Some(match e {
+                        AttestationBackendError::Timeout => "timeout",
+                        _ => "unavailable",
+                    });
+                    None
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
         _ => None,
     };
+    let attested = attestation.is_some();
 
     let response = serde_json::to_string(&IndexerResponsePayload {
         graphql_response: res,
         attestation,
     })?;
 
-    Ok(Response::new(response.into()))
+    let mut headers = parts.headers;
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        GRAPH_ATTESTABLE,
+        HeaderValue::from_static(if attested { "true" } else { "false" }),
+    );
+    if let Some(failure) = failure {
+        headers.insert(ATTESTATION_FAILURE, HeaderValue::from_static(failure));
+    }
+
+    let mut response = Response::new(response.into());
+    *response.headers_mut() = headers;
+    Ok(response)
+}
+
+/// A successful query, buffered by a middleware running outside the
+/// attestation middleware
+pub(super) struct BufferedQuery {
+    pub request: Bytes,
+    pub parts: Parts,
+    pub body: Bytes,
+    /// the response of the attestation middleware, unset when the body isn't one
+    pub payload: Option<IndexerResponsePayload>,
+}
+
+impl BufferedQuery {
+    /// Runs the request, buffering it and its response. Responses that aren't
+    /// buffered, unsuccessful ones or errors reading the bodies, are returned
+    /// as they are to be sent
+    pub async fn run(request: Request, next: Next) -> Result<Self, Response> {
+        let (parts, body) = request.into_parts();
+        let request = to_bytes(body, usize::MAX)
+            .await
+            .map_err(|e| IndexerServiceError::AxumError(e).into_response())?;
+        let response = next
+            .run(Request::from_parts(parts, request.clone().into()))
+            .await;
+        if !response.status().is_success() {
+            return Err(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX)
+            .await
+            .map_err(|e| IndexerServiceError::AxumError(e).into_response())?;
+        let payload = serde_json::from_slice(&body).ok();
+        Ok(Self {
+            request,
+            parts,
+            body,
+            payload,
+        })
+    }
+}
+
+impl IntoResponse for BufferedQuery {
+    /// The response, as it was received
+    fn into_response(self) -> Response {
+        Response::from_parts(self.parts, self.body.into())
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -83,6 +283,9 @@ pub enum AttestationError {
 
     #[error("there was an error while serializing the response: {0}")]
     SerializationError(#[from] serde_json::Error),
+
+    #[error("Could not sign the attestation: {0}")]
+    Backend(#[from] AttestationBackendError),
 }
 
 impl StatusCodeExt for AttestationError {
@@ -92,6 +295,7 @@ impl StatusCodeExt for AttestationError {
             AttestationError::AxumError(_)
             | AttestationError::FromUtf8Error(_)
             | AttestationError::SerializationError(_) => StatusCode::BAD_GATEWAY,
+            AttestationError::Backend(e) => e.status_code(),
         }
     }
 }
@@ -104,27 +308,67 @@ impl IntoResponse for AttestationError {
 
 #[cfg(test)]
 mod tests {
-    use alloy::primitives::Address;
+    use std::{
+        io::Read,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use alloy::{primitives::Address, signers::Signature};
     use axum::{
         body::{to_bytes, Body},
-        http::{Request, Response},
-        middleware::from_fn,
+        http::{
+            header::{ACCEPT_ENCODING, CONTENT_ENCODING},
+            Request, Response,
+        },
+        middleware::from_fn_with_state,
         routing::get,
         Router,
     };
+    use flate2::read::GzDecoder;
     use indexer_allocation::Allocation;
-    use indexer_attestation::AttestationSigner;
+    use indexer_attestation::{AttestationPayload, AttestationSigner};
+    use indexer_config::AttestationFailurePolicy;
     use reqwest::StatusCode;
     use test_assets::{INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::attestation::eip712_domain;
+    use tokio::sync::watch;
     use tower::ServiceExt;
+    use tower_http::compression::CompressionLayer;
 
     use crate::middleware::{
-        attestation::IndexerResponsePayload, attestation_middleware, AttestationInput,
+        attestation::{IndexerResponsePayload, ATTESTATION_FAILURE, GRAPH_ATTESTABLE},
+        attestation_backend::{
+            AttestationBackend, AttestationBackendError, AttestationBackendState,
+        },
+        attestation_middleware,
+        auth::AuthOutcome,
+        AttestationInput, AttestationScope,
     };
 
     const REQUEST: &str = "request";
     const RESPONSE: &str = "response";
 
+    /// Signs with the key of the test allocation, or fails without one
+    struct MockBackend(Option<AttestationSigner>);
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for MockBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            _: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            self.0
+                .as_ref()
+                .map(|signer| signer.sign(payload))
+                .ok_or(AttestationBackendError::Unavailable("down".to_string()))
+        }
+    }
+
     fn allocation_signer() -> (Allocation, AttestationSigner) {
         let allocation = INDEXER_ALLOCATIONS
             .values()
@@ -138,17 +382,28 @@ mod tests {
         (allocation, signer)
     }
 
+    fn backend_state(signer: Option<AttestationSigner>) -> AttestationBackendState {
+        AttestationBackendState {
+            backend: Arc::new(MockBackend(signer)),
+            domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+            unattested_free_queries: false,
+            failure_policy: Default::default(),
+        }
+    }
+
     async fn payload_from_response(res: Response<Body>) -> IndexerResponsePayload {
         let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
 
         serde_json::from_slice(&bytes).unwrap()
     }
 
-    async fn send_request(app: Router, signer: Option<AttestationSigner>) -> Response<Body> {
+    async fn send_request(app: Router, allocation: Option<&Allocation>) -> Response<Body> {
         let mut request = Request::builder().uri("/");
 
-        if let Some(signer) = signer {
-            request = request.extension(signer);
+        if let Some(allocation) = allocation {
+            request = request
+                .extension(crate::middleware::Allocation(allocation.id))
+                .extension(allocation.subgraph_deployment.id);
         }
 
         app.oneshot(request.body(Body::empty()).unwrap())
@@ -159,7 +414,8 @@ mod tests {
     #[tokio::test]
     async fn test_create_attestation() {
         let (allocation, signer) = allocation_signer();
-        let middleware = from_fn(attestation_middleware);
+        let middleware =
+            from_fn_with_state(backend_state(Some(signer.clone())), attestation_middleware);
 
         let handle = move |_: Request<Body>| async move {
             let mut res = Response::new(RESPONSE.to_string());
@@ -172,8 +428,9 @@ mod tests {
         let app = Router::new().route("/", get(handle)).layer(middleware);
 
         // with signer
-        let res = send_request(app, Some(signer.clone())).await;
+        let res = send_request(app, Some(&allocation)).await;
         assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "true");
 
         let response = payload_from_response(res).await;
         assert_eq!(response.graphql_response, RESPONSE.to_string());
@@ -184,16 +441,103 @@ mod tests {
             .is_ok());
     }
 
+    #[tokio::test]
+    async fn test_attestation_scope() {
+        const DATA: &str = r#"{"a": [1, 2]}"#;
+        let body = format!(r#"{{"data": {DATA}, "errors": [{{"message": "partial"}}]}}"#);
+        let (allocation, signer) = allocation_signer();
+
+        let attest = |scope: AttestationScope, body: String| {
+            let handle = move |_: Request<Body>| async move {
+                let mut res = Response::new(body);
+                res.extensions_mut().insert(AttestationInput::Attestable {
+                    req: REQUEST.to_string(),
+                });
+                res.extensions_mut().insert(scope);
+                res
+            };
+            Router::new()
+                .route("/", get(handle))
+                .layer(from_fn_with_state(
+                    backend_state(Some(signer.clone())),
+                    attestation_middleware,
+                ))
+        };
+
+        // only the data member, byte for byte
+        let res = send_request(
+            attest(AttestationScope::DataOnly, body.clone()),
+            Some(&allocation),
+        )
+        .await;
+        let response = payload_from_response(res).await;
+        assert_eq!(response.graphql_response, body);
+        let attestation = response.attestation.unwrap();
+        assert!(signer
+            .verify(&attestation, REQUEST, DATA, &allocation.id)
+            .is_ok());
+        assert!(signer
+            .verify(&attestation, REQUEST, &body, &allocation.id)
+            .is_err());
+
+        // responses without data are attested in full
+        let res = send_request(
+            attest(AttestationScope::DataOnly, RESPONSE.to_string()),
+            Some(&allocation),
+        )
+        .await;
+        let attestation = payload_from_response(res).await.attestation.unwrap();
+        assert!(signer
+            .verify(&attestation, REQUEST, RESPONSE, &allocation.id)
+            .is_ok());
+
+        let res = send_request(
+            attest(AttestationScope::Custom("custom".into()), body),
+            Some(&allocation),
+        )
+        .await;
+        let attestation = payload_from_response(res).await.attestation.unwrap();
+        assert!(signer
+            .verify(&attestation, REQUEST, "custom", &allocation.id)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_marked_not_attestable() {
+        let (allocation, signer) = allocation_signer();
+        let middleware = from_fn_with_state(backend_state(Some(signer)), attestation_middleware);
+
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res.headers_mut()
+                .insert(GRAPH_ATTESTABLE, "false".parse().unwrap());
+            res
+        };
+
+        let app = Router::new().route("/", get(handle)).layer(middleware);
+
+        let res = send_request(app, Some(&allocation)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "false");
+
+        let response = payload_from_response(res).await;
+        assert!(response.attestation.is_none());
+    }
+
     #[tokio::test]
     async fn test_non_assignable() {
-        let (_, signer) = allocation_signer();
+        let (allocation, signer) = allocation_signer();
         let handle = move |_: Request<Body>| async move { Response::new(RESPONSE.to_string()) };
 
-        let middleware = from_fn(attestation_middleware);
+        let middleware = from_fn_with_state(backend_state(Some(signer)), attestation_middleware);
         let app = Router::new().route("/", get(handle)).layer(middleware);
 
-        let res = send_request(app, Some(signer.clone())).await;
+        let res = send_request(app, Some(&allocation)).await;
         assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "false");
 
         let response = payload_from_response(res).await;
         assert_eq!(response.graphql_response, RESPONSE.to_string());
@@ -202,14 +546,238 @@ mod tests {
 
     #[tokio::test]
     async fn test_no_signer() {
+        let (_, signer) = allocation_signer();
         let handle = move |_: Request<Body>| async move {
             Response::new(RESPONSE.to_string());
         };
 
-        let middleware = from_fn(attestation_middleware);
+        let middleware = from_fn_with_state(backend_state(Some(signer)), attestation_middleware);
         let app = Router::new().route("/", get(handle)).layer(middleware);
 
         let res = send_request(app, None).await;
         assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[tokio::test]
+    async fn test_backend_unavailable() {
+        let (allocation, _) = allocation_signer();
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res
+        };
+
+        let middleware = from_fn_with_state(backend_state(None), attestation_middleware);
+        let app = Router::new().route("/", get(handle)).layer(middleware);
+
+        let res = send_request(app, Some(&allocation)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// Has no signer for any allocation
+    struct NoSignerBackend;
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for NoSignerBackend {
+        async fn sign(
+            &self,
+            _: &AttestationPayload,
+            allocation: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            Err(AttestationBackendError::NoSigner(*allocation))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unattested_free_queries() {
+        let (allocation, _) = allocation_signer();
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res
+        };
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn_with_state(
+                AttestationBackendState {
+                    backend: Arc::new(NoSignerBackend),
+                    domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+                    unattested_free_queries: true,
+                    failure_policy: Default::default(),
+                },
+                attestation_middleware,
+            ));
+        let send = |outcome: AuthOutcome| {
+            let request = Request::builder()
+                .uri("/")
+                .extension(crate::middleware::Allocation(allocation.id))
+                .extension(allocation.subgraph_deployment.id)
+                .extension(outcome)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        // free queries are served without an attestation
+        let res = send(AuthOutcome::FreeQuery).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "false");
+        let response = payload_from_response(res).await;
+        assert_eq!(response.graphql_response, RESPONSE.to_string());
+        assert!(response.attestation.is_none());
+
+        // paid queries still need a signer
+        let res = send(AuthOutcome::RequirePayment).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_attestation_with_compression() {
+        let (allocation, signer) = allocation_signer();
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res
+        };
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn_with_state(
+                backend_state(Some(signer.clone())),
+                attestation_middleware,
+            ))
+            .layer(CompressionLayer::new());
+        let request = Request::builder()
+            .uri("/")
+            .header(ACCEPT_ENCODING, "gzip")
+            .extension(crate::middleware::Allocation(allocation.id))
+            .extension(allocation.subgraph_deployment.id)
+            .body(Body::empty())
+            .unwrap();
+
+        let res = app.oneshot(request).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        let compressed = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let mut body = Vec::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut body)
+            .unwrap();
+        let response: IndexerResponsePayload = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response.graphql_response, RESPONSE);
+
+        // the attestation covers the content, not the bytes on the wire
+        assert!(signer
+            .verify(
+                &response.attestation.unwrap(),
+                REQUEST,
+                RESPONSE,
+                &allocation.id
+            )
+            .is_ok());
+    }
+
+    /// Unavailable for its first `failures` signings
+    struct FlakyBackend {
+        signer: AttestationSigner,
+        failures: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for FlakyBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            _: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| {
+                    failures.checked_sub(1)
+                })
+                .is_ok();
+            if failing {
+                return Err(AttestationBackendError::Unavailable(
+                    "restarting".to_string(),
+                ));
+            }
+            Ok(self.signer.sign(payload))
+        }
+    }
+
+    async fn send_with_policy(
+        failure_policy: AttestationFailurePolicy,
+        failures: usize,
+    ) -> Response<Body> {
+        let (allocation, signer) = allocation_signer();
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res
+        };
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn_with_state(
+                AttestationBackendState {
+                    backend: Arc::new(FlakyBackend {
+                        signer,
+                        failures: AtomicUsize::new(failures),
+                    }),
+                    failure_policy,
+                    ..backend_state(None)
+                },
+                attestation_middleware,
+            ));
+        send_request(app, Some(&allocation)).await
+    }
+
+    #[tokio::test]
+    async fn test_failure_policy_fail() {
+        let res = send_with_policy(AttestationFailurePolicy::Fail, 1).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_failure_policy_serve_unattested() {
+        let res = send_with_policy(AttestationFailurePolicy::ServeUnattested, 1).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "false");
+        assert_eq!(res.headers()[ATTESTATION_FAILURE], "unavailable");
+        let response = payload_from_response(res).await;
+        assert_eq!(response.graphql_response, RESPONSE);
+        assert!(response.attestation.is_none());
+
+        // attested as usual while the backend works
+        let res = send_with_policy(AttestationFailurePolicy::ServeUnattested, 0).await;
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "true");
+        assert!(res.headers().get(ATTESTATION_FAILURE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failure_policy_retry() {
+        let retry = |deadline_ms| AttestationFailurePolicy::Retry {
+            deadline_secs: Duration::from_millis(deadline_ms),
+            retry_interval_secs: Duration::from_millis(20),
+        };
+
+        // the backend is back before the deadline
+        let (allocation, signer) = allocation_signer();
+        let res = send_with_policy(retry(1000), 3).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_ATTESTABLE], "true");
+        let attestation = payload_from_response(res).await.attestation.unwrap();
+        assert!(signer
+            .verify(&attestation, REQUEST, RESPONSE, &allocation.id)
+            .is_ok());
+
+        // it isn't
+        let res = send_with_policy(retry(100), 100).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
@@ -0,0 +1,725 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signs attestations with keys held by a backend
+//!
+//! The attestation is computed in the service, only the EIP-712 hash of it is
+//! signed by the backend with the key of the allocation. Keys are derived from
+//! the operator mnemonic by default, or held by a signing service when a remote
+//! signer is configured.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use alloy::{
+    dyn_abi::Eip712Domain,
+    primitives::{Address, Bytes, B256},
+    signers::Signature,
+};
+use indexer_config::AttestationFailurePolicy;
+use lru::LruCache;
+use prometheus::IntGauge;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{watch, Semaphore};
+use tracing::warn;
+
+use super::AttestationState;
+use crate::{
+    error::StatusCodeExt,
+    metrics::{ATTESTATION_SIGNING_IN_FLIGHT, ATTESTATION_SIGNING_QUEUED},
+    tap::UnknownAllocations,
+};
+
+use indexer_attestation::AttestationPayload;
+
+#[derive(Debug, Error)]
+pub enum AttestationBackendError {
+    #[error("No attestation signer for allocation {0}")]
+    NoSigner(Address),
+    #[error("Attestation signer is unavailable: {0}")]
+    Unavailable(String),
+    #[error("Attestation signer did not answer in time")]
+    Timeout,
+}
+
+impl AttestationBackendError {
+    /// The backend couldn't be reached, rather than refusing to sign
+    pub fn is_outage(&self) -> bool {
+        matches!(
+            self,
+            AttestationBackendError::Unavailable(_) | AttestationBackendError::Timeout
+        )
+    }
+}
+
+impl StatusCodeExt for AttestationBackendError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AttestationBackendError::NoSigner(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AttestationBackendError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AttestationBackendError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+/// Holds the allocation keys and signs attestations with them
+#[async_trait::async_trait]
+pub trait AttestationBackend: Send + Sync {
+    /// Signs the [AttestationPayload::signing_hash] with the key of the allocation
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError>;
+
+    /// Whether verifiers only accept a signature once, e.g. because the
+    /// backend binds it to a nonce. Such signatures are never reused by the
+    /// [CachingBackend]. Assumed unless the backend knows better
+    fn nonce_bound(&self) -> bool {
+        true
+    }
+}
+
+/// Keys derived from the operator mnemonic, held in memory
+#[async_trait::async_trait]
+impl AttestationBackend for AttestationState {
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError> {
+        let signer = self
+            .signer(allocation)
+            .await
+            .ok_or(AttestationBackendError::NoSigner(*allocation))?;
+        Ok(signer.sign(payload))
+    }
+
+    /// The EIP-712 hash is signed deterministically, the same attestation
+    /// always has the same signature
+    fn nonce_bound(&self) -> bool {
+        false
+    }
+}
+
+/// Signing service holding the allocation keys, e.g. in front of an HSM
+pub struct RemoteSigner {
+    client: reqwest::Client,
+    url: Url,
+    timeout: Duration,
+}
+
+#[derive(Serialize)]
+struct SignRequest<'a> {
+    allocation: &'a Address,
+    hash: &'a B256,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    signature: Bytes,
+}
+
+impl RemoteSigner {
+    pub fn new(client: reqwest::Client, url: Url, timeout: Duration) -> Self {
+        Self {
+            client,
+            url,
+            timeout,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AttestationBackend for RemoteSigner {
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError> {
+        let error = |e: reqwest::Error| {
+            if e.is_timeout() {
+                AttestationBackendError::Timeout
+            } else {
+                AttestationBackendError::Unavailable(e.to_string())
+            }
+        };
+        let response = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .json(&SignRequest {
+                allocation,
+                hash: &payload.signing_hash,
+            })
+            .send()
+            .await
+            .map_err(error)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(AttestationBackendError::NoSigner(*allocation));
+        }
+        let SignResponse { signature } = response
+            .error_for_status()
+            .map_err(error)?
+            .json()
+            .await
+            .map_err(error)?;
+        let signature = Signature::try_from(signature.as_ref())
+            .map_err(|e| AttestationBackendError::Unavailable(format!("Invalid signature: {e}")))?;
+
+        // the allocation id is the address of the allocation key, a signature
+        // by any other key would be an attestation nobody can verify
+        match signature.recover_address_from_prehash(&payload.signing_hash) {
+            Ok(signer) if signer == *allocation => Ok(signature),
+            Ok(signer) => Err(AttestationBackendError::Unavailable(format!(
+                "Signature by {signer} instead of the allocation key"
+            ))),
+            Err(e) => Err(AttestationBackendError::Unavailable(format!(
+                "Invalid signature: {e}"
+            ))),
+        }
+    }
+}
+
+/// Decrements the gauge when dropped, also when signing is cancelled
+struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Tracks the signing operations of any backend in the
+/// `indexer_attestation_signing_in_flight` and `indexer_attestation_signing_queued`
+/// gauges
+///
+/// Without `max_concurrent` every signing starts right away and the queue stays
+/// empty, mnemonic signers answering almost at once. With a limit, signings
+/// over it wait in the queue, and a warning is logged each time the queue grows
+/// past `queue_warning_threshold`.
+pub struct MeteredBackend {
+    backend: Arc<dyn AttestationBackend>,
+    permits: Option<Semaphore>,
+    queue_warning_threshold: Option<usize>,
+    in_flight: IntGauge,
+    queued: IntGauge,
+}
+
+impl MeteredBackend {
+    pub fn new(
+        backend: Arc<dyn AttestationBackend>,
+        max_concurrent: Option<usize>,
+        queue_warning_threshold: Option<usize>,
+    ) -> Self {
+        Self::with_gauges(
+            backend,
+            max_concurrent,
+            queue_warning_threshold,
+            ATTESTATION_SIGNING_IN_FLIGHT.clone(),
+            ATTESTATION_SIGNING_QUEUED.clone(),
+        )
+    }
+
+    fn with_gauges(
+        backend: Arc<dyn AttestationBackend>,
+        max_concurrent: Option<usize>,
+        queue_warning_threshold: Option<usize>,
+        in_flight: IntGauge,
+        queued: IntGauge,
+    ) -> Self {
+        Self {
+            backend,
+            permits: max_concurrent.map(Semaphore::new),
+            queue_warning_threshold,
+            in_flight,
+            queued,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AttestationBackend for MeteredBackend {
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError> {
+        let permit = match &self.permits {
+            Some(permits) => {
+                let queued = GaugeGuard::new(&self.queued);
+                let depth = queued.0.get() as usize;
+                // only once per crossing, not for every signing past it
+                if self
+                    .queue_warning_threshold
+                    .is_some_and(|threshold| depth == threshold + 1)
+                {
+                    warn!(
+                        queued = depth,
+                        "Attestation signing queue is over its warning threshold"
+                    );
+                }
+                Some(
+                    permits
+                        .acquire()
+                        .await
+                        .expect("signing permits are never closed"),
+                )
+            }
+            None => None,
+        };
+        let _in_flight = GaugeGuard::new(&self.in_flight);
+        let signature = self.backend.sign(payload, allocation).await;
+        drop(permit);
+        signature
+    }
+
+    fn nonce_bound(&self) -> bool {
+        self.backend.nonce_bound()
+    }
+}
+
+/// Waits for the signer of allocations the backend doesn't know yet
+///
+/// Receipts of allocations missing from the network subgraph may be accepted
+/// while it syncs, their signer only shows up with the allocation. Signing is
+/// retried every `poll_interval` until it succeeds or `grace` is over, counting
+/// from the first receipt of the allocation. Allocations whose receipts weren't
+/// accepted as unknown have no signer to wait for and fail right away.
+pub struct DeferredSigner {
+    backend: Arc<dyn AttestationBackend>,
+    unknown_allocations: UnknownAllocations,
+    grace: Duration,
+    poll_interval: Duration,
+}
+
+impl DeferredSigner {
+    pub fn new(
+        backend: Arc<dyn AttestationBackend>,
+        unknown_allocations: UnknownAllocations,
+        grace: Duration,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            backend,
+            unknown_allocations,
+            grace,
+            poll_interval,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AttestationBackend for DeferredSigner {
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError> {
+        let Some(first_seen) = self.unknown_allocations.first_seen(allocation) else {
+            return self.backend.sign(payload, allocation).await;
+        };
+        let deadline = tokio::time::Instant::from_std(first_seen + self.grace);
+        loop {
+            match self.backend.sign(payload, allocation).await {
+                Err(AttestationBackendError::NoSigner(_))
+                    if tokio::time::Instant::now() + self.poll_interval < deadline =>
+                {
+                    tokio::time::sleep(self.poll_interval).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn nonce_bound(&self) -> bool {
+        self.backend.nonce_bound()
+    }
+}
+
+/// Reuses the signatures of attestations signed before
+///
+/// The signing hash covers the dispute manager domain, the deployment and the
+/// attested request and response, an allocation attesting the same response to
+/// the same request again gets the signature it was given the first time. It is
+/// only reused when the backend isn't [nonce bound](AttestationBackend::nonce_bound),
+/// the others sign every attestation. The least recently used signatures are
+/// dropped beyond `capacity`.
+pub struct CachingBackend {
+    backend: Arc<dyn AttestationBackend>,
+    signatures: Mutex<LruCache<(Address, B256), Signature>>,
+}
+
+impl CachingBackend {
+    pub fn new(backend: Arc<dyn AttestationBackend>, capacity: NonZeroUsize) -> Self {
+        Self {
+            backend,
+            signatures: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AttestationBackend for CachingBackend {
+    async fn sign(
+        &self,
+        payload: &AttestationPayload,
+        allocation: &Address,
+    ) -> Result<Signature, AttestationBackendError> {
+        if self.backend.nonce_bound() {
+            return self.backend.sign(payload, allocation).await;
+        }
+        let key = (*allocation, payload.signing_hash);
+        if let Some(signature) = self.signatures.lock().unwrap().get(&key) {
+            return Ok(*signature);
+        }
+
+        let signature = self.backend.sign(payload, allocation).await?;
+        self.signatures.lock().unwrap().put(key, signature);
+        Ok(signature)
+    }
+
+    fn nonce_bound(&self) -> bool {
+        self.backend.nonce_bound()
+    }
+}
+
+/// What the attestation middleware needs to attest a response
+#[derive(Clone)]
+pub struct AttestationBackendState {
+    pub backend: Arc<dyn AttestationBackend>,
+    /// domain of the dispute manager attestations are verified against
+    pub domain: watch::Receiver<Eip712Domain>,
+    /// serve free queries unattested instead of failing without a signer
+    pub unattested_free_queries: bool,
+    /// what to do when the backend is unavailable or times out
+    pub failure_policy: AttestationFailurePolicy,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use alloy::{primitives::Address, signers::Signature};
+    use indexer_attestation::AttestationPayload;
+    use indexer_monitor::attestation_signers;
+    use prometheus::IntGauge;
+    use reqwest::Url;
+    use serde_json::json;
+    use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use tokio::sync::watch;
+    use wiremock::{
+        matchers::{body_partial_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{
+        AttestationBackend, AttestationBackendError, CachingBackend, DeferredSigner,
+        MeteredBackend, RemoteSigner,
+    };
+    use crate::{middleware::AttestationState, tap::UnknownAllocations};
+
+    fn mnemonic_backend() -> AttestationState {
+        let (_, allocations_rx) = watch::channel(INDEXER_ALLOCATIONS.clone());
+        let (_, dispute_manager_rx) = watch::channel(*DISPUTE_MANAGER_ADDRESS);
+        AttestationState {
+            attestation_signers: attestation_signers(
+                allocations_rx,
+                INDEXER_MNEMONIC.clone(),
+                1,
+                dispute_manager_rx,
+            ),
+            lazy_attestation_signers: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mnemonic_backend() {
+        let backend = mnemonic_backend();
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let signer = backend.signer(&allocation).await.unwrap();
+        let payload = signer.payload("request", "response");
+
+        let signature = backend.sign(&payload, &allocation).await.unwrap();
+        assert_eq!(signature, signer.sign(&payload));
+
+        assert!(matches!(
+            backend.sign(&payload, &Address::ZERO).await,
+            Err(AttestationBackendError::NoSigner(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_remote_signer() {
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let signer = mnemonic_backend().signer(&allocation).await.unwrap();
+        let payload = signer.payload("request", "response");
+        let signature = signer.sign(&payload);
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "allocation": allocation })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "signature": format!("0x{}", alloy::hex::encode(signature.as_bytes())),
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "allocation": Address::ZERO })))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let url = Url::parse(&server.uri()).unwrap();
+        let remote = RemoteSigner::new(reqwest::Client::new(), url, Duration::from_secs(1));
+        assert_eq!(remote.sign(&payload, &allocation).await.unwrap(), signature);
+        assert!(matches!(
+            remote.sign(&payload, &Address::ZERO).await,
+            Err(AttestationBackendError::NoSigner(_))
+        ));
+
+        // a signature by the key of another allocation isn't used
+        let other = *INDEXER_ALLOCATIONS.keys().nth(1).unwrap();
+        let other_signer = mnemonic_backend().signer(&other).await.unwrap();
+        let wrong_key = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "signature": format!(
+                    "0x{}",
+                    alloy::hex::encode(other_signer.sign(&payload).as_bytes())
+                ),
+            })))
+            .mount(&wrong_key)
+            .await;
+        let url = Url::parse(&wrong_key.uri()).unwrap();
+        let remote = RemoteSigner::new(reqwest::Client::new(), url, Duration::from_secs(1));
+        assert!(matches!(
+            remote.sign(&payload, &allocation).await,
+            Err(AttestationBackendError::Unavailable(_))
+        ));
+
+        // a slow signer times out
+        let slow = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&slow)
+            .await;
+        let url = Url::parse(&slow.uri()).unwrap();
+        let remote = RemoteSigner::new(reqwest::Client::new(), url, Duration::from_millis(50));
+        assert!(matches!(
+            remote.sign(&payload, &allocation).await,
+            Err(AttestationBackendError::Timeout)
+        ));
+    }
+
+    struct SlowBackend;
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for SlowBackend {
+        async fn sign(
+            &self,
+            _: &AttestationPayload,
+            allocation: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Err(AttestationBackendError::NoSigner(*allocation))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signing_gauges() {
+        let in_flight = IntGauge::new("in_flight", "Signings in flight").unwrap();
+        let queued = IntGauge::new("queued", "Signings queued").unwrap();
+        let backend = Arc::new(MeteredBackend::with_gauges(
+            Arc::new(SlowBackend),
+            Some(1),
+            Some(1),
+            in_flight.clone(),
+            queued.clone(),
+        ));
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let payload = mnemonic_backend()
+            .signer(&allocation)
+            .await
+            .unwrap()
+            .payload("request", "response");
+
+        let signings: Vec<_> = (0..3)
+            .map(|_| {
+                let backend = backend.clone();
+                let payload = payload.clone();
+                tokio::spawn(async move { backend.sign(&payload, &allocation).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // one signing at a time, the others wait for it
+        assert_eq!(in_flight.get(), 1);
+        assert_eq!(queued.get(), 2);
+
+        for signing in signings {
+            assert!(signing.await.unwrap().is_err());
+        }
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(queued.get(), 0);
+    }
+
+    /// Has no signer until `available_at`
+    struct LateBackend {
+        signer: AttestationState,
+        available_at: tokio::time::Instant,
+    }
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for LateBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            allocation: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            if tokio::time::Instant::now() < self.available_at {
+                return Err(AttestationBackendError::NoSigner(*allocation));
+            }
+            self.signer.sign(payload, allocation).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_signer() {
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let signer = mnemonic_backend().signer(&allocation).await.unwrap();
+        let payload = signer.payload("request", "response");
+        let late = |delay| {
+            Arc::new(LateBackend {
+                signer: mnemonic_backend(),
+                available_at: tokio::time::Instant::now() + delay,
+            })
+        };
+
+        // receipts of the allocation were accepted while it was unknown
+        let unknown_allocations = |grace| {
+            let unknown_allocations = UnknownAllocations::default();
+            unknown_allocations.within_grace(allocation, grace);
+            unknown_allocations
+        };
+
+        // the signer shows up within the grace period
+        let deferred = DeferredSigner::new(
+            late(Duration::from_millis(100)),
+            unknown_allocations(Duration::from_secs(1)),
+            Duration::from_secs(1),
+            Duration::from_millis(20),
+        );
+        assert_eq!(
+            deferred.sign(&payload, &allocation).await.unwrap(),
+            signer.sign(&payload)
+        );
+
+        // it doesn't
+        let deferred = DeferredSigner::new(
+            late(Duration::from_secs(10)),
+            unknown_allocations(Duration::from_millis(100)),
+            Duration::from_millis(100),
+            Duration::from_millis(20),
+        );
+        assert!(matches!(
+            deferred.sign(&payload, &allocation).await,
+            Err(AttestationBackendError::NoSigner(_))
+        ));
+
+        // without receipts accepted as unknown, there is no signer to wait for
+        let deferred = DeferredSigner::new(
+            late(Duration::from_secs(10)),
+            UnknownAllocations::default(),
+            Duration::from_secs(10),
+            Duration::from_millis(20),
+        );
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            deferred.sign(&payload, &allocation).await,
+            Err(AttestationBackendError::NoSigner(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Counts the signings of the mnemonic backend
+    struct CountingBackend {
+        signer: AttestationState,
+        nonce_bound: bool,
+        signings: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for CountingBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            allocation: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            self.signings.fetch_add(1, Ordering::SeqCst);
+            self.signer.sign(payload, allocation).await
+        }
+
+        fn nonce_bound(&self) -> bool {
+            self.nonce_bound
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_backend() {
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let signer = mnemonic_backend().signer(&allocation).await.unwrap();
+        let payload = signer.payload("request", "response");
+        let other_payload = signer.payload("request", "other response");
+        let caching = |nonce_bound| {
+            let backend = Arc::new(CountingBackend {
+                signer: mnemonic_backend(),
+                nonce_bound,
+                signings: AtomicUsize::new(0),
+            });
+            (
+                backend.clone(),
+                CachingBackend::new(backend, NonZeroUsize::MIN),
+            )
+        };
+
+        // the signature of the same attestation is reused
+        let (backend, cached) = caching(false);
+        let signature = cached.sign(&payload, &allocation).await.unwrap();
+        assert_eq!(signature, signer.sign(&payload));
+        assert_eq!(cached.sign(&payload, &allocation).await.unwrap(), signature);
+        assert_eq!(backend.signings.load(Ordering::SeqCst), 1);
+
+        // other responses are signed, pushing the first one out
+        assert_eq!(
+            cached.sign(&other_payload, &allocation).await.unwrap(),
+            signer.sign(&other_payload)
+        );
+        cached.sign(&payload, &allocation).await.unwrap();
+        assert_eq!(backend.signings.load(Ordering::SeqCst), 3);
+
+        // nonce bound signatures are never reused
+        let (backend, cached) = caching(true);
+        cached.sign(&payload, &allocation).await.unwrap();
+        cached.sign(&payload, &allocation).await.unwrap();
+        assert_eq!(backend.signings.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,36 +1,62 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-mod bearer;
-mod or;
+mod authenticator;
 mod tap;
 
-pub use bearer::Bearer;
-pub use or::OrExt;
+pub use authenticator::{AuthOutcome, Authenticated, Authenticator, FreeQueryToken};
 pub use tap::tap_receipt_authorize;
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
+    use std::collections::HashSet;
+
     use axum::body::Body;
-    use axum::http::{Request, Response};
+    use axum::http::{request::Parts, Request, Response};
     use reqwest::{header, StatusCode};
     use sqlx::PgPool;
     use tap_core::{manager::Manager, receipt::checks::CheckList};
     use tower::{Service, ServiceBuilder, ServiceExt};
     use tower_http::auth::AsyncRequireAuthorizationLayer;
 
-    use crate::middleware::auth::{self, Bearer, OrExt};
-    use crate::tap::IndexerTapContext;
+    use crate::middleware::auth::{
+        self, AuthOutcome, Authenticated, Authenticator, FreeQueryToken,
+    };
+    use crate::tap::{CheckFailureLog, IndexerTapContext};
     use test_assets::{
         assert_while_retry, create_signed_receipt, SignedReceiptRequest, TAP_EIP712_DOMAIN,
     };
 
     const BEARER_TOKEN: &str = "test";
+    const API_KEY_HEADER: &str = "x-api-key";
+
+    /// Example of an operator scheme: known keys query for free, unknown
+    /// keys are refused and requests without a key pay as usual
+    struct ApiKeys(HashSet<String>);
+
+    impl Authenticator for ApiKeys {
+        fn authenticate(&self, parts: &Parts) -> AuthOutcome {
+            match parts.headers.get(API_KEY_HEADER) {
+                None => AuthOutcome::RequirePayment,
+                Some(key) => match key.to_str() {
+                    Ok(key) if self.0.contains(key) => AuthOutcome::FreeQuery,
+                    _ => AuthOutcome::Reject,
+                },
+            }
+        }
+    }
 
     async fn service(
         pgpool: PgPool,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
+        authenticated_service(pgpool, Arc::new(FreeQueryToken::new(BEARER_TOKEN))).await
+    }
+
+    async fn authenticated_service(
+        pgpool: PgPool,
+        authenticator: Arc<dyn Authenticator>,
     ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
         let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
         let tap_manager = Arc::new(Manager::new(
@@ -49,9 +75,13 @@ mod tests {
             )
             .unwrap(),
         ));
-        let free_query = Bearer::new(BEARER_TOKEN);
-        let tap_auth = auth::tap_receipt_authorize(tap_manager, metric);
-        let authorize_requests = free_query.or(tap_auth);
+        let tap_auth = auth::tap_receipt_authorize(
+            tap_manager,
+            metric,
+            None,
+            Arc::new(CheckFailureLog::new(None)),
+        );
+        let authorize_requests = Authenticated::new(authenticator, tap_auth);
 
         let authorization_middleware = AsyncRequireAuthorizationLayer::new(authorize_requests);
 
@@ -122,4 +152,30 @@ mod tests {
         let res = service.call(req).await.unwrap();
         assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
     }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_custom_authenticator(pgpool: PgPool) {
+        let api_keys = ApiKeys(HashSet::from(["key".to_string()]));
+        let mut service = authenticated_service(pgpool.clone(), Arc::new(api_keys)).await;
+
+        let mut req = Request::new(Default::default());
+        req.headers_mut()
+            .insert(API_KEY_HEADER, "key".parse().unwrap());
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a rejected request is refused even with a valid receipt
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let mut req = Request::new(Default::default());
+        req.headers_mut()
+            .insert(API_KEY_HEADER, "unknown".parse().unwrap());
+        req.extensions_mut().insert(receipt);
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // without a key, the request pays
+        let req = Request::new(Default::default());
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+    }
 }
@@ -0,0 +1,175 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decide how a request is authenticated before any receipt is checked
+//!
+//! An [Authenticator] looks at the request head and either lets the query
+//! through for free, rejects it, or leaves it to the Tap receipt checks.
+//! The free query token is the built-in authenticator, operators can provide
+//! their own to the router.
+
+use std::{future::Future, pin::Pin, sync::Arc, task::Poll};
+
+use axum::{
+    body::Body,
+    http::{request::Parts, HeaderValue, Request, Response},
+    response::IntoResponse,
+};
+use pin_project::pin_project;
+use reqwest::header;
+use tower_http::auth::AsyncAuthorizeRequest;
+
+use crate::error::IndexerServiceError;
+
+/// What an [Authenticator] decided for a request
+///
+/// Free queries carry [AuthOutcome::FreeQuery] in their extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// serve the query without a receipt
+    FreeQuery,
+    /// the query must be paid with a valid receipt
+    RequirePayment,
+    /// refuse the request, even if it carries a receipt
+    Reject,
+}
+
+/// Authentication scheme consulted before the receipt checks
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, parts: &Parts) -> AuthOutcome;
+}
+
+/// Serves free queries to requests carrying `Authorization: Bearer <token>`
+#[derive(Debug, Clone)]
+pub struct FreeQueryToken {
+    header_value: HeaderValue,
+}
+
+impl FreeQueryToken {
+    pub fn new(token: &str) -> Self {
+        Self {
+            header_value: format!("Bearer {}", token)
+                .parse()
+                .expect("token is not a valid header value"),
+        }
+    }
+}
+
+impl Authenticator for FreeQueryToken {
+    fn authenticate(&self, parts: &Parts) -> AuthOutcome {
+        match parts.headers.get(header::AUTHORIZATION) {
+            Some(actual) if actual == self.header_value => AuthOutcome::FreeQuery,
+            _ => AuthOutcome::RequirePayment,
+        }
+    }
+}
+
+/// Runs the authenticator and, if it requires payment, the receipt authorization
+pub struct Authenticated<P> {
+    authenticator: Arc<dyn Authenticator>,
+    payment: P,
+}
+
+impl<P> Authenticated<P> {
+    pub fn new(authenticator: Arc<dyn Authenticator>, payment: P) -> Self {
+        Self {
+            authenticator,
+            payment,
+        }
+    }
+}
+
+impl<P: Clone> Clone for Authenticated<P> {
+    fn clone(&self) -> Self {
+        Self {
+            authenticator: self.authenticator.clone(),
+            payment: self.payment.clone(),
+        }
+    }
+}
+
+impl<P, B, Fut> AsyncAuthorizeRequest<B> for Authenticated<P>
+where
+    B: 'static + Send,
+    P: AsyncAuthorizeRequest<B, RequestBody = B, ResponseBody = Body, Future = Fut>
+        + Clone
+        + 'static
+        + Send,
+    Fut: Future<Output = Result<Request<B>, Response<Body>>> + Send,
+{
+    type RequestBody = B;
+    type ResponseBody = Body;
+
+    type Future = AuthenticatedFuture<Fut, B>;
+
+    fn authorize(&mut self, request: Request<B>) -> Self::Future {
+        let (mut parts, body) = request.into_parts();
+        let outcome = self.authenticator.authenticate(&parts);
+        if outcome == AuthOutcome::FreeQuery {
+            parts.extensions.insert(outcome);
+        }
+        let request = Request::from_parts(parts, body);
+        match outcome {
+            AuthOutcome::FreeQuery => AuthenticatedFuture::with_result(Ok(request)),
+            AuthOutcome::Reject => AuthenticatedFuture::with_result(Err(
+                IndexerServiceError::Unauthorized.into_response(),
+            )),
+            AuthOutcome::RequirePayment => {
+                AuthenticatedFuture::with_future(self.payment.authorize(request))
+            }
+        }
+    }
+}
+
+#[pin_project::pin_project(project = KindProj)]
+pub enum Kind<Fut, Req> {
+    QueryResult {
+        #[pin]
+        fut: Fut,
+    },
+    ReturnResult {
+        result: Option<Result<Request<Req>, Response<Body>>>,
+    },
+}
+
+#[pin_project]
+pub struct AuthenticatedFuture<Fut, Req> {
+    #[pin]
+    kind: Kind<Fut, Req>,
+}
+
+impl<Fut, Req> AuthenticatedFuture<Fut, Req> {
+    fn with_result(result: Result<Request<Req>, Response<Body>>) -> Self {
+        Self {
+            kind: Kind::ReturnResult {
+                result: Some(result),
+            },
+        }
+    }
+
+    fn with_future(fut: Fut) -> Self {
+        Self {
+            kind: Kind::QueryResult { fut },
+        }
+    }
+}
+
+impl<Fut, Req> Future for AuthenticatedFuture<Fut, Req>
+where
+    Fut: Future<Output = Result<Request<Req>, Response<Body>>>,
+{
+    type Output = Result<Request<Req>, Response<Body>>;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let this = self.project();
+        match this.kind.project() {
+            KindProj::QueryResult { fut } => fut.poll(cx),
+            KindProj::ReturnResult { result } => {
+                Poll::Ready(result.take().expect("cannot poll twice"))
+            }
+        }
+    }
+}
@@ -7,7 +7,11 @@
 //! as part of the checks.
 //!
 //! This also uses MetricLabels injected in the receipts to provide
-//! metrics related to receipt check failure
+//! metrics related to receipt check failure, and writes refused receipts to
+//! the receipt log if there is one. Check failures are logged through the
+//! [CheckFailureLog], which may throttle them. The [PendingSettlements] of the
+//! checks are applied once the receipt is accepted. Receipts of queries past
+//! their [Deadline] aren't stored, the query won't be served
 
 use std::{future::Future, sync::Arc};
 
@@ -18,11 +22,18 @@ use axum::{
 };
 use tap_core::{
     manager::{adapters::ReceiptStore, Manager},
-    receipt::{Context, SignedReceipt},
+    receipt::{Context, ReceiptError, SignedReceipt},
+    Error as TapError,
 };
 use tower_http::auth::AsyncAuthorizeRequest;
 
-use crate::{error::IndexerServiceError, middleware::prometheus_metrics::MetricLabels};
+use crate::{
+    error::IndexerServiceError,
+    middleware::{prometheus_metrics::MetricLabels, Deadline, Sender},
+    tap::{
+        AgoraQuery, CheckFailureLog, FailedCheck, PendingSettlements, ReceiptLog, ReceiptLogRecord,
+    },
+};
 
 /// Middleware to verify and store TAP receipts
 ///
@@ -32,6 +43,8 @@ use crate::{error::IndexerServiceError, middleware::prometheus_metrics::MetricLa
 pub fn tap_receipt_authorize<T, B>(
     tap_manager: Arc<Manager<T>>,
     failed_receipt_metric: &'static prometheus::CounterVec,
+    receipt_log: Option<ReceiptLog>,
+    check_failure_log: Arc<CheckFailureLog>,
 ) -> impl AsyncAuthorizeRequest<
     B,
     RequestBody = B,
@@ -49,22 +62,55 @@ where
         let labels = request.extensions().get::<MetricLabels>().cloned();
         // load context from previous middlewares
         let ctx = request.extensions().get::<Arc<Context>>().cloned();
+        let deadline = request.extensions().get::<Deadline>().copied();
+        let deadline_passed =
+            move || deadline.is_some_and(|deadline| deadline.remaining().is_none());
         let tap_manager = tap_manager.clone();
+        let receipt_log = receipt_log.clone();
+        let check_failure_log = check_failure_log.clone();
 
         async move {
             let execute = || async {
                 let receipt = receipt.ok_or(IndexerServiceError::ReceiptNotFound)?;
-                // Verify the receipt and store it in the database
+                let ctx = ctx.unwrap_or_default();
+                if deadline_passed() {
+                    return Err(IndexerServiceError::DeadlineExceeded);
+                }
+                // Verify the receipt and store it in the database, the checks
+                // refusing it once the deadline passed while they ran
                 tap_manager
-                    .verify_and_store_receipt(&ctx.unwrap_or_default(), receipt)
+                    .verify_and_store_receipt(&ctx, receipt.clone())
                     .await
-                    .inspect_err(|_| {
+                    .inspect_err(|error| {
+                        let sender = ctx.get::<Sender>().map(|Sender(sender)| *sender);
+                        if let TapError::ReceiptError(ReceiptError::CheckFailure(failure)) = error {
+                            check_failure_log.log(sender, FailedCheck::get(&ctx), failure);
+                        }
                         if let Some(labels) = labels {
                             failed_receipt_metric
                                 .with_label_values(&labels.get_labels())
                                 .inc()
                         }
+                        if let Some(receipt_log) = receipt_log {
+                            receipt_log.log(ReceiptLogRecord::new(
+                                &receipt,
+                                sender,
+                                ctx.get::<AgoraQuery>().map(|query| query.deployment_id),
+                                error.to_string(),
+                            ));
+                        }
+                    })
+                    .map_err(|error| match error {
+                        TapError::ReceiptError(ReceiptError::RetryableCheck(_))
+                            if deadline_passed() =>
+                        {
+                            IndexerServiceError::DeadlineExceeded
+                        }
+                        error => error.into(),
                     })?;
+                if let Some(settlements) = ctx.get::<PendingSettlements>() {
+                    settlements.settle();
+                }
                 Ok::<_, IndexerServiceError>(request)
             };
             execute().await.map_err(|error| error.into_response())
@@ -77,7 +123,13 @@ mod tests {
 
     use core::panic;
     use rstest::*;
-    use std::sync::Arc;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
     use tower::{Service, ServiceBuilder, ServiceExt};
 
     use axum::{
@@ -85,14 +137,14 @@ mod tests {
         http::{Request, Response},
     };
     use prometheus::core::Collector;
-    use reqwest::StatusCode;
+    use reqwest::{header::RETRY_AFTER, StatusCode};
     use sqlx::PgPool;
     use tap_core::{
         manager::Manager,
         receipt::{
             checks::{Check, CheckError, CheckList, CheckResult},
             state::Checking,
-            ReceiptWithState,
+            Context, ReceiptWithState,
         },
     };
     use test_assets::{
@@ -104,8 +156,9 @@ mod tests {
         middleware::{
             auth::tap_receipt_authorize,
             prometheus_metrics::{MetricLabelProvider, MetricLabels},
+            Deadline,
         },
-        tap::IndexerTapContext,
+        tap::{CheckFailureLog, IndexerTapContext, PendingSettlements},
     };
 
     #[fixture]
@@ -124,6 +177,7 @@ mod tests {
     }
 
     const FAILED_NONCE: u64 = 99;
+    const RETRYABLE_NONCE: u64 = 98;
 
     async fn service(
         metric: &'static prometheus::CounterVec,
@@ -139,10 +193,10 @@ mod tests {
                 _: &tap_core::receipt::Context,
                 receipt: &ReceiptWithState<Checking>,
             ) -> CheckResult {
-                if receipt.signed_receipt().message.nonce == FAILED_NONCE {
-                    Err(CheckError::Failed(anyhow::anyhow!("Failed")))
-                } else {
-                    Ok(())
+                match receipt.signed_receipt().message.nonce {
+                    FAILED_NONCE => Err(CheckError::Failed(anyhow::anyhow!("Failed"))),
+                    RETRYABLE_NONCE => Err(CheckError::Retryable(anyhow::anyhow!("Timed out"))),
+                    _ => Ok(()),
                 }
             }
         }
@@ -152,7 +206,8 @@ mod tests {
             context,
             CheckList::new(vec![Arc::new(MyCheck)]),
         ));
-        let tap_auth = tap_receipt_authorize(manager, metric);
+        let tap_auth =
+            tap_receipt_authorize(manager, metric, None, Arc::new(CheckFailureLog::new(None)));
         let authorization_middleware = AsyncRequireAuthorizationLayer::new(tap_auth);
 
         let mut service = ServiceBuilder::new()
@@ -191,6 +246,48 @@ mod tests {
         })
     }
 
+    #[rstest]
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_settled_only_once_accepted(
+        metric: &'static prometheus::CounterVec,
+        #[ignore] pgpool: PgPool,
+    ) {
+        let mut service = service(metric, pgpool.clone()).await;
+        let settled = Arc::new(AtomicUsize::new(0));
+        let request = |nonce| {
+            let settled = settled.clone();
+            async move {
+                let settlements = PendingSettlements::default();
+                settlements.defer(move || {
+                    settled.fetch_add(1, Ordering::SeqCst);
+                });
+                let mut ctx = Context::new();
+                ctx.insert(settlements);
+                let receipt =
+                    create_signed_receipt(SignedReceiptRequest::builder().nonce(nonce).build())
+                        .await;
+                let mut req = Request::new(Body::default());
+                req.extensions_mut().insert(receipt);
+                req.extensions_mut().insert(Arc::new(ctx));
+                req
+            }
+        };
+
+        let res = service.call(request(FAILED_NONCE).await).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(settled.load(Ordering::SeqCst), 0);
+
+        let res = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request(1).await)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(settled.load(Ordering::SeqCst), 1);
+    }
+
     #[rstest]
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_invalid_receipt_with_failed_metric(
@@ -238,4 +335,70 @@ mod tests {
         let res = service.call(req).await.unwrap();
         assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
     }
+
+    #[rstest]
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_database_unavailable(
+        metric: &'static prometheus::CounterVec,
+        #[ignore] pgpool: PgPool,
+    ) {
+        let mut service = service(metric, pgpool.clone()).await;
+        pgpool.close().await;
+
+        // the first receipts are queued, the write failing marks the database
+        // as unavailable and the following ones are refused
+        assert_while_retry!({
+            let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+            let mut req = Request::new(Body::default());
+            req.extensions_mut().insert(receipt);
+            let res = service.ready().await.unwrap().call(req).await.unwrap();
+            if res.status() == StatusCode::SERVICE_UNAVAILABLE {
+                assert_eq!(res.headers()[RETRY_AFTER], "5");
+            }
+            res.status() != StatusCode::SERVICE_UNAVAILABLE
+        });
+    }
+
+    #[rstest]
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_receipt_past_deadline_is_not_stored(
+        metric: &'static prometheus::CounterVec,
+        #[ignore] pgpool: PgPool,
+    ) {
+        let mut service = service(metric, pgpool.clone()).await;
+
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let mut req = Request::new(Body::default());
+        req.extensions_mut().insert(receipt);
+        req.extensions_mut().insert(Deadline(Instant::now()));
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sqlx::query!("SELECT * FROM scalar_tap_receipts")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[rstest]
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_retryable_check_failure(
+        metric: &'static prometheus::CounterVec,
+        #[ignore] pgpool: PgPool,
+    ) {
+        let mut service = service(metric, pgpool.clone()).await;
+
+        let receipt = create_signed_receipt(
+            SignedReceiptRequest::builder()
+                .nonce(RETRYABLE_NONCE)
+                .build(),
+        )
+        .await;
+        let mut req = Request::new(Body::default());
+        req.extensions_mut().insert(receipt);
+        let res = service.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
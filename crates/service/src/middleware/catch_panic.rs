@@ -0,0 +1,122 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{any::Any, panic::AssertUnwindSafe};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::FutureExt;
+use indexer_config::RequestPanicAction;
+use thegraph_core::DeploymentId;
+use tracing::error;
+
+use crate::error::SubgraphServiceError;
+
+use super::RequestId;
+
+/// State to be used by catch panic middleware
+#[derive(Clone)]
+pub struct CatchPanicState {
+    pub action: RequestPanicAction,
+}
+
+/// Answers a query whose handler panicked with a 500 instead of dropping the
+/// connection, or aborts the process if configured so
+///
+/// Uses the DeploymentId and RequestId Extensions, if any, to log which
+/// deployment was queried and by which request
+pub async fn catch_panic_middleware(
+    State(state): State<CatchPanicState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let deployment = request.extensions().get::<DeploymentId>().copied();
+    let request_id = request.extensions().get::<RequestId>().cloned();
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+
+    match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            error!(
+                deployment = deployment.map(|deployment| deployment.to_string()),
+                request_id = request_id
+                    .as_ref()
+                    .and_then(|RequestId(request_id)| request_id.to_str().ok()),
+                %method,
+                %uri,
+                panic = panic_message(&*panic),
+                "Query handler panicked"
+            );
+            if state.action == RequestPanicAction::Abort {
+                std::process::abort();
+            }
+            SubgraphServiceError::FailedToProvideResponse.into_response()
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{HeaderValue, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use indexer_config::RequestPanicAction;
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+    use tracing_test::traced_test;
+
+    use super::{catch_panic_middleware, CatchPanicState};
+    use crate::middleware::RequestId;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_panic_is_answered() {
+        let state = CatchPanicState {
+            action: RequestPanicAction::Respond,
+        };
+        let app = Router::new()
+            .route("/panic", get(|| async { panic!("query handler bug") }))
+            .route("/", get(|| async { "ok" }))
+            .layer(from_fn_with_state(state, catch_panic_middleware));
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/panic")
+                    .extension(RequestId(HeaderValue::from_static("request-1")))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "Failed to provide a response");
+        assert!(logs_contain("request_id=\"request-1\""));
+        assert!(logs_contain("query handler bug"));
+
+        // the service keeps serving
+        let res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
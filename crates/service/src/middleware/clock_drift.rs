@@ -0,0 +1,33 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tap_core::receipt::SignedReceipt;
+use tokio::sync::watch;
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by clock drift middleware
+#[derive(Clone)]
+pub struct ClockDriftState {
+    pub drifted: watch::Receiver<bool>,
+}
+
+/// Refuses receipt-bearing requests while the host clock is too far off for
+/// the receipt timestamps to be checked
+///
+/// Requires signed receipt Extension to be added
+pub async fn clock_drift_middleware(
+    State(state): State<ClockDriftState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if *state.drifted.borrow() && request.extensions().get::<SignedReceipt>().is_some() {
+        return IndexerServiceError::ClockOutOfSync.into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,118 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by content type middleware
+#[derive(Clone)]
+pub struct ContentTypeState {
+    /// media types accepted, lowercase and without parameters
+    pub accepted: Arc<HashSet<String>>,
+}
+
+impl ContentTypeState {
+    pub fn new(accepted: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            accepted: Arc::new(
+                accepted
+                    .into_iter()
+                    .map(|media_type| media_type.to_ascii_lowercase())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Refuses requests with a `415` unless their `Content-Type` is accepted
+///
+/// Parameters such as `charset` are ignored. Requests without a content type
+/// are refused too, their body would have to be guessed.
+pub async fn content_type_middleware(
+    State(state): State<ContentTypeState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+    let media_type = content_type.as_deref().map(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    });
+    match media_type {
+        Some(media_type) if state.accepted.contains(&media_type) => next.run(request).await,
+        _ => IndexerServiceError::UnsupportedContentType(content_type).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request},
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{content_type_middleware, ContentTypeState};
+
+    async fn send(content_type: Option<&str>) -> StatusCode {
+        let app = Router::new()
+            .route("/", post(|| async { "ok" }))
+            .layer(from_fn_with_state(
+                ContentTypeState::new(["application/json".to_string()]),
+                content_type_middleware,
+            ));
+        let mut request = Request::builder().method("POST").uri("/");
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        app.oneshot(request.body(Body::from("{}")).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_accepted_content_type() {
+        assert_eq!(send(Some("application/json")).await, StatusCode::OK);
+        assert_eq!(
+            send(Some("Application/JSON; charset=utf-8")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_content_type() {
+        assert_eq!(
+            send(Some("application/msgpack")).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            send(Some("text/plain")).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_content_type() {
+        assert_eq!(send(None).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tells in paid responses what the query was charged and appraised at
+//!
+//! The charged value is the value of the receipt the query was accepted with,
+//! the appraisal the value the `minimum_value` check compared it to. They are
+//! sent in headers, which are never attested, so the attestation still covers
+//! the response of graph-node as is.
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use indexer_config::CostMetadata;
+use tap_core::receipt::SignedReceipt;
+
+use crate::tap::AppraisalSlot;
+
+/// Header set on paid responses to the value of their receipt, in GRT wei
+pub const QUERY_CHARGED_VALUE: &str = "query-charged-value";
+/// Header set on paid responses to the appraisal their receipt was checked
+/// against, as `<value in GRT wei>; source=<deployment|global|default>`
+pub const QUERY_APPRAISAL: &str = "query-appraisal";
+
+/// Adds the cost metadata to paid responses, free queries are left as they are
+///
+/// Requires the signed receipt and the AppraisalSlot Extensions.
+pub async fn cost_metadata_middleware(
+    State(mode): State<CostMetadata>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let charged_value = request
+        .extensions()
+        .get::<SignedReceipt>()
+        .map(|receipt| receipt.message.value);
+    let appraisal_slot = request.extensions().get::<AppraisalSlot>().cloned();
+
+    let response = next.run(request).await;
+    let Some(charged_value) = charged_value else {
+        return response;
+    };
+    if mode == CostMetadata::Disabled {
+        return response;
+    }
+    let appraisal = appraisal_slot.as_ref().and_then(AppraisalSlot::get);
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(
+        QUERY_CHARGED_VALUE,
+        HeaderValue::try_from(charged_value.to_string()).expect("digits are a valid header value"),
+    );
+    if let Some(appraisal) = appraisal {
+        let value = format!("{}; source={}", appraisal.value, appraisal.source.as_str());
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            parts.headers.insert(QUERY_APPRAISAL, value);
+        }
+    }
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use alloy::{primitives::Address, signers::Signature};
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, Response},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use indexer_attestation::{AttestationPayload, AttestationSigner};
+    use indexer_config::CostMetadata;
+    use serde_json::Value;
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC,
+    };
+    use thegraph_core::attestation::{eip712_domain, Attestation};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{cost_metadata_middleware, QUERY_APPRAISAL, QUERY_CHARGED_VALUE};
+    use crate::{
+        middleware::{
+            attestation_middleware, Allocation, AttestationBackend, AttestationBackendError,
+            AttestationBackendState, AttestationInput,
+        },
+        tap::{Appraisal, AppraisalSlot, AppraisalSource},
+    };
+
+    const REQUEST: &str = "request";
+    const RESPONSE: &str = r#"{"data":{"a":1}}"#;
+
+    struct MockBackend(AttestationSigner);
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for MockBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            _: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            Ok(self.0.sign(payload))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cost_metadata() {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let signer =
+            AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), &allocation, 1, Address::ZERO)
+                .unwrap();
+        let backend_state = AttestationBackendState {
+            backend: Arc::new(MockBackend(signer.clone())),
+            domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+            unattested_free_queries: false,
+            failure_policy: Default::default(),
+        };
+        let receipt =
+            create_signed_receipt(SignedReceiptRequest::builder().value(100).build()).await;
+
+        let send = |mode: CostMetadata, paid: bool| {
+            let app = Router::new()
+                .route(
+                    "/",
+                    get(|| async {
+                        let mut res = Response::new(RESPONSE.to_string());
+                        res.extensions_mut().insert(AttestationInput::Attestable {
+                            req: REQUEST.to_string(),
+                        });
+                        res
+                    }),
+                )
+                .layer(from_fn_with_state(mode, cost_metadata_middleware))
+                .layer(from_fn_with_state(
+                    backend_state.clone(),
+                    attestation_middleware,
+                ));
+            // the value check appraised the query
+            let appraisal = AppraisalSlot::default();
+            appraisal.set(Appraisal {
+                value: 80,
+                source: AppraisalSource::Deployment,
+            });
+            let mut request = Request::builder()
+                .uri("/")
+                .extension(Allocation(allocation.id))
+                .extension(allocation.subgraph_deployment.id)
+                .extension(appraisal);
+            if paid {
+                request = request.extension(receipt.clone());
+            }
+            async move {
+                let res = app
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let headers = res.headers().clone();
+                let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                let payload: Value = serde_json::from_slice(&bytes).unwrap();
+                let graphql_response = payload["graphQLResponse"].as_str().unwrap().to_string();
+                let attestation: Attestation =
+                    serde_json::from_value(payload["attestation"].clone()).unwrap();
+                (headers, graphql_response, attestation)
+            }
+        };
+
+        // nothing is added by default, the attestation covers the response as is
+        let (headers, graphql_response, attestation) = send(CostMetadata::Disabled, true).await;
+        assert!(!headers.contains_key(QUERY_CHARGED_VALUE));
+        assert!(!headers.contains_key(QUERY_APPRAISAL));
+        assert_eq!(graphql_response, RESPONSE);
+        assert!(signer
+            .verify(&attestation, REQUEST, RESPONSE, &allocation.id)
+            .is_ok());
+
+        // headers don't change what is attested
+        let (headers, graphql_response, attestation) = send(CostMetadata::Headers, true).await;
+        assert_eq!(headers[QUERY_CHARGED_VALUE], "100");
+        assert_eq!(headers[QUERY_APPRAISAL], "80; source=deployment");
+        assert_eq!(graphql_response, RESPONSE);
+        assert!(signer
+            .verify(&attestation, REQUEST, RESPONSE, &allocation.id)
+            .is_ok());
+
+        // free queries aren't charged
+        let (headers, _, _) = send(CostMetadata::Headers, false).await;
+        assert!(!headers.contains_key(QUERY_CHARGED_VALUE));
+    }
+}
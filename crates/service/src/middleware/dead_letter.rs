@@ -0,0 +1,110 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::DeploymentId;
+use tracing::{error, warn};
+
+use super::sender::Sender;
+use crate::tap::DeadLetterStore;
+
+/// Why the query of a response can never succeed, added to the error responses
+/// as an Extension
+#[derive(Debug, Clone)]
+pub struct PermanentFailure(pub String);
+
+/// Records the receipt of queries that failed for good in the [DeadLetterStore]
+///
+/// Transient failures, like graph-node being unreachable, are left out as
+/// retrying the query may succeed.
+///
+/// Requires signed receipt Extension to be added
+pub async fn dead_letter_middleware(
+    State(store): State<DeadLetterStore>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let receipt = request.extensions().get::<SignedReceipt>().cloned();
+    let sender = request
+        .extensions()
+        .get::<Sender>()
+        .map(|Sender(sender)| *sender);
+    let deployment_id = request.extensions().get::<DeploymentId>().copied();
+
+    let response = next.run(request).await;
+    if let (Some(receipt), Some(PermanentFailure(failure))) =
+        (receipt, response.extensions().get::<PermanentFailure>())
+    {
+        warn!(%failure, ?sender, "Dead-lettering the receipt of a query that can't be served");
+        if let Err(e) = store
+            .record(&receipt, sender, deployment_id, failure.clone())
+            .await
+        {
+            error!(error = %e, "Failed to dead-letter receipt");
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use sqlx::PgPool;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_SENDER};
+    use tower::ServiceExt;
+
+    use super::dead_letter_middleware;
+    use crate::{error::SubgraphServiceError, middleware::Sender, tap::DeadLetterStore};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_dead_letters_permanent_failures(pgpool: PgPool) {
+        let store = DeadLetterStore::new(pgpool);
+        let app = Router::new()
+            .route(
+                "/invalid",
+                get(|| async {
+                    Err::<(), _>(SubgraphServiceError::InvalidQuery("Unexpected `}`".into()))
+                }),
+            )
+            .route(
+                "/timeout",
+                get(|| async { Err::<(), _>(SubgraphServiceError::DeadlineExceeded) }),
+            )
+            .layer(from_fn_with_state(store.clone(), dead_letter_middleware));
+        let send = |uri: &'static str, nonce: u64| {
+            let app = app.clone();
+            async move {
+                let receipt =
+                    create_signed_receipt(SignedReceiptRequest::builder().nonce(nonce).build())
+                        .await;
+                let request = Request::builder()
+                    .uri(uri)
+                    .extension(receipt)
+                    .extension(Sender(TAP_SENDER.1))
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        // the query may go through on a retry
+        assert_eq!(send("/timeout", 1).await, StatusCode::GATEWAY_TIMEOUT);
+        assert!(store.list(10).await.unwrap().is_empty());
+
+        assert_eq!(send("/invalid", 2).await, StatusCode::BAD_REQUEST);
+        let records = store.list(10).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].nonce, 2);
+        assert_eq!(
+            records[0].sender,
+            Some(TAP_SENDER.1.to_string().to_lowercase())
+        );
+        assert_eq!(records[0].failure, "Invalid query: Unexpected `}`");
+    }
+}
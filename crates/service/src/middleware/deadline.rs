@@ -0,0 +1,98 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+/// Point in time by which the query has to be answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    /// Time left until the deadline, `None` once it passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// State to be used by deadline middleware
+#[derive(Clone)]
+pub struct DeadlineState {
+    pub timeout: Duration,
+}
+
+/// Gives the request its [Deadline], counted from now
+///
+/// Everything the request goes through afterwards, like waiting in the request
+/// queue or checking the receipt, uses up the time left for graph-node.
+pub async fn deadline_middleware(
+    State(state): State<DeadlineState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    request
+        .extensions_mut()
+        .insert(Deadline(Instant::now() + state.timeout));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::{to_bytes, Body},
+        extract::Request,
+        middleware::{from_fn, from_fn_with_state, Next},
+        routing::get,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+
+    use super::{deadline_middleware, Deadline, DeadlineState};
+
+    #[tokio::test]
+    async fn test_deadline_shrinks() {
+        const TIMEOUT: Duration = Duration::from_secs(1);
+        const CHECKS: Duration = Duration::from_millis(100);
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(deadline): Extension<Deadline>| async move {
+                    deadline.remaining().unwrap().as_millis().to_string()
+                }),
+            )
+            // slow receipt checks
+            .layer(from_fn(|request: Request, next: Next| async move {
+                tokio::time::sleep(CHECKS).await;
+                next.run(request).await
+            }))
+            .layer(from_fn_with_state(
+                DeadlineState { timeout: TIMEOUT },
+                deadline_middleware,
+            ));
+
+        let res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let remaining: u128 = String::from_utf8(
+            to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap()
+        .parse()
+        .unwrap();
+        assert!(remaining <= (TIMEOUT - CHECKS).as_millis());
+    }
+}
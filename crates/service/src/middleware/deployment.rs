@@ -1,18 +1,68 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
 use axum::{
-    extract::{Path, Request},
+    extract::{Path, Request, State},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
     RequestExt,
 };
 use thegraph_core::DeploymentId;
+use tokio::sync::watch;
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by deployment middleware
+#[derive(Clone)]
+pub struct DeploymentState {
+    /// friendlier names operators can use instead of the deployment id
+    pub aliases: Arc<HashMap<String, DeploymentId>>,
+    /// deployments this instance serves, every deployment if empty
+    pub served: Arc<HashSet<DeploymentId>>,
+    /// deployments refused outright, replaced at runtime by the admin api
+    pub denied: watch::Receiver<HashSet<DeploymentId>>,
+}
+
+impl Default for DeploymentState {
+    fn default() -> Self {
+        Self {
+            aliases: Default::default(),
+            served: Default::default(),
+            denied: watch::channel(HashSet::new()).1,
+        }
+    }
+}
 
 /// Injects deployment id in the extensions from the path
-pub async fn deployment_middleware(mut request: Request, next: Next) -> Response {
-    let deployment_id = request.extract_parts::<Path<DeploymentId>>().await.ok();
-    if let Some(Path(deployment_id)) = deployment_id {
+///
+/// The path segment is either a deployment id or one of the configured
+/// aliases. Anything else is answered with `400`, deployments left out of
+/// the served ones with `404` and denied deployments with `403`, before any
+/// receipt is read.
+pub async fn deployment_middleware(
+    State(state): State<DeploymentState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Ok(Path(id)) = request.extract_parts::<Path<String>>().await {
+        let deployment_id = match id.parse::<DeploymentId>() {
+            Ok(deployment_id) => deployment_id,
+            Err(_) => match state.aliases.get(&id) {
+                Some(deployment_id) => *deployment_id,
+                None => return IndexerServiceError::InvalidDeploymentId(id).into_response(),
+            },
+        };
+        if !state.served.is_empty() && !state.served.contains(&deployment_id) {
+            return IndexerServiceError::DeploymentNotServed(deployment_id).into_response();
+        }
+        if state.denied.borrow().contains(&deployment_id) {
+            return IndexerServiceError::DeploymentDenied(deployment_id).into_response();
+        }
         request.extensions_mut().insert(deployment_id);
     }
     next.run(request).await
@@ -20,22 +70,28 @@ pub async fn deployment_middleware(mut request: Request, next: Next) -> Response
 
 #[cfg(test)]
 mod tests {
-    use super::deployment_middleware;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use super::{deployment_middleware, DeploymentState};
     use axum::{
-        body::Body,
+        body::{to_bytes, Body},
         http::{Extensions, Request},
-        middleware::from_fn,
-        routing::get,
+        middleware::from_fn_with_state,
+        routing::{get, post},
         Router,
     };
     use reqwest::StatusCode;
-    use test_assets::ESCROW_SUBGRAPH_DEPLOYMENT;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
     use thegraph_core::DeploymentId;
+    use tokio::sync::watch;
     use tower::ServiceExt;
 
     #[tokio::test]
     async fn test_deployment_middleware() {
-        let middleware = from_fn(deployment_middleware);
+        let middleware = from_fn_with_state(DeploymentState::default(), deployment_middleware);
 
         let deployment = *ESCROW_SUBGRAPH_DEPLOYMENT;
 
@@ -62,4 +118,125 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_deployment_alias() {
+        let deployment = *ESCROW_SUBGRAPH_DEPLOYMENT;
+        let state = DeploymentState {
+            aliases: Arc::new(HashMap::from([("escrow".to_string(), deployment)])),
+            ..Default::default()
+        };
+
+        let handle = move |extensions: Extensions| async move {
+            assert_eq!(extensions.get::<DeploymentId>(), Some(&deployment));
+            Body::empty()
+        };
+
+        let app = Router::new()
+            .route("/:deployment_id", get(handle))
+            .layer(from_fn_with_state(state, deployment_middleware));
+
+        let send = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(send("/escrow").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            send("/network").await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_deployment_id() {
+        let app = Router::new()
+            .route("/subgraphs/id/:id", post(|| async { Body::empty() }))
+            .layer(from_fn_with_state(
+                DeploymentState::default(),
+                deployment_middleware,
+            ));
+
+        let res = app
+            .oneshot(
+                Request::post("/subgraphs/id/not-a-valid-id")
+                    .body(Body::from(r#"{"query": "{ a }"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"message": "Invalid deployment id: `not-a-valid-id`"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_served_deployments() {
+        let served = *ESCROW_SUBGRAPH_DEPLOYMENT;
+        let state = DeploymentState {
+            served: Arc::new(HashSet::from([served])),
+            ..Default::default()
+        };
+
+        let app = Router::new()
+            .route("/:deployment_id", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(state, deployment_middleware));
+
+        let send = |deployment: DeploymentId| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(format!("/{deployment}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(send(served).await.unwrap().status(), StatusCode::OK);
+        let res = send(*NETWORK_SUBGRAPH_DEPLOYMENT).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_denied_deployments() {
+        let denied = *ESCROW_SUBGRAPH_DEPLOYMENT;
+        let allowed = *NETWORK_SUBGRAPH_DEPLOYMENT;
+        let (denied_tx, denied_rx) = watch::channel(HashSet::from([denied]));
+        let state = DeploymentState {
+            denied: denied_rx,
+            ..Default::default()
+        };
+
+        let app = Router::new()
+            .route("/:deployment_id", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(state, deployment_middleware));
+
+        let send = |deployment: DeploymentId| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(format!("/{deployment}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(send(allowed).await.unwrap().status(), StatusCode::OK);
+        let res = send(denied).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"message": format!("Deployment `{denied}` is denied by the indexer")})
+        );
+
+        // the list is replaced at runtime
+        denied_tx.send_replace(HashSet::from([allowed]));
+        assert_eq!(send(denied).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send(allowed).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
 }
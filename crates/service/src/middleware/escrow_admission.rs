@@ -0,0 +1,184 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderName,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexer_config::{EscrowAdmissionConfig, QueryPriority};
+use thegraph_core::DeploymentId;
+use tokio::sync::watch;
+
+use super::sender::Sender;
+use crate::{error::IndexerServiceError, tap::EscrowHeadroom};
+
+/// State to be used by escrow admission middleware
+#[derive(Clone)]
+pub struct EscrowAdmissionState {
+    pub header: HeaderName,
+    pub default_priority: QueryPriority,
+    pub deployment_priorities: Arc<HashMap<DeploymentId, QueryPriority>>,
+    pub low_priority_min_headroom: f64,
+    pub normal_priority_min_headroom: f64,
+    pub headroom: watch::Receiver<EscrowHeadroom>,
+}
+
+impl EscrowAdmissionState {
+    pub fn new(
+        config: &EscrowAdmissionConfig,
+        headroom: watch::Receiver<EscrowHeadroom>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            header: HeaderName::try_from(config.priority_header.as_str())?,
+            default_priority: config.default_priority,
+            deployment_priorities: Arc::new(config.deployment_priorities.clone()),
+            low_priority_min_headroom: config.low_priority_min_headroom,
+            normal_priority_min_headroom: config.normal_priority_min_headroom,
+            headroom,
+        })
+    }
+
+    fn priority(&self, request: &Request) -> QueryPriority {
+        let header = request
+            .headers()
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| match value.trim().to_ascii_lowercase().as_str() {
+                "low" => Some(QueryPriority::Low),
+                "normal" => Some(QueryPriority::Normal),
+                "high" => Some(QueryPriority::High),
+                _ => None,
+            });
+        header
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<DeploymentId>()
+                    .and_then(|deployment| self.deployment_priorities.get(deployment))
+                    .copied()
+            })
+            .unwrap_or(self.default_priority)
+    }
+}
+
+/// Refuses the lower priority queries of senders running out of escrow
+///
+/// Once the headroom of the sender falls below the fraction of its priority,
+/// the query is refused with a `429`. High priority queries always go through,
+/// the balance check refusing them once the escrow is exhausted. Senders with
+/// no known headroom are left to the receipt checks.
+///
+/// Requires Sender Extension to be added
+pub async fn escrow_admission_middleware(
+    State(state): State<EscrowAdmissionState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(Sender(sender)) = request.extensions().get::<Sender>().cloned() else {
+        return next.run(request).await;
+    };
+    let min_headroom = match state.priority(&request) {
+        QueryPriority::Low => state.low_priority_min_headroom,
+        QueryPriority::Normal => state.normal_priority_min_headroom,
+        QueryPriority::High => return next.run(request).await,
+    };
+    let headroom = state.headroom.borrow().get(&sender).copied();
+    if headroom.is_some_and(|headroom| headroom < min_headroom) {
+        return IndexerServiceError::EscrowHeadroomReserved(sender).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{
+        body::Body,
+        http::{HeaderName, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use indexer_config::QueryPriority;
+    use reqwest::StatusCode;
+    use test_assets::ESCROW_SUBGRAPH_DEPLOYMENT;
+    use thegraph_core::Address;
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{escrow_admission_middleware, EscrowAdmissionState};
+    use crate::middleware::sender::Sender;
+
+    const SENDER: Address = Address::repeat_byte(1);
+
+    #[tokio::test]
+    async fn test_shrinking_headroom() {
+        let (headroom_tx, headroom) = watch::channel(HashMap::from([(SENDER, 1.0)]));
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(from_fn_with_state(
+                EscrowAdmissionState {
+                    header: HeaderName::from_static("x-query-priority"),
+                    default_priority: QueryPriority::Normal,
+                    deployment_priorities: Arc::new(HashMap::from([(
+                        *ESCROW_SUBGRAPH_DEPLOYMENT,
+                        QueryPriority::Low,
+                    )])),
+                    low_priority_min_headroom: 0.5,
+                    normal_priority_min_headroom: 0.2,
+                    headroom,
+                },
+                escrow_admission_middleware,
+            ));
+        let send = |priority: Option<&str>| {
+            let mut request = Request::builder().uri("/").extension(Sender(SENDER));
+            if let Some(priority) = priority {
+                request = request.header("x-query-priority", priority);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+        let send_low_deployment = || {
+            let request = Request::builder()
+                .uri("/")
+                .extension(Sender(SENDER))
+                .extension(*ESCROW_SUBGRAPH_DEPLOYMENT)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        // plenty of headroom
+        assert_eq!(send(Some("low")).await, StatusCode::OK);
+        assert_eq!(send(None).await, StatusCode::OK);
+
+        // low priority queries are refused first
+        headroom_tx.send_replace(HashMap::from([(SENDER, 0.4)]));
+        assert_eq!(send(Some("low")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            send_low_deployment().await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(send(None).await, StatusCode::OK);
+        assert_eq!(send(Some("high")).await, StatusCode::OK);
+
+        // then normal ones
+        headroom_tx.send_replace(HashMap::from([(SENDER, 0.1)]));
+        assert_eq!(send(Some("Normal")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(Some("high")).await, StatusCode::OK);
+
+        // high priority until it runs out
+        headroom_tx.send_replace(HashMap::from([(SENDER, 0.0)]));
+        assert_eq!(send(Some("high")).await, StatusCode::OK);
+
+        // unknown headroom is left to the checks
+        headroom_tx.send_replace(HashMap::new());
+        assert_eq!(send(Some("low")).await, StatusCode::OK);
+    }
+}
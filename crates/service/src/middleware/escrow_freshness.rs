@@ -0,0 +1,194 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexer_config::EscrowUnavailablePolicy;
+use indexer_monitor::EscrowFreshness;
+use tap_core::receipt::SignedReceipt;
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by escrow freshness middleware
+#[derive(Clone)]
+pub struct EscrowFreshnessState {
+    pub freshness: EscrowFreshness,
+    pub policy: EscrowUnavailablePolicy,
+    /// how long stale accounts are used with [EscrowUnavailablePolicy::ServeStale]
+    pub max_staleness: Option<Duration>,
+}
+
+impl EscrowFreshnessState {
+    /// How long the escrow accounts couldn't be read for
+    pub fn staleness(&self) -> Duration {
+        self.freshness.staleness()
+    }
+
+    /// Whether receipts can be checked against the escrow accounts at hand
+    pub fn serves_paid(&self) -> bool {
+        let staleness = self.staleness();
+        match self.policy {
+            EscrowUnavailablePolicy::ServeStale => self
+                .max_staleness
+                .map_or(true, |max_staleness| staleness <= max_staleness),
+            EscrowUnavailablePolicy::RejectPaid | EscrowUnavailablePolicy::FreeOnly => {
+                staleness.is_zero()
+            }
+        }
+    }
+
+    /// Whether the service should be routed queries to, which it isn't when
+    /// it refuses paid queries with [EscrowUnavailablePolicy::RejectPaid]
+    pub fn ready(&self) -> bool {
+        self.policy == EscrowUnavailablePolicy::FreeOnly || self.serves_paid()
+    }
+}
+
+/// Refuses receipt-bearing requests while the escrow accounts can't be read,
+/// as `escrow_unavailable_policy` says, before their receipt is checked. The
+/// `SenderBalance` check refuses the receipts it is given all the same
+///
+/// Requires signed receipt Extension to be added
+pub async fn escrow_freshness_middleware(
+    State(state): State<EscrowFreshnessState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.extensions().get::<SignedReceipt>().is_some() && !state.serves_paid() {
+        return IndexerServiceError::EscrowUnavailable.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::anyhow;
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use indexer_config::EscrowUnavailablePolicy;
+    use indexer_monitor::{EscrowAccounts, EscrowSource, FreshnessTrackingSource};
+    use reqwest::StatusCode;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+    use tower::ServiceExt;
+
+    use super::{escrow_freshness_middleware, EscrowFreshnessState};
+
+    /// Serves empty accounts until it is told the subgraph is down
+    #[derive(Default)]
+    struct FlakySource(AtomicBool);
+
+    #[async_trait::async_trait]
+    impl EscrowSource for FlakySource {
+        async fn escrow_accounts(&self) -> anyhow::Result<EscrowAccounts> {
+            if self.0.load(Ordering::SeqCst) {
+                Err(anyhow!("escrow subgraph is down"))
+            } else {
+                Ok(EscrowAccounts::default())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_escrow_unavailable_policies() {
+        let source = Arc::new(FlakySource::default());
+        let (tracking, freshness) = FreshnessTrackingSource::new(source.clone());
+        tracking.escrow_accounts().await.unwrap();
+
+        let send = |policy: EscrowUnavailablePolicy, max_staleness: Option<Duration>| {
+            let state = EscrowFreshnessState {
+                freshness: freshness.clone(),
+                policy,
+                max_staleness,
+            };
+            let app = Router::new()
+                .route("/", get(|| async { "ok" }))
+                .layer(from_fn_with_state(
+                    state.clone(),
+                    escrow_freshness_middleware,
+                ));
+            async move {
+                let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+                let paid = Request::builder()
+                    .uri("/")
+                    .extension(receipt)
+                    .body(Body::empty())
+                    .unwrap();
+                let free = Request::builder().uri("/").body(Body::empty()).unwrap();
+                (
+                    app.clone().oneshot(paid).await.unwrap().status(),
+                    app.oneshot(free).await.unwrap().status(),
+                    state.ready(),
+                )
+            }
+        };
+
+        // every policy serves paid queries while the accounts are fresh
+        for policy in [
+            EscrowUnavailablePolicy::ServeStale,
+            EscrowUnavailablePolicy::RejectPaid,
+            EscrowUnavailablePolicy::FreeOnly,
+        ] {
+            assert_eq!(
+                send(policy, None).await,
+                (StatusCode::OK, StatusCode::OK, true)
+            );
+        }
+
+        source.0.store(true, Ordering::SeqCst);
+        tracking.escrow_accounts().await.unwrap_err();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(freshness.staleness() >= Duration::from_millis(50));
+
+        // the last accounts read are used up to the max staleness
+        assert_eq!(
+            send(EscrowUnavailablePolicy::ServeStale, None).await,
+            (StatusCode::OK, StatusCode::OK, true)
+        );
+        assert_eq!(
+            send(
+                EscrowUnavailablePolicy::ServeStale,
+                Some(Duration::from_secs(60))
+            )
+            .await,
+            (StatusCode::OK, StatusCode::OK, true)
+        );
+        assert_eq!(
+            send(
+                EscrowUnavailablePolicy::ServeStale,
+                Some(Duration::from_millis(10))
+            )
+            .await,
+            (StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK, false)
+        );
+        // paid queries are refused right away, and only free_only stays ready
+        assert_eq!(
+            send(EscrowUnavailablePolicy::RejectPaid, None).await,
+            (StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK, false)
+        );
+        assert_eq!(
+            send(EscrowUnavailablePolicy::FreeOnly, None).await,
+            (StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK, true)
+        );
+
+        // fresh again once the accounts are read
+        source.0.store(false, Ordering::SeqCst);
+        tracking.escrow_accounts().await.unwrap();
+        assert_eq!(freshness.staleness(), Duration::ZERO);
+        assert_eq!(
+            send(EscrowUnavailablePolicy::RejectPaid, None).await,
+            (StatusCode::OK, StatusCode::OK, true)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderName,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::IndexerServiceError;
+
+/// Experimental handling enabled for a request, also available to the
+/// checks in the tap context
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestFeatures(pub HashSet<String>);
+
+impl RequestFeatures {
+    pub fn enabled(&self, feature: &str) -> bool {
+        self.0.contains(feature)
+    }
+}
+
+/// State to be used by features middleware
+#[derive(Clone)]
+pub struct FeaturesState {
+    pub header: HeaderName,
+    /// features that can be enabled, lowercase
+    pub allowed: Arc<HashSet<String>>,
+    /// refuse requests enabling other features instead of leaving them out
+    pub reject_unknown: bool,
+}
+
+/// Reads the features listed in the header into the [RequestFeatures] of
+/// the request
+///
+/// Names are separated by commas and compared without case. Features that
+/// aren't allowed are left out, or refuse the request with a `400`.
+pub async fn features_middleware(
+    State(state): State<FeaturesState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let mut features = HashSet::new();
+    for value in request.headers().get_all(&state.header) {
+        let names = String::from_utf8_lossy(value.as_bytes()).to_ascii_lowercase();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if state.allowed.contains(name) {
+                features.insert(name.to_string());
+            } else if state.reject_unknown {
+                return IndexerServiceError::UnsupportedFeature(name.to_string()).into_response();
+            }
+        }
+    }
+    request.extensions_mut().insert(RequestFeatures(features));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use axum::{
+        body::Body,
+        http::{HeaderName, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Extension, Router,
+    };
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{features_middleware, FeaturesState, RequestFeatures};
+
+    async fn send(reject_unknown: bool, features: &str) -> (StatusCode, String) {
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(features): Extension<RequestFeatures>| async move {
+                        let mut features: Vec<_> = features.0.into_iter().collect();
+                        features.sort();
+                        features.join(",")
+                    },
+                ),
+            )
+            .layer(from_fn_with_state(
+                FeaturesState {
+                    header: HeaderName::from_static("x-indexer-features"),
+                    allowed: Arc::new(HashSet::from(["new-appraisal".to_string()])),
+                    reject_unknown,
+                },
+                features_middleware,
+            ));
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header("x-indexer-features", features)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_allowed_features() {
+        assert_eq!(
+            send(false, "New-Appraisal, internals").await,
+            (StatusCode::OK, "new-appraisal".to_string())
+        );
+        assert_eq!(send(true, "new-appraisal").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reject_unknown_features() {
+        assert_eq!(
+            send(true, "new-appraisal,internals").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
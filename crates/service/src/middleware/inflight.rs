@@ -0,0 +1,23 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use prometheus::IntGauge;
+
+use crate::metrics::INFLIGHT_REQUESTS;
+
+/// Decrements the gauge when dropped, so cancelled requests are accounted for
+struct InflightGuard<'a>(&'a IntGauge);
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Tracks how many requests are being handled, used to follow the drain on shutdown
+pub async fn inflight_middleware(request: Request, next: Next) -> Response {
+    INFLIGHT_REQUESTS.inc();
+    let _guard = InflightGuard(&INFLIGHT_REQUESTS);
+    next.run(request).await
+}
@@ -1,9 +1,16 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
-use axum::{extract::Request, middleware::Next, response::Response};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
 use thegraph_core::DeploymentId;
 
 use super::{
@@ -15,6 +22,36 @@ use super::{
 const NO_DEPLOYMENT_ID: &str = "no-deployment";
 const NO_ALLOCATION: &str = "no-allocation";
 const NO_SENDER: &str = "no-sender";
+const OTHER_DEPLOYMENT_ID: &str = "other";
+
+/// State to be used by labels middleware
+///
+/// Keeps the deployments given their own label, once `max_deployments` of
+/// them are known the others are labelled `other`
+#[derive(Clone)]
+pub struct LabelsState {
+    max_deployments: usize,
+    deployments: Arc<Mutex<HashSet<DeploymentId>>>,
+}
+
+impl LabelsState {
+    pub fn new(max_deployments: usize) -> Self {
+        Self {
+            max_deployments,
+            deployments: Default::default(),
+        }
+    }
+
+    fn deployment_label(&self, deployment_id: &DeploymentId) -> String {
+        let mut deployments = self.deployments.lock().unwrap();
+        if deployments.contains(deployment_id) || deployments.len() < self.max_deployments {
+            deployments.insert(*deployment_id);
+            deployment_id.to_string()
+        } else {
+            OTHER_DEPLOYMENT_ID.to_string()
+        }
+    }
+}
 
 /// Labels used by metrics which implements MetricLabelProvider
 ///
@@ -52,7 +89,11 @@ impl MetricLabelProvider for SenderAllocationDeploymentLabels {
 /// Injects Metric Labels to be used by MetricMiddleware
 ///
 /// Soft requirement for Sender, Allocation and Deployment extensions
-pub async fn labels_middleware(mut request: Request, next: Next) -> Response {
+pub async fn labels_middleware(
+    State(state): State<LabelsState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
     let sender: Option<String> = request
         .extensions()
         .get::<Sender>()
@@ -66,12 +107,12 @@ pub async fn labels_middleware(mut request: Request, next: Next) -> Response {
     let deployment_id: Option<String> = request
         .extensions()
         .get::<DeploymentId>()
-        .map(|s| s.clone().to_string());
+        .map(|deployment_id| state.deployment_label(deployment_id));
 
     let labels: MetricLabels = Arc::new(SenderAllocationDeploymentLabels {
         sender,
         allocation,
-        deployment_id: deployment_id.clone(),
+        deployment_id,
     });
 
     request.extensions_mut().insert(labels);
@@ -83,28 +124,30 @@ pub async fn labels_middleware(mut request: Request, next: Next) -> Response {
 mod tests {
     use crate::middleware::{
         allocation::Allocation,
-        labels::{NO_ALLOCATION, NO_DEPLOYMENT_ID, NO_SENDER},
+        labels::{NO_ALLOCATION, NO_DEPLOYMENT_ID, NO_SENDER, OTHER_DEPLOYMENT_ID},
         prometheus_metrics::MetricLabels,
         sender::Sender,
+        PrometheusMetricsMiddlewareLayer,
     };
 
-    use super::labels_middleware;
+    use super::{labels_middleware, LabelsState};
 
     use alloy::primitives::Address;
     use axum::{
         body::Body,
         http::{Extensions, Request},
-        middleware::from_fn,
+        middleware::from_fn_with_state,
         routing::get,
         Router,
     };
     use reqwest::StatusCode;
-    use test_assets::ESCROW_SUBGRAPH_DEPLOYMENT;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::DeploymentId;
     use tower::ServiceExt;
 
     #[tokio::test]
     async fn test_label_middleware() {
-        let middleware = from_fn(labels_middleware);
+        let middleware = from_fn_with_state(LabelsState::new(10), labels_middleware);
 
         let deployment = *ESCROW_SUBGRAPH_DEPLOYMENT;
         let sender = Address::ZERO;
@@ -144,7 +187,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_empty_label_middleware() {
-        let middleware = from_fn(labels_middleware);
+        let middleware = from_fn_with_state(LabelsState::new(10), labels_middleware);
 
         let handle = move |extensions: Extensions| async move {
             let metrics = extensions
@@ -165,4 +208,57 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_deployment_labels_are_capped() {
+        let registry = prometheus::Registry::new();
+        let histogram = prometheus::register_histogram_vec_with_registry!(
+            "test_query_handler_seconds",
+            "Test",
+            &["deployment", "allocation", "sender", "status_code"],
+            registry,
+        )
+        .unwrap();
+
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(PrometheusMetricsMiddlewareLayer::new(histogram.clone()))
+            .layer(from_fn_with_state(LabelsState::new(2), labels_middleware));
+
+        let third_deployment: DeploymentId = "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz"
+            .parse()
+            .unwrap();
+        let send = |deployment: DeploymentId| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/")
+                    .extension(deployment)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        for deployment in [
+            *ESCROW_SUBGRAPH_DEPLOYMENT,
+            *NETWORK_SUBGRAPH_DEPLOYMENT,
+            *ESCROW_SUBGRAPH_DEPLOYMENT,
+            third_deployment,
+        ] {
+            assert_eq!(send(deployment).await.unwrap().status(), StatusCode::OK);
+        }
+
+        let count = |deployment: &str| {
+            histogram
+                .with_label_values(&[deployment, NO_ALLOCATION, NO_SENDER, "200"])
+                .get_sample_count()
+        };
+        assert_eq!(count(&ESCROW_SUBGRAPH_DEPLOYMENT.to_string()), 2);
+        assert_eq!(count(&NETWORK_SUBGRAPH_DEPLOYMENT.to_string()), 1);
+        // over the cap
+        assert_eq!(count(&third_deployment.to_string()), 0);
+        assert_eq!(count(OTHER_DEPLOYMENT_ID), 1);
+
+        // known deployments keep their label
+        send(*NETWORK_SUBGRAPH_DEPLOYMENT).await.unwrap();
+        assert_eq!(count(&NETWORK_SUBGRAPH_DEPLOYMENT.to_string()), 2);
+    }
 }
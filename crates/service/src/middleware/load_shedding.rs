@@ -0,0 +1,112 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::PgPool;
+use tap_core::receipt::SignedReceipt;
+
+use crate::{
+    error::IndexerServiceError,
+    metrics::{DATABASE_POOL_SATURATION, LOAD_SHED_REQUESTS},
+    tap::ReceiptQueue,
+};
+
+/// State to be used by load shedding middleware
+#[derive(Clone)]
+pub struct LoadSheddingState {
+    pub pgpool: PgPool,
+    /// receipts waiting to be written to the database
+    pub receipt_queue: ReceiptQueue,
+    /// how many queued receipts are tolerated while the pool is saturated
+    pub receipt_queue_threshold: usize,
+}
+
+/// Sheds receipt-bearing requests while the database can't keep up
+///
+/// The pool is considered saturated when every connection is checked out.
+/// If on top of that the receipt write queue is over the threshold, accepting
+/// more paid queries only makes the backlog grow, so they are refused with
+/// 503 until the database catches up. Free queries are never shed.
+///
+/// Requires signed receipt Extension to be added
+pub async fn load_shedding_middleware(
+    State(state): State<LoadSheddingState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let max_connections = state.pgpool.options().get_max_connections();
+    let in_use = (state.pgpool.size() as usize).saturating_sub(state.pgpool.num_idle());
+    DATABASE_POOL_SATURATION.set(in_use as f64 / max_connections as f64);
+
+    let saturated = state.pgpool.num_idle() == 0 && state.pgpool.size() >= max_connections;
+    if saturated
+        && state.receipt_queue.depth() >= state.receipt_queue_threshold
+        && request.extensions().get::<SignedReceipt>().is_some()
+    {
+        LOAD_SHED_REQUESTS.inc();
+        return IndexerServiceError::ServiceNotReady.into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use sqlx::{postgres::PgPoolOptions, PgPool};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_EIP712_DOMAIN};
+    use tower::ServiceExt;
+
+    use super::{load_shedding_middleware, LoadSheddingState};
+    use crate::tap::IndexerTapContext;
+
+    async fn send_request(app: Router, with_receipt: bool) -> StatusCode {
+        let mut request = Request::builder().uri("/");
+        if with_receipt {
+            let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+            request = request.extension(receipt);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_load_shedding(pgpool: PgPool) {
+        let pgpool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with(pgpool.connect_options().as_ref().clone())
+            .await
+            .unwrap();
+        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        let state = LoadSheddingState {
+            pgpool: pgpool.clone(),
+            receipt_queue: context.receipt_queue(),
+            receipt_queue_threshold: 0,
+        };
+
+        let middleware = from_fn_with_state(state, load_shedding_middleware);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware);
+
+        // pool has idle connections
+        assert_eq!(send_request(app.clone(), true).await, StatusCode::OK);
+
+        // hold the only connection
+        let _connection = pgpool.acquire().await.unwrap();
+        assert_eq!(
+            send_request(app.clone(), true).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // free queries are still served
+        assert_eq!(send_request(app, false).await, StatusCode::OK);
+    }
+}
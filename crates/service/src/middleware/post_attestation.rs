@@ -0,0 +1,334 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs side effects on attested responses, once they are attested and before
+//! they are sent
+//!
+//! Lets integrations emit an event or an audit record correlating the query
+//! with its attestation, without changing the response. Hooks run in the
+//! background: their failures are logged, and responses attested while too
+//! many hooks are running are sent without running it.
+
+use std::sync::Arc;
+
+use alloy::primitives::Address;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::{Attestation, DeploymentId};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use super::{
+    attestation::{BufferedQuery, IndexerResponsePayload},
+    request_id::RequestId,
+    sender::Sender,
+    Allocation,
+};
+
+/// Hooks running at once before new attested responses are left out
+const MAX_CONCURRENT_HOOKS: usize = 100;
+
+/// What an attested response was served for
+#[derive(Debug, Clone)]
+pub struct AttestedContext {
+    pub deployment: DeploymentId,
+    pub allocation: Address,
+    /// unset for free queries
+    pub sender: Option<Address>,
+    /// unset for free queries
+    pub receipt: Option<SignedReceipt>,
+    pub request_id: Option<RequestId>,
+}
+
+/// Side effect run on every attested response
+///
+/// It is given the request as received, the GraphQL response as attested and
+/// its attestation. Errors are logged, the response being sent regardless.
+#[async_trait::async_trait]
+pub trait PostAttestationHook: Send + Sync {
+    async fn on_attested(
+        &self,
+        context: &AttestedContext,
+        request: &str,
+        response: &str,
+        attestation: &Attestation,
+    ) -> anyhow::Result<()>;
+}
+
+/// State to be used by post attestation middleware
+#[derive(Clone)]
+pub struct PostAttestationState {
+    hook: Arc<dyn PostAttestationHook>,
+    permits: Arc<Semaphore>,
+}
+
+impl PostAttestationState {
+    pub fn new(hook: Arc<dyn PostAttestationHook>) -> Self {
+        Self {
+            hook,
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_HOOKS)),
+        }
+    }
+}
+
+/// Runs the [PostAttestationHook] on the attested responses, in the background
+///
+/// Requires Allocation and DeploymentId Extensions to be added. Must run
+/// outside the attestation middleware.
+pub async fn post_attestation_middleware(
+    State(state): State<PostAttestationState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (Some(Allocation(allocation)), Some(deployment)) = (
+        request.extensions().get::<Allocation>().cloned(),
+        request.extensions().get::<DeploymentId>().copied(),
+    ) else {
+        return next.run(request).await;
+    };
+    let context = AttestedContext {
+        deployment,
+        allocation,
+        sender: request
+            .extensions()
+            .get::<Sender>()
+            .map(|Sender(sender)| *sender),
+        receipt: request.extensions().get::<SignedReceipt>().cloned(),
+        request_id: request.extensions().get::<RequestId>().cloned(),
+    };
+
+    let mut query = match BufferedQuery::run(request, next).await {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+    let Some(IndexerResponsePayload {
+        graphql_response,
+        attestation: Some(attestation),
+    }) = query.payload.take()
+    else {
+        return query.into_response();
+    };
+    // only the hooks running count against the limit, not the queries
+    let Ok(permit) = state.permits.clone().try_acquire_owned() else {
+        warn!("Too many post attestation hooks running, leaving a response out");
+        return query.into_response();
+    };
+    let request = String::from_utf8_lossy(&query.request).into_owned();
+    tokio::spawn(async move {
+        if let Err(e) = state
+            .hook
+            .on_attested(&context, &request, &graphql_response, &attestation)
+            .await
+        {
+            warn!(
+                error = %e,
+                deployment = %context.deployment,
+                allocation = %context.allocation,
+                "Post attestation hook failed"
+            );
+        }
+        drop(permit);
+    });
+    query.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use alloy::{primitives::Address, signers::Signature};
+    use anyhow::anyhow;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, Response},
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use indexer_attestation::{AttestationPayload, AttestationSigner};
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use test_assets::{INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::attestation::{eip712_domain, Attestation};
+    use tokio::sync::{mpsc, watch, Notify, Semaphore};
+    use tower::ServiceExt;
+
+    use super::{
+        post_attestation_middleware, AttestedContext, PostAttestationHook, PostAttestationState,
+    };
+    use crate::middleware::{
+        attestation_middleware, Allocation, AttestationBackend, AttestationBackendError,
+        AttestationBackendState, AttestationInput,
+    };
+
+    const REQUEST: &str = r#"{"query":"{ a }"}"#;
+    const SLOW_REQUEST: &str = r#"{"query":"{ b }"}"#;
+    const RESPONSE: &str = r#"{"data":{"a":1}}"#;
+
+    struct MockBackend(AttestationSigner);
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for MockBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            _: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            Ok(self.0.sign(payload))
+        }
+    }
+
+    /// Passes on what it is called with, failing every time
+    struct ChannelHook(mpsc::UnboundedSender<(AttestedContext, String, String, Attestation)>);
+
+    #[async_trait::async_trait]
+    impl PostAttestationHook for ChannelHook {
+        async fn on_attested(
+            &self,
+            context: &AttestedContext,
+            request: &str,
+            response: &str,
+            attestation: &Attestation,
+        ) -> anyhow::Result<()> {
+            self.0.send((
+                context.clone(),
+                request.to_string(),
+                response.to_string(),
+                attestation.clone(),
+            ))?;
+            Err(anyhow!("queue is down"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hook_gets_the_attestation() {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let signer =
+            AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), &allocation, 1, Address::ZERO)
+                .unwrap();
+        let backend_state = AttestationBackendState {
+            backend: Arc::new(MockBackend(signer.clone())),
+            domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+            unattested_free_queries: false,
+            failure_policy: Default::default(),
+        };
+        let (hook_tx, mut hook_rx) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/",
+                post(|body: String| async move {
+                    let mut res = Response::new(RESPONSE.to_string());
+                    res.extensions_mut()
+                        .insert(AttestationInput::Attestable { req: body });
+                    res
+                }),
+            )
+            .layer(from_fn_with_state(backend_state, attestation_middleware))
+            .layer(from_fn_with_state(
+                PostAttestationState::new(Arc::new(ChannelHook(hook_tx))),
+                post_attestation_middleware,
+            ));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/")
+            .extension(Allocation(allocation.id))
+            .extension(allocation.subgraph_deployment.id)
+            .body(Body::from(REQUEST))
+            .unwrap();
+        let res = app.oneshot(request).await.unwrap();
+        // the failing hook leaves the response as it is
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let payload: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(payload["graphQLResponse"], RESPONSE);
+        let sent: Attestation = serde_json::from_value(payload["attestation"].clone()).unwrap();
+
+        let (context, request, response, attestation) = hook_rx.recv().await.unwrap();
+        assert_eq!(context.allocation, allocation.id);
+        assert_eq!(context.deployment, allocation.subgraph_deployment.id);
+        assert!(context.sender.is_none());
+        assert_eq!(request, REQUEST);
+        assert_eq!(response, RESPONSE);
+        assert_eq!(
+            (attestation.request_cid, attestation.response_cid),
+            (sent.request_cid, sent.response_cid)
+        );
+        assert_eq!(
+            (attestation.r, attestation.s, attestation.v),
+            (sent.r, sent.s, sent.v)
+        );
+        assert!(signer
+            .verify(&attestation, REQUEST, RESPONSE, &allocation.id)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queries_in_flight_dont_hold_permits() {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let signer =
+            AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), &allocation, 1, Address::ZERO)
+                .unwrap();
+        let backend_state = AttestationBackendState {
+            backend: Arc::new(MockBackend(signer)),
+            domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+            unattested_free_queries: false,
+            failure_policy: Default::default(),
+        };
+        let (hook_tx, mut hook_rx) = mpsc::unbounded_channel();
+        // a single hook can run at once
+        let state = PostAttestationState {
+            hook: Arc::new(ChannelHook(hook_tx)),
+            permits: Arc::new(Semaphore::new(1)),
+        };
+        let (entered_tx, mut entered_rx) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let app = Router::new()
+            .route(
+                "/",
+                post({
+                    let release = release.clone();
+                    move |body: String| {
+                        let (entered_tx, release) = (entered_tx.clone(), release.clone());
+                        async move {
+                            if body == SLOW_REQUEST {
+                                entered_tx.send(()).unwrap();
+                                release.notified().await;
+                            }
+                            let mut res = Response::new(RESPONSE.to_string());
+                            res.extensions_mut()
+                                .insert(AttestationInput::Attestable { req: body });
+                            res
+                        }
+                    }
+                }),
+            )
+            .layer(from_fn_with_state(backend_state, attestation_middleware))
+            .layer(from_fn_with_state(state, post_attestation_middleware));
+        let request = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/")
+                .extension(Allocation(allocation.id))
+                .extension(allocation.subgraph_deployment.id)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let slow = tokio::spawn(app.clone().oneshot(request(SLOW_REQUEST)));
+        entered_rx.recv().await.unwrap();
+
+        // the slow query being served doesn't leave this one out
+        let res = app.oneshot(request(REQUEST)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_, request, _, _) = hook_rx.recv().await.unwrap();
+        assert_eq!(request, REQUEST);
+
+        release.notify_one();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
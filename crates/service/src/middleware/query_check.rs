@@ -0,0 +1,65 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use axum::{
+    body::to_bytes,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use thegraph_core::DeploymentId;
+
+use crate::{
+    error::IndexerServiceError,
+    routes::{check_query, QueryLimits},
+};
+
+/// State to be used by query check middleware
+#[derive(Clone, Default)]
+pub struct QueryCheckState {
+    /// operations accepted for deployments that restrict them
+    pub allowed_operations: Arc<HashMap<DeploymentId, HashSet<String>>>,
+    /// depth and field limits of the queries forwarded to graph-node
+    pub query_limits: Arc<QueryLimits>,
+}
+
+/// Refuses queries the deployment doesn't allow or that exceed its query
+/// limits, before their receipt is checked and stored
+///
+/// Requires the DeploymentId extension to be added.
+pub async fn query_check_middleware(
+    State(state): State<QueryCheckState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(deployment) = request.extensions().get::<DeploymentId>().copied() else {
+        return next.run(request).await;
+    };
+    let allowed_operations = state.allowed_operations.get(&deployment);
+    let query_limits = state.query_limits.for_deployment(&deployment);
+    if allowed_operations.is_none()
+        && query_limits.max_depth.is_none()
+        && query_limits.max_fields.is_none()
+    {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return IndexerServiceError::AxumError(e).into_response(),
+    };
+    if let Err(e) = check_query(
+        allowed_operations,
+        query_limits,
+        &String::from_utf8_lossy(&bytes),
+    ) {
+        return e.into_response();
+    }
+    next.run(Request::from_parts(parts, bytes.into())).await
+}
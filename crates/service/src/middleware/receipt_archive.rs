@@ -0,0 +1,175 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::DeploymentId;
+
+use super::{
+    attestation::{BufferedQuery, IndexerResponsePayload},
+    sender::Sender,
+};
+use crate::tap::{ArchivedQuery, ReceiptArchive};
+
+/// Archives a sample of the paid queries served in the [ReceiptArchive]
+///
+/// Requires signed receipt Extension to be added. Must run outside the
+/// attestation middleware, for the attestation to be archived.
+pub async fn receipt_archive_middleware(
+    State(archive): State<ReceiptArchive>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(receipt) = request.extensions().get::<SignedReceipt>().cloned() else {
+        return next.run(request).await;
+    };
+    if !archive.sampled() {
+        return next.run(request).await;
+    }
+    let sender = request
+        .extensions()
+        .get::<Sender>()
+        .map(|Sender(sender)| *sender);
+    let deployment_id = request.extensions().get::<DeploymentId>().copied();
+
+    let mut query = match BufferedQuery::run(request, next).await {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+    let (response, attestation) = match query.payload.take() {
+        Some(IndexerResponsePayload {
+            graphql_response,
+            attestation,
+        }) => (graphql_response, attestation),
+        None => (String::from_utf8_lossy(&query.body).into_owned(), None),
+    };
+    archive.archive(ArchivedQuery::new(
+        &receipt,
+        sender,
+        deployment_id,
+        String::from_utf8_lossy(&query.request).into_owned(),
+        response,
+        attestation,
+    ));
+    query.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::post, Router};
+    use bigdecimal::ToPrimitive;
+    use indexer_config::{ReceiptArchiveConfig, ReceiptArchiveSink};
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+    use tower::ServiceExt;
+
+    use super::receipt_archive_middleware;
+    use crate::tap::ReceiptArchive;
+
+    async fn archived(pgpool: &PgPool) -> Vec<(i64, String, String)> {
+        sqlx::query!(
+            r#"
+                SELECT nonce, request, response
+                FROM scalar_tap_receipt_archive
+                ORDER BY nonce
+            "#
+        )
+        .fetch_all(pgpool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.nonce.to_i64().unwrap(), row.request, row.response))
+        .collect()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_samples_paid_queries(pgpool: PgPool) {
+        let app = |sample_rate: f64| {
+            let pgpool = pgpool.clone();
+            async move {
+                let archive = ReceiptArchive::new(
+                    &ReceiptArchiveConfig {
+                        sample_rate,
+                        retention_secs: Duration::from_secs(3600),
+                        sink: ReceiptArchiveSink::Database,
+                    },
+                    pgpool,
+                )
+                .await
+                .unwrap();
+                Router::new()
+                    .route(
+                        "/",
+                        post(|| async {
+                            axum::Json(json!({
+                                "graphQLResponse": r#"{"data":{"a":1}}"#,
+                                "attestation": null,
+                            }))
+                        }),
+                    )
+                    .layer(from_fn_with_state(archive, receipt_archive_middleware))
+            }
+        };
+        let send = |app: Router, nonce: Option<u64>| async move {
+            let mut request = Request::builder().method("POST").uri("/");
+            if let Some(nonce) = nonce {
+                let receipt =
+                    create_signed_receipt(SignedReceiptRequest::builder().nonce(nonce).build())
+                        .await;
+                request = request.extension(receipt);
+            }
+            let res = app
+                .oneshot(request.body(Body::from(r#"{"query":"{ a }"}"#)).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        };
+        let wait_for = |count: usize| {
+            let pgpool = pgpool.clone();
+            async move {
+                for _ in 0..100 {
+                    if archived(&pgpool).await.len() >= count {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+
+        // free queries and unsampled paid queries are left out
+        let never = app(0.0).await;
+        send(never.clone(), None).await;
+        for nonce in 0..10 {
+            send(never.clone(), Some(nonce)).await;
+        }
+        let always = app(1.0).await;
+        send(always.clone(), None).await;
+        send(always, Some(100)).await;
+        wait_for(1).await;
+        assert_eq!(
+            archived(&pgpool).await,
+            vec![(
+                100,
+                r#"{"query":"{ a }"}"#.to_string(),
+                r#"{"data":{"a":1}}"#.to_string()
+            )]
+        );
+
+        // about half of them
+        let half = app(0.5).await;
+        for nonce in 1000..1200 {
+            send(half.clone(), Some(nonce)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let sampled = archived(&pgpool).await.len() - 1;
+        assert!((50..150).contains(&sampled), "{sampled} archived");
+    }
+}
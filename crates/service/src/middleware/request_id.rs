@@ -0,0 +1,106 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+/// Identifies a request in the responses and logs of the service and, when
+/// propagated, in the calls it makes on behalf of the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub HeaderValue);
+
+/// State to be used by request id middleware
+#[derive(Clone)]
+pub struct RequestIdState {
+    pub header: HeaderName,
+    /// keep the id sent by the client instead of generating one
+    pub honor_inbound: bool,
+}
+
+/// Gives the request its [RequestId] and echoes it back in the response
+///
+/// Requests without an inbound id, or when inbound ids aren't honored, are
+/// given a new UUID.
+pub async fn request_id_middleware(
+    State(state): State<RequestIdState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = state
+        .honor_inbound
+        .then(|| request.headers().get(&state.header).cloned())
+        .flatten()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&Uuid::now_v7().to_string()).expect("UUIDs are valid headers")
+        });
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(state.header, request_id);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{HeaderName, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::{request_id_middleware, RequestId, RequestIdState};
+
+    async fn request_id(honor_inbound: bool, inbound: Option<&str>) -> String {
+        let header = HeaderName::from_static("x-request-id");
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(RequestId(id)): Extension<RequestId>| async move {
+                        id.to_str().unwrap().to_string()
+                    },
+                ),
+            )
+            .layer(from_fn_with_state(
+                RequestIdState {
+                    header: header.clone(),
+                    honor_inbound,
+                },
+                request_id_middleware,
+            ));
+
+        let mut request = Request::builder().uri("/");
+        if let Some(inbound) = inbound {
+            request = request.header(&header, inbound);
+        }
+        let res = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let echoed = res.headers()[&header].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        // handlers see the id that is echoed back
+        assert_eq!(body, echoed.as_bytes());
+        echoed
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        assert_eq!(request_id(true, Some("inbound")).await, "inbound");
+        assert!(Uuid::parse_str(&request_id(true, None).await).is_ok());
+        assert!(Uuid::parse_str(&request_id(false, Some("inbound")).await).is_ok());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// State to be used by request log middleware
+#[derive(Clone)]
+pub struct RequestLogState {
+    /// fraction of successful requests to log, between 0 and 1
+    pub sample_rate: watch::Receiver<f64>,
+}
+
+/// Logs every failed request and a sample of the successful ones
+///
+/// The sampling decision is taken for each request, so the rate can be changed
+/// at runtime without affecting requests already in flight.
+pub async fn request_log_middleware(
+    State(state): State<RequestLogState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let sample_rate = *state.sample_rate.borrow();
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let matched_path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed();
+
+    let status = response.status();
+    if !status.is_success() {
+        warn!(
+            %method,
+            %uri,
+            matched_path,
+            status = status.as_u16(),
+            elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+            "Request failed"
+        );
+    } else if sample_rate > 0.0 && rand::random::<f64>() < sample_rate {
+        info!(
+            %method,
+            %uri,
+            matched_path,
+            status = status.as_u16(),
+            elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+            "Request served"
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+    use tracing_test::traced_test;
+
+    use super::{request_log_middleware, RequestLogState};
+
+    async fn send_request(app: Router, uri: &str) {
+        app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_request_log_sampling() {
+        let (sample_rate_tx, sample_rate) = watch::channel(0.0);
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/fail", get(|| async { StatusCode::BAD_REQUEST }))
+            .layer(from_fn_with_state(
+                RequestLogState { sample_rate },
+                request_log_middleware,
+            ));
+
+        send_request(app.clone(), "/ok").await;
+        assert!(!logs_contain("Request served"));
+
+        // failures are always logged
+        send_request(app.clone(), "/fail").await;
+        assert!(logs_contain("Request failed"));
+
+        sample_rate_tx.send_replace(1.0);
+        send_request(app, "/ok").await;
+        assert!(logs_contain("Request served"));
+    }
+}
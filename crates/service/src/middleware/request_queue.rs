@@ -0,0 +1,152 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::{
+    error::IndexerServiceError,
+    metrics::{REQUEST_QUEUE_DEPTH, REQUEST_QUEUE_WAIT},
+};
+
+/// State to be used by request queue middleware
+#[derive(Clone)]
+pub struct RequestQueueState {
+    /// one permit per request allowed to run concurrently
+    permits: Arc<Semaphore>,
+    /// requests currently waiting for a permit
+    queued: Arc<AtomicUsize>,
+    max_queue_length: usize,
+    max_wait: Duration,
+}
+
+impl RequestQueueState {
+    pub fn new(
+        max_concurrent_requests: usize,
+        max_queue_length: usize,
+        max_wait: Duration,
+    ) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent_requests)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queue_length,
+            max_wait,
+        }
+    }
+}
+
+/// Takes the request out of the queue when dropped, so cancelled requests are accounted for
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        REQUEST_QUEUE_DEPTH.dec();
+    }
+}
+
+/// Limits how many queries are handled at once
+///
+/// Requests over the limit wait in a bounded queue for up to `max_wait`.
+/// They are refused with 503 if the queue is already full or if no slot frees
+/// up in time, trading latency for fewer rejections during short bursts.
+pub async fn request_queue_middleware(
+    State(state): State<RequestQueueState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let _permit = match state.permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if state.queued.fetch_add(1, Ordering::SeqCst) >= state.max_queue_length {
+                state.queued.fetch_sub(1, Ordering::SeqCst);
+                return IndexerServiceError::ServiceNotReady.into_response();
+            }
+            REQUEST_QUEUE_DEPTH.inc();
+            let guard = QueuedGuard(&state.queued);
+
+            let queued_at = Instant::now();
+            let permit =
+                tokio::time::timeout(state.max_wait, state.permits.clone().acquire_owned()).await;
+            REQUEST_QUEUE_WAIT.observe(queued_at.elapsed().as_secs_f64());
+            drop(guard);
+
+            match permit {
+                Ok(Ok(permit)) => permit,
+                _ => return IndexerServiceError::ServiceNotReady.into_response(),
+            }
+        }
+    };
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{request_queue_middleware, RequestQueueState};
+
+    fn app(max_wait: Duration) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }),
+            )
+            .layer(from_fn_with_state(
+                RequestQueueState::new(1, 1, max_wait),
+                request_queue_middleware,
+            ))
+    }
+
+    async fn send(app: Router) -> StatusCode {
+        app.oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_queued_request_times_out() {
+        let app = app(Duration::from_millis(50));
+
+        let running = tokio::spawn(send(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let queued = tokio::spawn(send(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // the queue only holds one request
+        assert_eq!(send(app.clone()).await, StatusCode::SERVICE_UNAVAILABLE);
+        // the running request doesn't finish before the queued one gives up
+        assert_eq!(queued.await.unwrap(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(running.await.unwrap(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_queued_request_runs_once_slot_frees() {
+        let app = app(Duration::from_secs(5));
+
+        let running = tokio::spawn(send(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert_eq!(send(app.clone()).await, StatusCode::OK);
+        assert_eq!(running.await.unwrap(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tap_core::receipt::SignedReceipt;
+use tokio::sync::watch;
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by safe mode middleware
+#[derive(Clone)]
+pub struct SafeModeState {
+    pub safe_mode: watch::Receiver<bool>,
+}
+
+/// Refuses every receipt-bearing request while safe mode is engaged
+///
+/// Used during incident response to stop accepting receipts without
+/// restarting the service. Free queries don't carry a receipt and are still
+/// served.
+///
+/// Requires signed receipt Extension to be added
+pub async fn safe_mode_middleware(
+    State(state): State<SafeModeState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if *state.safe_mode.borrow() && request.extensions().get::<SignedReceipt>().is_some() {
+        return IndexerServiceError::SafeMode.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{safe_mode_middleware, SafeModeState};
+
+    async fn send_request(app: Router, with_receipt: bool) -> StatusCode {
+        let mut request = Request::builder().uri("/");
+        if with_receipt {
+            let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+            request = request.extension(receipt);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_safe_mode_toggle() {
+        let (safe_mode_tx, safe_mode) = watch::channel(false);
+        let middleware = from_fn_with_state(SafeModeState { safe_mode }, safe_mode_middleware);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware);
+
+        assert_eq!(send_request(app.clone(), true).await, StatusCode::OK);
+
+        safe_mode_tx.send_replace(true);
+        assert_eq!(
+            send_request(app.clone(), true).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // free queries are still served
+        assert_eq!(send_request(app.clone(), false).await, StatusCode::OK);
+
+        safe_mode_tx.send_replace(false);
+        assert_eq!(send_request(app, true).await, StatusCode::OK);
+    }
+}
@@ -1,18 +1,23 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::time::Duration;
+
 use alloy::{dyn_abi::Eip712Domain, primitives::Address};
 use axum::{
     extract::{Request, State},
     middleware::Next,
     response::Response,
 };
-use indexer_monitor::EscrowAccounts;
+use indexer_monitor::{EscrowAccounts, EscrowAccountsError, EscrowAccountsRefresh};
 use tap_core::receipt::SignedReceipt;
 use tokio::sync::watch;
 
 use crate::error::IndexerServiceError;
 
+/// How long a receipt of an unknown signer waits for the refreshed accounts
+const ESCROW_REFRESH_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Stated used by sender middleware
 #[derive(Clone)]
 pub struct SenderState {
@@ -20,6 +25,9 @@ pub struct SenderState {
     pub domain_separator: Eip712Domain,
     /// Used to get the sender address given the signer address
     pub escrow_accounts: watch::Receiver<EscrowAccounts>,
+    /// refresh the escrow accounts once when the signer is unknown, as its
+    /// sender may have just created its escrow
+    pub escrow_refresh: Option<EscrowAccountsRefresh>,
 }
 
 /// The current query Sender address
@@ -38,6 +46,11 @@ impl From<Sender> for String {
 /// free queries.
 /// That's why we don't fail with 400.
 ///
+/// With an escrow refresh, the receipt of an unknown signer triggers a
+/// refresh and is looked up again in the refreshed accounts, which the
+/// escrow balance check reads too. Refreshes are debounced, the receipts of
+/// unknown signers arriving in between are refused right away.
+///
 /// Requires Receipt extension
 pub async fn sender_middleware(
     State(state): State<SenderState>,
@@ -49,15 +62,39 @@ pub async fn sender_middleware(
         let sender = state
             .escrow_accounts
             .borrow()
-            .get_sender_for_signer(&signer)?;
+            .get_sender_for_signer(&signer);
+        let sender = match (sender, &state.escrow_refresh) {
+            (Err(EscrowAccountsError::NoSenderFound { .. }), Some(refresh)) => {
+                refresh_escrow_accounts(state.escrow_accounts.clone(), refresh).await;
+                state
+                    .escrow_accounts
+                    .borrow()
+                    .get_sender_for_signer(&signer)
+            }
+            (sender, _) => sender,
+        }?;
         request.extensions_mut().insert(Sender(sender));
     }
 
     Ok(next.run(request).await)
 }
 
+/// Waits for the accounts to be refreshed, unless a refresh was requested
+/// too recently
+async fn refresh_escrow_accounts(
+    mut escrow_accounts: watch::Receiver<EscrowAccounts>,
+    refresh: &EscrowAccountsRefresh,
+) {
+    escrow_accounts.mark_unchanged();
+    if refresh.request() {
+        let _ = tokio::time::timeout(ESCROW_REFRESH_TIMEOUT, escrow_accounts.changed()).await;
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, time::Duration};
+
     use crate::middleware::sender::SenderState;
 
     use super::{sender_middleware, Sender};
@@ -68,13 +105,13 @@ mod tests {
         routing::get,
         Router,
     };
-    use indexer_monitor::EscrowAccounts;
+    use indexer_monitor::{EscrowAccounts, EscrowAccountsRefresh};
     use reqwest::StatusCode;
     use test_assets::{
         create_signed_receipt, SignedReceiptRequest, ESCROW_ACCOUNTS_BALANCES,
         ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
     };
-    use tokio::sync::watch;
+    use tokio::sync::{watch, Notify};
     use tower::ServiceExt;
 
     #[tokio::test]
@@ -87,6 +124,7 @@ mod tests {
         let state = SenderState {
             domain_separator: test_assets::TAP_EIP712_DOMAIN.clone(),
             escrow_accounts,
+            escrow_refresh: None,
         };
 
         let middleware = from_fn_with_state(state, sender_middleware);
@@ -113,4 +151,59 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_refresh_on_unknown_sender() {
+        let (escrow_accounts_tx, escrow_accounts) = watch::channel(EscrowAccounts::default());
+        let trigger = Arc::new(Notify::new());
+
+        let app = |escrow_refresh: Option<EscrowAccountsRefresh>| {
+            let state = SenderState {
+                domain_separator: test_assets::TAP_EIP712_DOMAIN.clone(),
+                escrow_accounts: escrow_accounts.clone(),
+                escrow_refresh,
+            };
+            Router::new()
+                .route(
+                    "/",
+                    get(|extensions: Extensions| async move {
+                        let sender = extensions.get::<Sender>().expect("Should contain sender");
+                        assert_eq!(sender.0, test_assets::TAP_SENDER.1);
+                        Body::empty()
+                    }),
+                )
+                .layer(from_fn_with_state(state, sender_middleware))
+        };
+        let send = |app: Router| async move {
+            let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+            app.oneshot(
+                Request::builder()
+                    .uri("/")
+                    .extension(receipt)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+        };
+
+        // refused without a refresh
+        assert_eq!(send(app(None)).await, StatusCode::PAYMENT_REQUIRED);
+
+        // the sender only shows up in the refreshed accounts
+        let refreshed = tokio::spawn({
+            let trigger = trigger.clone();
+            async move {
+                trigger.notified().await;
+                escrow_accounts_tx.send_replace(EscrowAccounts::new(
+                    ESCROW_ACCOUNTS_BALANCES.to_owned(),
+                    ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+                ));
+            }
+        });
+        let refresh = EscrowAccountsRefresh::new(trigger, Duration::from_secs(60));
+        assert_eq!(send(app(Some(refresh))).await, StatusCode::OK);
+        refreshed.await.unwrap();
+    }
 }
@@ -0,0 +1,33 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tap_core::receipt::SignedReceipt;
+use tokio::sync::watch;
+
+use crate::error::IndexerServiceError;
+
+/// State to be used by subgraph sync middleware
+#[derive(Clone)]
+pub struct SubgraphSyncState {
+    pub out_of_sync: watch::Receiver<bool>,
+}
+
+/// Refuses receipt-bearing requests while the escrow and network subgraphs
+/// are too far apart for the receipt checks to be trusted
+///
+/// Requires signed receipt Extension to be added
+pub async fn subgraph_sync_middleware(
+    State(state): State<SubgraphSyncState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if *state.out_of_sync.borrow() && request.extensions().get::<SignedReceipt>().is_some() {
+        return IndexerServiceError::SubgraphsOutOfSync.into_response();
+    }
+    next.run(request).await
+}
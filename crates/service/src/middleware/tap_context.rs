@@ -5,22 +5,31 @@
 //!
 //! Requires Deployment Id extension to available
 
-use serde_json::value::RawValue;
-use std::sync::Arc;
+use serde_json::{value::RawValue, Value};
+use std::{str::FromStr, sync::Arc};
 
+use alloy::primitives::Address;
 use axum::{
     body::to_bytes,
-    extract::{Path, Request},
+    extract::{Path, Request, State},
     middleware::Next,
     response::Response,
     RequestExt,
 };
-use tap_core::receipt::Context;
+use tap_core::receipt::{Context, SignedReceipt};
 use thegraph_core::DeploymentId;
 
-use crate::{error::IndexerServiceError, tap::AgoraQuery};
+use crate::{
+    error::{IndexerServiceError, InvalidRequestDetails},
+    tap::{AgoraQuery, AppraisalSlot, FailedCheck, PendingSettlements},
+};
 
-use super::sender::Sender;
+use super::{
+    auth::{AuthOutcome, Authenticator},
+    deadline::Deadline,
+    features::RequestFeatures,
+    sender::Sender,
+};
 
 /// Graphql query body to be decoded and passed to agora context
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -29,8 +38,85 @@ pub struct QueryBody {
     pub variables: Option<Box<RawValue>>,
 }
 
+/// Header stating the token the value of the receipt is denominated in
+const RECEIPT_TOKEN_HEADER: &str = "tap-receipt-token";
+
+/// Feature normalizing the variables of a single request, as
+/// [ContextState::normalize_variable_numbers] does for all of them
+pub const NORMALIZE_VARIABLE_NUMBERS_FEATURE: &str = "normalize-variable-numbers";
+
+/// Token the value of the receipt is denominated in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiptToken(pub Address);
+
+/// State to be used by context middleware
+#[derive(Clone, Default)]
+pub struct ContextState {
+    /// recognizes the free queries, which are served without a receipt
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// include where the body failed to deserialize in the error response
+    pub verbose_errors: bool,
+    /// token assumed for receipts not stating theirs
+    pub default_token: Option<Address>,
+    /// write the numbers of the variables the same way before appraising the query
+    pub normalize_variable_numbers: bool,
+}
+
+/// Rewrites the numbers of `value` with an integral value that fits in 64 bits
+/// as integers, `1e2` and `100.0` becoming `100`
+fn normalize_numbers(value: &mut Value) {
+    match value {
+        Value::Number(number) => {
+            let Some(float) = number.as_f64().filter(|_| number.is_f64()) else {
+                return;
+            };
+            if float.fract() != 0.0 {
+                return;
+            }
+            if (0.0..u64::MAX as f64).contains(&float) {
+                *number = (float as u64).into();
+            } else if (i64::MIN as f64..0.0).contains(&float) {
+                *number = (float as i64).into();
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(normalize_numbers),
+        Value::Object(values) => values.values_mut().for_each(normalize_numbers),
+        Value::Null | Value::Bool(_) | Value::String(_) => {}
+    }
+}
+
+/// Variables the query is appraised with, as given to the cost models
+///
+/// By default these are the raw JSON variables of the request, or an empty
+/// string without variables. When normalizing, the variables are parsed
+/// and written back compactly with the keys of objects sorted, the numbers
+/// with an integral value that fits in 64 bits as integers and the other
+/// numbers in the shortest form reading back as the same float. Requests
+/// differing only in how they write the same numbers are then appraised the
+/// same. Variables that aren't valid JSON are kept as they are.
+fn appraisal_variables(variables: Option<&RawValue>, normalize: bool) -> String {
+    let Some(variables) = variables else {
+        return String::new();
+    };
+    if !normalize {
+        return variables.to_string();
+    }
+    match serde_json::from_str::<Value>(variables.get()) {
+        Ok(mut value) => {
+            normalize_numbers(&mut value);
+            value.to_string()
+        }
+        Err(_) => variables.to_string(),
+    }
+}
+
 /// Injects tap context in the extensions to be used by tap_receipt_authorize
+///
+/// An empty body can't be served: without a receipt the request is refused
+/// as unpaid with a `402`, with one or as a free query as an empty query with
+/// a `400`.
 pub async fn context_middleware(
+    State(state): State<ContextState>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, IndexerServiceError> {
@@ -42,16 +128,52 @@ pub async fn context_middleware(
         },
     };
     let sender = request.extensions().get::<Sender>().cloned();
+    let deadline = request.extensions().get::<Deadline>().copied();
+    let features = request
+        .extensions()
+        .get::<RequestFeatures>()
+        .cloned()
+        .unwrap_or_default();
+    let token = match request.headers().get(RECEIPT_TOKEN_HEADER) {
+        Some(token) => {
+            let token = token.to_str().unwrap_or_default();
+            Some(ReceiptToken(Address::from_str(token).map_err(|_| {
+                IndexerServiceError::InvalidReceiptToken(token.to_string())
+            })?))
+        }
+        None => state.default_token.map(ReceiptToken),
+    };
 
     let (mut parts, body) = request.into_parts();
     let bytes = to_bytes(body, usize::MAX).await?;
-    let query_body: QueryBody = serde_json::from_slice(&bytes)?;
+    if bytes.trim_ascii().is_empty() {
+        let free_query = state.authenticator.as_ref().is_some_and(|authenticator| {
+            authenticator.authenticate(&parts) == AuthOutcome::FreeQuery
+        });
+        return Err(
+            if free_query || parts.extensions.get::<SignedReceipt>().is_some() {
+                IndexerServiceError::EmptyQuery
+            } else {
+                IndexerServiceError::ReceiptNotFound
+            },
+        );
+    }
+    let query_body: QueryBody =
+        serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&bytes))
+            .map_err(|e| {
+                let details = state.verbose_errors.then(|| InvalidRequestDetails {
+                    path: e.path().to_string(),
+                    line: e.inner().line(),
+                    column: e.inner().column(),
+                    reason: e.inner().to_string(),
+                });
+                IndexerServiceError::InvalidRequest(details)
+            })?;
 
-    let variables = query_body
-        .variables
-        .as_ref()
-        .map(ToString::to_string)
-        .unwrap_or_default();
+    let variables = appraisal_variables(
+        query_body.variables.as_deref(),
+        state.normalize_variable_numbers || features.enabled(NORMALIZE_VARIABLE_NUMBERS_FEATURE),
+    );
 
     let mut ctx = Context::new();
     ctx.insert(AgoraQuery {
@@ -63,6 +185,21 @@ pub async fn context_middleware(
     if let Some(sender) = sender {
         ctx.insert(sender);
     }
+    if let Some(token) = token {
+        ctx.insert(token);
+    }
+    ctx.insert(features);
+    if let Some(deadline) = deadline {
+        ctx.insert(deadline);
+    }
+    // filled by the value check, for the cost metadata of the response
+    let appraisal = AppraisalSlot::default();
+    ctx.insert(appraisal.clone());
+    parts.extensions.insert(appraisal);
+    // applied by tap_receipt_authorize once the receipt is accepted
+    ctx.insert(PendingSettlements::default());
+    // the check refusing the receipt, for the throttling of the failure logs
+    ctx.insert(FailedCheck::default());
     parts.extensions.insert(Arc::new(ctx));
     let request = Request::from_parts(parts, bytes.into());
     Ok(next.run(request).await)
@@ -70,28 +207,39 @@ pub async fn context_middleware(
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{collections::HashSet, sync::Arc};
 
     use axum::{
-        body::Body,
-        http::{Extensions, Request},
-        middleware::from_fn,
+        body::{to_bytes, Body},
+        http::{header::AUTHORIZATION, Extensions, Request},
+        middleware::from_fn_with_state,
         routing::get,
         Router,
     };
     use reqwest::StatusCode;
     use tap_core::receipt::Context;
-    use test_assets::ESCROW_SUBGRAPH_DEPLOYMENT;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, ESCROW_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::Address;
     use tower::ServiceExt;
 
     use crate::{
-        middleware::tap_context::{context_middleware, QueryBody},
+        middleware::{
+            auth::FreeQueryToken,
+            tap_context::{
+                appraisal_variables, context_middleware, ContextState, QueryBody, ReceiptToken,
+                NORMALIZE_VARIABLE_NUMBERS_FEATURE,
+            },
+            RequestFeatures,
+        },
         tap::AgoraQuery,
     };
 
+    const TOKEN: Address = Address::repeat_byte(7);
+    const FREE_QUERY_TOKEN: &str = "free-query-token";
+
     #[tokio::test]
     async fn test_context_middleware() {
-        let middleware = from_fn(context_middleware);
+        let middleware = from_fn_with_state(ContextState::default(), context_middleware);
         let deployment = *ESCROW_SUBGRAPH_DEPLOYMENT;
         let query_body = QueryBody {
             query: "hello".to_string(),
@@ -130,4 +278,206 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    async fn send_malformed_body(verbose_errors: bool) -> (StatusCode, serde_json::Value) {
+        let middleware = from_fn_with_state(
+            ContextState {
+                verbose_errors,
+                ..Default::default()
+            },
+            context_middleware,
+        );
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(middleware);
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .extension(*ESCROW_SUBGRAPH_DEPLOYMENT)
+                    .body(r#"{"query": 1}"#.to_string())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_verbose() {
+        let (status, body) = send_malformed_body(true).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["details"]["path"], "query");
+        assert_eq!(body["details"]["line"], 1);
+        assert!(body["details"]["reason"]
+            .as_str()
+            .unwrap()
+            .contains("expected a string"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_not_verbose() {
+        let (status, body) = send_malformed_body(false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Invalid request body");
+        assert!(body.get("details").is_none());
+    }
+
+    async fn send_empty_body(
+        with_receipt: bool,
+        free_query_token: Option<&str>,
+    ) -> (StatusCode, serde_json::Value) {
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(
+                ContextState {
+                    authenticator: Some(Arc::new(FreeQueryToken::new(FREE_QUERY_TOKEN))),
+                    ..Default::default()
+                },
+                context_middleware,
+            ));
+
+        let mut request = Request::builder()
+            .uri("/")
+            .extension(*ESCROW_SUBGRAPH_DEPLOYMENT);
+        if let Some(token) = free_query_token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if with_receipt {
+            let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+            request = request.extension(receipt);
+        }
+        let res = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_without_receipt() {
+        let (status, body) = send_empty_body(false, None).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["message"], "No Tap receipt was found in the request");
+
+        let (status, _) = send_empty_body(false, Some("not-the-token")).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_with_receipt() {
+        let (status, body) = send_empty_body(true, None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Invalid request body: empty query");
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_free_query() {
+        let (status, body) = send_empty_body(false, Some(FREE_QUERY_TOKEN)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Invalid request body: empty query");
+    }
+
+    #[tokio::test]
+    async fn test_receipt_token() {
+        let middleware = from_fn_with_state(
+            ContextState {
+                default_token: Some(TOKEN),
+                ..Default::default()
+            },
+            context_middleware,
+        );
+        let handle = |extensions: Extensions| async move {
+            let ctx = extensions
+                .get::<Arc<Context>>()
+                .expect("Should contain context");
+            let ReceiptToken(token) = ctx.get::<ReceiptToken>().expect("should contain token");
+            token.to_string()
+        };
+        let app = Router::new().route("/", get(handle)).layer(middleware);
+
+        let send = |token: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/")
+                .extension(*ESCROW_SUBGRAPH_DEPLOYMENT);
+            if let Some(token) = token {
+                request = request.header("tap-receipt-token", token);
+            }
+            app.clone()
+                .oneshot(request.body(r#"{"query": "{ a }"}"#.to_string()).unwrap())
+        };
+
+        // receipts not stating their token are in the default one
+        let res = send(None).await.unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, TOKEN.to_string());
+
+        let other = Address::repeat_byte(8).to_string();
+        let res = send(Some(&other)).await.unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, other);
+
+        let res = send(Some("not-a-token")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_normalized_variables() {
+        let variables = |raw: &str, normalize| {
+            let raw = serde_json::value::RawValue::from_string(raw.to_string()).unwrap();
+            appraisal_variables(Some(&raw), normalize)
+        };
+        let scientific = r#"{"first": 1e2, "skip": -2.0E1, "where": {"price_gt": 0.5}}"#;
+        let integers = r#"{"where":{"price_gt":0.5},"skip":-20,"first":100}"#;
+
+        assert_ne!(variables(scientific, false), variables(integers, false));
+        assert_eq!(variables(scientific, true), variables(integers, true));
+        assert_eq!(
+            variables(integers, true),
+            r#"{"first":100,"skip":-20,"where":{"price_gt":0.5}}"#
+        );
+        assert_eq!(appraisal_variables(None, true), "");
+    }
+
+    #[tokio::test]
+    async fn test_normalize_variables_feature() {
+        let handle = |extensions: Extensions| async move {
+            let ctx = extensions.get::<Arc<Context>>().unwrap();
+            assert!(ctx.get::<RequestFeatures>().is_some());
+            ctx.get::<AgoraQuery>().unwrap().variables.clone()
+        };
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn_with_state(
+                ContextState::default(),
+                context_middleware,
+            ));
+        let variables = |features: HashSet<String>| {
+            let request = Request::builder()
+                .uri("/")
+                .extension(*ESCROW_SUBGRAPH_DEPLOYMENT)
+                .extension(RequestFeatures(features))
+                .body(r#"{"query": "hello", "variables": {"first": 1e2}}"#.to_string())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(request).await.unwrap();
+                to_bytes(res.into_body(), usize::MAX).await.unwrap()
+            }
+        };
+
+        assert_eq!(variables(HashSet::new()).await, r#"{"first": 1e2}"#);
+        assert_eq!(
+            variables(HashSet::from([
+                NORMALIZE_VARIABLE_NUMBERS_FEATURE.to_string()
+            ]))
+            .await,
+            r#"{"first":100}"#
+        );
+    }
 }
@@ -1,20 +1,59 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{extract::Request, middleware::Next, response::Response, RequestExt};
-use axum_extra::TypedHeader;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    RequestExt,
+};
+use axum_extra::{headers::Header, TypedHeader};
 
-use crate::service::TapReceipt;
+use crate::{
+    error::IndexerServiceError,
+    service::{validate_receipt, ReceiptFieldError, TapReceipt, TAP_RECEIPT_INVALID},
+};
+
+/// State to be used by receipt middleware
+#[derive(Clone, Copy, Default)]
+pub struct ReceiptState {
+    /// refuse malformed receipts, see [validate_receipt]
+    pub strict: bool,
+}
 
 /// Injects tap receipts in the extensions
 ///
 /// A request won't always have a receipt because they might be
 /// free queries.
-/// That's why we don't fail with 400.
+/// That's why we don't fail with 400, unless the receipt is validated
+/// strictly and the request has one that is malformed.
 ///
 /// This is useful to not deserialize multiple times the same receipt
-pub async fn receipt_middleware(mut request: Request, next: Next) -> Response {
-    if let Ok(TypedHeader(TapReceipt(receipt))) =
+pub async fn receipt_middleware(
+    State(state): State<ReceiptState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if state.strict {
+        if let Some(raw_receipt) = request.headers().get(TapReceipt::name()) {
+            let receipt = raw_receipt
+                .to_str()
+                .map_err(|_| ReceiptFieldError {
+                    field: "receipt".into(),
+                    reason: "is not valid UTF-8".into(),
+                })
+                .and_then(validate_receipt);
+            match receipt {
+                Ok(receipt) => {
+                    request.extensions_mut().insert(receipt);
+                }
+                Err(e) => {
+                    TAP_RECEIPT_INVALID.inc();
+                    return IndexerServiceError::MalformedReceipt(e).into_response();
+                }
+            }
+        }
+    } else if let Ok(TypedHeader(TapReceipt(receipt))) =
         request.extract_parts::<TypedHeader<TapReceipt>>().await
     {
         request.extensions_mut().insert(receipt);
@@ -24,12 +63,15 @@ pub async fn receipt_middleware(mut request: Request, next: Next) -> Response {
 
 #[cfg(test)]
 mod tests {
-    use crate::{middleware::tap_receipt::receipt_middleware, service::TapReceipt};
+    use crate::{
+        middleware::tap_receipt::{receipt_middleware, ReceiptState},
+        service::TapReceipt,
+    };
 
     use axum::{
-        body::Body,
+        body::{to_bytes, Body},
         http::{Extensions, Request},
-        middleware::from_fn,
+        middleware::from_fn_with_state,
         routing::get,
         Router,
     };
@@ -41,7 +83,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_receipt_middleware() {
-        let middleware = from_fn(receipt_middleware);
+        let middleware = from_fn_with_state(ReceiptState::default(), receipt_middleware);
 
         let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
         let receipt_json = serde_json::to_string(&receipt).unwrap();
@@ -69,4 +111,57 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_strict_receipt_validation() {
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let mut malformed = serde_json::to_value(&receipt).unwrap();
+        malformed["message"]["value"] = serde_json::json!(0);
+
+        let handle = |extensions: Extensions| async move {
+            if extensions.get::<SignedReceipt>().is_some() {
+                "paid"
+            } else {
+                "free"
+            }
+        };
+        let send = |strict: bool, receipt: String| {
+            let app = Router::new()
+                .route("/", get(handle))
+                .layer(from_fn_with_state(
+                    ReceiptState { strict },
+                    receipt_middleware,
+                ));
+            async move {
+                let res = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/")
+                            .header(TapReceipt::name(), receipt)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                let status = res.status();
+                let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        // the receipt is dropped without strict validation
+        assert_eq!(
+            send(false, malformed.to_string()).await,
+            (StatusCode::OK, "free".to_string())
+        );
+        let (status, body) = send(true, malformed.to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("Malformed receipt: `message.value` is zero"));
+
+        let receipt = serde_json::to_string(&receipt).unwrap();
+        assert_eq!(
+            send(true, receipt).await,
+            (StatusCode::OK, "paid".to_string())
+        );
+    }
 }
@@ -0,0 +1,212 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Request, State},
+    http::{header::SERVER, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexer_config::VersionHeadersConfig;
+use tracing::debug;
+
+use crate::error::IndexerServiceError;
+
+/// Version of indexer-service advertised in responses
+pub const INDEXER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub static GRAPH_INDEXER_VERSION: HeaderName = HeaderName::from_static("graph-indexer-version");
+pub static GRAPH_GATEWAY_VERSION: HeaderName = HeaderName::from_static("graph-gateway-version");
+
+/// Version of the gateway a request comes from, as sent in `Graph-Gateway-Version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayVersion(pub String);
+
+/// State to be used by version headers middleware
+#[derive(Clone)]
+pub struct VersionHeadersState {
+    /// advertise [INDEXER_VERSION] in `Graph-Indexer-Version`
+    pub advertise_version: bool,
+    pub server: Option<HeaderValue>,
+    /// refuse gateways older than this
+    pub min_gateway_version: Option<Vec<u64>>,
+}
+
+impl VersionHeadersState {
+    pub fn new(config: &VersionHeadersConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            advertise_version: config.advertise_version,
+            server: config
+                .server
+                .as_deref()
+                .map(HeaderValue::from_str)
+                .transpose()?,
+            min_gateway_version: config
+                .min_gateway_version
+                .as_deref()
+                .map(|version| {
+                    parse_version(version)
+                        .ok_or_else(|| anyhow!("Invalid minimum gateway version `{version}`"))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+/// Numeric components of a version like `v1.2.3-beta`, pre-release and build
+/// suffixes are left out, and trailing zeros so that `1.2` is `1.2.0`
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim();
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let version = version.split(['-', '+']).next()?;
+    let mut components = version
+        .split('.')
+        .map(|component| component.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    while components.last() == Some(&0) {
+        components.pop();
+    }
+    Some(components)
+}
+
+/// Advertises the version of the service in the response headers and reads the
+/// version of the gateway from the request
+///
+/// The gateway version is added as a [GatewayVersion] Extension. Gateways older
+/// than the minimum version are refused with a `400`, those with no or an
+/// unparseable version are let through.
+pub async fn version_headers_middleware(
+    State(state): State<VersionHeadersState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let gateway_version = request
+        .headers()
+        .get(&GRAPH_GATEWAY_VERSION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut response = match gateway_version {
+        Some(version) => {
+            debug!(gateway_version = %version, "Request from gateway");
+            let outdated = state
+                .min_gateway_version
+                .as_ref()
+                .is_some_and(|min| parse_version(&version).is_some_and(|version| version < *min));
+            if outdated {
+                IndexerServiceError::GatewayVersionUnsupported(version).into_response()
+            } else {
+                request.extensions_mut().insert(GatewayVersion(version));
+                next.run(request).await
+            }
+        }
+        None => next.run(request).await,
+    };
+
+    let headers = response.headers_mut();
+    if state.advertise_version {
+        headers.insert(
+            GRAPH_INDEXER_VERSION.clone(),
+            HeaderValue::from_static(INDEXER_VERSION),
+        );
+    }
+    if let Some(server) = state.server {
+        headers.insert(SERVER, server);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::Extension,
+        http::{header::SERVER, HeaderValue, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{
+        parse_version, version_headers_middleware, GatewayVersion, VersionHeadersState,
+        GRAPH_GATEWAY_VERSION, GRAPH_INDEXER_VERSION, INDEXER_VERSION,
+    };
+
+    fn app(state: VersionHeadersState) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|gateway: Option<Extension<GatewayVersion>>| async move {
+                    gateway
+                        .map(|Extension(GatewayVersion(version))| version)
+                        .unwrap_or_default()
+                }),
+            )
+            .layer(from_fn_with_state(state, version_headers_middleware))
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.2.3"), Some(vec![1, 2, 3]));
+        assert_eq!(parse_version("v1.10.0-beta.1"), Some(vec![1, 10]));
+        assert_eq!(parse_version("1.2"), parse_version("1.2.0"));
+        assert_eq!(parse_version("gateway"), None);
+    }
+
+    #[tokio::test]
+    async fn test_version_headers() {
+        let app = app(VersionHeadersState {
+            advertise_version: true,
+            server: Some(HeaderValue::from_static("indexer-service")),
+            min_gateway_version: Some(vec![1, 2]),
+        });
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(&GRAPH_INDEXER_VERSION).unwrap(),
+            INDEXER_VERSION
+        );
+        assert_eq!(response.headers().get(SERVER).unwrap(), "indexer-service");
+
+        let send = |version: &str| {
+            let request = Request::builder()
+                .uri("/")
+                .header(&GRAPH_GATEWAY_VERSION, version)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let response = send("1.10.0").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "1.10.0");
+
+        // refused, still advertising the version
+        let response = send("1.1.9").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get(&GRAPH_INDEXER_VERSION).unwrap(),
+            INDEXER_VERSION
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_headers() {
+        let app = app(VersionHeadersState {
+            advertise_version: false,
+            server: None,
+            min_gateway_version: None,
+        });
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert!(response.headers().get(&GRAPH_INDEXER_VERSION).is_none());
+        assert!(response.headers().get(SERVER).is_none());
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! How addresses and deployment ids are written in the bodies built by the
+//! service.
+//!
+//! Attestations keep their canonical encoding and graph-node responses are
+//! forwarded untouched, so the attested bytes don't depend on the format.
+
+use indexer_config::{AddressFormat, DeploymentIdFormat};
+use thegraph_core::{Address, DeploymentId};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResponseFormat {
+    pub address: AddressFormat,
+    pub deployment_id: DeploymentIdFormat,
+}
+
+impl ResponseFormat {
+    pub fn address(&self, address: &Address) -> String {
+        match self.address {
+            AddressFormat::Checksummed => address.to_checksum(None),
+            AddressFormat::Lowercase => format!("{address:#x}"),
+        }
+    }
+
+    pub fn deployment_id(&self, deployment: &DeploymentId) -> String {
+        match self.deployment_id {
+            DeploymentIdFormat::Base58 => deployment.to_string(),
+            DeploymentIdFormat::Hex => format!("{deployment:#x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use indexer_config::{AddressFormat, DeploymentIdFormat};
+    use thegraph_core::{Address, DeploymentId};
+
+    use super::ResponseFormat;
+
+    const ADDRESS: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+    const DEPLOYMENT_BASE58: &str = "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz";
+    const DEPLOYMENT_HEX: &str =
+        "0x7d5a99f603f231d53a4f39d1521f98d2e8bb279cf29bebfd0687dc98458e7f89";
+
+    fn format(address: AddressFormat, deployment_id: DeploymentIdFormat) -> ResponseFormat {
+        ResponseFormat {
+            address,
+            deployment_id,
+        }
+    }
+
+    #[test]
+    fn test_address_format() {
+        let address = Address::from_str(ADDRESS).unwrap();
+
+        let checksummed = format(AddressFormat::Checksummed, Default::default());
+        assert_eq!(checksummed.address(&address), ADDRESS);
+
+        let lowercase = format(AddressFormat::Lowercase, Default::default());
+        assert_eq!(lowercase.address(&address), ADDRESS.to_lowercase());
+    }
+
+    #[test]
+    fn test_deployment_id_format() {
+        let deployment = DeploymentId::from_str(DEPLOYMENT_BASE58).unwrap();
+
+        let base58 = format(Default::default(), DeploymentIdFormat::Base58);
+        assert_eq!(base58.deployment_id(&deployment), DEPLOYMENT_BASE58);
+
+        let hex = format(Default::default(), DeploymentIdFormat::Hex);
+        assert_eq!(hex.deployment_id(&deployment), DEPLOYMENT_HEX);
+
+        // both forms designate the same deployment
+        assert_eq!(DeploymentId::from_str(DEPLOYMENT_HEX).unwrap(), deployment);
+    }
+}
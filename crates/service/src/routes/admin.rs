@@ -0,0 +1,243 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response as AxumResponse},
+    Json,
+};
+use indexer_config::{AllocationQuotaConfig, Config, ConfigPrefix};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use thegraph_core::{Address, DeploymentId};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+use crate::{
+    middleware::AllocationQuota,
+    tap::{CheckPipeline, CheckSettings, DeadLetterStore},
+};
+
+#[derive(Clone)]
+pub struct AdminState {
+    pub check_pipeline: Arc<CheckPipeline>,
+    pub safe_mode: Arc<watch::Sender<bool>>,
+    pub request_log_sample_rate: Arc<watch::Sender<f64>>,
+    pub allocation_quotas: Arc<watch::Sender<HashMap<Address, AllocationQuota>>>,
+    pub denied_deployments: Arc<watch::Sender<HashSet<DeploymentId>>>,
+    /// set when dead-lettering receipts is enabled
+    pub dead_letter: Option<DeadLetterStore>,
+    pub config_path: Option<PathBuf>,
+    pub config_profile: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Sample rate must be between 0 and 1, got {0}")]
+    InvalidSampleRate(f64),
+    #[error("The sender allow-list is not enabled")]
+    SenderAllowListDisabled,
+    #[error("Dead-lettering receipts is not enabled")]
+    DeadLetterDisabled,
+    #[error("Failed to read the dead-letter receipts: {0}")]
+    DeadLetterUnavailable(String),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> AxumResponse {
+        warn!(%self, "Admin request rejected");
+        let body = json!({
+            "error": self.to_string(),
+        });
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    }
+}
+
+/// Re-reads the configuration file and swaps the receipt checks.
+///
+/// The new configuration is fully validated first, an invalid file leaves the
+/// running checks untouched.
+pub async fn reload_checks(
+    State(state): State<AdminState>,
+) -> Result<impl IntoResponse, AdminError> {
+    let config = Config::parse_with_profile(
+        ConfigPrefix::Service,
+        state.config_path.as_ref(),
+        state.config_profile.as_deref(),
+    )
+    .map_err(AdminError::InvalidConfig)?;
+    let changes = state
+        .check_pipeline
+        .reload(CheckSettings::from_config(&config))
+        .await;
+    Ok(Json(json!({ "changes": changes })))
+}
+
+/// Bytes of the replay report buffered ahead of the client reading it
+const REPLAY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Runs the stored receipts through the current checks without changing any
+/// state, streaming which would now fail and why.
+pub async fn replay_receipts(State(state): State<AdminState>) -> impl IntoResponse {
+    let replay = state.check_pipeline.replay().await;
+    let (writer, reader) = tokio::io::duplex(REPLAY_BUFFER_BYTES);
+    tokio::spawn(async move {
+        match replay.run(writer).await {
+            Ok(()) => info!("Receipt replay finished"),
+            Err(e) => warn!(error = %e, "Receipt replay stopped"),
+        }
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SafeModeRequest {
+    enabled: bool,
+}
+
+pub async fn get_safe_mode(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(json!({ "enabled": *state.safe_mode.borrow() }))
+}
+
+/// Engages or lifts safe mode, taking effect for the next request.
+pub async fn set_safe_mode(
+    State(state): State<AdminState>,
+    Json(SafeModeRequest { enabled }): Json<SafeModeRequest>,
+) -> impl IntoResponse {
+    let previous = state.safe_mode.send_replace(enabled);
+    match (previous, enabled) {
+        (false, true) => warn!("SAFE MODE ENGAGED: all paid queries will be refused"),
+        (true, false) => info!("Safe mode lifted: paid queries are accepted again"),
+        _ => {}
+    }
+    Json(json!({ "enabled": enabled }))
+}
+
+#[derive(Deserialize)]
+pub struct SampleRateRequest {
+    rate: f64,
+}
+
+pub async fn get_request_log_sample_rate(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(json!({ "rate": *state.request_log_sample_rate.borrow() }))
+}
+
+pub async fn set_request_log_sample_rate(
+    State(state): State<AdminState>,
+    Json(SampleRateRequest { rate }): Json<SampleRateRequest>,
+) -> Result<impl IntoResponse, AdminError> {
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(AdminError::InvalidSampleRate(rate));
+    }
+    let previous = state.request_log_sample_rate.send_replace(rate);
+    info!(previous, rate, "Request log sample rate changed");
+    Ok(Json(json!({ "rate": rate })))
+}
+
+pub async fn get_allocation_quotas(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.allocation_quotas.borrow().clone())
+}
+
+/// Replaces the quotas of every allocation, usage so far is kept.
+pub async fn set_allocation_quotas(
+    State(state): State<AdminState>,
+    Json(quotas): Json<HashMap<Address, AllocationQuotaConfig>>,
+) -> impl IntoResponse {
+    let quotas: HashMap<_, _> = quotas
+        .iter()
+        .map(|(allocation, quota)| (*allocation, AllocationQuota::from(quota)))
+        .collect();
+    info!(allocations = quotas.len(), "Allocation quotas replaced");
+    state.allocation_quotas.send_replace(quotas.clone());
+    Json(quotas)
+}
+
+pub async fn get_denied_deployments(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.denied_deployments.borrow().clone())
+}
+
+/// Replaces the denied deployments, taking effect for the next request.
+pub async fn set_denied_deployments(
+    State(state): State<AdminState>,
+    Json(denied): Json<HashSet<DeploymentId>>,
+) -> impl IntoResponse {
+    let previous = state.denied_deployments.send_replace(denied.clone());
+    for deployment in denied.difference(&previous) {
+        warn!(%deployment, "Deployment denied: its queries will be refused");
+    }
+    for deployment in previous.difference(&denied) {
+        info!(%deployment, "Deployment no longer denied");
+    }
+    Json(denied)
+}
+
+pub async fn get_sender_allow_list(
+    State(state): State<AdminState>,
+) -> Result<impl IntoResponse, AdminError> {
+    let allowed = state
+        .check_pipeline
+        .sender_allow_list()
+        .await
+        .ok_or(AdminError::SenderAllowListDisabled)?;
+    Ok(Json(allowed))
+}
+
+/// Replaces the senders accepted by the allow-list check, taking effect for
+/// the next receipt. Kept until the configured allow-list changes.
+pub async fn set_sender_allow_list(
+    State(state): State<AdminState>,
+    Json(allowed): Json<HashSet<Address>>,
+) -> Result<impl IntoResponse, AdminError> {
+    let previous = state
+        .check_pipeline
+        .set_sender_allow_list(allowed.clone())
+        .await
+        .ok_or(AdminError::SenderAllowListDisabled)?;
+    for sender in allowed.difference(&previous) {
+        info!(%sender, "Sender allowed");
+    }
+    for sender in previous.difference(&allowed) {
+        warn!(%sender, "Sender no longer allowed: its receipts will be refused");
+    }
+    Ok(Json(allowed))
+}
+
+/// Dead-lettered receipts returned when no limit is asked for
+const DEFAULT_DEAD_LETTER_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct DeadLetterQuery {
+    limit: Option<i64>,
+}
+
+/// Lists the latest dead-lettered receipts, with why their query failed.
+pub async fn get_dead_letter_receipts(
+    State(state): State<AdminState>,
+    Query(DeadLetterQuery { limit }): Query<DeadLetterQuery>,
+) -> Result<impl IntoResponse, AdminError> {
+    let dead_letter = state
+        .dead_letter
+        .as_ref()
+        .ok_or(AdminError::DeadLetterDisabled)?;
+    let receipts = dead_letter
+        .list(limit.unwrap_or(DEFAULT_DEAD_LETTER_LIMIT).max(0))
+        .await
+        .map_err(|e| AdminError::DeadLetterUnavailable(e.to_string()))?;
+    Ok(Json(receipts))
+}
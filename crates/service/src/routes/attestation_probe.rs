@@ -0,0 +1,176 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attests a well-known payload, letting gateways check that the signer of an
+//! allocation works before they route queries to it.
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Response as AxumResponse},
+    Json,
+};
+use indexer_attestation::AttestationPayload;
+use indexer_monitor::AllocationWatcher;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use thegraph_core::Address;
+use thiserror::Error;
+
+use crate::{
+    error::StatusCodeExt,
+    middleware::{AttestationBackendError, AttestationBackendState},
+};
+
+/// Request the probe attestation is computed over
+pub const PROBE_REQUEST: &str = r#"{"query":"{ attestationProbe }"}"#;
+/// Response the probe attestation is computed over
+pub const PROBE_RESPONSE: &str = r#"{"data":{"attestationProbe":true}}"#;
+
+#[derive(Debug, Error)]
+pub enum AttestationProbeError {
+    #[error("No attestation signer for allocation {0}")]
+    NoSigner(Address),
+    #[error(transparent)]
+    Backend(AttestationBackendError),
+}
+
+impl From<AttestationBackendError> for AttestationProbeError {
+    fn from(error: AttestationBackendError) -> Self {
+        match error {
+            AttestationBackendError::NoSigner(allocation) => Self::NoSigner(allocation),
+            error => Self::Backend(error),
+        }
+    }
+}
+
+impl IntoResponse for AttestationProbeError {
+    fn into_response(self) -> AxumResponse {
+        let status = match &self {
+            AttestationProbeError::NoSigner(_) => StatusCode::NOT_FOUND,
+            AttestationProbeError::Backend(e) => e.status_code(),
+        };
+        let body = json!({
+            "error": self.to_string(),
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+#[derive(Clone)]
+pub struct AttestationProbeState {
+    pub attestation: AttestationBackendState,
+    /// the deployment of the allocation is part of the attestation
+    pub allocations: AllocationWatcher,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationProbeParams {
+    allocation: Address,
+}
+
+/// Attests [PROBE_REQUEST] and [PROBE_RESPONSE] with the signer of the
+/// allocation, which the gateway verifies like any other attestation.
+pub async fn attestation_probe(
+    State(state): State<AttestationProbeState>,
+    Query(AttestationProbeParams { allocation }): Query<AttestationProbeParams>,
+) -> Result<impl IntoResponse, AttestationProbeError> {
+    let deployment = state
+        .allocations
+        .borrow()
+        .get(&allocation)
+        .map(|allocation| allocation.subgraph_deployment.id)
+        .ok_or(AttestationProbeError::NoSigner(allocation))?;
+    let state = state.attestation;
+    let domain = state.domain.borrow().clone();
+    let payload = AttestationPayload::new(&domain, &deployment, PROBE_REQUEST, PROBE_RESPONSE);
+    let signature = state.backend.sign(&payload, &allocation).await?;
+    let attestation = payload.into_attestation(&signature);
+    Ok(Json(json!({
+        "request": PROBE_REQUEST,
+        "response": PROBE_RESPONSE,
+        "attestation": attestation,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use indexer_attestation::AttestationSigner;
+    use indexer_monitor::attestation_signers;
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::{attestation::eip712_domain, Address, Attestation};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{attestation_probe, AttestationProbeState, PROBE_REQUEST, PROBE_RESPONSE};
+    use crate::middleware::{AttestationBackendState, AttestationState};
+
+    #[tokio::test]
+    async fn test_attestation_probe() {
+        let allocation = *INDEXER_ALLOCATIONS.keys().next().unwrap();
+        let (_, allocations_rx) = watch::channel(INDEXER_ALLOCATIONS.clone());
+        let (_, dispute_manager_rx) = watch::channel(*DISPUTE_MANAGER_ADDRESS);
+        let attestation_signers = attestation_signers(
+            allocations_rx.clone(),
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx,
+        );
+        let signer: AttestationSigner = attestation_signers
+            .borrow()
+            .get(&allocation)
+            .unwrap()
+            .clone();
+        let state = AttestationProbeState {
+            attestation: AttestationBackendState {
+                backend: Arc::new(AttestationState {
+                    attestation_signers,
+                    lazy_attestation_signers: None,
+                }),
+                domain: watch::channel(eip712_domain(1, *DISPUTE_MANAGER_ADDRESS)).1,
+                unattested_free_queries: false,
+            },
+            allocations: allocations_rx,
+        };
+        let app = Router::new()
+            .route("/attestation-probe", get(attestation_probe))
+            .with_state(state);
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/attestation-probe?allocation={allocation}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let attestation: Attestation = serde_json::from_value(body["attestation"].clone()).unwrap();
+        // signed by the allocation key over the well-known payload
+        assert!(signer
+            .verify(&attestation, PROBE_REQUEST, PROBE_RESPONSE, &allocation)
+            .is_ok());
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/attestation-probe?allocation={}", Address::ZERO))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
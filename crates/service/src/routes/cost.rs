@@ -3,7 +3,10 @@
 
 use std::str::FromStr;
 
-use crate::database::cost_model::{self, CostModel};
+use crate::{
+    database::cost_model::{self, CostModel},
+    response_format::ResponseFormat,
+};
 use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
 use lazy_static::lazy_static;
 use prometheus::{
@@ -62,10 +65,10 @@ pub struct GraphQlCostModel {
     pub variables: Option<Value>,
 }
 
-impl From<CostModel> for GraphQlCostModel {
-    fn from(model: CostModel) -> Self {
+impl GraphQlCostModel {
+    fn new(model: CostModel, format: &ResponseFormat) -> Self {
         Self {
-            deployment: model.deployment.to_string(),
+            deployment: format.deployment_id(&model.deployment),
             model: model.model,
             variables: model.variables,
         }
@@ -124,8 +127,12 @@ impl Query {
         deployment_ids: Vec<DeploymentId>,
     ) -> Result<Vec<GraphQlCostModel>, anyhow::Error> {
         let pool = &ctx.data_unchecked::<PgPool>();
+        let format = ctx.data_unchecked::<ResponseFormat>();
         let cost_models = cost_model::cost_models(pool, &deployment_ids).await?;
-        Ok(cost_models.into_iter().map(|m| m.into()).collect())
+        Ok(cost_models
+            .into_iter()
+            .map(|m| GraphQlCostModel::new(m, format))
+            .collect())
     }
 
     async fn _cost_model(
@@ -134,16 +141,18 @@ impl Query {
         deployment_id: DeploymentId,
     ) -> Result<Option<GraphQlCostModel>, anyhow::Error> {
         let pool = &ctx.data_unchecked::<PgPool>();
+        let format = ctx.data_unchecked::<ResponseFormat>();
         cost_model::cost_model(pool, &deployment_id)
             .await
-            .map(|model_opt| model_opt.map(GraphQlCostModel::from))
+            .map(|model_opt| model_opt.map(|model| GraphQlCostModel::new(model, format)))
     }
 }
 
 pub type CostSchema = Schema<Query, EmptyMutation, EmptySubscription>;
 
-pub async fn build_schema(data: PgPool) -> CostSchema {
+pub async fn build_schema(data: PgPool, format: ResponseFormat) -> CostSchema {
     Schema::build(Query, EmptyMutation, EmptySubscription)
         .data(data)
+        .data(format)
         .finish()
 }
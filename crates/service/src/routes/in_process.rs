@@ -0,0 +1,151 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Query route run in-process, for the transports other than HTTP
+//!
+//! The WebSocket and gRPC transports build an HTTP request out of their
+//! messages and run it through the regular query route, so queries go through
+//! the same checks and attestation. [InProcessQueries] reads the response
+//! back: the `{graphQLResponse, attestation}` payload, or the message of the
+//! error the route answered with.
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+    Router,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use thiserror::Error;
+use tower::ServiceExt;
+
+/// Room for the attestation next to the `graphQLResponse` string
+const PAYLOAD_OVERHEAD_BYTES: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum InProcessQueryError {
+    /// the query route refused the query, with its message
+    #[error("{1}")]
+    Refused(StatusCode, String),
+    /// the response of the query route couldn't be read
+    #[error("{0}")]
+    InvalidResponse(String),
+}
+
+#[derive(Clone)]
+pub struct InProcessQueries {
+    routes: Router,
+    /// largest payload read back from the query route
+    max_payload_bytes: usize,
+}
+
+impl InProcessQueries {
+    /// Queries run through `routes`, whose graph-node responses are bounded by
+    /// `max_response_body_bytes`, `service.max_response_body_bytes`
+    pub fn new(routes: Router, max_response_body_bytes: Option<usize>) -> Self {
+        // the response is escaped in the `graphQLResponse` string, which at
+        // most doubles it
+        let max_payload_bytes = max_response_body_bytes.map_or(usize::MAX, |max_bytes| {
+            max_bytes
+                .saturating_mul(2)
+                .saturating_add(PAYLOAD_OVERHEAD_BYTES)
+        });
+        Self {
+            routes,
+            max_payload_bytes,
+        }
+    }
+
+    /// Runs `request` through the query route, decoding the payload it
+    /// answered with
+    pub async fn run<T: DeserializeOwned>(
+        &self,
+        request: Request<Body>,
+    ) -> Result<T, InProcessQueryError> {
+        let response = match self.routes.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), self.max_payload_bytes)
+            .await
+            .map_err(|e| InProcessQueryError::InvalidResponse(e.to_string()))?;
+
+        if !status.is_success() {
+            // service errors are `{"message": ..}`, others are plain text
+            let message = serde_json::from_slice::<Value>(&bytes)
+                .ok()
+                .and_then(|body| body.get("message")?.as_str().map(ToString::to_string))
+                .unwrap_or_else(|| String::from_utf8_lossy(&bytes).into_owned());
+            return Err(InProcessQueryError::Refused(status, message));
+        }
+        serde_json::from_slice(&bytes)
+            .map_err(|e| InProcessQueryError::InvalidResponse(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+
+    use super::{InProcessQueries, InProcessQueryError};
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_payload_or_error_message() {
+        let routes = Router::new()
+            .route(
+                "/ok",
+                post(|| async {
+                    Json(json!({"graphQLResponse": "x".repeat(2000), "attestation": null}))
+                }),
+            )
+            .route(
+                "/refused",
+                post(|| async {
+                    (
+                        StatusCode::PAYMENT_REQUIRED,
+                        Json(json!({"message": "No valid receipt"})),
+                    )
+                }),
+            )
+            .route(
+                "/plain",
+                post(|| async { (StatusCode::BAD_GATEWAY, "bad gateway") }),
+            );
+
+        let queries = InProcessQueries::new(routes.clone(), None);
+        let payload: Value = queries.run(request("/ok")).await.unwrap();
+        assert_eq!(payload["graphQLResponse"].as_str().unwrap().len(), 2000);
+        assert!(matches!(
+            queries.run::<Value>(request("/refused")).await,
+            Err(InProcessQueryError::Refused(StatusCode::PAYMENT_REQUIRED, message))
+                if message == "No valid receipt"
+        ));
+        assert!(matches!(
+            queries.run::<Value>(request("/plain")).await,
+            Err(InProcessQueryError::Refused(StatusCode::BAD_GATEWAY, message))
+                if message == "bad gateway"
+        ));
+
+        // payloads over the bound aren't read
+        let queries = InProcessQueries::new(routes, Some(100));
+        assert!(matches!(
+            queries.run::<Value>(request("/ok")).await,
+            Err(InProcessQueryError::InvalidResponse(_))
+        ));
+    }
+}
@@ -1,14 +1,34 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod admin;
+mod attestation_probe;
 pub mod cost;
 pub mod dips;
 mod health;
+#[cfg(any(feature = "websocket", feature = "grpc"))]
+mod in_process;
+mod query_complexity;
+mod ready;
 mod request_handler;
+mod singleflight;
 mod static_subgraph;
 mod status;
+mod tap_stats;
+#[cfg(feature = "websocket")]
+mod websocket;
 
+pub use attestation_probe::{attestation_probe, AttestationProbeState};
 pub use health::health;
-pub use request_handler::request_handler;
-pub use static_subgraph::static_subgraph_request_handler;
+#[cfg(any(feature = "websocket", feature = "grpc"))]
+pub(crate) use in_process::{InProcessQueries, InProcessQueryError};
+pub use query_complexity::QueryLimits;
+pub use ready::ready;
+pub(crate) use request_handler::check_query;
+pub use request_handler::{request_handler, ResponseTransformer};
+pub use singleflight::Singleflight;
+pub use static_subgraph::{static_subgraph_request_handler, StaticSubgraphState};
 pub use status::status;
+pub use tap_stats::{tap_stats, TapStatsState};
+#[cfg(feature = "websocket")]
+pub use websocket::websocket_handler;
@@ -0,0 +1,253 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rejects GraphQL queries that are too deep or select too many fields before
+//! they are forwarded to graph-node.
+
+use std::collections::HashMap;
+
+use graphql::graphql_parser::query as q;
+use indexer_config::QueryLimitsConfig;
+use thegraph_core::DeploymentId;
+
+use crate::error::SubgraphServiceError;
+
+/// Query limits applied to every deployment, with per-deployment overrides
+#[derive(Debug, Clone, Default)]
+pub struct QueryLimits {
+    default: QueryLimitsConfig,
+    per_deployment: HashMap<DeploymentId, QueryLimitsConfig>,
+}
+
+impl QueryLimits {
+    pub fn new(
+        default: QueryLimitsConfig,
+        per_deployment: HashMap<DeploymentId, QueryLimitsConfig>,
+    ) -> Self {
+        Self {
+            default,
+            per_deployment,
+        }
+    }
+
+    /// Limits for the deployment, unset overrides falling back to the defaults
+    pub fn for_deployment(&self, deployment: &DeploymentId) -> QueryLimitsConfig {
+        match self.per_deployment.get(deployment) {
+            Some(limits) => QueryLimitsConfig {
+                max_depth: limits.max_depth.or(self.default.max_depth),
+                max_fields: limits.max_fields.or(self.default.max_fields),
+            },
+            None => self.default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Complexity {
+    depth: usize,
+    fields: usize,
+}
+
+/// Walks the selection sets of a document, expanding fragment spreads.
+///
+/// Fragments are only walked once, so documents spreading the same fragment
+/// many times can't make the analysis itself expensive.
+struct ComplexityWalker<'a, 'b> {
+    fragments: HashMap<&'a str, &'a q::SelectionSet<'b, String>>,
+    visited: HashMap<&'a str, Complexity>,
+    visiting: Vec<&'a str>,
+}
+
+impl<'a, 'b> ComplexityWalker<'a, 'b> {
+    fn selection_set(&mut self, selection_set: &'a q::SelectionSet<'b, String>) -> Complexity {
+        let mut complexity = Complexity::default();
+        for selection in &selection_set.items {
+            let inner = match selection {
+                q::Selection::Field(field) => {
+                    let inner = self.selection_set(&field.selection_set);
+                    Complexity {
+                        depth: inner.depth + 1,
+                        fields: inner.fields.saturating_add(1),
+                    }
+                }
+                q::Selection::FragmentSpread(spread) => self.fragment(&spread.fragment_name),
+                q::Selection::InlineFragment(fragment) => {
+                    self.selection_set(&fragment.selection_set)
+                }
+            };
+            complexity.depth = complexity.depth.max(inner.depth);
+            complexity.fields = complexity.fields.saturating_add(inner.fields);
+        }
+        complexity
+    }
+
+    fn fragment(&mut self, name: &'a str) -> Complexity {
+        if let Some(complexity) = self.visited.get(name) {
+            return *complexity;
+        }
+        // unknown or cyclic fragments are rejected by graph-node anyway
+        let Some(selection_set) = self.fragments.get(name).copied() else {
+            return Complexity::default();
+        };
+        if self.visiting.contains(&name) {
+            return Complexity::default();
+        }
+        self.visiting.push(name);
+        let complexity = self.selection_set(selection_set);
+        self.visiting.pop();
+        self.visited.insert(name, complexity);
+        complexity
+    }
+}
+
+/// Depth and field count of the most complex operation in the document
+fn complexity(document: &q::Document<'_, String>) -> Complexity {
+    let fragments = document
+        .definitions
+        .iter()
+        .filter_map(|def| match def {
+            q::Definition::Fragment(fragment) => {
+                Some((fragment.name.as_str(), &fragment.selection_set))
+            }
+            q::Definition::Operation(_) => None,
+        })
+        .collect();
+    let mut walker = ComplexityWalker {
+        fragments,
+        visited: HashMap::new(),
+        visiting: Vec::new(),
+    };
+
+    let mut complexity = Complexity::default();
+    for definition in &document.definitions {
+        let selection_set = match definition {
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => &query.selection_set,
+            q::Definition::Operation(q::OperationDefinition::Mutation(mutation)) => {
+                &mutation.selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::Subscription(subscription)) => {
+                &subscription.selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            q::Definition::Fragment(_) => continue,
+        };
+        let operation = walker.selection_set(selection_set);
+        complexity.depth = complexity.depth.max(operation.depth);
+        complexity.fields = complexity.fields.max(operation.fields);
+    }
+    complexity
+}
+
+pub fn check_query_complexity(
+    limits: QueryLimitsConfig,
+    document: &q::Document<String>,
+) -> Result<(), SubgraphServiceError> {
+    let Complexity { depth, fields } = complexity(document);
+    if let Some(max_depth) = limits.max_depth.filter(|max_depth| depth > *max_depth) {
+        return Err(SubgraphServiceError::QueryTooComplex(format!(
+            "query depth {depth} exceeds the limit of {max_depth}"
+        )));
+    }
+    if let Some(max_fields) = limits.max_fields.filter(|max_fields| fields > *max_fields) {
+        return Err(SubgraphServiceError::QueryTooComplex(format!(
+            "query selects {fields} fields, more than the limit of {max_fields}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use graphql::graphql_parser::query as q;
+    use indexer_config::QueryLimitsConfig;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+
+    use super::{check_query_complexity, complexity, Complexity, QueryLimits};
+
+    fn parse(query: &str) -> q::Document<'_, String> {
+        q::parse_query(query).unwrap()
+    }
+
+    #[test]
+    fn test_complexity() {
+        let document = parse("{ a { b { c } d } e }");
+        assert_eq!(
+            complexity(&document),
+            Complexity {
+                depth: 3,
+                fields: 5
+            }
+        );
+
+        // fragments are counted every time they are spread
+        let document =
+            parse("query { a { ...F } b { ... on B { ...F } } } fragment F on T { x { y } z }");
+        assert_eq!(
+            complexity(&document),
+            Complexity {
+                depth: 3,
+                fields: 8
+            }
+        );
+
+        // cyclic fragments don't loop forever
+        let document = parse("{ a { ...F } } fragment F on T { b { ...F } }");
+        assert_eq!(
+            complexity(&document),
+            Complexity {
+                depth: 2,
+                fields: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let document = parse("{ a { b { c } } }");
+        let limits = |max_depth, max_fields| QueryLimitsConfig {
+            max_depth,
+            max_fields,
+        };
+
+        assert!(check_query_complexity(limits(None, None), &document).is_ok());
+        assert!(check_query_complexity(limits(Some(3), Some(3)), &document).is_ok());
+        assert!(check_query_complexity(limits(Some(2), None), &document).is_err());
+        assert!(check_query_complexity(limits(None, Some(2)), &document).is_err());
+    }
+
+    #[test]
+    fn test_per_deployment_limits() {
+        let overridden = *NETWORK_SUBGRAPH_DEPLOYMENT;
+        let other = *ESCROW_SUBGRAPH_DEPLOYMENT;
+
+        let limits = QueryLimits::new(
+            QueryLimitsConfig {
+                max_depth: Some(10),
+                max_fields: Some(100),
+            },
+            HashMap::from([(
+                overridden,
+                QueryLimitsConfig {
+                    max_depth: Some(3),
+                    max_fields: None,
+                },
+            )]),
+        );
+
+        let QueryLimitsConfig {
+            max_depth,
+            max_fields,
+        } = limits.for_deployment(&overridden);
+        assert_eq!((max_depth, max_fields), (Some(3), Some(100)));
+
+        let QueryLimitsConfig {
+            max_depth,
+            max_fields,
+        } = limits.for_deployment(&other);
+        assert_eq!((max_depth, max_fields), (Some(10), Some(100)));
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+
+use crate::middleware::EscrowFreshnessState;
+
+/// Whether the service is ready to be routed queries, with how stale the
+/// escrow accounts are. Answers 503 while it isn't
+pub async fn ready(State(escrow): State<Option<EscrowFreshnessState>>) -> impl IntoResponse {
+    let (ready, staleness) = match &escrow {
+        Some(escrow) => (escrow.ready(), Some(escrow.staleness().as_secs_f64())),
+        // the escrow accounts were provided as they are
+        None => (true, None),
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let serves_paid = escrow
+        .as_ref()
+        .map_or(true, EscrowFreshnessState::serves_paid);
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "paid_queries": serves_paid,
+            "escrow_staleness_secs": staleness,
+        })),
+    )
+}
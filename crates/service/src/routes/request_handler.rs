@@ -1,53 +1,310 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{error::SubgraphServiceError, middleware::AttestationInput, service::GraphNodeState};
+use crate::{
+    error::SubgraphServiceError,
+    middleware::{AttestationInput, Deadline, RequestId, GRAPH_ATTESTABLE},
+    routes::query_complexity::check_query_complexity,
+    service::GraphNodeState,
+};
+use std::{collections::HashSet, time::Duration};
+
+use alloy::primitives::keccak256;
 use axum::{
-    extract::{Path, State},
+    extract::State,
     http::{HeaderValue, Response},
     response::IntoResponse,
+    Extension,
 };
-use reqwest::header::CONTENT_TYPE;
+use graphql::graphql_parser::query as q;
+use indexer_config::QueryLimitsConfig;
+use reqwest::header::{AGE, CONTENT_TYPE};
+use serde::Deserialize;
 use thegraph_core::DeploymentId;
-use tracing::trace;
+use tracing::{trace, warn};
 
-const GRAPH_ATTESTABLE: &str = "graph-attestable";
 const GRAPH_INDEXED: &str = "graph-indexed";
+/// Header set on responses to the number of the block the query ran against,
+/// when graph-node reports it in `graph-indexed`. Responses also carry the
+/// standard `Age` header, the seconds since a cache in front of graph-node
+/// stored the response, `0` for responses graph-node just computed.
+pub const GRAPH_BLOCK: &str = "graph-block";
 
-pub async fn request_handler(
-    Path(deployment): Path<DeploymentId>,
-    State(state): State<GraphNodeState>,
-    req: String,
-) -> Result<impl IntoResponse, SubgraphServiceError> {
-    trace!("Handling request for deployment `{deployment}`");
+/// What graph-node tells about a response besides its body
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ResponseMetadata {
+    pub attestable: bool,
+    pub graph_indexed: Option<HeaderValue>,
+    /// block the query ran against
+    pub block: Option<u64>,
+    /// time since the response was cached, `None` if it wasn't served from a cache
+    pub age: Option<Duration>,
+}
+
+impl ResponseMetadata {
+    fn new(response: &reqwest::Response, attest_error_responses: bool) -> Self {
+        #[derive(Deserialize)]
+        struct BlockPointer {
+            number: u64,
+        }
+
+        let attestable = response
+            .headers()
+            .get(GRAPH_ATTESTABLE)
+            .map_or(false, |value| {
+                value.to_str().map(|value| value == "true").unwrap_or(false)
+            });
+        // error responses are attested too, unless the operator opted out
+        let attestable = attestable && (response.status().is_success() || attest_error_responses);
+
+        let graph_indexed = response.headers().get(GRAPH_INDEXED).cloned();
+        let block = graph_indexed
+            .as_ref()
+            .and_then(|value| serde_json::from_slice::<BlockPointer>(value.as_bytes()).ok())
+            .map(|block| block.number);
+        let age = response
+            .headers()
+            .get(AGE)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        Self {
+            attestable,
+            graph_indexed,
+            block,
+            age,
+        }
+    }
+}
+
+/// Rewrites the graph-node responses before they are attested and sent
+///
+/// Lets operators redact fields or rewrite URLs in responses. The attestation
+/// is computed over the transformed body, which is what the gateway receives.
+pub trait ResponseTransformer: Send + Sync {
+    fn transform(&self, deployment: &DeploymentId, body: String) -> String;
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OperationRequest {
+    query: String,
+    operation_name: Option<String>,
+}
+
+fn operation_name<'a>(operation: &'a q::OperationDefinition<'_, String>) -> Option<&'a String> {
+    match operation {
+        q::OperationDefinition::Query(query) => query.name.as_ref(),
+        q::OperationDefinition::Mutation(mutation) => mutation.name.as_ref(),
+        q::OperationDefinition::Subscription(subscription) => subscription.name.as_ref(),
+        q::OperationDefinition::SelectionSet(_) => None,
+    }
+}
+
+/// Accepts the request if the operation it runs is in the allow-list, either
+/// by name or by the keccak256 hash of the normalized query.
+///
+/// The name is the one of the operation in the document that graph-node runs,
+/// the one `operationName` selects or the only one, never a name only the
+/// client states. The query is normalized by printing it back from its parsed
+/// form, so whitespace and formatting differences don't change the hash.
+fn check_operation_allowed(
+    allowed_operations: &HashSet<String>,
+    requested_operation: Option<String>,
+    document: &q::Document<String>,
+) -> Result<(), SubgraphServiceError> {
+    let mut operations = document.definitions.iter().filter_map(|def| match def {
+        q::Definition::Operation(op) => Some(op),
+        q::Definition::Fragment(_) => None,
+    });
+    let operation = match requested_operation {
+        Some(requested) => {
+            operations.find(|op| operation_name(op).is_some_and(|name| *name == requested))
+        }
+        None => match (operations.next(), operations.next()) {
+            (Some(op), None) => Some(op),
+            _ => None,
+        },
+    };
+    let operation_name = operation.and_then(operation_name).cloned();
+    if operation_name
+        .as_ref()
+        .is_some_and(|name| allowed_operations.contains(name))
+    {
+        return Ok(());
+    }
+
+    let query_hash = keccak256(document.to_string()).to_string();
+    if allowed_operations.contains(&query_hash) {
+        return Ok(());
+    }
+
+    Err(SubgraphServiceError::OperationNotAllowed(
+        operation_name.unwrap_or(query_hash),
+    ))
+}
 
+/// Runs the checks that need the parsed query, only parsing it if the
+/// deployment has an allow-list or query limits.
+pub(crate) fn check_query(
+    allowed_operations: Option<&HashSet<String>>,
+    query_limits: QueryLimitsConfig,
+    req: &str,
+) -> Result<(), SubgraphServiceError> {
+    if allowed_operations.is_none()
+        && query_limits.max_depth.is_none()
+        && query_limits.max_fields.is_none()
+    {
+        return Ok(());
+    }
+
+    let request: OperationRequest =
+        serde_json::from_str(req).map_err(|e| SubgraphServiceError::InvalidQuery(e.to_string()))?;
+    let document: q::Document<String> = q::parse_query(&request.query)
+        .map_err(|e| SubgraphServiceError::InvalidQuery(e.to_string()))?;
+
+    if let Some(allowed_operations) = allowed_operations {
+        check_operation_allowed(allowed_operations, request.operation_name, &document)?;
+    }
+    check_query_complexity(query_limits, &document)
+}
+
+/// Reads the body of a graph-node response, giving up as soon as it grows
+/// past `max_bytes` so an oversized response is never buffered whole.
+async fn read_limited_body(
+    mut response: reqwest::Response,
+    max_bytes: usize,
+) -> Result<String, SubgraphServiceError> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+    {
+        return Err(SubgraphServiceError::ResponseTooLarge(max_bytes));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(SubgraphServiceError::QueryForwardingError)?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(SubgraphServiceError::ResponseTooLarge(max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Queries that ran out of time while graph-node handled them
+fn forwarding_error(error: reqwest::Error, deadline: Option<Deadline>) -> SubgraphServiceError {
+    if error.is_timeout() && deadline.is_some() {
+        SubgraphServiceError::DeadlineExceeded
+    } else {
+        SubgraphServiceError::QueryForwardingError(error)
+    }
+}
+
+/// What the middlewares found out about the query that matters to forwarding it
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub deadline: Option<Deadline>,
+    pub request_id: Option<RequestId>,
+}
+
+/// Forwards the query to graph-node, returning its response body
+///
+/// With a [Deadline], graph-node is given the time left until it and the
+/// query fails once it passes. The [RequestId] is sent along when the state
+/// has a header to propagate it in.
+pub async fn process_request(
+    state: &GraphNodeState,
+    deployment: &DeploymentId,
+    req: &str,
+    ctx: &RequestContext,
+) -> Result<(String, ResponseMetadata), SubgraphServiceError> {
+    let deadline = ctx.deadline;
     let deployment_url = state
         .graph_node_query_base_url
         .join(&format!("subgraphs/id/{deployment}"))
-        .map_err(|_| SubgraphServiceError::InvalidDeployment(deployment))?;
+        .map_err(|_| {
+            SubgraphServiceError::InvalidDeployment(state.response_format.deployment_id(deployment))
+        })?;
 
-    let response = state
+    let mut request = state
         .graph_node_client
         .post(deployment_url)
-        .body(req.clone())
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(req.to_string())
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let (Some(header), Some(RequestId(request_id))) =
+        (state.request_id_header.as_ref(), ctx.request_id.as_ref())
+    {
+        request = request.header(header, request_id);
+    }
+    if let Some(deadline) = deadline {
+        let remaining = deadline
+            .remaining()
+            .ok_or(SubgraphServiceError::DeadlineExceeded)?;
+        request = request.timeout(remaining);
+    }
+    let response = request
         .send()
         .await
-        .map_err(SubgraphServiceError::QueryForwardingError)?;
+        .map_err(|e| forwarding_error(e, deadline))?;
 
-    let attestable = response
-        .headers()
-        .get(GRAPH_ATTESTABLE)
-        .map_or(false, |value| {
-            value.to_str().map(|value| value == "true").unwrap_or(false)
-        });
+    let metadata = ResponseMetadata::new(&response, state.attest_error_responses);
+    let body = match state.max_response_body_bytes {
+        Some(max_bytes) => read_limited_body(response, max_bytes)
+            .await
+            .map_err(|e| match e {
+                SubgraphServiceError::QueryForwardingError(e) => forwarding_error(e, deadline),
+                e => e,
+            })
+            .inspect_err(|e| {
+                if let SubgraphServiceError::ResponseTooLarge(_) = e {
+                    warn!(%deployment, query = %req, "Refusing to forward oversized response");
+                }
+            })?,
+        None => response
+            .text()
+            .await
+            .map_err(|e| forwarding_error(e, deadline))?,
+    };
+    Ok((body, metadata))
+}
 
-    let graph_indexed = response.headers().get(GRAPH_INDEXED).cloned();
-    let body = response
-        .text()
-        .await
-        .map_err(SubgraphServiceError::QueryForwardingError)?;
-    let attestation_input = if attestable {
+pub async fn request_handler(
+    Extension(deployment): Extension<DeploymentId>,
+    deadline: Option<Extension<Deadline>>,
+    request_id: Option<Extension<RequestId>>,
+    State(state): State<GraphNodeState>,
+    req: String,
+) -> Result<impl IntoResponse, SubgraphServiceError> {
+    trace!("Handling request for deployment `{deployment}`");
+
+    // the queue and the receipt checks may have used up all the time
+    let deadline = deadline.map(|Extension(deadline)| deadline);
+    if deadline.is_some_and(|deadline| deadline.remaining().is_none()) {
+        return Err(SubgraphServiceError::DeadlineExceeded);
+    }
+
+    let ctx = RequestContext {
+        deadline,
+        request_id: request_id.map(|Extension(request_id)| request_id),
+    };
+    let (body, metadata) = match state.singleflight.as_ref() {
+        Some(singleflight) => {
+            singleflight
+                .run(&deployment, &req, deadline, || {
+                    process_request(&state, &deployment, &req, &ctx)
+                })
+                .await?
+        }
+        None => process_request(&state, &deployment, &req, &ctx).await?,
+    };
+    let body = match state.response_transformer.as_ref() {
+        Some(transformer) => transformer.transform(&deployment, body),
+        None => body,
+    };
+    let attestation_input = if metadata.attestable {
         AttestationInput::Attestable { req }
     } else {
         AttestationInput::NotAttestable
@@ -55,10 +312,517 @@ pub async fn request_handler(
 
     let mut response = Response::new(body);
     response.extensions_mut().insert(attestation_input);
+    response
+        .extensions_mut()
+        .insert(state.attestation_scope.clone());
+    let headers = response.headers_mut();
+    headers.insert(
+        GRAPH_ATTESTABLE,
+        HeaderValue::from_static(if metadata.attestable { "true" } else { "false" }),
+    );
 
-    if let Some(graph_indexed) = graph_indexed {
-        response.headers_mut().append(GRAPH_INDEXED, graph_indexed);
+    if let Some(graph_indexed) = metadata.graph_indexed {
+        headers.append(GRAPH_INDEXED, graph_indexed);
     }
+    if let Some(block) = metadata.block {
+        headers.insert(GRAPH_BLOCK, HeaderValue::from(block));
+    }
+    let age = metadata.age.unwrap_or_default().as_secs();
+    headers.insert(AGE, HeaderValue::from(age));
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use alloy::{
+        primitives::{keccak256, Address},
+        signers::Signature,
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use indexer_attestation::{AttestationPayload, AttestationSigner};
+    use indexer_config::QueryLimitsConfig;
+    use reqwest::{
+        header::{HeaderName, HeaderValue, AGE},
+        StatusCode, Url,
+    };
+    use test_assets::{INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::{
+        attestation::{eip712_domain, Attestation},
+        DeploymentId,
+    };
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+    use wiremock::{
+        matchers::{self, method, path},
+        Mock, MockBuilder, MockServer, ResponseTemplate,
+    };
+
+    use graphql::graphql_parser::query as q;
+
+    use super::{check_query, request_handler, ResponseTransformer, GRAPH_BLOCK, GRAPH_INDEXED};
+    use crate::{
+        middleware::{
+            attestation_middleware, deployment_middleware, query_check_middleware,
+            request_id_middleware, Allocation, AttestationBackend, AttestationBackendError,
+            AttestationBackendState, AttestationInput, Deadline, DeploymentState, QueryCheckState,
+            RequestIdState, GRAPH_ATTESTABLE,
+        },
+        routes::{QueryLimits, Singleflight},
+        service::GraphNodeState,
+    };
+
+    /// Deployment of the first test allocation
+    fn deployment() -> DeploymentId {
+        INDEXER_ALLOCATIONS
+            .values()
+            .next()
+            .unwrap()
+            .subgraph_deployment
+            .id
+    }
+
+    /// Mock of the graph-node queries of the deployment
+    fn graph_node_query(deployment: DeploymentId) -> MockBuilder {
+        Mock::given(method("POST")).and(path(format!("/subgraphs/id/{deployment}")))
+    }
+
+    /// Starts a graph-node answering with the mock, and the state forwarding
+    /// queries to it with every option off
+    async fn graph_node(mock: Mock) -> (MockServer, GraphNodeState) {
+        let mock_server = MockServer::start().await;
+        mock_server.register(mock).await;
+        let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+        let state = GraphNodeState {
+            graph_node_client: reqwest::Client::new(),
+            graph_node_status_url: graph_node_url.clone(),
+            graph_node_query_base_url: graph_node_url,
+            attest_error_responses: true,
+            attestation_scope: Default::default(),
+            max_response_body_bytes: None,
+            response_transformer: None,
+            request_id_header: None,
+            response_format: Default::default(),
+            singleflight: None,
+        };
+        (mock_server, state)
+    }
+
+    /// Serves the request handler the way the router does
+    fn app(state: GraphNodeState) -> Router {
+        checked_app(state, QueryCheckState::default())
+    }
+
+    /// Serves the request handler, checking the queries first
+    fn checked_app(state: GraphNodeState, query_check: QueryCheckState) -> Router {
+        Router::new()
+            .route("/subgraphs/id/:id", post(request_handler))
+            .layer(from_fn_with_state(query_check, query_check_middleware))
+            .layer(from_fn_with_state(
+                DeploymentState::default(),
+                deployment_middleware,
+            ))
+            .with_state(state)
+    }
+
+    fn query_request(deployment: DeploymentId, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/subgraphs/id/{deployment}"))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn forward_graph_node_error(attest_error_responses: bool) -> Option<AttestationInput> {
+        let deployment = deployment();
+        let (_mock_server, state) = graph_node(
+            graph_node_query(deployment).respond_with(
+                ResponseTemplate::new(500)
+                    .insert_header(GRAPH_ATTESTABLE, "true")
+                    .set_body_string(r#"{"errors":[{"message":"no data"}]}"#),
+            ),
+        )
+        .await;
+        let res = app(GraphNodeState {
+            attest_error_responses,
+            ..state
+        })
+        .oneshot(query_request(deployment, "query"))
+        .await
+        .unwrap();
+        res.extensions().get::<AttestationInput>().cloned()
+    }
+
+    #[tokio::test]
+    async fn test_error_response_attested_by_default() {
+        let attestation_input = forward_graph_node_error(true).await;
+        assert!(matches!(
+            attestation_input,
+            Some(AttestationInput::Attestable { req }) if req == "query"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_error_response_not_attested_when_disabled() {
+        let attestation_input = forward_graph_node_error(false).await;
+        assert!(matches!(
+            attestation_input,
+            Some(AttestationInput::NotAttestable)
+        ));
+    }
+
+    #[test]
+    fn test_operation_allowed_by_hash() {
+        let query = r#"{"query": "query Pairs { pairs(first: 10) { id } }"}"#;
+        let document: q::Document<String> =
+            q::parse_query("query Pairs { pairs(first: 10) { id } }").unwrap();
+        let hash = keccak256(document.to_string()).to_string();
+        let allowed = HashSet::from([hash]);
+
+        assert!(check_query(Some(&allowed), Default::default(), query).is_ok());
+        // formatting doesn't change the hash
+        let reformatted = r#"{"query": "query Pairs {\n pairs(first: 10)   { id }\n}"}"#;
+        assert!(check_query(Some(&allowed), Default::default(), reformatted).is_ok());
+
+        let other = r#"{"query": "query Pairs { pairs(first: 20) { id } }"}"#;
+        assert!(check_query(Some(&allowed), Default::default(), other).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_operation_allow_list() {
+        let deployment = deployment();
+        let (mock_server, state) = graph_node(
+            graph_node_query(deployment)
+                .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"data":{}}"#)),
+        )
+        .await;
+        let app = checked_app(
+            state,
+            QueryCheckState {
+                allowed_operations: Arc::new(HashMap::from([(
+                    deployment,
+                    HashSet::from(["Allowed".to_string()]),
+                )])),
+                ..Default::default()
+            },
+        );
+        let send = |body: &'static str| app.clone().oneshot(query_request(deployment, body));
+
+        let res = send(r#"{"query": "query Allowed { a }"}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(r#"{"query": "query Other { a }"}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // the explicit operation name picks which operation runs
+        let res =
+            send(r#"{"query": "query Allowed { a } query Other { a }", "operationName": "Other"}"#)
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // the name has to be the one of an operation of the query
+        let res = send(r#"{"query": "query Other { a }", "operationName": "Allowed"}"#)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // refused queries never reach graph-node
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+
+        // anonymous operations can only be allowed by hash
+        let res = send(r#"{"query": "{ a }"}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_query_too_deep() {
+        let deployment = deployment();
+        let (_mock_server, state) = graph_node(
+            graph_node_query(deployment)
+                .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"data":{}}"#)),
+        )
+        .await;
+        let app = checked_app(
+            state,
+            QueryCheckState {
+                query_limits: Arc::new(QueryLimits::new(
+                    QueryLimitsConfig {
+                        max_depth: Some(2),
+                        max_fields: None,
+                    },
+                    HashMap::new(),
+                )),
+                ..Default::default()
+            },
+        );
+        let send = |body: &'static str| app.clone().oneshot(query_request(deployment, body));
+
+        let res = send(r#"{"query": "{ a { b } }"}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(r#"{"query": "{ a { b { c } } }"}"#).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("query depth 3 exceeds the limit of 2"));
+    }
+
+    #[tokio::test]
+    async fn test_identical_queries_coalesced() {
+        let deployment = deployment();
+        let (mock_server, state) = graph_node(
+            graph_node_query(deployment)
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header(GRAPH_ATTESTABLE, "true")
+                        .set_body_string(r#"{"data":{"a":1}}"#)
+                        .set_delay(Duration::from_millis(200)),
+                )
+                .expect(1),
+        )
+        .await;
+        let app = app(GraphNodeState {
+            singleflight: Some(Singleflight::new()),
+            ..state
+        });
+
+        // formatted differently, still the same query
+        let queries = [r#"{"query": "{ a }"}"#, r#"{"query": "{\n  a\n}"}"#];
+        let requests: Vec<_> = (0..10)
+            .map(|i| {
+                tokio::spawn(
+                    app.clone()
+                        .oneshot(query_request(deployment, queries[i % 2])),
+                )
+            })
+            .collect();
+        for (i, request) in requests.into_iter().enumerate() {
+            let res = request.await.unwrap().unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            // each request is attested on its own
+            assert!(matches!(
+                res.extensions().get::<AttestationInput>(),
+                Some(AttestationInput::Attestable { req }) if req == queries[i % 2]
+            ));
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, r#"{"data":{"a":1}}"#);
+        }
+        mock_server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_response_body_limit() {
+        let deployment = deployment();
+        let (_mock_server, state) = graph_node(
+            graph_node_query(deployment).respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header(GRAPH_ATTESTABLE, "true")
+                    .set_body_string(format!(r#"{{"data":"{}"}}"#, "a".repeat(1024))),
+            ),
+        )
+        .await;
+        let app = |max_response_body_bytes| {
+            app(GraphNodeState {
+                max_response_body_bytes,
+                ..state.clone()
+            })
+        };
+
+        let res = app(Some(2048))
+            .oneshot(query_request(deployment, "query"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the oversized body is neither forwarded nor marked as attestable
+        let res = app(Some(1024))
+            .oneshot(query_request(deployment, "query"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert!(res.extensions().get::<AttestationInput>().is_none());
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Response too large"));
+    }
+
+    #[tokio::test]
+    async fn test_block_and_age_headers() {
+        let deployment = deployment();
+        let respond = |cached: bool| async move {
+            let mut template = ResponseTemplate::new(200)
+                .insert_header(GRAPH_INDEXED, r#"{"hash":"0x01","number":42}"#)
+                .set_body_string(r#"{"data":{}}"#);
+            if cached {
+                template = template.insert_header(AGE, "30");
+            }
+            let (_mock_server, state) =
+                graph_node(graph_node_query(deployment).respond_with(template)).await;
+            app(state)
+                .oneshot(query_request(deployment, "query"))
+                .await
+                .unwrap()
+        };
+
+        // fresh responses are zero seconds old
+        let res = respond(false).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[GRAPH_BLOCK], "42");
+        assert_eq!(res.headers()[AGE], "0");
+
+        // cached ones keep the age of the cache in front of graph-node
+        let res = respond(true).await;
+        assert_eq!(res.headers()[GRAPH_BLOCK], "42");
+        assert_eq!(res.headers()[AGE], "30");
+    }
+
+    /// Signs with the key of the test allocation
+    struct SignerBackend(AttestationSigner);
+
+    #[async_trait::async_trait]
+    impl AttestationBackend for SignerBackend {
+        async fn sign(
+            &self,
+            payload: &AttestationPayload,
+            _: &Address,
+        ) -> Result<Signature, AttestationBackendError> {
+            Ok(self.0.sign(payload))
+        }
+    }
+
+    struct Redact;
+
+    impl ResponseTransformer for Redact {
+        fn transform(&self, _: &DeploymentId, body: String) -> String {
+            body.replace("secret", "redacted")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attestation_covers_transformed_response() {
+        const ORIGINAL: &str = r#"{"data":{"user":"secret"}}"#;
+        const TRANSFORMED: &str = r#"{"data":{"user":"redacted"}}"#;
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let deployment = allocation.subgraph_deployment.id;
+        let signer =
+            AttestationSigner::new(&INDEXER_MNEMONIC.to_string(), &allocation, 1, Address::ZERO)
+                .unwrap();
+
+        let (_mock_server, state) = graph_node(
+            graph_node_query(deployment).respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header(GRAPH_ATTESTABLE, "true")
+                    .set_body_string(ORIGINAL),
+            ),
+        )
+        .await;
+        let attestation_state = AttestationBackendState {
+            backend: Arc::new(SignerBackend(signer.clone())),
+            domain: watch::channel(eip712_domain(1, Address::ZERO)).1,
+            unattested_free_queries: false,
+            failure_policy: Default::default(),
+        };
+        let app = app(GraphNodeState {
+            response_transformer: Some(Arc::new(Redact)),
+            ..state
+        })
+        .layer(from_fn_with_state(
+            attestation_state,
+            attestation_middleware,
+        ));
+
+        let mut request = query_request(deployment, "query");
+        request.extensions_mut().insert(Allocation(allocation.id));
+        let res = app.oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["graphQLResponse"], TRANSFORMED);
+        let attestation: Attestation = serde_json::from_value(body["attestation"].clone()).unwrap();
+        assert!(signer
+            .verify(&attestation, "query", TRANSFORMED, &allocation.id)
+            .is_ok());
+        assert!(signer
+            .verify(&attestation, "query", ORIGINAL, &allocation.id)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deadline_exceeded() {
+        let deployment = deployment();
+        let (mock_server, state) = graph_node(
+            graph_node_query(deployment).respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"data":{}}"#)
+                    .set_delay(Duration::from_millis(500)),
+            ),
+        )
+        .await;
+        let app = app(state);
+        let send = |deadline: Duration| {
+            let mut request = query_request(deployment, "query");
+            request
+                .extensions_mut()
+                .insert(Deadline(Instant::now() + deadline));
+            app.clone().oneshot(request)
+        };
+
+        // out of time before graph-node is queried
+        let res = send(Duration::ZERO).await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+        // out of time while graph-node handles the query
+        let res = send(Duration::from_millis(100)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let res = send(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_request_id_is_echoed_and_propagated() {
+        const REQUEST_ID: &str = "gateway-request-1";
+        let header = HeaderName::from_static("x-request-id");
+        let deployment = deployment();
+
+        // graph-node only answers queries carrying the request id
+        let (_mock_server, state) = graph_node(
+            graph_node_query(deployment)
+                .and(matchers::header("x-request-id", REQUEST_ID))
+                .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"data":{}}"#)),
+        )
+        .await;
+        let app = app(GraphNodeState {
+            request_id_header: Some(header.clone()),
+            ..state
+        })
+        .layer(from_fn_with_state(
+            RequestIdState {
+                header: header.clone(),
+                honor_inbound: true,
+            },
+            request_id_middleware,
+        ));
+
+        let mut request = query_request(deployment, "query");
+        request
+            .headers_mut()
+            .insert(&header, HeaderValue::from_static(REQUEST_ID));
+        let res = app.oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[&header], REQUEST_ID);
+    }
+}
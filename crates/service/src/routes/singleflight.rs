@@ -0,0 +1,272 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coalesces identical queries that are in flight at the same time
+//!
+//! Queries are identical when they go to the same deployment with the same
+//! normalized query, operation name and variables. The block a query runs
+//! against is part of its arguments or variables, so queries for different
+//! blocks are never coalesced, while queries for the latest block arriving
+//! together share the block graph-node answers the first one with.
+//!
+//! Only graph-node is asked once: every request still has its receipt checked
+//! and is attested on its own.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use graphql::graphql_parser::query as q;
+use serde::Deserialize;
+use serde_json::Value;
+use thegraph_core::DeploymentId;
+use tokio::sync::watch;
+
+use super::request_handler::ResponseMetadata;
+use crate::{error::SubgraphServiceError, middleware::Deadline};
+
+/// The response of graph-node, `None` when the query failed
+type SharedResponse = Option<(String, ResponseMetadata)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct QueryKey {
+    deployment: DeploymentId,
+    query: String,
+}
+
+impl QueryKey {
+    fn new(deployment: &DeploymentId, req: &str) -> Self {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Request {
+            query: String,
+            operation_name: Option<String>,
+            variables: Option<Value>,
+        }
+
+        // requests that don't parse are only coalesced with the exact same body
+        let query = serde_json::from_str::<Request>(req)
+            .ok()
+            .and_then(|request| {
+                let document = q::parse_query::<String>(&request.query).ok()?;
+                Some(
+                    serde_json::json!([
+                        document.to_string(),
+                        request.operation_name,
+                        request.variables,
+                    ])
+                    .to_string(),
+                )
+            })
+            .unwrap_or_else(|| req.to_string());
+        Self {
+            deployment: *deployment,
+            query,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Singleflight {
+    in_flight: Mutex<HashMap<QueryKey, watch::Receiver<Option<SharedResponse>>>>,
+}
+
+/// Stops coalescing into the query once it's done, or when its request is
+/// dropped, in which case the waiters run the query themselves
+struct InFlight<'a> {
+    singleflight: &'a Singleflight,
+    key: QueryKey,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.singleflight
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+impl Singleflight {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Runs `process` for the query unless an identical one is in flight, in
+    /// which case its response is shared
+    ///
+    /// Errors aren't shared: when the query in flight fails, the waiters run
+    /// `process` themselves, so each gets its own error. Waiters stop waiting
+    /// at their own `deadline`.
+    pub async fn run<F, Fut>(
+        &self,
+        deployment: &DeploymentId,
+        req: &str,
+        deadline: Option<Deadline>,
+        process: F,
+    ) -> Result<(String, ResponseMetadata), SubgraphServiceError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, ResponseMetadata), SubgraphServiceError>>,
+    {
+        let key = QueryKey::new(deployment, req);
+        let sender = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(receiver) => Err(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    in_flight.insert(key.clone(), receiver);
+                    Ok(sender)
+                }
+            }
+        };
+        let sender = match sender {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                let shared = receiver.wait_for(Option::is_some);
+                let shared = match deadline {
+                    Some(Deadline(deadline)) => tokio::time::timeout_at(deadline.into(), shared)
+                        .await
+                        .map_err(|_| SubgraphServiceError::DeadlineExceeded)?,
+                    None => shared.await,
+                };
+                let shared = shared.ok().and_then(|response| response.clone().flatten());
+                return match shared {
+                    Some(response) => Ok(response),
+                    None => process().await,
+                };
+            }
+        };
+
+        let in_flight = InFlight {
+            singleflight: self,
+            key,
+        };
+        let result = process().await;
+        drop(in_flight);
+        sender.send_replace(Some(result.as_ref().ok().cloned()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+
+    use super::{QueryKey, Singleflight};
+    use crate::{error::SubgraphServiceError, middleware::Deadline};
+
+    #[test]
+    fn test_query_key_normalizes_query() {
+        let deployment = &*NETWORK_SUBGRAPH_DEPLOYMENT;
+        let key = |req: &str| QueryKey::new(deployment, req);
+
+        assert_eq!(
+            key(r#"{"query": "{ pairs(block: {number: 10}) { id } }"}"#),
+            key(r#"{"query": "{\n  pairs(block: {number: 10})   { id }\n}"}"#),
+        );
+        assert_ne!(
+            key(r#"{"query": "{ pairs(block: {number: 10}) { id } }"}"#),
+            key(r#"{"query": "{ pairs(block: {number: 11}) { id } }"}"#),
+        );
+        assert_ne!(
+            key(r#"{"query": "query($b: Int) { a }", "variables": {"b": 1}}"#),
+            key(r#"{"query": "query($b: Int) { a }", "variables": {"b": 2}}"#),
+        );
+        assert_ne!(
+            key(r#"{"query": "{ a }"}"#),
+            QueryKey::new(&ESCROW_SUBGRAPH_DEPLOYMENT, r#"{"query": "{ a }"}"#),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_shared() {
+        let singleflight = Singleflight::new();
+        let processed = Arc::new(AtomicUsize::new(0));
+        let requests: Vec<_> = (0..3)
+            .map(|_| {
+                let singleflight = singleflight.clone();
+                let processed = processed.clone();
+                tokio::spawn(async move {
+                    singleflight
+                        .run(
+                            &NETWORK_SUBGRAPH_DEPLOYMENT,
+                            r#"{"query": "{ a }"}"#,
+                            None,
+                            || async {
+                                processed.fetch_add(1, Ordering::SeqCst);
+                                tokio::time::sleep(Duration::from_millis(50)).await;
+                                Err(SubgraphServiceError::DeadlineExceeded)
+                            },
+                        )
+                        .await
+                })
+            })
+            .collect();
+        for request in requests {
+            assert!(matches!(
+                request.await.unwrap(),
+                Err(SubgraphServiceError::DeadlineExceeded)
+            ));
+        }
+        assert_eq!(processed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_waiter_stops_at_its_deadline() {
+        let singleflight = Singleflight::new();
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let in_flight = tokio::spawn({
+            let singleflight = singleflight.clone();
+            async move {
+                singleflight
+                    .run(
+                        &NETWORK_SUBGRAPH_DEPLOYMENT,
+                        r#"{"query": "{ a }"}"#,
+                        None,
+                        || async {
+                            started_tx.send(()).unwrap();
+                            tokio::time::sleep(Duration::from_secs(60)).await;
+                            Err(SubgraphServiceError::DeadlineExceeded)
+                        },
+                    )
+                    .await
+            }
+        });
+        started_rx.await.unwrap();
+
+        let processed = AtomicUsize::new(0);
+        let start = Instant::now();
+        let result = singleflight
+            .run(
+                &NETWORK_SUBGRAPH_DEPLOYMENT,
+                r#"{"query": "{ a }"}"#,
+                Some(Deadline(start + Duration::from_millis(50))),
+                || async {
+                    processed.fetch_add(1, Ordering::SeqCst);
+                    Err(SubgraphServiceError::DeadlineExceeded)
+                },
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(SubgraphServiceError::DeadlineExceeded)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // it gave up waiting instead of running the query itself
+        assert_eq!(processed.load(Ordering::SeqCst), 0);
+        in_flight.abort();
+    }
+}
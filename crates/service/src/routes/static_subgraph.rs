@@ -1,19 +1,42 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{body::Bytes, extract::State, response::IntoResponse, Json};
-use reqwest::StatusCode;
+use axum::{body::Bytes, extract::State, response::IntoResponse, Extension, Json};
+use reqwest::{
+    header::{HeaderMap, HeaderName},
+    StatusCode,
+};
 use serde_json::json;
 use tracing::warn;
 
 use indexer_monitor::SubgraphClient;
 
+use crate::middleware::RequestId;
+
+/// State to be used by the network and escrow subgraph routes
+#[derive(Clone)]
+pub struct StaticSubgraphState {
+    pub subgraph_client: &'static SubgraphClient,
+    /// header the request id is sent to the subgraph in, unset when it isn't propagated
+    pub request_id_header: Option<HeaderName>,
+}
+
 #[autometrics::autometrics]
 pub async fn static_subgraph_request_handler(
-    State(subgraph_client): State<&'static SubgraphClient>,
+    State(state): State<StaticSubgraphState>,
+    request_id: Option<Extension<RequestId>>,
     body: Bytes,
 ) -> Result<impl IntoResponse, StaticSubgraphError> {
-    let response = subgraph_client.query_raw(body).await?;
+    let mut headers = HeaderMap::new();
+    if let (Some(header), Some(Extension(RequestId(request_id)))) =
+        (state.request_id_header, request_id)
+    {
+        headers.insert(header, request_id);
+    }
+    let response = state
+        .subgraph_client
+        .query_raw_with_headers(body, headers)
+        .await?;
 
     Ok((
         response.status(),
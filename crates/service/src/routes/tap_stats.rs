@@ -0,0 +1,95 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response as AxumResponse},
+    Json,
+};
+use reqwest::StatusCode;
+use serde_json::json;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use crate::{database::is_statement_timeout, tap::EscrowChanges};
+
+#[derive(Debug, Error)]
+pub enum TapStatsError {
+    #[error("Failed to fetch TAP stats: {0}")]
+    Database(sqlx::Error),
+    #[error("Fetching TAP stats took too long, please retry later")]
+    Timeout,
+}
+
+impl From<sqlx::Error> for TapStatsError {
+    fn from(error: sqlx::Error) -> Self {
+        if is_statement_timeout(&error) {
+            Self::Timeout
+        } else {
+            Self::Database(error)
+        }
+    }
+}
+
+impl IntoResponse for TapStatsError {
+    fn into_response(self) -> AxumResponse {
+        tracing::error!(%self, "Failed to serve TAP stats");
+        let status = match self {
+            TapStatsError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TapStatsError::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let body = json!({
+            "error": self.to_string(),
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+/// State to be used by the TAP stats route
+#[derive(Clone)]
+pub struct TapStatsState {
+    pub pgpool: PgPool,
+    pub escrow_changes: EscrowChanges,
+}
+
+/// Summary of the TAP state kept by the indexer.
+///
+/// RAV redemptions are tracked by tap-agent, which only marks a RAV as
+/// redeemed once its redeem transaction has enough confirmations. The latest
+/// escrow balance changes come first in `escrowChanges`.
+pub async fn tap_stats(
+    State(TapStatsState {
+        pgpool,
+        escrow_changes,
+    }): State<TapStatsState>,
+) -> Result<impl IntoResponse, TapStatsError> {
+    let redemptions = sqlx::query!(
+        r#"
+            SELECT
+                COUNT(*) FILTER (
+                    WHERE redemption_block_number IS NULL AND redeemed_at IS NULL
+                ) AS "pending!",
+                COUNT(*) FILTER (
+                    WHERE redemption_block_number IS NOT NULL AND redeemed_at IS NULL
+                ) AS "awaiting_confirmation!",
+                COUNT(*) FILTER (WHERE redeemed_at IS NOT NULL) AS "redeemed!",
+                SUM(value_aggregate) FILTER (WHERE redeemed_at IS NULL) AS unredeemed_value,
+                SUM(value_aggregate) FILTER (WHERE redeemed_at IS NOT NULL) AS redeemed_value
+            FROM scalar_tap_ravs
+            WHERE last
+        "#
+    )
+    .fetch_one(&pgpool)
+    .await?;
+
+    Ok(Json(json!({
+        "ravRedemptions": {
+            "pending": redemptions.pending,
+            "awaitingConfirmation": redemptions.awaiting_confirmation,
+            "redeemed": redemptions.redeemed,
+            "unredeemedValue": redemptions.unredeemed_value.unwrap_or_default().to_string(),
+            "redeemedValue": redemptions.redeemed_value.unwrap_or_default().to_string(),
+        },
+        "escrowChanges": escrow_changes.latest(),
+    })))
+}
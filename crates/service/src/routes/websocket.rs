@@ -0,0 +1,309 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serves queries over WebSocket, following the `graphql-transport-ws` protocol
+//!
+//! Every `subscribe` message is answered by running the query once through the
+//! regular query route, so the receipt it carries in `extensions.receipt` goes
+//! through the same TAP checks and each `next` message gets its own
+//! attestation. Live views re-subscribe with a new receipt to get fresh data.
+//!
+//! The `next` payload is the same `{graphQLResponse, attestation}` object that
+//! is returned over HTTP, the attestation being computed over the
+//! `graphQLResponse` string.
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        Path, State, WebSocketUpgrade,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Method, Request,
+    },
+    response::Response,
+};
+use axum_extra::headers::Header;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::InProcessQueries;
+use crate::service::TapReceipt;
+
+const PROTOCOL: &str = "graphql-transport-ws";
+
+// close codes defined by the protocol
+const INVALID_MESSAGE: u16 = 4400;
+const UNAUTHORIZED: u16 = 4401;
+const TOO_MANY_INIT_REQUESTS: u16 = 4429;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit,
+    Ping,
+    Pong,
+    Subscribe {
+        id: String,
+        payload: SubscribePayload,
+    },
+    Complete,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubscribePayload {
+    query: String,
+    variables: Option<Value>,
+    operation_name: Option<String>,
+    #[serde(default)]
+    extensions: SubscribeExtensions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SubscribeExtensions {
+    /// signed receipt, in the same form as the `tap-receipt` header
+    receipt: Option<Value>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    Pong,
+    Next {
+        id: String,
+        payload: Value,
+    },
+    Error {
+        id: String,
+        payload: Vec<ErrorPayload>,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+struct ErrorPayload {
+    message: String,
+}
+
+/// Upgrades `/subgraphs/id/:id/ws` connections, queries being served by `query_routes`
+pub async fn websocket_handler(
+    Path(deployment): Path<String>,
+    State(query_routes): State<InProcessQueries>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let connection = Connection {
+        query_routes,
+        deployment,
+        // lets free query tokens apply to the whole connection
+        authorization: headers.get(AUTHORIZATION).cloned(),
+        acknowledged: false,
+    };
+    ws.protocols([PROTOCOL])
+        .on_upgrade(move |socket| serve_socket(socket, connection))
+}
+
+async fn serve_socket(mut socket: WebSocket, mut connection: Connection) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => return,
+            _ => continue,
+        };
+        match connection.handle(&text).await {
+            Ok(replies) => {
+                for reply in replies {
+                    let reply = serde_json::to_string(&reply).expect("Message to be serializable");
+                    if socket.send(Message::Text(reply)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err((code, reason)) => {
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code,
+                        reason: reason.into(),
+                    })))
+                    .await;
+                return;
+            }
+        }
+    }
+}
+
+struct Connection {
+    query_routes: InProcessQueries,
+    deployment: String,
+    authorization: Option<HeaderValue>,
+    acknowledged: bool,
+}
+
+impl Connection {
+    /// Replies to a client message, or the code and reason to close the connection with
+    async fn handle(&mut self, text: &str) -> Result<Vec<ServerMessage>, (u16, &'static str)> {
+        let message: ClientMessage =
+            serde_json::from_str(text).map_err(|_| (INVALID_MESSAGE, "Invalid message"))?;
+        match message {
+            ClientMessage::ConnectionInit if self.acknowledged => {
+                Err((TOO_MANY_INIT_REQUESTS, "Too many initialisation requests"))
+            }
+            ClientMessage::ConnectionInit => {
+                self.acknowledged = true;
+                Ok(vec![ServerMessage::ConnectionAck])
+            }
+            ClientMessage::Ping => Ok(vec![ServerMessage::Pong]),
+            // queries complete as soon as they are answered, there is nothing to stop
+            ClientMessage::Pong | ClientMessage::Complete => Ok(vec![]),
+            ClientMessage::Subscribe { .. } if !self.acknowledged => {
+                Err((UNAUTHORIZED, "Unauthorized"))
+            }
+            ClientMessage::Subscribe { id, payload } => Ok(self.subscribe(id, payload).await),
+        }
+    }
+
+    async fn subscribe(&self, id: String, payload: SubscribePayload) -> Vec<ServerMessage> {
+        let mut body = serde_json::json!({
+            "query": payload.query,
+            "variables": payload.variables,
+        });
+        if let Some(operation_name) = payload.operation_name {
+            body["operationName"] = operation_name.into();
+        }
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/subgraphs/id/{}", self.deployment))
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(receipt) = payload.extensions.receipt {
+            request = request.header(TapReceipt::name(), receipt.to_string());
+        }
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization.clone());
+        }
+        let request = match request.body(Body::from(body.to_string())) {
+            Ok(request) => request,
+            Err(e) => return error(id, e.to_string()),
+        };
+
+        match self.query_routes.run(request).await {
+            Ok(payload) => vec![
+                ServerMessage::Next {
+                    id: id.clone(),
+                    payload,
+                },
+                ServerMessage::Complete { id },
+            ],
+            Err(e) => error(id, e.to_string()),
+        }
+    }
+}
+
+fn error(id: String, message: String) -> Vec<ServerMessage> {
+    vec![ServerMessage::Error {
+        id,
+        payload: vec![ErrorPayload { message }],
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Json, Router,
+    };
+    use serde_json::json;
+
+    use super::{Connection, ErrorPayload, ServerMessage, UNAUTHORIZED};
+    use crate::routes::InProcessQueries;
+
+    fn connection() -> Connection {
+        // stands in for the query route, only accepting requests with a receipt
+        let query_routes = Router::new().route(
+            "/subgraphs/id/:id",
+            post(|headers: HeaderMap, body: String| async move {
+                if !headers.contains_key("tap-receipt") {
+                    return Err((
+                        StatusCode::PAYMENT_REQUIRED,
+                        Json(json!({"message": "No valid receipt or free query auth token provided"})),
+                    ));
+                }
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                Ok(Json(json!({
+                    "graphQLResponse": format!("{{\"data\":{{\"query\":{}}}}}", body["query"]),
+                    "attestation": {"requestCID": "0x00"},
+                })))
+            }),
+        );
+        Connection {
+            query_routes: InProcessQueries::new(query_routes, None),
+            deployment: "deployment".into(),
+            authorization: None,
+            acknowledged: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_requires_init() {
+        let mut connection = connection();
+        let subscribe = r#"{"type": "subscribe", "id": "1", "payload": {"query": "{ a }"}}"#;
+
+        assert_eq!(
+            connection.handle(subscribe).await.unwrap_err().0,
+            UNAUTHORIZED
+        );
+        assert_eq!(
+            connection
+                .handle(r#"{"type": "connection_init"}"#)
+                .await
+                .unwrap(),
+            vec![ServerMessage::ConnectionAck]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_runs_query_with_receipt() {
+        let mut connection = connection();
+        connection
+            .handle(r#"{"type": "connection_init", "payload": {}}"#)
+            .await
+            .unwrap();
+
+        let subscribe = r#"{
+            "type": "subscribe",
+            "id": "1",
+            "payload": {"query": "{ a }", "extensions": {"receipt": {"message": {}}}}
+        }"#;
+        assert_eq!(
+            connection.handle(subscribe).await.unwrap(),
+            vec![
+                ServerMessage::Next {
+                    id: "1".into(),
+                    payload: json!({
+                        "graphQLResponse": r#"{"data":{"query":"{ a }"}}"#,
+                        "attestation": {"requestCID": "0x00"},
+                    }),
+                },
+                ServerMessage::Complete { id: "1".into() },
+            ]
+        );
+
+        // without a receipt the query is refused, like over HTTP
+        let subscribe = r#"{"type": "subscribe", "id": "2", "payload": {"query": "{ a }"}}"#;
+        assert_eq!(
+            connection.handle(subscribe).await.unwrap(),
+            vec![ServerMessage::Error {
+                id: "2".into(),
+                payload: vec![ErrorPayload {
+                    message: "No valid receipt or free query auth token provided".into()
+                }],
+            }]
+        );
+    }
+}
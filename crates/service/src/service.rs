@@ -1,34 +1,68 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::time::Duration;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
-use axum::{extract::Request, serve, ServiceExt};
-use indexer_config::{Config, GraphNodeConfig, SubgraphConfig};
-use indexer_monitor::{DeploymentDetails, SubgraphClient};
+use indexer_config::{Config, EscrowSourceConfig, GraphNodeConfig, ListenRole, SubgraphConfig};
+use indexer_monitor::{
+    DeploymentDetails, EscrowSource, HttpEscrowSource, SubgraphClient, SubgraphQueryLimit,
+};
 use release::IndexerServiceRelease;
-use reqwest::Url;
+use reqwest::{header::HeaderName, Url};
 use tap_core::tap_eip712_domain;
-use tokio::{net::TcpListener, signal};
+use tokio::{
+    net::{TcpListener, TcpSocket},
+    signal,
+    task::JoinSet,
+};
 use tower_http::normalize_path::NormalizePath;
 
-use crate::{cli::Cli, database, metrics::serve_metrics};
+use crate::{
+    cli::Cli,
+    database,
+    metrics::{serve_metrics, INFLIGHT_REQUESTS, SHUTDOWN_PHASE},
+    middleware::AttestationScope,
+    response_format::ResponseFormat,
+    routes::{ResponseTransformer, Singleflight},
+};
 use clap::Parser;
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
+mod connection;
+mod effective_config;
 mod release;
 mod router;
 mod tap_receipt_header;
 
-pub use router::ServiceRouter;
-pub use tap_receipt_header::TapReceipt;
+pub use router::{ServiceRouter, ServiceRouters};
+pub use tap_receipt_header::{
+    validate_receipt, ReceiptFieldError, TapReceipt, TAP_RECEIPT_INVALID,
+};
 
 #[derive(Clone)]
 pub struct GraphNodeState {
     pub graph_node_client: reqwest::Client,
     pub graph_node_status_url: Url,
     pub graph_node_query_base_url: Url,
+    pub attest_error_responses: bool,
+    /// part of the response attested, for responses graph-node marks attestable
+    pub attestation_scope: AttestationScope,
+    /// responses from graph-node larger than this are refused instead of forwarded
+    pub max_response_body_bytes: Option<usize>,
+    /// applied to the responses before they are attested
+    pub response_transformer: Option<Arc<dyn ResponseTransformer>>,
+    /// header the request id is sent to graph-node in, unset when it isn't propagated
+    pub request_id_header: Option<HeaderName>,
+    pub response_format: ResponseFormat,
+    /// shares the response of identical queries in flight, unset when they
+    /// each go to graph-node
+    pub singleflight: Option<Arc<Singleflight>>,
 }
 
 const HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
@@ -39,16 +73,25 @@ pub async fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // Load the service configuration
-    let config = Config::parse(indexer_config::ConfigPrefix::Service, cli.config.as_ref())
-        .map_err(|e| {
-            error!(
-                "Invalid configuration file `{}`: {}, if a value is missing you can also use \
+    let config = Config::parse_with_profile(
+        indexer_config::ConfigPrefix::Service,
+        cli.config.as_ref(),
+        cli.profile.as_deref(),
+    )
+    .map_err(|e| {
+        error!(
+            "Invalid configuration file `{}`: {}, if a value is missing you can also use \
                 --config to fill the rest of the values",
-                cli.config.unwrap_or_default().display(),
-                e
-            );
-            anyhow!(e)
-        })?;
+            cli.config.clone().unwrap_or_default().display(),
+            e
+        );
+        anyhow!(e)
+    })?;
+
+    info!(
+        config = %effective_config::effective_config(&config),
+        "Effective configuration"
+    );
 
     // Parse basic configurations
     build_info::build_info!(fn build_info);
@@ -60,10 +103,16 @@ pub async fn run() -> anyhow::Result<()> {
         .build()
         .expect("Failed to init HTTP client");
 
+    let query_limit = config
+        .subgraphs
+        .max_concurrent_queries
+        .map(SubgraphQueryLimit::new);
+
     let network_subgraph = create_subgraph_client(
         http_client.clone(),
         &config.graph_node,
         &config.subgraphs.network.config,
+        query_limit.clone(),
     )
     .await;
 
@@ -71,6 +120,7 @@ pub async fn run() -> anyhow::Result<()> {
         http_client.clone(),
         &config.graph_node,
         &config.subgraphs.escrow.config,
+        query_limit,
     )
     .await;
 
@@ -81,8 +131,11 @@ pub async fn run() -> anyhow::Result<()> {
     // however, this can cause conflicts with the migrations run by indexer
     // agent. Hence we leave syncing and migrating entirely to the agent and
     // assume the models are up to date in the service.
-    let database =
-        database::connect(config.database.clone().get_formated_postgres_url().as_ref()).await;
+    let database = database::connect(
+        config.database.clone().get_formated_postgres_url().as_ref(),
+        config.service.database_statement_timeout_secs,
+    )
+    .await;
 
     let domain_separator = tap_eip712_domain(
         config.blockchain.chain_id as u64,
@@ -90,63 +143,219 @@ pub async fn run() -> anyhow::Result<()> {
     );
 
     let host_and_port = config.service.host_and_port;
+    let extra_listeners = config.service.listen.clone();
+    let listen_backlog = config.service.listen_backlog;
+    let reuse_address = config.service.reuse_address;
+    let reuse_port = config.service.reuse_port;
+    let shutdown_grace_period = config.service.shutdown_grace_period_secs;
+    let connection_idle_timeout = config.service.connection_idle_timeout_secs;
+    let wait_for_sync = config.service.wait_for_sync.clone();
+    let grpc = config.service.grpc.clone();
+    #[cfg(feature = "grpc")]
+    let url_prefix = config.service.url_prefix.clone();
+    #[cfg(feature = "grpc")]
+    let max_response_body_bytes = config.service.max_response_body_bytes;
+    let graph_node_status_url = config.graph_node.status_url.clone();
+    let subgraph_deployments = [
+        config.subgraphs.network.config.deployment_id,
+        config.subgraphs.escrow.config.deployment_id,
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    let escrow_source = match &config.subgraphs.escrow.accounts_source {
+        EscrowSourceConfig::Subgraph => None,
+        EscrowSourceConfig::Http { url } => {
+            info!(%url, "Reading the escrow accounts from an HTTP source");
+            Some(
+                Arc::new(HttpEscrowSource::new(http_client.clone(), url.clone()))
+                    as Arc<dyn EscrowSource>,
+            )
+        }
+    };
 
     let router = ServiceRouter::builder()
         .database(database)
         .domain_separator(domain_separator)
         .graph_node(config.graph_node)
-        .http_client(http_client)
+        .http_client(http_client.clone())
         .release(release)
         .indexer(config.indexer)
         .service(config.service)
         .dips(config.dips)
         .blockchain(config.blockchain)
         .timestamp_buffer_secs(config.tap.rav_request.timestamp_buffer_secs)
+        .receipt_time_source(config.tap.receipt_time_source)
+        .config_path(cli.config)
+        .config_profile(cli.profile)
         .network_subgraph(network_subgraph, config.subgraphs.network)
         .escrow_subgraph(escrow_subgraph, config.subgraphs.escrow)
+        .escrow_source(escrow_source)
         .build();
 
     serve_metrics(config.metrics.get_socket_addr());
 
-    info!(
-        address = %host_and_port,
-        "Serving requests",
+    // admin routes are only kept off the public addresses if they have their own
+    let ServiceRouters { public, admin } = router.create_routers().await?;
+    let has_admin_listener = extra_listeners
+        .iter()
+        .any(|listen| listen.role == ListenRole::Admin);
+    let public = if has_admin_listener {
+        public
+    } else {
+        public.merge(admin.clone())
+    };
+    let listeners = std::iter::once((host_and_port, ListenRole::Public)).chain(
+        extra_listeners
+            .into_iter()
+            .map(|listen| (listen.address, listen.role)),
     );
-    let listener = TcpListener::bind(&host_and_port)
-        .await
-        .expect("Failed to bind to indexer-service port");
-
-    let app = router.create_router().await?;
-    let router = NormalizePath::trim_trailing_slash(app);
-    //
-    let service = ServiceExt::<Request>::into_make_service(router);
-    Ok(serve(listener, service)
-        .with_graceful_shutdown(shutdown_handler())
-        .await?)
+
+    if let Some(wait_for_sync) = &wait_for_sync {
+        wait_for_sync::wait_for_sync(
+            &http_client,
+            &graph_node_status_url,
+            &subgraph_deployments,
+            wait_for_sync,
+        )
+        .await;
+    }
+
+    let drain = CancellationToken::new();
+    let mut servers = JoinSet::new();
+    for (address, role) in listeners {
+        info!(%address, ?role, "Serving requests");
+        let listener = bind_listener(address, listen_backlog, reuse_address, reuse_port)
+            .expect("Failed to bind to indexer-service port");
+        let router = match role {
+            ListenRole::Public => public.clone(),
+            ListenRole::Admin => admin.clone(),
+        };
+        let router = NormalizePath::trim_trailing_slash(router);
+        servers.spawn(connection::serve_connections(
+            listener,
+            router,
+            connection_idle_timeout,
+            drain.clone(),
+        ));
+    }
+    if let Some(grpc) = grpc {
+        #[cfg(feature = "grpc")]
+        {
+            info!(address = %grpc.host_and_port, "Serving queries over gRPC");
+            let listener = bind_listener(
+                grpc.host_and_port,
+                listen_backlog,
+                reuse_address,
+                reuse_port,
+            )
+            .expect("Failed to bind to the gRPC port");
+            let public = public.clone();
+            let drain = drain.clone();
+            servers.spawn(async move {
+                crate::grpc::serve_grpc(
+                    listener,
+                    public,
+                    &url_prefix,
+                    max_response_body_bytes,
+                    drain,
+                )
+                .await
+                .map_err(std::io::Error::other)
+            });
+        }
+        #[cfg(not(feature = "grpc"))]
+        warn!(
+            address = %grpc.host_and_port,
+            "gRPC is configured but indexer-service was built without the `grpc` feature"
+        );
+    }
+    let server = async move {
+        while let Some(result) = servers.join_next().await {
+            result??;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let drain_started = OnceLock::new();
+    tokio::select! {
+        result = server => {
+            result?;
+            if let Some(drain_started) = drain_started.get() {
+                SHUTDOWN_PHASE.set(2);
+                info!(elapsed = ?drain_started.elapsed(), "Drained all in-flight requests");
+            }
+        }
+        _ = async {
+            shutdown_handler().await;
+            SHUTDOWN_PHASE.set(1);
+            info!(
+                inflight_requests = INFLIGHT_REQUESTS.get(),
+                grace_period = ?shutdown_grace_period,
+                "Draining in-flight requests"
+            );
+            let _ = drain_started.set(Instant::now());
+            drain.cancel();
+            tokio::time::sleep(shutdown_grace_period).await;
+        } => {
+            warn!(
+                inflight_requests = INFLIGHT_REQUESTS.get(),
+                grace_period = ?shutdown_grace_period,
+                "Grace period elapsed, terminating with requests still in flight"
+            );
+        }
+    }
+    Ok(())
+}
+
+fn bind_listener(
+    address: SocketAddr,
+    backlog: u32,
+    reuse_address: bool,
+    reuse_port: bool,
+) -> std::io::Result<TcpListener> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(reuse_address)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(reuse_port)?;
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    if reuse_port {
+        warn!("SO_REUSEPORT is not supported on this platform, ignoring `reuse_port`");
+    }
+    socket.bind(address)?;
+    socket.listen(backlog)
 }
 
 async fn create_subgraph_client(
     http_client: reqwest::Client,
     graph_node: &GraphNodeConfig,
     subgraph_config: &SubgraphConfig,
+    query_limit: Option<SubgraphQueryLimit>,
 ) -> &'static SubgraphClient {
-    Box::leak(Box::new(
-        SubgraphClient::new(
-            http_client,
-            subgraph_config.deployment_id.map(|deployment| {
-                DeploymentDetails::for_graph_node_url(
-                    graph_node.status_url.clone(),
-                    graph_node.query_url.clone(),
-                    deployment,
-                )
-            }),
-            DeploymentDetails::for_query_url_with_token(
-                subgraph_config.query_url.clone(),
-                subgraph_config.query_auth_token.clone(),
-            ),
-        )
-        .await,
-    ))
+    let client = SubgraphClient::new(
+        http_client,
+        subgraph_config.deployment_id.map(|deployment| {
+            DeploymentDetails::for_graph_node_url(
+                graph_node.status_url.clone(),
+                graph_node.query_url.clone(),
+                deployment,
+            )
+        }),
+        DeploymentDetails::for_query_url_with_token(
+            subgraph_config.query_url.clone(),
+            subgraph_config.query_auth_token.clone(),
+        ),
+    )
+    .await;
+    Box::leak(Box::new(match query_limit {
+        Some(query_limit) => client.with_query_limit(query_limit),
+        None => client,
+    }))
 }
 
 /// Graceful shutdown handler
@@ -171,3 +380,27 @@ async fn shutdown_handler() {
 
     info!("Signal received, starting graceful shutdown");
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    use super::bind_listener;
+
+    #[tokio::test]
+    async fn test_rebind_after_close() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 128, true, false).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // closing the accepted side first leaves it in TIME_WAIT
+        let client = TcpStream::connect(address).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.shutdown().await.unwrap();
+        drop(server);
+        drop(client);
+        drop(listener);
+
+        let listener = bind_listener(address, 128, true, false).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
+    }
+}
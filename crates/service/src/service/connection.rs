@@ -0,0 +1,311 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accepts connections and serves HTTP/1 and HTTP/2 on them
+//!
+//! This is what `axum::serve` does, with connections that haven't read or
+//! written anything for the idle timeout being closed, and open connections
+//! counted in [OPEN_CONNECTIONS].
+
+use std::{
+    future::Future,
+    io,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{extract::Request, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::watch,
+    time::{sleep, Instant, Sleep},
+};
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
+use tower_http::normalize_path::NormalizePath;
+use tracing::{debug, warn};
+
+use crate::metrics::OPEN_CONNECTIONS;
+
+/// How long accepting waits after an error that isn't tied to a connection
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Errors of a single connection, as opposed to the listener
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Serves the router until `shutdown` is cancelled, then waits for the open
+/// connections to finish their requests
+pub async fn serve_connections(
+    listener: TcpListener,
+    router: NormalizePath<Router>,
+    idle_timeout: Option<Duration>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    // every connection holds a receiver, closing once they are all gone
+    let (closed_tx, closed_rx) = watch::channel(());
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                // the peer gave up on this connection, the next one may be fine
+                Err(e) if is_connection_error(&e) => continue,
+                // out of file descriptors or the like: accepting again right
+                // away would fail as well, leave time for connections to close
+                Err(e) => {
+                    warn!(
+                        error = %e,
+                        backoff = ?ACCEPT_ERROR_BACKOFF,
+                        "Failed to accept connection"
+                    );
+                    tokio::select! {
+                        _ = sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                        _ = shutdown.cancelled() => break,
+                    }
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        let closed_rx = closed_rx.clone();
+        tokio::spawn(async move {
+            OPEN_CONNECTIONS.inc();
+            match idle_timeout {
+                Some(idle_timeout) => {
+                    let in_flight = Arc::new(AtomicUsize::new(0));
+                    let stream =
+                        Box::pin(IdleTimeout::new(stream, idle_timeout, in_flight.clone()));
+                    serve_connection(stream, router, in_flight, shutdown).await
+                }
+                None => serve_connection(stream, router, Default::default(), shutdown).await,
+            }
+            OPEN_CONNECTIONS.dec();
+            drop(closed_rx);
+        });
+    }
+
+    drop(closed_rx);
+    closed_tx.closed().await;
+    Ok(())
+}
+
+async fn serve_connection<I>(
+    io: I,
+    router: NormalizePath<Router>,
+    in_flight: Arc<AtomicUsize>,
+    shutdown: CancellationToken,
+) where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = tower::service_fn(move |request: Request<hyper::body::Incoming>| {
+        let in_flight = InFlight::new(in_flight.clone());
+        let response = router.clone().oneshot(request);
+        async move {
+            let response = response.await;
+            drop(in_flight);
+            response
+        }
+    });
+    let builder = Builder::new(TokioExecutor::new());
+    let mut connection =
+        pin!(builder
+            .serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(service)));
+    let mut shutting_down = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(e) = result {
+                    debug!(error = %e, "Connection closed");
+                }
+                break;
+            }
+            _ = shutdown.cancelled(), if !shutting_down => {
+                shutting_down = true;
+                connection.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// Counts a request of the connection for as long as it is handled
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Fails reads once the stream had no activity for the timeout, unless a
+/// request is being handled
+#[pin_project]
+struct IdleTimeout<S> {
+    #[pin]
+    inner: S,
+    #[pin]
+    deadline: Sleep,
+    timeout: Duration,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, timeout: Duration, in_flight: Arc<AtomicUsize>) -> Self {
+        Self {
+            inner,
+            deadline: sleep(timeout),
+            timeout,
+            in_flight,
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        let filled = buf.filled().len();
+        if let Poll::Ready(result) = this.inner.poll_read(cx, buf) {
+            if buf.filled().len() > filled {
+                this.deadline.reset(Instant::now() + *this.timeout);
+            }
+            return Poll::Ready(result);
+        }
+
+        if this.deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        if this.in_flight.load(Ordering::SeqCst) > 0 {
+            // handling a request isn't idling, wait for the response
+            this.deadline.as_mut().reset(Instant::now() + *this.timeout);
+            let _ = this.deadline.poll(cx);
+            return Poll::Pending;
+        }
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection idle for longer than the idle timeout",
+        )))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                this.deadline.reset(Instant::now() + *this.timeout);
+            }
+        }
+        result
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                this.deadline.reset(Instant::now() + *this.timeout);
+            }
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::sync::CancellationToken;
+    use tower_http::normalize_path::NormalizePath;
+
+    use super::serve_connections;
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "done"
+            }),
+        );
+        tokio::spawn(serve_connections(
+            listener,
+            NormalizePath::trim_trailing_slash(router),
+            Some(Duration::from_millis(100)),
+            CancellationToken::new(),
+        ));
+
+        // a request taking longer than the timeout still gets its response
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0; 1024];
+        let read = stream.read(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response[..read]).starts_with("HTTP/1.1 200"));
+
+        // then the kept alive connection is closed once idle
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut response))
+            .await
+            .expect("idle connection should be closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+}
@@ -1,35 +1,51 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use alloy::dyn_abi::Eip712Domain;
+use anyhow::Context;
 use async_graphql_axum::GraphQL;
 use axum::{
     extract::MatchedPath,
-    http::Request,
+    http::{HeaderName, Request},
     middleware::{from_fn, from_fn_with_state},
     routing::{get, post, post_service},
     Json, Router,
 };
 use governor::{clock::QuantaInstant, middleware::NoOpMiddleware};
+use indexer_admin_auth::{admin_auth_middleware, AdminAuthState};
 use indexer_config::{
-    BlockchainConfig, DipsConfig, EscrowSubgraphConfig, GraphNodeConfig, IndexerConfig,
-    NetworkSubgraphConfig, ServiceConfig, ServiceTapConfig,
+    BlockGapAction, BlockchainConfig, ClockDriftAction, ClockReferenceConfig, CostMetadata,
+    DipsConfig, EscrowSubgraphConfig, EscrowUnavailablePolicy, GraphNodeConfig, IndexerConfig,
+    NetworkSubgraphConfig, QueryLimitsConfig, ReceiptTimeSource, ServiceConfig,
+    UnknownAllocationPolicy, UnknownFeatureAction,
 };
 use indexer_monitor::{
-    attestation_signers, deployment_to_allocation, dispute_manager, escrow_accounts,
-    indexer_allocations, AllocationWatcher, DisputeManagerWatcher, EscrowAccountsWatcher,
-    SubgraphClient,
+    attestation_signers, deployment_to_allocation, dispute_manager, escrow_accounts_from_source,
+    escrow_accounts_with_refresh, indexer_allocations, AllocationWatcher, DisputeManagerWatcher,
+    EscrowAccountsRefresh, EscrowAccountsWatcher, EscrowReservations, EscrowSource,
+    FreshnessTrackingSource, LazyAttestationSigners, OperatorMnemonics, SubgraphClient,
+    SubgraphEscrowSource,
 };
+use indexer_watcher::map_watcher;
 use reqwest::Method;
 use tap_core::{manager::Manager, receipt::checks::CheckList};
+use thegraph_core::{attestation, DeploymentId};
+use tokio::sync::watch;
 use tower::ServiceBuilder;
 use tower_governor::{
     governor::GovernorConfigBuilder, key_extractor::PeerIpKeyExtractor, GovernorLayer,
 };
 use tower_http::{
     auth::AsyncRequireAuthorizationLayer,
+    compression::CompressionLayer,
     cors::{self, CorsLayer},
     trace::TraceLayer,
     validate_request::ValidateRequestHeaderLayer,
@@ -38,21 +54,42 @@ use tracing::{info, info_span, warn};
 use typed_builder::TypedBuilder;
 
 use crate::{
+    clock_drift::{spawn_clock_drift_monitor, ClockReference, NtpReference, SubgraphReference},
     database::dips::{AgreementStore, InMemoryAgreementStore},
     metrics::{FAILED_RECEIPT, HANDLER_HISTOGRAM},
     middleware::{
-        allocation_middleware, attestation_middleware,
-        auth::{self, Bearer, OrExt},
-        context_middleware, deployment_middleware, labels_middleware, receipt_middleware,
-        sender_middleware, signer_middleware, AllocationState, AttestationState,
-        PrometheusMetricsMiddlewareLayer, SenderState,
+        allocation_middleware, allocation_quota_middleware, attestation_middleware,
+        auth::{self, Authenticated, Authenticator, FreeQueryToken},
+        catch_panic_middleware, clock_drift_middleware, content_type_middleware,
+        context_middleware, cost_metadata_middleware, dead_letter_middleware, deadline_middleware,
+        deployment_middleware, escrow_admission_middleware, escrow_freshness_middleware,
+        features_middleware, inflight_middleware, labels_middleware, load_shedding_middleware,
+        post_attestation_middleware, query_check_middleware, receipt_archive_middleware,
+        receipt_middleware, request_id_middleware, request_log_middleware,
+        request_queue_middleware, safe_mode_middleware, sender_middleware,
+        subgraph_sync_middleware, version_headers_middleware, AllocationQuota,
+        AllocationQuotaState, AllocationState, AttestationBackend, AttestationBackendState,
+        AttestationState, CachingBackend, CatchPanicState, ClockDriftState, ContentTypeState,
+        ContextState, DeadlineState, DeferredSigner, DeploymentState, EscrowAdmissionState,
+        EscrowFreshnessState, FeaturesState, LabelsState, LoadSheddingState, MeteredBackend,
+        PostAttestationHook, PostAttestationState, PrometheusMetricsMiddlewareLayer,
+        QueryCheckState, ReceiptState, RemoteSigner, RequestId, RequestIdState, RequestLogState,
+        RequestQueueState, SafeModeState, SenderState, SubgraphSyncState, VersionHeadersState,
     },
+    response_format::ResponseFormat,
     routes::{
         self,
+        admin::{self, AdminState},
+        attestation_probe,
         dips::{self, Price},
-        health, request_handler, static_subgraph_request_handler,
+        health, ready, request_handler, static_subgraph_request_handler, AttestationProbeState,
+        QueryLimits, ResponseTransformer, Singleflight, StaticSubgraphState, TapStatsState,
+    },
+    subgraph_consistency::spawn_subgraph_consistency,
+    tap::{
+        spawn_escrow_metrics, CheckFailureLog, CheckPipeline, CheckSettings, CheckState,
+        DeadLetterStore, EscrowChanges, IndexerTapContext, ReceiptArchive, ReceiptLog,
     },
-    tap::IndexerTapContext,
     wallet::public_key,
 };
 
@@ -77,7 +114,14 @@ pub struct ServiceRouter {
     blockchain: BlockchainConfig,
     timestamp_buffer_secs: Duration,
     #[builder(default)]
+    receipt_time_source: ReceiptTimeSource,
+    #[builder(default)]
     dips: Option<DipsConfig>,
+    // file the configuration is reloaded from by the admin routes
+    #[builder(default)]
+    config_path: Option<PathBuf>,
+    #[builder(default)]
+    config_profile: Option<String>,
 
     // either provide subgraph or watcher
     #[builder(default, setter(transform =
@@ -87,6 +131,10 @@ pub struct ServiceRouter {
     escrow_subgraph: Option<(&'static SubgraphClient, EscrowSubgraphConfig)>,
     #[builder(default, setter(strip_option))]
     escrow_accounts: Option<EscrowAccountsWatcher>,
+    // replaces the escrow subgraph as the source of the escrow accounts, synced
+    // on the escrow subgraph interval if one is provided
+    #[builder(default)]
+    escrow_source: Option<Arc<dyn EscrowSource>>,
 
     // provide network subgraph or allocations + dispute manager
     #[builder(default, setter(transform =
@@ -98,6 +146,19 @@ pub struct ServiceRouter {
     allocations: Option<AllocationWatcher>,
     #[builder(default, setter(strip_option))]
     dispute_manager: Option<DisputeManagerWatcher>,
+
+    // replaces the free query token when deciding which queries are paid
+    #[builder(default, setter(strip_option))]
+    authenticator: Option<Arc<dyn Authenticator>>,
+    // replaces the mnemonic and remote signers when signing attestations
+    #[builder(default, setter(strip_option))]
+    attestation_backend: Option<Arc<dyn AttestationBackend>>,
+    // rewrites the graph-node responses before they are attested
+    #[builder(default, setter(strip_option))]
+    response_transformer: Option<Arc<dyn ResponseTransformer>>,
+    // runs side effects on the attested responses before they are sent
+    #[builder(default, setter(strip_option))]
+    post_attestation_hook: Option<Arc<dyn PostAttestationHook>>,
 }
 
 const MISC_BURST_SIZE: u32 = 10;
@@ -107,29 +168,115 @@ const STATIC_BURST_SIZE: u32 = 50;
 const STATIC_BURST_PER_MILLISECOND: u64 = 20;
 
 const DISPUTE_MANAGER_INTERVAL: Duration = Duration::from_secs(3600);
+const ESCROW_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+const DEFERRED_SIGNER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// sync interval of an escrow source provided without the escrow subgraph
+const ESCROW_SOURCE_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+/// least time between two escrow refreshes triggered by unknown signers
+const ESCROW_REFRESH_DEBOUNCE: Duration = Duration::from_secs(10);
+/// signatures kept with `cache_attestations`
+const ATTESTATION_CACHE_CAPACITY: usize = 10_000;
 
 const DEFAULT_ROUTE: &str = "/";
 
 impl ServiceRouter {
+    /// Single router serving every route, for deployments listening on one address
     pub async fn create_router(self) -> anyhow::Result<Router> {
+        let ServiceRouters { public, admin } = self.create_routers().await?;
+        Ok(public.merge(admin))
+    }
+
+    /// Query and admin routes split, to be served on different addresses
+    pub async fn create_routers(self) -> anyhow::Result<ServiceRouters> {
         let IndexerConfig {
             indexer_address,
             operator_mnemonic,
+            previous_operator_mnemonics,
+            operator_priority,
         } = self.indexer;
         let ServiceConfig {
             serve_network_subgraph,
             serve_escrow_subgraph,
             serve_auth_token,
             url_prefix,
-            tap: ServiceTapConfig {
-                max_receipt_value_grt,
-            },
+            tap,
             free_query_auth_token,
+            attest_error_responses,
+            unattested_free_queries,
+            cache_attestations,
+            attestation_scope,
+            remote_signer,
+            attestation_failure_policy,
+            max_concurrent_signings,
+            signing_queue_warning_threshold,
+            load_shedding_receipt_queue_threshold,
+            safe_mode,
+            clock_drift,
+            verbose_errors,
+            accepted_content_types,
+            request_panic_action,
+            request_log_sample_rate,
+            max_deployment_metric_labels,
+            request_id,
+            version_headers,
+            request_features,
+            response_compression,
+            allowed_operations,
+            max_query_depth,
+            max_query_fields,
+            query_limits_per_deployment,
+            deployment_aliases,
+            max_concurrent_requests,
+            request_queue_length,
+            request_queue_max_wait_secs,
+            request_timeout_secs,
+            monitored_allocations,
+            monitored_deployments,
+            served_deployments,
+            denied_deployments,
+            allocation_quotas,
+            max_lazy_signers,
+            warm_allocations,
+            max_response_body_bytes,
+            coalesce_identical_queries,
+            cost_metadata,
+            address_format,
+            deployment_id_format,
+            admin_auth_token,
+            admin_signature,
             ..
         } = self.service;
 
+        let response_format = ResponseFormat {
+            address: address_format,
+            deployment_id: deployment_id_format,
+        };
+
+        let request_id_header = HeaderName::try_from(request_id.header.as_str())
+            .with_context(|| format!("Invalid request id header `{}`", request_id.header))?;
+        let version_headers_state = VersionHeadersState::new(&version_headers)
+            .context("Invalid version headers configuration")?;
+        // header the request id is sent along in to graph-node and the subgraphs
+        let propagated_request_id_header = request_id.propagate.then(|| request_id_header.clone());
+        let features_state = FeaturesState {
+            header: HeaderName::try_from(request_features.header.as_str()).with_context(|| {
+                format!(
+                    "Invalid request features header `{}`",
+                    request_features.header
+                )
+            })?,
+            allowed: Arc::new(
+                request_features
+                    .allowed
+                    .iter()
+                    .map(|feature| feature.to_ascii_lowercase())
+                    .collect(),
+            ),
+            reject_unknown: request_features.unknown_action == UnknownFeatureAction::Reject,
+        };
+
         // COST
-        let cost_schema = routes::cost::build_schema(self.database.clone()).await;
+        let cost_schema = routes::cost::build_schema(self.database.clone(), response_format).await;
         let post_cost = post_service(GraphQL::new(cost_schema));
 
         // STATUS
@@ -167,20 +314,161 @@ impl ServiceRouter {
             .expect("Failed to initialize indexer_allocations watcher"),
             (None, None) => panic!("No allocations or network subgraph was provided"),
         };
+        // Leave out the allocations of deployments this instance doesn't serve,
+        // so no signer or receipt check ever considers them
+        let served_deployments: Arc<HashSet<DeploymentId>> =
+            Arc::new(served_deployments.into_iter().collect());
+        let allocations = if served_deployments.is_empty() {
+            allocations
+        } else {
+            let served_deployments = served_deployments.clone();
+            map_watcher(allocations, move |allocations| {
+                allocations
+                    .into_iter()
+                    .filter(|(_, allocation)| {
+                        served_deployments.contains(&allocation.subgraph_deployment.id)
+                    })
+                    .collect()
+            })
+        };
 
         // Monitor escrow accounts
-        // if not provided, create monitor from subgraph
-        let escrow_accounts = match (self.escrow_accounts, self.escrow_subgraph.as_ref()) {
-            (Some(escrow_account), _) => escrow_account,
-            (_, Some((escrow_subgraph, escrow))) => escrow_accounts(
-                escrow_subgraph,
-                indexer_address,
-                escrow.config.syncing_interval_secs,
-                true, // Reject thawing signers eagerly
-            )
-            .await
-            .expect("Error creating escrow_accounts channel"),
-            (None, None) => panic!("No escrow accounts or escrow subgraph was provided"),
+        // if not provided, create monitor from the escrow source or subgraph,
+        // keeping track of how stale the accounts get when it can't be read
+        let (escrow_accounts, escrow_refresh, escrow_freshness_state) = match (
+            self.escrow_accounts,
+            self.escrow_source,
+            self.escrow_subgraph.as_ref(),
+        ) {
+            (Some(escrow_account), _, _) => (escrow_account, None, None),
+            (None, escrow_source, Some((escrow_subgraph, escrow))) => {
+                let escrow_source = escrow_source.unwrap_or_else(|| {
+                    Arc::new(SubgraphEscrowSource::new(
+                        escrow_subgraph,
+                        indexer_address,
+                        true, // Reject thawing signers eagerly
+                    ))
+                });
+                let (escrow_source, freshness) = FreshnessTrackingSource::new(escrow_source);
+                let (escrow_accounts, escrow_refresh) = watch_escrow_accounts(
+                    Arc::new(escrow_source),
+                    escrow.config.syncing_interval_secs,
+                    tap.refresh_escrow_on_unknown_sender,
+                )
+                .await;
+                let escrow_freshness_state = EscrowFreshnessState {
+                    freshness,
+                    policy: escrow.escrow_unavailable_policy,
+                    max_staleness: escrow.max_escrow_staleness_secs,
+                };
+                (
+                    escrow_accounts,
+                    escrow_refresh,
+                    Some(escrow_freshness_state),
+                )
+            }
+            // without the escrow subgraph settings, the source is synced on
+            // the default interval and its last accounts served while it fails
+            (None, Some(escrow_source), None) => {
+                let (escrow_source, freshness) = FreshnessTrackingSource::new(escrow_source);
+                let (escrow_accounts, escrow_refresh) = watch_escrow_accounts(
+                    Arc::new(escrow_source),
+                    ESCROW_SOURCE_SYNC_INTERVAL,
+                    tap.refresh_escrow_on_unknown_sender,
+                )
+                .await;
+                let escrow_freshness_state = EscrowFreshnessState {
+                    freshness,
+                    policy: EscrowUnavailablePolicy::ServeStale,
+                    max_staleness: None,
+                };
+                (
+                    escrow_accounts,
+                    escrow_refresh,
+                    Some(escrow_freshness_state),
+                )
+            }
+            (None, None, None) => {
+                panic!("No escrow accounts, escrow source or escrow subgraph was provided")
+            }
+        };
+        let (escrow_headroom_tx, escrow_headroom) = watch::channel(Default::default());
+        spawn_escrow_metrics(
+            self.database.clone(),
+            escrow_accounts.clone(),
+            ESCROW_METRICS_INTERVAL,
+            tap.low_escrow_balance_grt
+                .as_ref()
+                .map(|grt| grt.get_value()),
+            escrow_headroom_tx,
+        );
+        let escrow_admission_state = tap
+            .escrow_admission
+            .as_ref()
+            .map(|escrow_admission| {
+                EscrowAdmissionState::new(escrow_admission, escrow_headroom).with_context(|| {
+                    format!(
+                        "Invalid query priority header `{}`",
+                        escrow_admission.priority_header
+                    )
+                })
+            })
+            .transpose()?;
+
+        // TAP STATS
+        let get_tap_stats = get(routes::tap_stats).with_state(TapStatsState {
+            pgpool: self.database.clone(),
+            escrow_changes: EscrowChanges::spawn(escrow_accounts.clone()),
+        });
+
+        // Compare how far both subgraphs are indexed, optionally refusing paid
+        // queries while they disagree
+        let subgraph_sync_state = match (
+            self.network_subgraph.as_ref(),
+            self.escrow_subgraph.as_ref(),
+        ) {
+            (Some((network_subgraph, _)), Some((escrow_subgraph, escrow))) => {
+                let out_of_sync = spawn_subgraph_consistency(
+                    network_subgraph,
+                    escrow_subgraph,
+                    escrow.config.syncing_interval_secs,
+                    escrow.max_block_gap_to_network,
+                    escrow.block_gap_action,
+                );
+                (escrow.block_gap_action == BlockGapAction::Refuse)
+                    .then_some(SubgraphSyncState { out_of_sync })
+            }
+            _ => None,
+        };
+
+        // Compare the host clock with the reference, optionally refusing paid
+        // queries while it is off
+        let clock_drift_state = match &clock_drift {
+            Some(clock_drift) => {
+                let reference: Arc<dyn ClockReference> = match &clock_drift.reference {
+                    ClockReferenceConfig::Ntp { server } => {
+                        Arc::new(NtpReference::new(server.clone()))
+                    }
+                    ClockReferenceConfig::NetworkSubgraph => match self.network_subgraph.as_ref() {
+                        Some((network_subgraph, _)) => {
+                            Arc::new(SubgraphReference(network_subgraph))
+                        }
+                        None => anyhow::bail!(
+                            "The network subgraph is the clock reference, but none was provided"
+                        ),
+                    },
+                };
+                let drifted = spawn_clock_drift_monitor(
+                    reference,
+                    SystemTime::now,
+                    clock_drift.check_interval_secs,
+                    clock_drift.max_drift_secs,
+                    clock_drift.action,
+                );
+                (clock_drift.action == ClockDriftAction::Refuse)
+                    .then_some(ClockDriftState { drifted })
+            }
+            None => None,
         };
 
         // Monitor dispute manager address
@@ -195,14 +483,121 @@ impl ServiceRouter {
             (None, None) => panic!("No dispute allocations or network subgraph was provided"),
         };
 
-        // Maintain an up-to-date set of attestation signers, one for each
-        // allocation
-        let attestation_signers = attestation_signers(
-            allocations.clone(),
-            operator_mnemonic.clone(),
-            self.blockchain.chain_id as u64,
-            dispute_manager,
-        );
+        // Attestations are verified against the domain of the dispute manager
+        let chain_id = self.blockchain.chain_id as u64;
+        let attestation_domain = map_watcher(dispute_manager.clone(), move |dispute_manager| {
+            attestation::eip712_domain(chain_id, dispute_manager)
+        });
+
+        // The operator holding the allocation keys, reported by `/info`
+        let operator = match (&remote_signer, &operator_mnemonic) {
+            (Some(remote_signer), _) => remote_signer.operator_address,
+            (None, Some(operator_mnemonic)) => public_key(operator_mnemonic)?,
+            (None, None) => {
+                anyhow::bail!("An operator mnemonic is required without a remote signer")
+            }
+        };
+
+        // Sign attestations with the keys of a signing service if one is
+        // configured, or with the keys derived from the operator mnemonics
+        let attestation_backend: Arc<dyn AttestationBackend> =
+            match (self.attestation_backend, remote_signer) {
+                (Some(attestation_backend), _) => attestation_backend,
+                (None, Some(remote_signer)) => {
+                    info!(url = %remote_signer.url, "Signing attestations with a remote signer");
+                    Arc::new(RemoteSigner::new(
+                        self.http_client.clone(),
+                        remote_signer.url,
+                        remote_signer.timeout_secs,
+                    ))
+                }
+                (None, None) => {
+                    // Signers of allocations left out of the monitored ones are only
+                    // built once they are queried
+                    let lazy_signing = !monitored_allocations.is_empty()
+                        || !monitored_deployments.is_empty()
+                        || max_lazy_signers.is_some()
+                        || !warm_allocations.is_empty();
+                    let monitored_allocations_rx = if lazy_signing {
+                        map_watcher(allocations.clone(), move |allocations| {
+                            allocations
+                                .into_iter()
+                                .filter(|(id, allocation)| {
+                                    monitored_allocations.contains(id)
+                                        || monitored_deployments
+                                            .contains(&allocation.subgraph_deployment.id)
+                                })
+                                .collect()
+                        })
+                    } else {
+                        allocations.clone()
+                    };
+                    let operator_mnemonics = OperatorMnemonics::new(
+                        operator_mnemonic
+                            .into_iter()
+                            .chain(previous_operator_mnemonics)
+                            .collect(),
+                        operator_priority,
+                    );
+                    let lazy_attestation_signers = lazy_signing.then(|| {
+                        let lazy_signers = Arc::new(LazyAttestationSigners::new(
+                            allocations.clone(),
+                            operator_mnemonics.clone(),
+                            chain_id,
+                            dispute_manager.clone(),
+                            max_lazy_signers,
+                        ));
+                        lazy_signers.warm_up(warm_allocations.into_iter().collect());
+                        lazy_signers
+                    });
+
+                    // Maintain an up-to-date set of attestation signers, one for each
+                    // monitored allocation
+                    let attestation_signers = attestation_signers(
+                        monitored_allocations_rx,
+                        operator_mnemonics,
+                        chain_id,
+                        dispute_manager,
+                    );
+
+                    Arc::new(AttestationState {
+                        attestation_signers,
+                        lazy_attestation_signers,
+                    })
+                }
+            };
+        let mut attestation_backend: Arc<dyn AttestationBackend> = Arc::new(MeteredBackend::new(
+            attestation_backend,
+            max_concurrent_signings,
+            signing_queue_warning_threshold,
+        ));
+        // what the receipt checks learn, kept as they are rebuilt
+        let check_state = CheckState {
+            escrow_freshness: escrow_freshness_state.clone(),
+            ..CheckState::load(self.database.clone()).await
+        };
+        // receipts of unknown allocations are accepted while their signer
+        // may still show up, waiting for it doesn't hold a signing permit
+        if tap.unknown_allocation_policy == UnknownAllocationPolicy::AcceptAndDeferSigner {
+            attestation_backend = Arc::new(DeferredSigner::new(
+                attestation_backend,
+                check_state.unknown_allocations.clone(),
+                tap.unknown_allocation_grace_secs,
+                DEFERRED_SIGNER_POLL_INTERVAL,
+            ));
+        }
+        if cache_attestations {
+            attestation_backend = Arc::new(CachingBackend::new(
+                attestation_backend,
+                NonZeroUsize::new(ATTESTATION_CACHE_CAPACITY).expect("capacity is not zero"),
+            ));
+        }
+        let attestation_state = AttestationBackendState {
+            backend: attestation_backend,
+            domain: attestation_domain,
+            unattested_free_queries,
+            failure_policy: attestation_failure_policy,
+        };
 
         // Rate limits by allowing bursts of 10 requests and requiring 100ms of
         // time between consecutive requests after that, effectively rate
@@ -231,7 +626,10 @@ impl ServiceRouter {
                     post(static_subgraph_request_handler)
                         .route_layer(auth_layer)
                         .route_layer(static_subgraph_rate_limiter.clone())
-                        .with_state(network_subgraph),
+                        .with_state(StaticSubgraphState {
+                            subgraph_client: network_subgraph,
+                            request_id_header: propagated_request_id_header.clone(),
+                        }),
                 )
             }
             (_, true, _) => {
@@ -257,7 +655,10 @@ impl ServiceRouter {
                     post(static_subgraph_request_handler)
                         .route_layer(auth_layer)
                         .route_layer(static_subgraph_rate_limiter)
-                        .with_state(escrow_subgraph),
+                        .with_state(StaticSubgraphState {
+                            subgraph_client: escrow_subgraph,
+                            request_id_header: propagated_request_id_header.clone(),
+                        }),
                 )
             }
             (_, true, _) => {
@@ -267,53 +668,155 @@ impl ServiceRouter {
             _ => Router::new(),
         };
 
-        let post_request_handler = {
-            // Create tap manager to validate receipts
-            let tap_manager = {
-                // Create context
-                let indexer_context =
-                    IndexerTapContext::new(self.database.clone(), self.domain_separator.clone())
-                        .await;
-
-                let timestamp_error_tolerance = self.timestamp_buffer_secs;
-                let receipt_max_value = max_receipt_value_grt.get_value();
-
-                // Create checks
-                let checks = IndexerTapContext::get_checks(
-                    self.database,
+        if safe_mode {
+            warn!("SAFE MODE ENGAGED on startup: all paid queries will be refused");
+        }
+        let (safe_mode_tx, safe_mode_rx) = watch::channel(safe_mode);
+        let (request_log_sample_rate_tx, request_log_sample_rate_rx) =
+            watch::channel(request_log_sample_rate);
+        let (denied_deployments_tx, denied_deployments_rx) =
+            watch::channel(denied_deployments.into_iter().collect());
+        let (allocation_quotas_tx, allocation_quotas_rx) = watch::channel(
+            allocation_quotas
+                .iter()
+                .map(|(allocation, quota)| (*allocation, AllocationQuota::from(quota)))
+                .collect(),
+        );
+
+        let query_check_state = QueryCheckState {
+            allowed_operations: Arc::new(
+                allowed_operations
+                    .into_iter()
+                    .map(|(deployment, operations)| (deployment, operations.into_iter().collect()))
+                    .collect(),
+            ),
+            query_limits: Arc::new(QueryLimits::new(
+                QueryLimitsConfig {
+                    max_depth: max_query_depth,
+                    max_fields: max_query_fields,
+                },
+                query_limits_per_deployment,
+            )),
+        };
+
+        let dead_letter = tap
+            .dead_letter_receipts
+            .then(|| DeadLetterStore::new(self.database.clone()));
+
+        let (post_request_handler, check_pipeline) = {
+            // Create context
+            let indexer_context =
+                IndexerTapContext::new(self.database.clone(), self.domain_separator.clone())
+                    .await
+                    .with_receipt_queue_overflow(tap.receipt_queue_overflow)
+                    .with_receipt_time_source(self.receipt_time_source);
+
+            let load_shedding_state = LoadSheddingState {
+                pgpool: self.database.clone(),
+                receipt_queue: indexer_context.receipt_queue(),
+                receipt_queue_threshold: load_shedding_receipt_queue_threshold,
+            };
+
+            // Create the receipt checks, they can be rebuilt at runtime
+            let check_pipeline = Arc::new(
+                CheckPipeline::new(
+                    self.database.clone(),
                     allocations.clone(),
                     escrow_accounts.clone(),
-                    timestamp_error_tolerance,
-                    receipt_max_value,
+                    // held for the value underpaid receipts are quoted, until
+                    // a receipt pays it
+                    EscrowReservations::default(),
+                    check_state,
+                    CheckSettings::new(&tap, self.timestamp_buffer_secs),
                 )
-                .await;
-                // Returned static Manager
-                Arc::new(Manager::new(
-                    self.domain_separator.clone(),
-                    indexer_context,
-                    CheckList::new(checks),
-                ))
-            };
+                .await,
+            );
 
-            let attestation_state = AttestationState {
-                attestation_signers,
-            };
+            // Create tap manager to validate receipts
+            let tap_manager = Arc::new(Manager::new(
+                self.domain_separator.clone(),
+                indexer_context,
+                CheckList::new(vec![check_pipeline.checks()]),
+            ));
 
             let mut handler = post(request_handler);
 
+            // keep the receipts of queries that can never be served
+            if let Some(dead_letter) = &dead_letter {
+                handler = handler.route_layer(from_fn_with_state(
+                    dead_letter.clone(),
+                    dead_letter_middleware,
+                ));
+            }
+
+            // tell what paid queries were charged, in headers that aren't attested
+            if cost_metadata != CostMetadata::Disabled {
+                handler = handler
+                    .route_layer(from_fn_with_state(cost_metadata, cost_metadata_middleware));
+            }
+
             handler = handler
+                // answer queries whose handler panicked
+                .route_layer(from_fn_with_state(
+                    CatchPanicState {
+                        action: request_panic_action,
+                    },
+                    catch_panic_middleware,
+                ))
                 // create attestation
-                .route_layer(from_fn(attestation_middleware))
-                // inject signer
-                .route_layer(from_fn_with_state(attestation_state, signer_middleware));
+                .route_layer(from_fn_with_state(
+                    attestation_state.clone(),
+                    attestation_middleware,
+                ));
+
+            // run the post attestation hook on the attested responses
+            if let Some(hook) = &self.post_attestation_hook {
+                handler = handler.route_layer(from_fn_with_state(
+                    PostAttestationState::new(hook.clone()),
+                    post_attestation_middleware,
+                ));
+            }
+
+            // archive a sample of the paid queries, as they were attested
+            if let Some(receipt_archive) = &tap.receipt_archive {
+                let receipt_archive = ReceiptArchive::new(receipt_archive, self.database.clone())
+                    .await
+                    .context("Failed to open the receipt archive")?;
+                handler = handler.route_layer(from_fn_with_state(
+                    receipt_archive,
+                    receipt_archive_middleware,
+                ));
+            }
 
             // inject auth
             let failed_receipt_metric = Box::leak(Box::new(FAILED_RECEIPT.clone()));
-            let tap_auth = auth::tap_receipt_authorize(tap_manager, failed_receipt_metric);
+            let receipt_log = match &tap.receipt_log {
+                Some(receipt_log) => Some(
+                    ReceiptLog::open(&receipt_log.path, receipt_log.format)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to open receipt log {}", receipt_log.path.display())
+                        })?,
+                ),
+                None => None,
+            };
+            let check_failure_log =
+                Arc::new(CheckFailureLog::new(tap.check_failure_logging.as_ref()));
+            check_failure_log.spawn_flush();
+            let tap_auth = auth::tap_receipt_authorize(
+                tap_manager,
+                failed_receipt_metric,
+                receipt_log,
+                check_failure_log,
+            );
 
-            if let Some(free_auth_token) = &free_query_auth_token {
-                let free_query = Bearer::new(free_auth_token);
-                let result = free_query.or(tap_auth);
+            let authenticator = self.authenticator.or_else(|| {
+                free_query_auth_token
+                    .as_deref()
+                    .map(|token| Arc::new(FreeQueryToken::new(token)) as Arc<dyn Authenticator>)
+            });
+            if let Some(authenticator) = authenticator.clone() {
+                let result = Authenticated::new(authenticator, tap_auth);
                 let auth_layer = AsyncRequireAuthorizationLayer::new(result);
                 handler = handler.route_layer(auth_layer);
             } else {
@@ -327,28 +830,169 @@ impl ServiceRouter {
             };
             let sender_state = SenderState {
                 escrow_accounts,
+                escrow_refresh,
                 domain_separator: self.domain_separator,
             };
 
+            let request_queue_state = max_concurrent_requests.map(|max_concurrent_requests| {
+                RequestQueueState::new(
+                    max_concurrent_requests,
+                    request_queue_length,
+                    request_queue_max_wait_secs,
+                )
+            });
+
             let service_builder = ServiceBuilder::new()
+                // the timeout of the request counts from its arrival
+                .option_layer(request_timeout_secs.map(|timeout| {
+                    from_fn_with_state(DeadlineState { timeout }, deadline_middleware)
+                }))
+                // refuse bodies that wouldn't be parsed as they were meant to
+                .layer(from_fn_with_state(
+                    ContentTypeState::new(accepted_content_types),
+                    content_type_middleware,
+                ))
+                // read the experimental handling enabled for the request
+                .layer(from_fn_with_state(features_state, features_middleware))
+                // wait for a slot before any work is done on the request
+                .option_layer(
+                    request_queue_state
+                        .map(|state| from_fn_with_state(state, request_queue_middleware)),
+                )
                 // inject deployment id
-                .layer(from_fn(deployment_middleware))
+                .layer(from_fn_with_state(
+                    DeploymentState {
+                        aliases: Arc::new(deployment_aliases),
+                        served: served_deployments,
+                        denied: denied_deployments_rx,
+                    },
+                    deployment_middleware,
+                ))
+                // refuse queries the deployment doesn't accept
+                .layer(from_fn_with_state(
+                    query_check_state,
+                    query_check_middleware,
+                ))
                 // inject receipt
-                .layer(from_fn(receipt_middleware))
+                .layer(from_fn_with_state(
+                    ReceiptState {
+                        strict: tap.strict_receipt_validation,
+                    },
+                    receipt_middleware,
+                ))
+                // refuse paid queries during incident response
+                .layer(from_fn_with_state(
+                    SafeModeState {
+                        safe_mode: safe_mode_rx,
+                    },
+                    safe_mode_middleware,
+                ))
+                // refuse paid queries while escrow and network data disagree
+                .option_layer(
+                    subgraph_sync_state
+                        .map(|state| from_fn_with_state(state, subgraph_sync_middleware)),
+                )
+                // refuse paid queries while the escrow accounts can't be read
+                .option_layer(
+                    escrow_freshness_state
+                        .clone()
+                        .map(|state| from_fn_with_state(state, escrow_freshness_middleware)),
+                )
+                // refuse paid queries while the host clock is off
+                .option_layer(
+                    clock_drift_state
+                        .map(|state| from_fn_with_state(state, clock_drift_middleware)),
+                )
+                // shed paid queries while the database is saturated
+                .layer(from_fn_with_state(
+                    load_shedding_state,
+                    load_shedding_middleware,
+                ))
                 // inject allocation id
                 .layer(from_fn_with_state(allocation_state, allocation_middleware))
                 // inject sender
                 .layer(from_fn_with_state(sender_state, sender_middleware))
+                // refuse paid queries over the quota of their allocation, once
+                // their receipt is known to come from a sender
+                .layer(from_fn_with_state(
+                    AllocationQuotaState::new(allocation_quotas_rx),
+                    allocation_quota_middleware,
+                ))
+                // keep the end of the sender's escrow for its higher priority queries
+                .option_layer(
+                    escrow_admission_state
+                        .map(|state| from_fn_with_state(state, escrow_admission_middleware)),
+                )
                 // inject metrics labels
-                .layer(from_fn(labels_middleware))
+                .layer(from_fn_with_state(
+                    LabelsState::new(max_deployment_metric_labels),
+                    labels_middleware,
+                ))
                 // metrics for histogram and failure
                 .layer(PrometheusMetricsMiddlewareLayer::new(
                     HANDLER_HISTOGRAM.clone(),
                 ))
                 // tap context
-                .layer(from_fn(context_middleware));
+                .layer(from_fn_with_state(
+                    ContextState {
+                        authenticator,
+                        verbose_errors,
+                        default_token: tap.token_address,
+                        normalize_variable_numbers: tap.normalize_variable_numbers,
+                    },
+                    context_middleware,
+                ));
+
+            (handler.route_layer(service_builder), check_pipeline)
+        };
 
-            handler.route_layer(service_builder)
+        let admin_routes = match (admin_auth_token, admin_signature) {
+            (None, None) => Router::new(),
+            (admin_auth_token, admin_signature) => {
+                let admin_state = AdminState {
+                    check_pipeline,
+                    safe_mode: Arc::new(safe_mode_tx),
+                    request_log_sample_rate: Arc::new(request_log_sample_rate_tx),
+                    allocation_quotas: Arc::new(allocation_quotas_tx),
+                    denied_deployments: Arc::new(denied_deployments_tx),
+                    dead_letter,
+                    config_path: self.config_path,
+                    config_profile: self.config_profile,
+                };
+                Router::new()
+                    .route("/reload-checks", post(admin::reload_checks))
+                    .route("/replay-receipts", post(admin::replay_receipts))
+                    .route(
+                        "/safe-mode",
+                        get(admin::get_safe_mode).post(admin::set_safe_mode),
+                    )
+                    .route(
+                        "/request-log-sample-rate",
+                        get(admin::get_request_log_sample_rate)
+                            .post(admin::set_request_log_sample_rate),
+                    )
+                    .route(
+                        "/allocation-quotas",
+                        get(admin::get_allocation_quotas).post(admin::set_allocation_quotas),
+                    )
+                    .route(
+                        "/denied-deployments",
+                        get(admin::get_denied_deployments).post(admin::set_denied_deployments),
+                    )
+                    .route(
+                        "/sender-allow-list",
+                        get(admin::get_sender_allow_list).post(admin::set_sender_allow_list),
+                    )
+                    .route(
+                        "/dead-letter-receipts",
+                        get(admin::get_dead_letter_receipts),
+                    )
+                    .with_state(admin_state)
+                    .layer(from_fn_with_state(
+                        AdminAuthState::new(admin_auth_token.as_deref(), admin_signature),
+                        admin_auth_middleware,
+                    ))
+            }
         };
 
         // setup cors
@@ -366,12 +1010,17 @@ impl ServiceRouter {
                     .extensions()
                     .get::<MatchedPath>()
                     .map(MatchedPath::as_str);
+                let request_id = req
+                    .extensions()
+                    .get::<RequestId>()
+                    .and_then(|RequestId(request_id)| request_id.to_str().ok());
 
                 info_span!(
                     "http_request",
                     %method,
                     %uri,
                     matched_path,
+                    request_id,
                 )
             })
             // we disable failures here because we doing our own error logging
@@ -386,14 +1035,22 @@ impl ServiceRouter {
             None => Router::new(),
         };
 
-        let operator_address =
-            Json(serde_json::json!({ "publicKey": public_key(&operator_mnemonic)?}));
+        let operator_address = Json(serde_json::json!({
+            "publicKey": response_format.address(&operator)
+        }));
 
         // Graphnode state
         let graphnode_state = GraphNodeState {
             graph_node_client: self.http_client,
             graph_node_status_url: self.graph_node.status_url,
             graph_node_query_base_url: self.graph_node.query_url,
+            attest_error_responses,
+            attestation_scope: attestation_scope.into(),
+            max_response_body_bytes,
+            response_transformer: self.response_transformer,
+            request_id_header: propagated_request_id_header,
+            response_format,
+            singleflight: coalesce_identical_queries.then(Singleflight::new),
         };
 
         // data layer
@@ -401,15 +1058,40 @@ impl ServiceRouter {
             .route("/subgraphs/id/:id", post_request_handler)
             .with_state(graphnode_state.clone());
 
+        // every subscribe message is served as a query by the data routes
+        #[cfg(feature = "websocket")]
+        let data_routes = data_routes.clone().route(
+            "/subgraphs/id/:id/ws",
+            get(crate::routes::websocket_handler).with_state(crate::routes::InProcessQueries::new(
+                data_routes,
+                max_response_body_bytes,
+            )),
+        );
+
         let subgraphs_route = Router::new().nest(&url_prefix, data_routes);
+        // outside the attestation middleware, which sees the uncompressed response
+        let subgraphs_route = if response_compression.enabled {
+            subgraphs_route.layer(CompressionLayer::new())
+        } else {
+            subgraphs_route
+        };
 
         let misc_routes = Router::new()
             .route("/", get("Service is up and running"))
             .route("/info", get(operator_address))
+            .route("/ready", get(ready).with_state(escrow_freshness_state))
             .nest("/version", version)
             .nest("/escrow", serve_escrow_subgraph)
             .nest("/network", serve_network_subgraph)
             .nest("/dips", dips)
+            .route("/tap/stats", get_tap_stats)
+            .route(
+                "/attestation-probe",
+                get(attestation_probe).with_state(AttestationProbeState {
+                    attestation: attestation_state,
+                    allocations: allocations.clone(),
+                }),
+            )
             .route(
                 "/subgraph/health/:deployment_id",
                 get(health).with_state(graphnode_state.clone()),
@@ -420,14 +1102,80 @@ impl ServiceRouter {
             .route("/cost", post_cost)
             .route("/status", post_status.with_state(graphnode_state));
 
-        let router = Router::new()
+        let request_log_state = RequestLogState {
+            sample_rate: request_log_sample_rate_rx,
+        };
+        let request_id_state = RequestIdState {
+            header: request_id_header,
+            honor_inbound: request_id.honor_inbound,
+        };
+        let with_common_layers = |router: Router| {
+            router
+                .layer(cors_layer.clone())
+                .layer(from_fn_with_state(
+                    version_headers_state.clone(),
+                    version_headers_middleware,
+                ))
+                // log failed requests and a sample of the successful ones
+                .layer(from_fn_with_state(
+                    request_log_state.clone(),
+                    request_log_middleware,
+                ))
+                .layer(tracing_layer.clone())
+                .layer(from_fn(inflight_middleware))
+                // outermost, so every response carries the request id
+                .layer(from_fn_with_state(
+                    request_id_state.clone(),
+                    request_id_middleware,
+                ))
+        };
+
+        let public = Router::new()
             .merge(misc_routes)
             .merge(subgraphs_route)
-            .merge(extra_routes)
-            .layer(cors_layer)
-            .layer(tracing_layer);
+            .merge(extra_routes);
 
-        Ok(router)
+        let admin = Router::new().nest(
+            "/admin",
+            admin_routes.layer(create_rate_limiter(
+                MISC_BURST_PER_MILLISECOND,
+                MISC_BURST_SIZE,
+            )),
+        );
+
+        Ok(ServiceRouters {
+            public: with_common_layers(public),
+            admin: with_common_layers(admin),
+        })
+    }
+}
+
+/// Routers returned by [ServiceRouter::create_routers]
+pub struct ServiceRouters {
+    /// query, cost, status and informational routes
+    pub public: Router,
+    /// `/admin` routes, empty if `admin_auth_token` is not set
+    pub admin: Router,
+}
+
+/// Syncs the escrow accounts of `source` every `interval`, also on demand
+/// with `refresh`
+async fn watch_escrow_accounts(
+    source: Arc<dyn EscrowSource>,
+    interval: Duration,
+    refresh: bool,
+) -> (EscrowAccountsWatcher, Option<EscrowAccountsRefresh>) {
+    if refresh {
+        let (escrow_accounts, escrow_refresh) =
+            escrow_accounts_with_refresh(source, interval, ESCROW_REFRESH_DEBOUNCE)
+                .await
+                .expect("Error creating escrow_accounts channel");
+        (escrow_accounts, Some(escrow_refresh))
+    } else {
+        let escrow_accounts = escrow_accounts_from_source(source, interval)
+            .await
+            .expect("Error creating escrow_accounts channel");
+        (escrow_accounts, None)
     }
 }
 
@@ -1,10 +1,16 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::BTreeMap;
+
+use alloy::primitives::{Address, Signature};
 use axum_extra::headers::{self, Header, HeaderName, HeaderValue};
 use lazy_static::lazy_static;
 use prometheus::{register_counter, Counter};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
 use tap_core::receipt::SignedReceipt;
+use thiserror::Error;
 
 #[derive(Debug, PartialEq)]
 pub struct TapReceipt(pub SignedReceipt);
@@ -45,14 +51,113 @@ impl Header for TapReceipt {
     }
 }
 
+/// Receipts timestamped before 2020-01-01 are most likely timestamped in
+/// seconds or milliseconds instead of nanoseconds
+const MIN_TIMESTAMP_NS: u64 = 1_577_836_800_000_000_000;
+/// 2100-01-01, later timestamps can't have been meant
+const MAX_TIMESTAMP_NS: u64 = 4_102_444_800_000_000_000;
+
+/// A field of a receipt that is missing, unexpected or out of range
+#[derive(Debug, Error, PartialEq)]
+#[error("`{field}` {reason}")]
+pub struct ReceiptFieldError {
+    /// path of the field, e.g. `message.value`
+    pub field: String,
+    pub reason: String,
+}
+
+impl ReceiptFieldError {
+    fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Fields of the JSON object at `path`, which must be exactly `expected`
+fn object_fields<'a>(
+    raw: &'a str,
+    path: &str,
+    expected: &[&str],
+) -> Result<BTreeMap<String, &'a RawValue>, ReceiptFieldError> {
+    let fields: BTreeMap<String, &RawValue> = serde_json::from_str(raw)
+        .map_err(|e| ReceiptFieldError::new(path, format!("is not an object: {e}")))?;
+    let prefix = |field: &str| match path {
+        "receipt" => field.to_string(),
+        path => format!("{path}.{field}"),
+    };
+    if let Some(unknown) = fields
+        .keys()
+        .find(|field| !expected.contains(&field.as_str()))
+    {
+        return Err(ReceiptFieldError::new(
+            prefix(unknown),
+            "is not a receipt field",
+        ));
+    }
+    if let Some(missing) = expected.iter().find(|field| !fields.contains_key(**field)) {
+        return Err(ReceiptFieldError::new(prefix(missing), "is missing"));
+    }
+    Ok(fields)
+}
+
+fn parse_field<T: DeserializeOwned>(raw: &RawValue, path: &str) -> Result<T, ReceiptFieldError> {
+    serde_json::from_str(raw.get())
+        .map_err(|e| ReceiptFieldError::new(path, format!("is invalid: {e}")))
+}
+
+/// Parses a signed receipt, checking its structure field by field before its
+/// signature is ever verified
+///
+/// Every field must be there and no other, the allocation and signature can't
+/// be zero, the value must be at least 1 GRT wei and the timestamp in
+/// nanoseconds, between 2020 and 2100.
+pub fn validate_receipt(raw: &str) -> Result<SignedReceipt, ReceiptFieldError> {
+    let receipt = object_fields(raw, "receipt", &["message", "signature"])?;
+    let message = object_fields(
+        receipt["message"].get(),
+        "message",
+        &["allocation_id", "timestamp_ns", "nonce", "value"],
+    )?;
+
+    let allocation_id: Address = parse_field(message["allocation_id"], "message.allocation_id")?;
+    if allocation_id.is_zero() {
+        return Err(ReceiptFieldError::new(
+            "message.allocation_id",
+            "is the zero address",
+        ));
+    }
+    let timestamp_ns: u64 = parse_field(message["timestamp_ns"], "message.timestamp_ns")?;
+    if !(MIN_TIMESTAMP_NS..MAX_TIMESTAMP_NS).contains(&timestamp_ns) {
+        return Err(ReceiptFieldError::new(
+            "message.timestamp_ns",
+            format!("{timestamp_ns} is not a timestamp in nanoseconds between 2020 and 2100"),
+        ));
+    }
+    parse_field::<u64>(message["nonce"], "message.nonce")?;
+    let value: u128 = parse_field(message["value"], "message.value")?;
+    if value == 0 {
+        return Err(ReceiptFieldError::new("message.value", "is zero"));
+    }
+    let signature: Signature = parse_field(receipt["signature"], "signature")?;
+    if signature.r().is_zero() || signature.s().is_zero() {
+        return Err(ReceiptFieldError::new("signature", "has a zero `r` or `s`"));
+    }
+
+    serde_json::from_str(raw)
+        .map_err(|e| ReceiptFieldError::new("receipt", format!("is invalid: {e}")))
+}
+
 #[cfg(test)]
 mod test {
     use axum::http::HeaderValue;
     use axum_extra::headers::Header;
 
+    use serde_json::{json, Value};
     use test_assets::{create_signed_receipt, SignedReceiptRequest};
 
-    use super::TapReceipt;
+    use super::{validate_receipt, ReceiptFieldError, TapReceipt};
 
     #[tokio::test]
     async fn test_decode_valid_tap_receipt_header() {
@@ -83,4 +188,66 @@ mod test {
 
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_validate_malformed_receipts() {
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let valid = serde_json::to_value(&receipt).unwrap();
+        assert_eq!(validate_receipt(&valid.to_string()).unwrap(), receipt);
+
+        let validate = |change: &dyn Fn(&mut Value)| {
+            let mut receipt = valid.clone();
+            change(&mut receipt);
+            validate_receipt(&receipt.to_string()).unwrap_err()
+        };
+        let error = |field: &str, reason: &str| ReceiptFieldError {
+            field: field.into(),
+            reason: reason.into(),
+        };
+
+        assert_eq!(
+            validate(&|receipt| receipt["message"]["fee"] = json!(1)),
+            error("message.fee", "is not a receipt field")
+        );
+        assert_eq!(
+            validate(&|receipt| receipt["extra"] = json!(true)),
+            error("extra", "is not a receipt field")
+        );
+        assert_eq!(
+            validate(&|receipt| {
+                receipt["message"].as_object_mut().unwrap().remove("nonce");
+            }),
+            error("message.nonce", "is missing")
+        );
+        assert_eq!(
+            validate(&|receipt| {
+                receipt.as_object_mut().unwrap().remove("signature");
+            }),
+            error("signature", "is missing")
+        );
+        assert_eq!(
+            validate(&|receipt| {
+                receipt["message"]["allocation_id"] =
+                    json!("0x0000000000000000000000000000000000000000")
+            }),
+            error("message.allocation_id", "is the zero address")
+        );
+        assert_eq!(
+            validate(&|receipt| receipt["message"]["value"] = json!(0)),
+            error("message.value", "is zero")
+        );
+        // timestamped in seconds
+        assert_eq!(
+            validate(&|receipt| receipt["message"]["timestamp_ns"] = json!(1_700_000_000)).field,
+            "message.timestamp_ns"
+        );
+        assert_eq!(
+            validate(&|receipt| receipt["message"]["nonce"] = json!("one")).field,
+            "message.nonce"
+        );
+        assert_eq!(
+            validate(&|receipt| receipt["message"] = json!([])).field,
+            "message"
+        );
+    }
 }
@@ -0,0 +1,177 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compares the latest block indexed by the escrow and network subgraphs.
+//!
+//! Escrow accounts name senders whose allocations come from the network
+//! subgraph, so when one subgraph lags far behind the other, receipts are
+//! checked against data from different points in time. Both latest blocks are
+//! exported as metrics, and past the configured gap the service either warns
+//! or refuses paid queries until the subgraphs catch up.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::body::Bytes;
+use indexer_config::BlockGapAction;
+use indexer_monitor::SubgraphClient;
+use serde_json::Value;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::metrics::SUBGRAPH_LATEST_BLOCK;
+
+const LATEST_BLOCK_QUERY: &str = r#"{"query": "{ _meta { block { number } } }"}"#;
+
+/// Checks both subgraphs right away and then on every interval. The returned
+/// receiver is `true` while paid queries should be refused
+pub fn spawn_subgraph_consistency(
+    network_subgraph: &'static SubgraphClient,
+    escrow_subgraph: &'static SubgraphClient,
+    interval: Duration,
+    max_block_gap: Option<u64>,
+    action: BlockGapAction,
+) -> watch::Receiver<bool> {
+    let (out_of_sync_tx, out_of_sync_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut diverged = false;
+        loop {
+            interval.tick().await;
+            let (network, escrow) = tokio::join!(
+                latest_block(network_subgraph),
+                latest_block(escrow_subgraph)
+            );
+            let (network, escrow) = match (network, escrow) {
+                (Ok(network), Ok(escrow)) => (network, escrow),
+                (Err(e), _) | (_, Err(e)) => {
+                    // keep the last verdict instead of guessing
+                    warn!(error = %e, "Failed to query the latest block of the subgraphs");
+                    continue;
+                }
+            };
+            SUBGRAPH_LATEST_BLOCK
+                .with_label_values(&["network"])
+                .set(network as i64);
+            SUBGRAPH_LATEST_BLOCK
+                .with_label_values(&["escrow"])
+                .set(escrow as i64);
+
+            let Some(max_block_gap) = max_block_gap else {
+                continue;
+            };
+            let gap = network.abs_diff(escrow);
+            match (gap > max_block_gap, diverged) {
+                (true, false) => warn!(
+                    network_block = network,
+                    escrow_block = escrow,
+                    max_block_gap,
+                    "Escrow and network subgraphs are out of sync"
+                ),
+                (false, true) => info!(
+                    network_block = network,
+                    escrow_block = escrow,
+                    "Escrow and network subgraphs are back in sync"
+                ),
+                _ => {}
+            }
+            diverged = gap > max_block_gap;
+            out_of_sync_tx.send_if_modified(|out_of_sync| {
+                let refuse = diverged && action == BlockGapAction::Refuse;
+                std::mem::replace(out_of_sync, refuse) != refuse
+            });
+        }
+    });
+    out_of_sync_rx
+}
+
+async fn latest_block(subgraph: &SubgraphClient) -> anyhow::Result<u64> {
+    let response = subgraph
+        .query_raw(Bytes::from_static(LATEST_BLOCK_QUERY.as_bytes()))
+        .await?;
+    let body: Value = response.json().await?;
+    body["data"]["_meta"]["block"]["number"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("No block number in the `_meta` response: {body}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indexer_config::BlockGapAction;
+    use indexer_monitor::{DeploymentDetails, SubgraphClient};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::spawn_subgraph_consistency;
+    use crate::metrics::SUBGRAPH_LATEST_BLOCK;
+
+    async fn subgraph(mock_server: &MockServer, name: &str, block: u64) -> &'static SubgraphClient {
+        Mock::given(method("POST"))
+            .and(path(format!("/{name}")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {"_meta": {"block": {"number": block}}}
+            })))
+            .mount(mock_server)
+            .await;
+        Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&format!("{}/{name}", mock_server.uri())).unwrap(),
+            )
+            .await,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_refuses_when_subgraphs_diverge() {
+        let mock_server = MockServer::start().await;
+        let network = subgraph(&mock_server, "network", 1000).await;
+        let escrow = subgraph(&mock_server, "escrow", 900).await;
+
+        // within the gap, nothing to refuse
+        let mut in_sync = spawn_subgraph_consistency(
+            network,
+            escrow,
+            Duration::from_secs(60),
+            Some(100),
+            BlockGapAction::Refuse,
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!*in_sync.borrow_and_update());
+        assert_eq!(
+            SUBGRAPH_LATEST_BLOCK.with_label_values(&["escrow"]).get(),
+            900
+        );
+
+        let mut out_of_sync = spawn_subgraph_consistency(
+            network,
+            escrow,
+            Duration::from_secs(60),
+            Some(50),
+            BlockGapAction::Refuse,
+        );
+        tokio::time::timeout(Duration::from_secs(5), out_of_sync.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(*out_of_sync.borrow());
+
+        // only a warning
+        let mut warned = spawn_subgraph_consistency(
+            network,
+            escrow,
+            Duration::from_secs(60),
+            Some(50),
+            BlockGapAction::Warn,
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!*warned.borrow_and_update());
+    }
+}
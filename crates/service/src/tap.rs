@@ -1,16 +1,24 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::tap::checks::allocation_cap_check::AllocationCapCheck;
 use crate::tap::checks::allocation_eligible::AllocationEligible;
 use crate::tap::checks::deny_list_check::DenyListCheck;
+use crate::tap::checks::min_price_check::DeploymentMinimumPrice;
+use crate::tap::checks::pending_value_check::PendingValueCheck;
+use crate::tap::checks::price_list_check::PriceListCheck;
 use crate::tap::checks::receipt_max_val_check::ReceiptMaxValueCheck;
-use crate::tap::checks::sender_balance_check::SenderBalanceCheck;
+use crate::tap::checks::sender_allow_list_check::SenderAllowListCheck;
+use crate::tap::checks::sender_balance_check::{SenderBalanceCheck, TopUpGrace};
+use crate::tap::checks::timeout_check::TimeoutCheck;
 use crate::tap::checks::timestamp_check::TimestampCheck;
+use crate::tap::checks::timestamp_gap_check::TimestampGapCheck;
 use crate::tap::checks::value_check::MinimumValue;
 use alloy::dyn_abi::Eip712Domain;
 use alloy::primitives::Address;
 use indexer_allocation::Allocation;
-use indexer_monitor::EscrowAccounts;
+use indexer_config::{CheckName, ReceiptQueueOverflowPolicy, ReceiptTimeSource};
+use indexer_monitor::{EscrowAccounts, EscrowReservations};
 use receipt_store::{DatabaseReceipt, InnerContext};
 use sqlx::PgPool;
 use std::fmt::Debug;
@@ -18,14 +26,34 @@ use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 use tap_core::receipt::checks::ReceiptCheck;
 use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::watch::Receiver;
+use tokio::sync::watch::{self, Receiver};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{error, warn};
 
+mod check_failure_log;
+mod check_pipeline;
 mod checks;
+mod dead_letter;
+mod escrow_changes;
+mod escrow_metrics;
+mod receipt_archive;
+mod receipt_log;
+mod receipt_replay;
 mod receipt_store;
+mod settlements;
 
-pub use checks::value_check::AgoraQuery;
+pub use check_failure_log::{CheckFailureLog, FailedCheck};
+pub use check_pipeline::{CheckPipeline, CheckSettings, CheckState};
+pub use checks::allocation_eligible::UnknownAllocations;
+pub use checks::value_check::{AgoraQuery, Appraisal, AppraisalSlot, AppraisalSource};
+pub use dead_letter::{DeadLetterRecord, DeadLetterStore};
+pub use escrow_changes::EscrowChanges;
+pub use escrow_metrics::{spawn_escrow_metrics, EscrowHeadroom};
+pub use receipt_archive::{ArchivedQuery, ReceiptArchive};
+pub use receipt_log::{ReceiptLog, ReceiptLogRecord};
+pub use receipt_replay::ReceiptReplay;
+pub use receipt_store::ReceiptQueue;
+pub use settlements::PendingSettlements;
 
 const GRACE_PERIOD: u64 = 60;
 
@@ -34,43 +62,207 @@ pub struct IndexerTapContext {
     domain_separator: Arc<Eip712Domain>,
     receipt_producer: Sender<DatabaseReceipt>,
     cancelation_token: CancellationToken,
+    pgpool: PgPool,
+    /// false after the last write failed because the database couldn't be reached
+    database_available: watch::Receiver<bool>,
+    receipt_queue_overflow: ReceiptQueueOverflowPolicy,
+    receipt_time_source: ReceiptTimeSource,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum AdapterError {
     #[error(transparent)]
     AnyhowError(#[from] anyhow::Error),
+    #[error("Database is unavailable: {0}")]
+    DatabaseUnavailable(sqlx::Error),
+    /// Refused without trying, until the database is reachable again
+    #[error("Database is unavailable")]
+    DatabaseDown,
+    #[error("Receipt queue is full")]
+    ReceiptQueueFull,
+}
+
+impl AdapterError {
+    /// Errors caused by not reaching the database at all, as opposed to
+    /// the database refusing the query
+    pub fn is_connection_error(error: &sqlx::Error) -> bool {
+        matches!(
+            error,
+            sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::WorkerCrashed
+        )
+    }
 }
 
 impl IndexerTapContext {
+    /// Receipt checks with their names, in the order they should run
     pub async fn get_checks(
         pgpool: PgPool,
         indexer_allocations: Receiver<HashMap<Address, Allocation>>,
         escrow_accounts: Receiver<EscrowAccounts>,
-        timestamp_error_tolerance: Duration,
-        receipt_max_value: u128,
-    ) -> Vec<ReceiptCheck> {
-        vec![
-            Arc::new(AllocationEligible::new(indexer_allocations)),
-            Arc::new(SenderBalanceCheck::new(escrow_accounts)),
-            Arc::new(TimestampCheck::new(timestamp_error_tolerance)),
-            Arc::new(DenyListCheck::new(pgpool.clone()).await),
-            Arc::new(ReceiptMaxValueCheck::new(receipt_max_value)),
-            Arc::new(MinimumValue::new(pgpool, Duration::from_secs(GRACE_PERIOD)).await),
-        ]
+        reservations: EscrowReservations,
+        state: &CheckState,
+        settings: &CheckSettings,
+    ) -> Vec<(CheckName, ReceiptCheck)> {
+        let mut checks: Vec<(CheckName, ReceiptCheck)> = vec![
+            (
+                CheckName::AllocationEligible,
+                Arc::new(AllocationEligible::new(
+                    indexer_allocations.clone(),
+                    settings.unknown_allocation.0,
+                    settings.unknown_allocation.1,
+                    state.unknown_allocations.clone(),
+                )),
+            ),
+            (
+                CheckName::SenderBalance,
+                Arc::new(
+                    SenderBalanceCheck::new(
+                        escrow_accounts.clone(),
+                        reservations.clone(),
+                        settings.escrow_top_up_grace.map(|(max_value, duration)| {
+                            TopUpGrace::new(max_value, duration, state.top_up_grace.clone())
+                        }),
+                        settings.token,
+                    )
+                    .with_escrow_freshness(state.escrow_freshness.clone()),
+                ),
+            ),
+            (
+                CheckName::Timestamp,
+                Arc::new(TimestampCheck::new(settings.timestamp_error_tolerance)),
+            ),
+        ];
+        match settings.sender_list_check() {
+            Some(CheckName::DenyList) => checks.push((
+                CheckName::DenyList,
+                Arc::new(DenyListCheck::new(pgpool.clone()).await),
+            )),
+            Some(CheckName::SenderAllowList) => checks.push((
+                CheckName::SenderAllowList,
+                Arc::new(SenderAllowListCheck::new(state.sender_allow_list.clone())),
+            )),
+            // senders aren't checked against a list
+            _ => {}
+        }
+        checks.push((
+            CheckName::ReceiptMaxValue,
+            Arc::new(ReceiptMaxValueCheck::new(settings.receipt_max_value)),
+        ));
+        checks.push((
+            CheckName::MinimumPrice,
+            Arc::new(DeploymentMinimumPrice::new(
+                settings.min_price,
+                settings.min_price_per_deployment.clone(),
+            )),
+        ));
+        checks.push((
+            CheckName::MinimumValue,
+            Arc::new(
+                MinimumValue::new(
+                    pgpool.clone(),
+                    Duration::from_secs(GRACE_PERIOD),
+                    settings.token,
+                    settings.no_appraisal_policy,
+                )
+                .await
+                .with_reservations(reservations),
+            ),
+        ));
+        if let Some(max_pending_value) = settings.max_pending_value_per_sender {
+            checks.push((
+                CheckName::PendingValue,
+                Arc::new(PendingValueCheck::new(
+                    pgpool.clone(),
+                    escrow_accounts,
+                    max_pending_value,
+                )),
+            ));
+        }
+        if settings.enforce_allocation_cap {
+            checks.push((
+                CheckName::AllocationCap,
+                Arc::new(AllocationCapCheck::new(pgpool, indexer_allocations)),
+            ));
+        }
+        if let Some((max_gap, action)) = settings.max_timestamp_gap {
+            checks.push((
+                CheckName::TimestampGap,
+                Arc::new(TimestampGapCheck::new(
+                    max_gap,
+                    action,
+                    state.timestamp_history.clone(),
+                )),
+            ));
+        }
+        if let Some(price_list) = &settings.price_list {
+            checks.push((
+                CheckName::PriceList,
+                Arc::new(PriceListCheck::new(price_list.clone())),
+            ));
+        }
+
+        for name in settings.check_timeouts.keys() {
+            if !checks.iter().any(|(check, _)| check == name) {
+                warn!(
+                    check = name.as_str(),
+                    "Timeout configured for a disabled check"
+                );
+            }
+        }
+
+        settings
+            .ordered(checks)
+            .into_iter()
+            .map(|(name, check)| {
+                (
+                    name,
+                    TimeoutCheck::wrap(name.as_str(), settings.check_timeout(name), check),
+                )
+            })
+            .collect()
+    }
+
+    /// Handle used to observe how many receipts are waiting to be stored
+    pub fn receipt_queue(&self) -> ReceiptQueue {
+        ReceiptQueue(self.receipt_producer.clone())
+    }
+
+    /// Context handling a full receipt queue following `policy`
+    pub fn with_receipt_queue_overflow(mut self, policy: ReceiptQueueOverflowPolicy) -> Self {
+        self.receipt_queue_overflow = policy;
+        self
+    }
+
+    /// Context also storing when receipts were received, with
+    /// [ReceiptTimeSource::ServerReceive]
+    pub fn with_receipt_time_source(mut self, source: ReceiptTimeSource) -> Self {
+        self.receipt_time_source = source;
+        self
     }
 
     pub async fn new(pgpool: PgPool, domain_separator: Eip712Domain) -> Self {
         const MAX_RECEIPT_QUEUE_SIZE: usize = 1000;
         let (tx, rx) = mpsc::channel(MAX_RECEIPT_QUEUE_SIZE);
         let cancelation_token = CancellationToken::new();
-        let inner = InnerContext { pgpool };
+        let (database_available_tx, database_available) = watch::channel(true);
+        let inner = InnerContext {
+            pgpool: pgpool.clone(),
+            database_available: Arc::new(database_available_tx),
+        };
         Self::spawn_store_receipt_task(inner, rx, cancelation_token.clone());
 
         Self {
             cancelation_token,
             receipt_producer: tx,
             domain_separator: Arc::new(domain_separator),
+            pgpool,
+            database_available,
+            receipt_queue_overflow: Default::default(),
+            receipt_time_source: Default::default(),
         }
     }
 }
@@ -0,0 +1,260 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Logs receipt check failures, throttling similar ones
+//!
+//! A sender flooding malformed receipts fails the same check over and over.
+//! Past the configured number of log lines per window, the failures of a
+//! sender in the same check are only counted, and a summary of how many were
+//! suppressed is logged once the window is over.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+
+use indexer_config::{CheckFailureLoggingConfig, CheckName};
+use tap_core::receipt::Context;
+use thegraph_core::Address;
+use tracing::warn;
+
+/// Sender and check that refused the receipt, the error messages carrying
+/// values of the receipt
+type FailureKey = (Option<Address>, Option<CheckName>);
+
+/// First check refusing the receipt, recorded in its [Context] by the checks
+#[derive(Clone, Default)]
+pub struct FailedCheck(Arc<Mutex<Option<CheckName>>>);
+
+impl FailedCheck {
+    /// Records `check` in the context, if it is the first to refuse the receipt
+    pub fn record_in(ctx: &Context, check: CheckName) {
+        if let Some(failed) = ctx.get::<FailedCheck>() {
+            failed
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert(check);
+        }
+    }
+
+    pub fn get(ctx: &Context) -> Option<CheckName> {
+        *ctx.get::<FailedCheck>()?
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Identical failures logged and suppressed in the current window
+struct Failures {
+    window_start: Instant,
+    logged: usize,
+    suppressed: usize,
+}
+
+impl Failures {
+    fn new(window_start: Instant) -> Self {
+        Self {
+            window_start,
+            logged: 0,
+            suppressed: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Windows {
+    last_sweep: Option<Instant>,
+    failures: HashMap<FailureKey, Failures>,
+}
+
+/// What to log for a failure
+#[derive(Debug, PartialEq)]
+struct Recorded {
+    log: bool,
+    /// failures suppressed in windows that are over
+    summaries: Vec<(FailureKey, usize)>,
+}
+
+pub struct CheckFailureLog {
+    limit: Option<(usize, Duration)>,
+    windows: Mutex<Windows>,
+}
+
+impl CheckFailureLog {
+    pub fn new(config: Option<&CheckFailureLoggingConfig>) -> Self {
+        Self {
+            limit: config.map(|config| (config.max_failures, config.window_secs)),
+            windows: Default::default(),
+        }
+    }
+
+    pub fn log(&self, sender: Option<Address>, check: Option<CheckName>, failure: &str) {
+        let recorded = self.record(sender, check, Instant::now());
+        log_summaries(recorded.summaries);
+        if recorded.log {
+            warn!(?sender, ?check, %failure, "Receipt check failed");
+        }
+    }
+
+    /// Logs the summaries of the windows that are over every window, so the
+    /// last one is reported even when no other failure follows. Stops once
+    /// the log is dropped
+    pub fn spawn_flush(self: &Arc<Self>) {
+        let Some((_, window)) = self.limit.filter(|(_, window)| !window.is_zero()) else {
+            return;
+        };
+        let log = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(window);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(log) = Weak::upgrade(&log) else {
+                    break;
+                };
+                log_summaries(log.flush(Instant::now()));
+            }
+        });
+    }
+
+    /// Forgets the windows that are over, summing up what they suppressed
+    fn flush(&self, now: Instant) -> Vec<(FailureKey, usize)> {
+        let Some((_, window)) = self.limit else {
+            return Vec::new();
+        };
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        sweep(&mut windows, now, window)
+    }
+
+    fn record(&self, sender: Option<Address>, check: Option<CheckName>, now: Instant) -> Recorded {
+        let Some((max_failures, window)) = self.limit else {
+            return Recorded {
+                log: true,
+                summaries: Vec::new(),
+            };
+        };
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut summaries = Vec::new();
+
+        if windows
+            .last_sweep
+            .map_or(true, |last_sweep| now - last_sweep >= window)
+        {
+            summaries = sweep(&mut windows, now, window);
+        }
+
+        let key = (sender, check);
+        let failures = windows
+            .failures
+            .entry(key)
+            .or_insert_with(|| Failures::new(now));
+        if now - failures.window_start >= window {
+            if failures.suppressed > 0 {
+                summaries.push((key, failures.suppressed));
+            }
+            *failures = Failures::new(now);
+        }
+
+        let log = failures.logged < max_failures;
+        if log {
+            failures.logged += 1;
+        } else {
+            failures.suppressed += 1;
+        }
+        Recorded { log, summaries }
+    }
+}
+
+/// Forgets the windows over at `now`, returning what they suppressed
+fn sweep(windows: &mut Windows, now: Instant, window: Duration) -> Vec<(FailureKey, usize)> {
+    let mut summaries = Vec::new();
+    windows.last_sweep = Some(now);
+    windows.failures.retain(|key, failures| {
+        if now - failures.window_start < window {
+            return true;
+        }
+        if failures.suppressed > 0 {
+            summaries.push((*key, failures.suppressed));
+        }
+        false
+    });
+    summaries
+}
+
+fn log_summaries(summaries: Vec<(FailureKey, usize)>) {
+    for ((sender, check), suppressed) in summaries {
+        warn!(
+            ?sender,
+            ?check,
+            suppressed,
+            "Suppressed {suppressed} similar receipt check failures"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use indexer_config::{CheckFailureLoggingConfig, CheckName};
+    use thegraph_core::Address;
+
+    use super::CheckFailureLog;
+
+    #[test]
+    fn test_identical_failures_are_suppressed() {
+        let log = CheckFailureLog::new(Some(&CheckFailureLoggingConfig {
+            max_failures: 2,
+            window_secs: Duration::from_secs(60),
+        }));
+        let sender = Some(Address::repeat_byte(1));
+        let start = Instant::now();
+
+        let balance = Some(CheckName::SenderBalance);
+
+        let logged = (0..5)
+            .filter(|_| log.record(sender, balance, start).log)
+            .count();
+        assert_eq!(logged, 2);
+        // other senders and other checks have their own budget
+        assert!(log.record(Some(Address::ZERO), balance, start).log);
+        assert!(log.record(sender, Some(CheckName::Timestamp), start).log);
+
+        // the next window starts with the summary of the previous one
+        let recorded = log.record(sender, balance, start + Duration::from_secs(60));
+        assert!(recorded.log);
+        assert_eq!(recorded.summaries, vec![((sender, balance), 3)]);
+    }
+
+    #[test]
+    fn test_last_window_is_flushed() {
+        let log = CheckFailureLog::new(Some(&CheckFailureLoggingConfig {
+            max_failures: 1,
+            window_secs: Duration::from_secs(60),
+        }));
+        let sender = Some(Address::repeat_byte(1));
+        let balance = Some(CheckName::SenderBalance);
+        let start = Instant::now();
+        for _ in 0..3 {
+            log.record(sender, balance, start);
+        }
+
+        assert!(log.flush(start + Duration::from_secs(30)).is_empty());
+        // without any other failure, the summary is flushed once the window is over
+        assert_eq!(
+            log.flush(start + Duration::from_secs(60)),
+            vec![((sender, balance), 2)]
+        );
+        assert!(log.flush(start + Duration::from_secs(120)).is_empty());
+    }
+
+    #[test]
+    fn test_failures_are_logged_without_limit() {
+        let log = CheckFailureLog::new(None);
+        let now = Instant::now();
+        assert!((0..100).all(|_| log.record(None, None, now).log));
+    }
+}
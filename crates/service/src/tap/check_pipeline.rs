@@ -0,0 +1,838 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receipt checks that can be rebuilt at runtime
+//!
+//! The tap manager is created once with a single [ReloadableChecks] check that
+//! delegates to the current set of checks. Reloading builds a new set of checks
+//! and swaps it in an [ArcSwap]: requests that already started keep the checks
+//! they loaded, new requests pick up the new ones, without taking a lock.
+//!
+//! The [CheckMode] decides whether a receipt is refused on the first failing
+//! check or only after running all of them, in which case the sender gets every
+//! failure in a single error.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use alloy::primitives::Address;
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use indexer_allocation::Allocation;
+use indexer_config::{
+    CheckMode, CheckName, Config, PriceListConfig, ServiceTapConfig, TimestampGapAction,
+    UnknownAllocationPolicy,
+};
+use indexer_monitor::{EscrowAccounts, EscrowReservations};
+use sqlx::PgPool;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult, ReceiptCheck},
+    state::Checking,
+    Context, ReceiptWithState,
+};
+use thegraph_core::DeploymentId;
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+use crate::middleware::{Deadline, EscrowFreshnessState};
+
+use super::{
+    checks::{
+        allocation_eligible::UnknownAllocations, sender_balance_check::TopUpGraceWindows,
+        timestamp_gap_check::TimestampHistory, value_check::NoAppraisalPolicy,
+    },
+    receipt_replay::SKIPPED_CHECKS,
+    FailedCheck, IndexerTapContext, ReceiptReplay,
+};
+
+/// Configurable values used to build the receipt checks
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckSettings {
+    pub timestamp_error_tolerance: Duration,
+    pub receipt_max_value: u128,
+    pub min_price: u128,
+    pub min_price_per_deployment: HashMap<DeploymentId, u128>,
+    pub no_appraisal_policy: NoAppraisalPolicy,
+    /// only these senders are accepted, as configured
+    pub sender_allow_list: Option<HashSet<Address>>,
+    /// senders are checked against the deny-list
+    pub deny_list: bool,
+    pub max_pending_value_per_sender: Option<u128>,
+    pub enforce_allocation_cap: bool,
+    /// what to do with receipts of unknown allocations, and for how long
+    pub unknown_allocation: (UnknownAllocationPolicy, Duration),
+    /// value accepted from a sender without escrow balance, and for how long
+    pub escrow_top_up_grace: Option<(u128, Duration)>,
+    /// token receipts are accepted in, if they state one
+    pub token: Option<Address>,
+    pub max_timestamp_gap: Option<(Duration, TimestampGapAction)>,
+    pub default_check_timeout: Duration,
+    /// timeouts of specific checks
+    pub check_timeouts: HashMap<CheckName, Duration>,
+    /// checks to run ahead of the others
+    pub check_order: Vec<CheckName>,
+    pub check_mode: CheckMode,
+    pub price_list: Option<PriceListConfig>,
+}
+
+impl CheckSettings {
+    pub fn new(tap: &ServiceTapConfig, timestamp_error_tolerance: Duration) -> Self {
+        Self {
+            timestamp_error_tolerance,
+            receipt_max_value: tap.max_receipt_value_grt.get_value(),
+            min_price: tap.min_price_grt.as_ref().map_or(0, |grt| grt.get_value()),
+            min_price_per_deployment: tap
+                .min_price_per_deployment_grt
+                .iter()
+                .map(|(deployment, grt)| (*deployment, grt.get_value()))
+                .collect(),
+            no_appraisal_policy: tap
+                .no_appraisal_policy
+                .as_ref()
+                .map(Into::into)
+                .unwrap_or_default(),
+            sender_allow_list: tap.sender_allow_list.clone(),
+            deny_list: tap
+                .enable_deny_list
+                .unwrap_or(tap.sender_allow_list.is_none()),
+            max_pending_value_per_sender: tap
+                .max_pending_value_per_sender_grt
+                .as_ref()
+                .map(|grt| grt.get_value()),
+            enforce_allocation_cap: tap.enforce_allocation_cap,
+            unknown_allocation: (
+                tap.unknown_allocation_policy,
+                tap.unknown_allocation_grace_secs,
+            ),
+            escrow_top_up_grace: tap
+                .escrow_top_up_grace
+                .as_ref()
+                .map(|grace| (grace.max_value_grt.get_value(), grace.duration_secs)),
+            token: tap.token_address,
+            max_timestamp_gap: tap
+                .max_timestamp_gap_secs
+                .map(|max_gap| (max_gap, tap.timestamp_gap_action)),
+            default_check_timeout: tap.check_timeout_secs,
+            check_timeouts: tap.check_timeouts_secs.clone(),
+            check_order: tap.check_policy.order.clone(),
+            check_mode: tap.check_policy.mode,
+            price_list: tap.price_list.clone(),
+        }
+    }
+
+    /// Check of the receipt sender against a list, the allow-list replacing
+    /// the deny-list when it is set
+    pub fn sender_list_check(&self) -> Option<CheckName> {
+        match (&self.sender_allow_list, self.deny_list) {
+            (Some(_), _) => Some(CheckName::SenderAllowList),
+            (None, true) => Some(CheckName::DenyList),
+            (None, false) => None,
+        }
+    }
+
+    /// Checks built from these settings, in the order they run
+    pub fn enabled_checks(&self) -> Vec<CheckName> {
+        let mut checks = vec![
+            CheckName::AllocationEligible,
+            CheckName::SenderBalance,
+            CheckName::Timestamp,
+        ];
+        checks.extend(self.sender_list_check());
+        checks.extend([
+            CheckName::ReceiptMaxValue,
+            CheckName::MinimumPrice,
+            CheckName::MinimumValue,
+        ]);
+        if self.max_pending_value_per_sender.is_some() {
+            checks.push(CheckName::PendingValue);
+        }
+        if self.enforce_allocation_cap {
+            checks.push(CheckName::AllocationCap);
+        }
+        if self.max_timestamp_gap.is_some() {
+            checks.push(CheckName::TimestampGap);
+        }
+        if self.price_list.is_some() {
+            checks.push(CheckName::PriceList);
+        }
+        self.ordered(checks.into_iter().map(|name| (name, ())).collect())
+            .into_iter()
+            .map(|(name, ())| name)
+            .collect()
+    }
+
+    /// Moves the checks listed in the policy first, the others keep their
+    /// default order
+    pub fn ordered<T>(&self, mut checks: Vec<(CheckName, T)>) -> Vec<(CheckName, T)> {
+        let mut ordered = Vec::with_capacity(checks.len());
+        for name in &self.check_order {
+            match checks.iter().position(|(check, _)| check == name) {
+                Some(index) => ordered.push(checks.remove(index)),
+                None => warn!(
+                    check = name.as_str(),
+                    "Ordering a disabled or repeated check"
+                ),
+            }
+        }
+        ordered.extend(checks);
+        ordered
+    }
+
+    /// How long the check may take
+    pub fn check_timeout(&self, name: CheckName) -> Duration {
+        self.check_timeouts
+            .get(&name)
+            .copied()
+            .unwrap_or(self.default_check_timeout)
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            &config.service.tap,
+            config.tap.rav_request.timestamp_buffer_secs,
+        )
+    }
+
+    /// Human readable list of the values that differ between both settings
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.timestamp_error_tolerance != other.timestamp_error_tolerance {
+            changes.push(format!(
+                "timestamp_error_tolerance: {:?} -> {:?}",
+                self.timestamp_error_tolerance, other.timestamp_error_tolerance
+            ));
+        }
+        if self.receipt_max_value != other.receipt_max_value {
+            changes.push(format!(
+                "receipt_max_value: {} -> {}",
+                self.receipt_max_value, other.receipt_max_value
+            ));
+        }
+        if self.min_price != other.min_price {
+            changes.push(format!(
+                "min_price: {} -> {}",
+                self.min_price, other.min_price
+            ));
+        }
+        if self.min_price_per_deployment != other.min_price_per_deployment {
+            changes.push(format!(
+                "min_price_per_deployment: {:?} -> {:?}",
+                self.min_price_per_deployment, other.min_price_per_deployment
+            ));
+        }
+        if self.no_appraisal_policy != other.no_appraisal_policy {
+            changes.push(format!(
+                "no_appraisal_policy: {:?} -> {:?}",
+                self.no_appraisal_policy, other.no_appraisal_policy
+            ));
+        }
+        if self.sender_allow_list != other.sender_allow_list {
+            changes.push(format!(
+                "sender_allow_list: {:?} -> {:?}",
+                self.sender_allow_list, other.sender_allow_list
+            ));
+        }
+        if self.deny_list != other.deny_list {
+            changes.push(format!(
+                "deny_list: {} -> {}",
+                self.deny_list, other.deny_list
+            ));
+        }
+        if self.max_pending_value_per_sender != other.max_pending_value_per_sender {
+            changes.push(format!(
+                "max_pending_value_per_sender: {:?} -> {:?}",
+                self.max_pending_value_per_sender, other.max_pending_value_per_sender
+            ));
+        }
+        if self.enforce_allocation_cap != other.enforce_allocation_cap {
+            changes.push(format!(
+                "enforce_allocation_cap: {} -> {}",
+                self.enforce_allocation_cap, other.enforce_allocation_cap
+            ));
+        }
+        if self.unknown_allocation != other.unknown_allocation {
+            changes.push(format!(
+                "unknown_allocation: {:?} -> {:?}",
+                self.unknown_allocation, other.unknown_allocation
+            ));
+        }
+        if self.escrow_top_up_grace != other.escrow_top_up_grace {
+            changes.push(format!(
+                "escrow_top_up_grace: {:?} -> {:?}",
+                self.escrow_top_up_grace, other.escrow_top_up_grace
+            ));
+        }
+        if self.token != other.token {
+            changes.push(format!("token: {:?} -> {:?}", self.token, other.token));
+        }
+        if self.max_timestamp_gap != other.max_timestamp_gap {
+            changes.push(format!(
+                "max_timestamp_gap: {:?} -> {:?}",
+                self.max_timestamp_gap, other.max_timestamp_gap
+            ));
+        }
+        if self.default_check_timeout != other.default_check_timeout {
+            changes.push(format!(
+                "default_check_timeout: {:?} -> {:?}",
+                self.default_check_timeout, other.default_check_timeout
+            ));
+        }
+        if self.check_timeouts != other.check_timeouts {
+            changes.push(format!(
+                "check_timeouts: {:?} -> {:?}",
+                self.check_timeouts, other.check_timeouts
+            ));
+        }
+        if self.check_order != other.check_order {
+            changes.push(format!(
+                "check_order: {:?} -> {:?}",
+                self.check_order, other.check_order
+            ));
+        }
+        if self.check_mode != other.check_mode {
+            changes.push(format!(
+                "check_mode: {:?} -> {:?}",
+                self.check_mode, other.check_mode
+            ));
+        }
+        if self.price_list != other.price_list {
+            changes.push(format!(
+                "price_list: {:?} -> {:?}",
+                self.price_list, other.price_list
+            ));
+        }
+        changes
+    }
+}
+
+/// What the checks learn from the receipts they see or are told at runtime,
+/// kept across reloads
+#[derive(Clone, Default)]
+pub struct CheckState {
+    pub timestamp_history: TimestampHistory,
+    pub top_up_grace: TopUpGraceWindows,
+    pub unknown_allocations: UnknownAllocations,
+    /// how stale the escrow accounts the balances are read from are, unset
+    /// when they were provided as they are
+    pub escrow_freshness: Option<EscrowFreshnessState>,
+    /// senders accepted by the allow-list check, as last configured or set
+    /// through the admin routes
+    pub sender_allow_list: Arc<RwLock<HashSet<Address>>>,
+}
+
+impl CheckState {
+    /// State of the checks serving requests, with what is kept in the database
+    pub async fn load(pgpool: PgPool) -> Self {
+        Self {
+            top_up_grace: TopUpGraceWindows::load(pgpool).await,
+            ..Default::default()
+        }
+    }
+}
+
+/// Named checks, run in order following the [CheckMode]
+pub struct CheckRunner {
+    mode: CheckMode,
+    checks: Vec<(CheckName, ReceiptCheck)>,
+}
+
+impl CheckRunner {
+    pub fn new(mode: CheckMode, checks: Vec<(CheckName, ReceiptCheck)>) -> Self {
+        Self { mode, checks }
+    }
+
+    pub async fn run(&self, ctx: &Context, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let mut failures = Vec::new();
+        for (name, check) in &self.checks {
+            if let Err(e) = check.check(ctx, receipt).await {
+                FailedCheck::record_in(ctx, *name);
+                if self.mode == CheckMode::FirstFailure {
+                    return Err(e);
+                }
+                failures.push((*name, e));
+            }
+        }
+
+        match failures.len() {
+            // the query won't be served, the receipt mustn't be stored
+            0 if ctx
+                .get::<Deadline>()
+                .is_some_and(|deadline| deadline.remaining().is_none()) =>
+            {
+                Err(CheckError::Retryable(anyhow!(
+                    "Deadline passed while checking the receipt"
+                )))
+            }
+            0 => Ok(()),
+            1 => Err(failures.pop().expect("one failure").1),
+            _ => {
+                // only worth retrying when none of the checks refused the receipt for good
+                let retryable = failures
+                    .iter()
+                    .all(|(_, e)| matches!(e, CheckError::Retryable(_)));
+                let message = failures
+                    .into_iter()
+                    .map(|(name, e)| match e {
+                        CheckError::Failed(e) | CheckError::Retryable(e) => {
+                            format!("{}: {e}", name.as_str())
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                let error = anyhow!("Receipt failed several checks: {message}");
+                Err(if retryable {
+                    CheckError::Retryable(error)
+                } else {
+                    CheckError::Failed(error)
+                })
+            }
+        }
+    }
+}
+
+/// Check that runs whatever set of checks is current when the receipt arrives
+pub struct ReloadableChecks {
+    checks: Arc<ArcSwap<CheckRunner>>,
+}
+
+#[async_trait::async_trait]
+impl Check for ReloadableChecks {
+    async fn check(&self, ctx: &Context, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let checks = self.checks.load_full();
+        checks.run(ctx, receipt).await
+    }
+}
+
+/// Builds the receipt checks and swaps them when the settings change
+pub struct CheckPipeline {
+    pgpool: PgPool,
+    indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+    escrow_accounts: watch::Receiver<EscrowAccounts>,
+    reservations: EscrowReservations,
+    state: CheckState,
+    settings: Mutex<CheckSettings>,
+    checks: Arc<ArcSwap<CheckRunner>>,
+}
+
+impl CheckPipeline {
+    pub async fn new(
+        pgpool: PgPool,
+        indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+        escrow_accounts: watch::Receiver<EscrowAccounts>,
+        reservations: EscrowReservations,
+        state: CheckState,
+        settings: CheckSettings,
+    ) -> Self {
+        if let Some(sender_allow_list) = &settings.sender_allow_list {
+            *state.sender_allow_list.write().unwrap() = sender_allow_list.clone();
+        }
+        let checks = IndexerTapContext::get_checks(
+            pgpool.clone(),
+            indexer_allocations.clone(),
+            escrow_accounts.clone(),
+            reservations.clone(),
+            &state,
+            &settings,
+        )
+        .await;
+        let checks = CheckRunner::new(settings.check_mode, checks);
+        Self {
+            pgpool,
+            indexer_allocations,
+            escrow_accounts,
+            reservations,
+            state,
+            settings: Mutex::new(settings),
+            checks: Arc::new(ArcSwap::from_pointee(checks)),
+        }
+    }
+
+    /// Check to be handed to the tap manager
+    pub fn checks(&self) -> ReceiptCheck {
+        Arc::new(ReloadableChecks {
+            checks: self.checks.clone(),
+        })
+    }
+
+    /// Rebuilds the checks if the settings changed, returning what changed
+    pub async fn reload(&self, settings: CheckSettings) -> Vec<String> {
+        // hold the lock during the rebuild so concurrent reloads are serialized
+        let mut current = self.settings.lock().await;
+        let changes = current.diff(&settings);
+        if changes.is_empty() {
+            info!("Receipt checks reload requested, but nothing changed");
+            return changes;
+        }
+
+        // the configured allow-list replaces the one set at runtime once it changes
+        if let Some(sender_allow_list) = settings
+            .sender_allow_list
+            .as_ref()
+            .filter(|&list| current.sender_allow_list.as_ref() != Some(list))
+        {
+            *self.state.sender_allow_list.write().unwrap() = sender_allow_list.clone();
+        }
+        let checks = IndexerTapContext::get_checks(
+            self.pgpool.clone(),
+            self.indexer_allocations.clone(),
+            self.escrow_accounts.clone(),
+            self.reservations.clone(),
+            &self.state,
+            &settings,
+        )
+        .await;
+        self.checks
+            .store(Arc::new(CheckRunner::new(settings.check_mode, checks)));
+        *current = settings;
+
+        for change in &changes {
+            info!(%change, "Receipt checks reloaded");
+        }
+        changes
+    }
+
+    /// Senders accepted by the allow-list check, unset when it is disabled
+    pub async fn sender_allow_list(&self) -> Option<HashSet<Address>> {
+        // the lock keeps a reload from enabling or disabling the check meanwhile
+        let settings = self.settings.lock().await;
+        settings.sender_allow_list.as_ref()?;
+        Some(self.state.sender_allow_list.read().unwrap().clone())
+    }
+
+    /// Replaces the senders accepted by the allow-list check, taking effect for
+    /// the next receipt. Returns the previous senders, unset when the check is
+    /// disabled and nothing was replaced
+    pub async fn set_sender_allow_list(
+        &self,
+        senders: HashSet<Address>,
+    ) -> Option<HashSet<Address>> {
+        let settings = self.settings.lock().await;
+        settings.sender_allow_list.as_ref()?;
+        let mut sender_allow_list = self.state.sender_allow_list.write().unwrap();
+        Some(std::mem::replace(&mut *sender_allow_list, senders))
+    }
+
+    /// Replay of the stored receipts against checks built from the current
+    /// settings, sharing no state with the checks serving requests
+    pub async fn replay(&self) -> ReceiptReplay {
+        let settings = CheckSettings {
+            // the balance is judged as it is now, not in hope of a top-up
+            escrow_top_up_grace: None,
+            ..self.settings.lock().await.clone()
+        };
+        let checks = IndexerTapContext::get_checks(
+            self.pgpool.clone(),
+            self.indexer_allocations.clone(),
+            self.escrow_accounts.clone(),
+            // reservations are only ever held for live queries
+            EscrowReservations::default(),
+            // nor does the replay learn from the receipts it sees, the
+            // senders allowed are those allowed now
+            &CheckState {
+                sender_allow_list: self.state.sender_allow_list.clone(),
+                ..Default::default()
+            },
+            &settings,
+        )
+        .await
+        .into_iter()
+        .filter(|(name, _)| !SKIPPED_CHECKS.contains(name))
+        .collect();
+        ReceiptReplay {
+            pgpool: self.pgpool.clone(),
+            checks,
+            indexer_allocations: self.indexer_allocations.clone(),
+            escrow_accounts: self.escrow_accounts.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use alloy::primitives::Address;
+    use anyhow::anyhow;
+    use indexer_config::{CheckMode, CheckName, TimestampGapAction, UnknownAllocationPolicy};
+    use indexer_monitor::EscrowAccounts;
+    use sqlx::PgPool;
+    use tap_core::receipt::{
+        checks::{Check, CheckError, CheckResult, ReceiptCheck},
+        state::Checking,
+        Context, ReceiptWithState,
+    };
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, ESCROW_ACCOUNTS_BALANCES,
+        ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, INDEXER_ALLOCATIONS,
+    };
+    use tokio::sync::watch;
+
+    use super::{CheckPipeline, CheckRunner, CheckSettings, CheckState};
+    use crate::tap::{AgoraQuery, IndexerTapContext};
+
+    fn settings(receipt_max_value: u128) -> CheckSettings {
+        CheckSettings {
+            timestamp_error_tolerance: Duration::from_secs(30),
+            receipt_max_value,
+            min_price: 0,
+            min_price_per_deployment: HashMap::new(),
+            no_appraisal_policy: Default::default(),
+            sender_allow_list: None,
+            deny_list: true,
+            max_pending_value_per_sender: None,
+            enforce_allocation_cap: false,
+            unknown_allocation: (UnknownAllocationPolicy::Reject, Duration::ZERO),
+            escrow_top_up_grace: None,
+            token: None,
+            max_timestamp_gap: None,
+            default_check_timeout: Duration::from_secs(5),
+            check_timeouts: HashMap::new(),
+            check_order: vec![],
+            check_mode: CheckMode::FirstFailure,
+            price_list: None,
+        }
+    }
+
+    /// Counts its runs and fails with the given error, if any
+    struct StubCheck {
+        runs: Arc<AtomicUsize>,
+        error: Option<fn() -> CheckError>,
+    }
+
+    #[async_trait::async_trait]
+    impl Check for StubCheck {
+        async fn check(&self, _: &Context, _: &ReceiptWithState<Checking>) -> CheckResult {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            self.error.map_or(Ok(()), |error| Err(error()))
+        }
+    }
+
+    fn stub(runs: &Arc<AtomicUsize>, error: Option<fn() -> CheckError>) -> ReceiptCheck {
+        Arc::new(StubCheck {
+            runs: runs.clone(),
+            error,
+        })
+    }
+
+    fn failed() -> CheckError {
+        CheckError::Failed(anyhow!("failed"))
+    }
+
+    fn retryable() -> CheckError {
+        CheckError::Retryable(anyhow!("try later"))
+    }
+
+    async fn receipt() -> ReceiptWithState<Checking> {
+        ReceiptWithState::new(create_signed_receipt(SignedReceiptRequest::builder().build()).await)
+    }
+
+    #[tokio::test]
+    async fn test_first_failure_stops_at_first_error() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runner = CheckRunner::new(
+            CheckMode::FirstFailure,
+            vec![
+                (CheckName::Timestamp, stub(&runs, None)),
+                (CheckName::DenyList, stub(&runs, Some(retryable))),
+                (CheckName::MinimumValue, stub(&runs, Some(failed))),
+            ],
+        );
+
+        let error = runner.run(&Context::new(), &receipt().await).await;
+        assert!(matches!(error, Err(CheckError::Retryable(e)) if e.to_string() == "try later"));
+        // the last check never ran
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_collect_all_aggregates_errors() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let runner = CheckRunner::new(
+            CheckMode::CollectAll,
+            vec![
+                (CheckName::Timestamp, stub(&runs, Some(retryable))),
+                (CheckName::DenyList, stub(&runs, None)),
+                (CheckName::MinimumValue, stub(&runs, Some(failed))),
+            ],
+        );
+
+        let error = runner.run(&Context::new(), &receipt().await).await;
+        let Err(CheckError::Failed(e)) = error else {
+            panic!("expected a failure, got {error:?}");
+        };
+        assert_eq!(
+            e.to_string(),
+            "Receipt failed several checks: timestamp: try later; minimum_value: failed"
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        // only retryable failures keep the receipt retryable
+        let runner = CheckRunner::new(
+            CheckMode::CollectAll,
+            vec![
+                (CheckName::Timestamp, stub(&runs, Some(retryable))),
+                (CheckName::DenyList, stub(&runs, Some(retryable))),
+            ],
+        );
+        let error = runner.run(&Context::new(), &receipt().await).await;
+        assert!(matches!(error, Err(CheckError::Retryable(_))));
+
+        // a single failure is returned as is
+        let runner = CheckRunner::new(
+            CheckMode::CollectAll,
+            vec![(CheckName::Timestamp, stub(&runs, Some(failed)))],
+        );
+        let error = runner.run(&Context::new(), &receipt().await).await;
+        assert!(matches!(error, Err(CheckError::Failed(e)) if e.to_string() == "failed"));
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_checks_run_in_configured_order(pgpool: PgPool) {
+        let settings = CheckSettings {
+            sender_allow_list: Some(HashSet::from([Address::ZERO])),
+            deny_list: false,
+            max_pending_value_per_sender: Some(1000),
+            enforce_allocation_cap: true,
+            max_timestamp_gap: Some((Duration::from_secs(60), TimestampGapAction::Reject)),
+            check_order: vec![CheckName::TimestampGap, CheckName::Timestamp],
+            ..settings(1000)
+        };
+        let checks = IndexerTapContext::get_checks(
+            pgpool,
+            watch::channel(INDEXER_ALLOCATIONS.clone()).1,
+            watch::channel(EscrowAccounts::default()).1,
+            Default::default(),
+            &CheckState::default(),
+            &settings,
+        )
+        .await;
+
+        let names = checks.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(names, settings.enabled_checks());
+        assert_eq!(
+            names,
+            vec![
+                CheckName::TimestampGap,
+                CheckName::Timestamp,
+                CheckName::AllocationEligible,
+                CheckName::SenderBalance,
+                CheckName::SenderAllowList,
+                CheckName::ReceiptMaxValue,
+                CheckName::MinimumPrice,
+                CheckName::MinimumValue,
+                CheckName::PendingValue,
+                CheckName::AllocationCap,
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_reload_swaps_checks(pgpool: PgPool) {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let indexer_allocations = watch::channel(INDEXER_ALLOCATIONS.clone()).1;
+        let escrow_accounts = watch::channel(EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.clone(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+        ))
+        .1;
+
+        let pipeline = CheckPipeline::new(
+            pgpool,
+            indexer_allocations,
+            escrow_accounts,
+            Default::default(),
+            CheckState::default(),
+            settings(1000),
+        )
+        .await;
+        let checks = pipeline.checks();
+
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: allocation.subgraph_deployment.id,
+            query: "query { a }".into(),
+            variables: "".into(),
+        });
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(
+                SignedReceiptRequest::builder()
+                    .allocation_id(allocation.id)
+                    .value(100)
+                    .build(),
+            )
+            .await,
+        );
+
+        assert!(checks.check(&ctx, &receipt).await.is_ok());
+
+        // same settings, nothing to do
+        assert!(pipeline.reload(settings(1000)).await.is_empty());
+
+        let changes = pipeline.reload(settings(50)).await;
+        assert_eq!(changes, vec!["receipt_max_value: 1000 -> 50".to_string()]);
+        assert!(checks.check(&ctx, &receipt).await.is_err());
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_sender_allow_list_set_at_runtime(pgpool: PgPool) {
+        let pipeline = CheckPipeline::new(
+            pgpool,
+            watch::channel(INDEXER_ALLOCATIONS.clone()).1,
+            watch::channel(EscrowAccounts::default()).1,
+            Default::default(),
+            CheckState::default(),
+            settings(1000),
+        )
+        .await;
+        // nothing to set while the check is disabled
+        assert_eq!(pipeline.sender_allow_list().await, None);
+        assert_eq!(pipeline.set_sender_allow_list(HashSet::new()).await, None);
+
+        let allowed = |senders: &[Address]| CheckSettings {
+            sender_allow_list: Some(senders.iter().copied().collect()),
+            deny_list: false,
+            ..settings(1000)
+        };
+        let sender = Address::repeat_byte(1);
+        pipeline.reload(allowed(&[Address::ZERO])).await;
+        assert_eq!(
+            pipeline.sender_allow_list().await,
+            Some(HashSet::from([Address::ZERO]))
+        );
+
+        assert_eq!(
+            pipeline
+                .set_sender_allow_list(HashSet::from([sender]))
+                .await,
+            Some(HashSet::from([Address::ZERO]))
+        );
+        // kept across reloads of other settings
+        pipeline
+            .reload(CheckSettings {
+                receipt_max_value: 50,
+                ..allowed(&[Address::ZERO])
+            })
+            .await;
+        assert_eq!(
+            pipeline.sender_allow_list().await,
+            Some(HashSet::from([sender]))
+        );
+
+        // until the configured list changes
+        pipeline.reload(allowed(&[Address::ZERO, sender])).await;
+        assert_eq!(
+            pipeline.sender_allow_list().await,
+            Some(HashSet::from([Address::ZERO, sender]))
+        );
+    }
+}
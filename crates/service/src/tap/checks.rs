@@ -1,9 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod allocation_cap_check;
 pub mod allocation_eligible;
+pub mod cached_totals;
 pub mod deny_list_check;
+pub mod min_price_check;
+pub mod pending_value_check;
+pub mod price_list_check;
 pub mod receipt_max_val_check;
+pub mod sender_allow_list_check;
 pub mod sender_balance_check;
+pub mod timeout_check;
 pub mod timestamp_check;
+pub mod timestamp_gap_check;
 pub mod value_check;
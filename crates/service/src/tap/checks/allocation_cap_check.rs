@@ -0,0 +1,186 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use bigdecimal::ToPrimitive;
+use indexer_allocation::Allocation;
+use sqlx::PgPool;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+use tokio::sync::watch::Receiver;
+
+use super::cached_totals::CachedTotals;
+
+/// Refuses receipts that would raise the value collected on an allocation
+/// above its allocated tokens, receipts past that point can't be collected.
+///
+/// The value of an allocation is that of its RAVs plus the receipts they don't
+/// cover yet, over every sender. Receipts of allocations that are not in the
+/// snapshot are left to [super::allocation_eligible::AllocationEligible]. The
+/// value of an allocation is cached for a few seconds, see [CachedTotals].
+pub struct AllocationCapCheck {
+    pgpool: PgPool,
+    indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+    collected: CachedTotals<Address>,
+}
+
+impl AllocationCapCheck {
+    pub fn new(
+        pgpool: PgPool,
+        indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+    ) -> Self {
+        Self {
+            pgpool,
+            indexer_allocations,
+            collected: CachedTotals::default(),
+        }
+    }
+
+    /// Value of the RAVs and stored receipts of the allocation
+    async fn read_collected(&self, allocation_id: &Address) -> Result<u128, CheckError> {
+        let totals = sqlx::query!(
+            r#"
+                SELECT
+                    (SELECT SUM(value) FROM scalar_tap_receipts WHERE allocation_id = $1) AS pending,
+                    (SELECT SUM(value_aggregate) FROM scalar_tap_ravs WHERE allocation_id = $1) AS aggregated
+            "#,
+            allocation_id.encode_hex(),
+        )
+        .fetch_one(&self.pgpool)
+        .await
+        .map_err(|e| CheckError::Failed(anyhow!(e)))?;
+        [totals.pending, totals.aggregated]
+            .into_iter()
+            .try_fold(0u128, |total, value| {
+                value
+                    .map_or(Some(0), |value| value.to_u128())
+                    .map(|value| total.saturating_add(value))
+            })
+            .ok_or(CheckError::Failed(anyhow!(
+                "Could not compute the value collected on allocation `{}`",
+                allocation_id
+            )))
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for AllocationCapCheck {
+    async fn check(
+        &self,
+        _: &tap_core::receipt::Context,
+        receipt: &ReceiptWithState<Checking>,
+    ) -> CheckResult {
+        let allocation_id = receipt.signed_receipt().message.allocation_id;
+        let Some(allocated_tokens) = self
+            .indexer_allocations
+            .borrow()
+            .get(&allocation_id)
+            .map(|allocation| allocation.allocated_tokens)
+        else {
+            return Ok(());
+        };
+        let cap: u128 = allocated_tokens.try_into().unwrap_or(u128::MAX);
+
+        let collected = self
+            .collected
+            .get(&allocation_id, || self.read_collected(&allocation_id))
+            .await?;
+
+        let receipt_value = receipt.signed_receipt().message.value;
+        let total = collected.saturating_add(receipt_value);
+        if total > cap {
+            return Err(CheckError::Failed(anyhow!(
+                "Receipt would raise the value collected on allocation `{}` to `{}`, above \
+                its allocated tokens of `{}`. The allocation can't support more receipts",
+                allocation_id,
+                total,
+                cap,
+            )));
+        }
+        self.collected.add(&allocation_id, receipt_value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{hex::ToHexExt, primitives::U256};
+    use sqlx::PgPool;
+    use tap_core::{
+        manager::adapters::ReceiptStore,
+        receipt::{checks::Check, Context, ReceiptWithState},
+    };
+    use test_assets::{
+        assert_while_retry, create_signed_receipt, SignedReceiptRequest, ALLOCATION_ID_0,
+        INDEXER_ALLOCATIONS, TAP_EIP712_DOMAIN, TAP_SENDER,
+    };
+    use tokio::sync::watch;
+
+    use super::AllocationCapCheck;
+    use crate::tap::IndexerTapContext;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_allocation_cap(pgpool: PgPool) {
+        let mut allocations = INDEXER_ALLOCATIONS.clone();
+        allocations
+            .get_mut(&*ALLOCATION_ID_0)
+            .unwrap()
+            .allocated_tokens = U256::from(1000);
+        let indexer_allocations = watch::channel(allocations).1;
+        let check = AllocationCapCheck::new(pgpool.clone(), indexer_allocations.clone());
+
+        let ctx = Context::new();
+        let receipt = |value| async move {
+            ReceiptWithState::new(
+                create_signed_receipt(
+                    SignedReceiptRequest::builder()
+                        .allocation_id(*ALLOCATION_ID_0)
+                        .value(value)
+                        .build(),
+                )
+                .await,
+            )
+        };
+
+        // nothing collected yet
+        assert!(check.check(&ctx, &receipt(1001).await).await.is_err());
+        assert!(check.check(&ctx, &receipt(1000).await).await.is_ok());
+        // the accepted receipt counts before it is stored
+        assert!(check.check(&ctx, &receipt(1).await).await.is_err());
+
+        // RAVs and the receipts they don't cover count towards the cap
+        sqlx::query(
+            r#"
+                INSERT INTO scalar_tap_ravs
+                    (sender_address, signature, allocation_id, timestamp_ns, value_aggregate)
+                VALUES ($1, '', $2, 0, 300)
+            "#,
+        )
+        .bind(TAP_SENDER.1.encode_hex())
+        .bind(ALLOCATION_ID_0.encode_hex())
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        context.store_receipt(receipt(600).await).await.unwrap();
+        assert_while_retry!({
+            sqlx::query!("SELECT * FROM scalar_tap_receipts")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap()
+                .is_empty()
+        });
+
+        // a check without a cached total reads the stored ones
+        let check = AllocationCapCheck::new(pgpool.clone(), indexer_allocations);
+        let err = check.check(&ctx, &receipt(101).await).await.unwrap_err();
+        assert!(check.check(&ctx, &receipt(100).await).await.is_ok());
+        assert!(err.to_string().contains("allocated tokens"));
+    }
+}
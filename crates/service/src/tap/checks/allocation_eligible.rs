@@ -1,12 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use alloy::primitives::Address;
 use anyhow::anyhow;
 
 use indexer_allocation::Allocation;
+use indexer_config::UnknownAllocationPolicy;
 use tap_core::receipt::{
     checks::{Check, CheckError, CheckResult},
     state::Checking,
@@ -14,17 +19,60 @@ use tap_core::receipt::{
 };
 use tokio::sync::watch::Receiver;
 
+/// Unknown allocations tracked at once, older ones are forgotten past that
+const MAX_UNKNOWN_ALLOCATIONS: usize = 10_000;
+
+/// When receipts of each unknown allocation were first seen, kept across
+/// reloads of the checks and read by the attestation backend waiting for
+/// their signer
+#[derive(Clone, Default)]
+pub struct UnknownAllocations(Arc<Mutex<HashMap<Address, Instant>>>);
+
+impl UnknownAllocations {
+    /// Whether the allocation may still show up in the network subgraph,
+    /// counting from its first receipt
+    pub fn within_grace(&self, allocation_id: Address, grace: Duration) -> bool {
+        let now = Instant::now();
+        let mut first_seen = self.0.lock().unwrap();
+        if first_seen.len() >= MAX_UNKNOWN_ALLOCATIONS && !first_seen.contains_key(&allocation_id) {
+            first_seen.retain(|_, seen| now.duration_since(*seen) < grace);
+            if first_seen.len() >= MAX_UNKNOWN_ALLOCATIONS {
+                return false;
+            }
+        }
+        let seen = *first_seen.entry(allocation_id).or_insert(now);
+        now.duration_since(seen) < grace
+    }
+
+    /// When the first receipt of the allocation was seen while it was unknown
+    pub fn first_seen(&self, allocation_id: &Address) -> Option<Instant> {
+        self.0.lock().unwrap().get(allocation_id).copied()
+    }
+}
+
 pub struct AllocationEligible {
     indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+    policy: UnknownAllocationPolicy,
+    grace: Duration,
+    unknown_allocations: UnknownAllocations,
 }
 
 impl AllocationEligible {
-    pub fn new(indexer_allocations: Receiver<HashMap<Address, Allocation>>) -> Self {
+    pub fn new(
+        indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+        policy: UnknownAllocationPolicy,
+        grace: Duration,
+        unknown_allocations: UnknownAllocations,
+    ) -> Self {
         Self {
             indexer_allocations,
+            policy,
+            grace,
+            unknown_allocations,
         }
     }
 }
+
 #[async_trait::async_trait]
 impl Check for AllocationEligible {
     async fn check(
@@ -33,16 +81,148 @@ impl Check for AllocationEligible {
         receipt: &ReceiptWithState<Checking>,
     ) -> CheckResult {
         let allocation_id = receipt.signed_receipt().message.allocation_id;
-        if !self
+        if self
             .indexer_allocations
             .borrow()
             .contains_key(&allocation_id)
         {
-            return Err(CheckError::Failed(anyhow!(
+            return Ok(());
+        }
+        let within_grace = || {
+            self.unknown_allocations
+                .within_grace(allocation_id, self.grace)
+        };
+        match self.policy {
+            // the attestation backend waits for the allocation signer instead
+            UnknownAllocationPolicy::AcceptAndDeferSigner if within_grace() => Ok(()),
+            UnknownAllocationPolicy::RetryableUnavailable if within_grace() => {
+                Err(CheckError::Retryable(anyhow!(
+                    "Receipt allocation ID `{}` is not known yet, the network subgraph may \
+                    not have synced it",
+                    allocation_id
+                )))
+            }
+            _ => Err(CheckError::Failed(anyhow!(
                 "Receipt allocation ID `{}` is not eligible for this indexer",
                 allocation_id
-            )));
+            ))),
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use alloy::primitives::address;
+    use indexer_config::UnknownAllocationPolicy;
+    use tap_core::receipt::{
+        checks::{Check, CheckError},
+        Context, ReceiptWithState,
+    };
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, ALLOCATION_ID_0, INDEXER_ALLOCATIONS,
+    };
+    use tokio::sync::watch;
+
+    use super::{AllocationEligible, UnknownAllocations};
+
+    fn check(policy: UnknownAllocationPolicy, grace: Duration) -> AllocationEligible {
+        AllocationEligible::new(
+            watch::channel(INDEXER_ALLOCATIONS.clone()).1,
+            policy,
+            grace,
+            UnknownAllocations::default(),
+        )
+    }
+
+    async fn receipt(known: bool) -> ReceiptWithState<tap_core::receipt::state::Checking> {
+        let allocation_id = if known {
+            *ALLOCATION_ID_0
+        } else {
+            address!("00000000000000000000000000000000000000aa")
+        };
+        ReceiptWithState::new(
+            create_signed_receipt(
+                SignedReceiptRequest::builder()
+                    .allocation_id(allocation_id)
+                    .build(),
+            )
+            .await,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reject_unknown_allocation() {
+        let check = check(UnknownAllocationPolicy::Reject, Duration::from_secs(60));
+        let ctx = Context::new();
+        assert!(check.check(&ctx, &receipt(true).await).await.is_ok());
+        assert!(matches!(
+            check.check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Failed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_allocation_retryable_during_grace() {
+        let check = check(
+            UnknownAllocationPolicy::RetryableUnavailable,
+            Duration::from_millis(50),
+        );
+        let ctx = Context::new();
+        assert!(check.check(&ctx, &receipt(true).await).await.is_ok());
+        assert!(matches!(
+            check.check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Retryable(_))
+        ));
+
+        // the allocation didn't show up in time
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(
+            check.check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Failed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_accept_unknown_allocation_during_grace() {
+        let check = check(
+            UnknownAllocationPolicy::AcceptAndDeferSigner,
+            Duration::from_millis(50),
+        );
+        let ctx = Context::new();
+        assert!(check.check(&ctx, &receipt(false).await).await.is_ok());
+
+        // the allocation didn't show up in time
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(
+            check.check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Failed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_grace_kept_across_rebuilds() {
+        let unknown_allocations = UnknownAllocations::default();
+        let check = || {
+            AllocationEligible::new(
+                watch::channel(INDEXER_ALLOCATIONS.clone()).1,
+                UnknownAllocationPolicy::RetryableUnavailable,
+                Duration::from_millis(50),
+                unknown_allocations.clone(),
+            )
+        };
+        let ctx = Context::new();
+        assert!(matches!(
+            check().check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Retryable(_))
+        ));
+
+        // a reload doesn't give the allocation a new grace period
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(
+            check().check(&ctx, &receipt(false).await).await,
+            Err(CheckError::Failed(_))
+        ));
     }
 }
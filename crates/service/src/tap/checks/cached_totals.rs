@@ -0,0 +1,104 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tap_core::receipt::checks::CheckError;
+
+/// How long a total read from the database is used before it is read again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Totals of stored receipt values, read from the database at most once per
+/// [REFRESH_INTERVAL] instead of on every receipt
+///
+/// The values of the receipts accepted in between are added to the cached
+/// total, so it doesn't fall behind while the receipts are being stored. A
+/// receipt refused by a later check is still counted until the next read,
+/// which errs on the side of refusing.
+pub struct CachedTotals<K> {
+    totals: Mutex<HashMap<K, CachedTotal>>,
+}
+
+struct CachedTotal {
+    stored: u128,
+    read_at: Instant,
+    /// value accepted since the total was read
+    accepted: u128,
+}
+
+impl<K> Default for CachedTotals<K> {
+    fn default() -> Self {
+        Self {
+            totals: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> CachedTotals<K> {
+    /// Total of `key`, read with `read` if the cached one is too old
+    pub async fn get<F, Fut>(&self, key: &K, read: F) -> Result<u128, CheckError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<u128, CheckError>>,
+    {
+        if let Some(total) = self
+            .totals
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|total| total.read_at.elapsed() < REFRESH_INTERVAL)
+        {
+            return Ok(total.stored.saturating_add(total.accepted));
+        }
+
+        let stored = read().await?;
+        self.totals.lock().unwrap().insert(
+            key.clone(),
+            CachedTotal {
+                stored,
+                read_at: Instant::now(),
+                accepted: 0,
+            },
+        );
+        Ok(stored)
+    }
+
+    /// Counts an accepted receipt until the total is read again
+    pub fn add(&self, key: &K, value: u128) {
+        if let Some(total) = self.totals.lock().unwrap().get_mut(key) {
+            total.accepted = total.accepted.saturating_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::CachedTotals;
+
+    #[tokio::test]
+    async fn test_total_is_read_once_and_follows_accepted_receipts() {
+        let totals = CachedTotals::default();
+        let reads = AtomicUsize::new(0);
+        let read = || async {
+            reads.fetch_add(1, Ordering::SeqCst);
+            Ok(100)
+        };
+
+        assert_eq!(totals.get(&1, read).await.unwrap(), 100);
+        totals.add(&1, 20);
+        assert_eq!(totals.get(&1, read).await.unwrap(), 120);
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // other keys have their own total
+        assert_eq!(totals.get(&2, read).await.unwrap(), 100);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+}
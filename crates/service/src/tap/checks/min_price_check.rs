@@ -0,0 +1,140 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    Context, ReceiptWithState,
+};
+use thegraph_core::DeploymentId;
+
+use crate::tap::AgoraQuery;
+
+/// Enforces a minimum price per query for each deployment,
+/// regardless of the value expected by the cost models
+///
+/// Deployments without an explicit minimum fall back to the global one.
+pub struct DeploymentMinimumPrice {
+    global_min_price: u128,
+    min_price_per_deployment: HashMap<DeploymentId, u128>,
+}
+
+impl DeploymentMinimumPrice {
+    pub fn new(
+        global_min_price: u128,
+        min_price_per_deployment: HashMap<DeploymentId, u128>,
+    ) -> Self {
+        Self {
+            global_min_price,
+            min_price_per_deployment,
+        }
+    }
+
+    fn min_price(&self, deployment_id: &DeploymentId) -> u128 {
+        self.min_price_per_deployment
+            .get(deployment_id)
+            .copied()
+            .unwrap_or(self.global_min_price)
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for DeploymentMinimumPrice {
+    async fn check(&self, ctx: &Context, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let agora_query = ctx
+            .get::<AgoraQuery>()
+            .ok_or(CheckError::Failed(anyhow!("Could not find agora query")))?;
+
+        let min_price = self.min_price(&agora_query.deployment_id);
+        let receipt_value = receipt.signed_receipt().message.value;
+
+        if receipt_value >= min_price {
+            Ok(())
+        } else {
+            Err(CheckError::Failed(anyhow!(
+                "Receipt value `{}` is below the minimum price `{}` for deployment `{}`",
+                receipt_value,
+                min_price,
+                agora_query.deployment_id,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tap_core::receipt::{checks::Check, Context, ReceiptWithState};
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, ESCROW_SUBGRAPH_DEPLOYMENT,
+        NETWORK_SUBGRAPH_DEPLOYMENT,
+    };
+    use thegraph_core::DeploymentId;
+
+    use super::DeploymentMinimumPrice;
+    use crate::tap::AgoraQuery;
+
+    const GLOBAL_MIN_PRICE: u128 = 10;
+    const DEPLOYMENT_MIN_PRICE: u128 = 100;
+
+    fn context(deployment_id: DeploymentId) -> Context {
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id,
+            query: "query { a }".into(),
+            variables: "".into(),
+        });
+        ctx
+    }
+
+    async fn receipt(value: u128) -> ReceiptWithState<tap_core::receipt::state::Checking> {
+        ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+        )
+    }
+
+    fn check() -> DeploymentMinimumPrice {
+        DeploymentMinimumPrice::new(
+            GLOBAL_MIN_PRICE,
+            HashMap::from([(*ESCROW_SUBGRAPH_DEPLOYMENT, DEPLOYMENT_MIN_PRICE)]),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_explicit_deployment_min_price() {
+        let check = check();
+        let ctx = context(*ESCROW_SUBGRAPH_DEPLOYMENT);
+
+        let error = check
+            .check(&ctx, &receipt(DEPLOYMENT_MIN_PRICE - 1).await)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains(&format!("minimum price `{DEPLOYMENT_MIN_PRICE}`")));
+
+        assert!(check
+            .check(&ctx, &receipt(DEPLOYMENT_MIN_PRICE).await)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_global_min_price() {
+        let check = check();
+        let ctx = context(*NETWORK_SUBGRAPH_DEPLOYMENT);
+
+        assert!(check
+            .check(&ctx, &receipt(GLOBAL_MIN_PRICE - 1).await)
+            .await
+            .is_err());
+        assert!(check
+            .check(&ctx, &receipt(GLOBAL_MIN_PRICE).await)
+            .await
+            .is_ok());
+    }
+}
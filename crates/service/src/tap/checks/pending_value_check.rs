@@ -0,0 +1,169 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use bigdecimal::ToPrimitive;
+use indexer_monitor::EscrowAccounts;
+use sqlx::PgPool;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+use tokio::sync::watch::Receiver;
+
+use super::cached_totals::CachedTotals;
+use crate::middleware::Sender;
+
+/// Caps the value of receipts the indexer holds for a sender that is not yet
+/// covered by a RAV.
+///
+/// Unlike [super::sender_balance_check::SenderBalanceCheck], this is about how
+/// much the indexer is willing to lose if a RAV can't be redeemed, not about
+/// the sender's funds. The pending value of a sender is cached for a few
+/// seconds, see [CachedTotals].
+pub struct PendingValueCheck {
+    pgpool: PgPool,
+    escrow_accounts: Receiver<EscrowAccounts>,
+    max_pending_value: u128,
+    pending: CachedTotals<Address>,
+}
+
+impl PendingValueCheck {
+    pub fn new(
+        pgpool: PgPool,
+        escrow_accounts: Receiver<EscrowAccounts>,
+        max_pending_value: u128,
+    ) -> Self {
+        Self {
+            pgpool,
+            escrow_accounts,
+            max_pending_value,
+            pending: CachedTotals::default(),
+        }
+    }
+}
+
+impl PendingValueCheck {
+    /// Value of the stored receipts of the sender, over all its signers
+    async fn read_pending(&self, sender: &Address) -> Result<u128, CheckError> {
+        let signers = self
+            .escrow_accounts
+            .borrow()
+            .get_signers_for_sender(sender)
+            .iter()
+            .map(|signer| signer.encode_hex())
+            .collect::<Vec<_>>();
+
+        // receipts are removed by tap-agent once they are covered by a RAV
+        sqlx::query!(
+            r#"
+                SELECT SUM(value) AS pending
+                FROM scalar_tap_receipts
+                WHERE signer_address IN (SELECT unnest($1::text[]))
+            "#,
+            &signers,
+        )
+        .fetch_one(&self.pgpool)
+        .await
+        .map_err(|e| CheckError::Failed(anyhow!(e)))?
+        .pending
+        .map_or(Some(0), |pending| pending.to_u128())
+        .ok_or(CheckError::Failed(anyhow!(
+            "Could not compute the pending value of sender `{}`",
+            sender
+        )))
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for PendingValueCheck {
+    async fn check(
+        &self,
+        ctx: &tap_core::receipt::Context,
+        receipt: &ReceiptWithState<Checking>,
+    ) -> CheckResult {
+        let Sender(receipt_sender) = ctx
+            .get::<Sender>()
+            .ok_or(CheckError::Failed(anyhow!("Could not find sender")))?;
+
+        let pending = self
+            .pending
+            .get(receipt_sender, || self.read_pending(receipt_sender))
+            .await?;
+
+        let receipt_value = receipt.signed_receipt().message.value;
+        let pending_value = pending.saturating_add(receipt_value);
+        if pending_value > self.max_pending_value {
+            return Err(CheckError::Failed(anyhow!(
+                "Receipt would raise the unaggregated value of sender `{}` to `{}`, above the \
+                limit of `{}`. Please request a RAV before sending more receipts",
+                receipt_sender,
+                pending_value,
+                self.max_pending_value,
+            )));
+        }
+        self.pending.add(receipt_sender, receipt_value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_monitor::EscrowAccounts;
+    use sqlx::PgPool;
+    use tap_core::{
+        manager::adapters::ReceiptStore,
+        receipt::{checks::Check, Context, ReceiptWithState},
+    };
+    use test_assets::{
+        assert_while_retry, create_signed_receipt, SignedReceiptRequest, ESCROW_ACCOUNTS_BALANCES,
+        ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_EIP712_DOMAIN, TAP_SENDER,
+    };
+    use tokio::sync::watch;
+
+    use super::PendingValueCheck;
+    use crate::{middleware::Sender, tap::IndexerTapContext};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_pending_value_cap(pgpool: PgPool) {
+        let escrow_accounts = watch::channel(EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.clone(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+        ))
+        .1;
+        let check = PendingValueCheck::new(pgpool.clone(), escrow_accounts.clone(), 1000);
+
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+
+        let receipt = |value| async move {
+            ReceiptWithState::new(
+                create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+            )
+        };
+
+        // nothing pending yet
+        assert!(check.check(&ctx, &receipt(1001).await).await.is_err());
+        assert!(check.check(&ctx, &receipt(1000).await).await.is_ok());
+        // the accepted receipt counts before it is stored
+        assert!(check.check(&ctx, &receipt(1).await).await.is_err());
+
+        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        context.store_receipt(receipt(600).await).await.unwrap();
+        assert_while_retry!({
+            sqlx::query!("SELECT * FROM scalar_tap_receipts")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap()
+                .is_empty()
+        });
+
+        // a check without a cached total reads the stored receipts
+        let check = PendingValueCheck::new(pgpool.clone(), escrow_accounts, 1000);
+        let err = check.check(&ctx, &receipt(401).await).await.unwrap_err();
+        assert!(check.check(&ctx, &receipt(400).await).await.is_ok());
+        assert!(err.to_string().contains("request a RAV"));
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks receipt values against a price list signed by the gateway
+//!
+//! The signature covers the `price_list` string of the fetched document as it
+//! was sent, so the list doesn't need a canonical encoding. See the price list
+//! section of `docs/Queries.md` for the format.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    primitives::{keccak256, Address, Bytes, B256},
+    signers::Signature,
+};
+use anyhow::{anyhow, ensure};
+use graphql::graphql_parser::query as q;
+use indexer_config::PriceListConfig;
+use serde::Deserialize;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    Context, ReceiptWithState,
+};
+use thegraph_core::DeploymentId;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::tap::AgoraQuery;
+
+#[derive(Deserialize)]
+struct SignedPriceList {
+    price_list: String,
+    signature: Bytes,
+}
+
+#[derive(Deserialize)]
+struct PriceList {
+    expires_at: u64,
+    entries: Vec<PriceListEntry>,
+}
+
+#[derive(Deserialize)]
+struct PriceListEntry {
+    deployment: DeploymentId,
+    /// keccak256 hash of the normalized query, all queries if not set
+    query_hash: Option<B256>,
+    price: u128,
+}
+
+/// Prices of a verified price list
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prices {
+    expires_at: SystemTime,
+    queries: HashMap<(DeploymentId, B256), u128>,
+    deployments: HashMap<DeploymentId, u128>,
+}
+
+impl Prices {
+    /// Reads a signed price list, refusing it unless one of `signers` signed it
+    pub fn verify(document: &[u8], signers: &HashSet<Address>) -> anyhow::Result<Self> {
+        let SignedPriceList {
+            price_list,
+            signature,
+        } = serde_json::from_slice(document)?;
+        let signer =
+            Signature::try_from(signature.as_ref())?.recover_address_from_msg(&price_list)?;
+        ensure!(
+            signers.contains(&signer),
+            "Price list is signed by {signer}, which is not authorized"
+        );
+
+        let PriceList {
+            expires_at,
+            entries,
+        } = serde_json::from_str(&price_list)?;
+        let mut prices = Self {
+            expires_at: UNIX_EPOCH + Duration::from_secs(expires_at),
+            queries: HashMap::new(),
+            deployments: HashMap::new(),
+        };
+        for entry in entries {
+            match entry.query_hash {
+                Some(query_hash) => prices
+                    .queries
+                    .insert((entry.deployment, query_hash), entry.price),
+                None => prices.deployments.insert(entry.deployment, entry.price),
+            };
+        }
+        Ok(prices)
+    }
+
+    /// Price of the query, that of its deployment unless the query itself is priced
+    fn price(&self, query: &AgoraQuery) -> Option<u128> {
+        q::parse_query::<String>(&query.query)
+            .ok()
+            .and_then(|document| {
+                let query_hash = keccak256(document.to_string());
+                self.queries.get(&(query.deployment_id, query_hash))
+            })
+            .or_else(|| self.deployments.get(&query.deployment_id))
+            .copied()
+    }
+}
+
+/// Accepts receipts worth exactly the price of their query in the last valid
+/// price list, which is fetched again on every refresh interval
+///
+/// Until the first list is loaded, receipts are refused as retryable: the
+/// check is rebuilt with its fetcher on every reload.
+pub struct PriceListCheck {
+    prices: Arc<RwLock<Option<Prices>>>,
+    fetcher_cancel_token: CancellationToken,
+}
+
+impl PriceListCheck {
+    pub fn new(config: PriceListConfig) -> Self {
+        let prices = Arc::new(RwLock::new(None));
+        let fetcher_cancel_token = CancellationToken::new();
+        tokio::spawn(Self::price_list_fetcher(
+            config,
+            prices.clone(),
+            fetcher_cancel_token.clone(),
+        ));
+        Self {
+            prices,
+            fetcher_cancel_token,
+        }
+    }
+
+    async fn price_list_fetcher(
+        config: PriceListConfig,
+        prices: Arc<RwLock<Option<Prices>>>,
+        cancel_token: CancellationToken,
+    ) {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(config.refresh_interval_secs);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            let fetched = async {
+                let response = client
+                    .get(config.url.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Prices::verify(&response.bytes().await?, &config.authorized_signers)
+            };
+            match fetched.await {
+                Ok(fetched) => {
+                    *prices.write().unwrap_or_else(PoisonError::into_inner) = Some(fetched);
+                }
+                // keep the last valid list, it still holds until it expires
+                Err(e) => warn!(error = %e, url = %config.url, "Failed to load the price list"),
+            }
+        }
+    }
+}
+
+impl Drop for PriceListCheck {
+    fn drop(&mut self) {
+        self.fetcher_cancel_token.cancel();
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for PriceListCheck {
+    async fn check(&self, ctx: &Context, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        let agora_query = ctx
+            .get::<AgoraQuery>()
+            .ok_or(CheckError::Failed(anyhow!("Could not find agora query")))?;
+        let prices = self.prices.read().unwrap_or_else(PoisonError::into_inner);
+        let prices = prices.as_ref().ok_or(CheckError::Retryable(anyhow!(
+            "No price list is loaded yet"
+        )))?;
+        if prices.expires_at <= SystemTime::now() {
+            return Err(CheckError::Failed(anyhow!("The price list has expired")));
+        }
+        let price = prices.price(agora_query).ok_or_else(|| {
+            CheckError::Failed(anyhow!(
+                "The price list has no price for the query of deployment {}",
+                agora_query.deployment_id
+            ))
+        })?;
+        let value = receipt.signed_receipt().message.value;
+        if value != price {
+            return Err(CheckError::Failed(anyhow!(
+                "Receipt value {value} is not the price list price {price}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, RwLock},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use alloy::{
+        hex,
+        primitives::keccak256,
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+    use graphql::graphql_parser::query as q;
+    use serde_json::json;
+    use tap_core::receipt::{
+        checks::{Check, CheckError},
+        Context, ReceiptWithState,
+    };
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, ESCROW_SUBGRAPH_DEPLOYMENT,
+        NETWORK_SUBGRAPH_DEPLOYMENT,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use super::{PriceListCheck, Prices};
+    use crate::tap::AgoraQuery;
+
+    const QUERY: &str = "{ pairs(first: 10) { id } }";
+
+    fn sign(signer: &PrivateKeySigner, expires_at: SystemTime) -> Vec<u8> {
+        let document = q::parse_query::<String>(QUERY).unwrap();
+        let price_list = json!({
+            "expires_at": expires_at.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            "entries": [
+                {
+                    "deployment": *ESCROW_SUBGRAPH_DEPLOYMENT,
+                    "query_hash": keccak256(document.to_string()),
+                    "price": 200,
+                },
+                { "deployment": *ESCROW_SUBGRAPH_DEPLOYMENT, "price": 100 },
+            ],
+        })
+        .to_string();
+        let signature = signer.sign_message_sync(price_list.as_bytes()).unwrap();
+        serde_json::to_vec(&json!({
+            "price_list": price_list,
+            "signature": hex::encode_prefixed(signature.as_bytes()),
+        }))
+        .unwrap()
+    }
+
+    fn check(prices: Prices) -> PriceListCheck {
+        PriceListCheck {
+            prices: Arc::new(RwLock::new(Some(prices))),
+            fetcher_cancel_token: CancellationToken::new(),
+        }
+    }
+
+    async fn run(check: &PriceListCheck, query: &str, value: u128) -> bool {
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: *ESCROW_SUBGRAPH_DEPLOYMENT,
+            query: query.into(),
+            variables: "".into(),
+        });
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+        );
+        check.check(&ctx, &receipt).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_valid_price_list() {
+        let signer = PrivateKeySigner::random();
+        let document = sign(&signer, SystemTime::now() + Duration::from_secs(60));
+        let check = check(Prices::verify(&document, &HashSet::from([signer.address()])).unwrap());
+
+        // priced by its hash, whatever its formatting
+        assert!(run(&check, QUERY, 200).await);
+        assert!(run(&check, "{pairs(first:10){id}}", 200).await);
+        // priced by the deployment
+        assert!(run(&check, "{ tokens { id } }", 100).await);
+
+        // no entry for the deployment
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: *NETWORK_SUBGRAPH_DEPLOYMENT,
+            query: QUERY.into(),
+            variables: "".into(),
+        });
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(200).build()).await,
+        );
+        assert!(check.check(&ctx, &receipt).await.is_err());
+
+        // signed by someone else
+        let other = PrivateKeySigner::random();
+        let document = sign(&other, SystemTime::now() + Duration::from_secs(60));
+        assert!(Prices::verify(&document, &HashSet::from([signer.address()])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_price_list_not_loaded() {
+        let check = PriceListCheck {
+            prices: Arc::new(RwLock::new(None)),
+            fetcher_cancel_token: CancellationToken::new(),
+        };
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: *ESCROW_SUBGRAPH_DEPLOYMENT,
+            query: QUERY.into(),
+            variables: "".into(),
+        });
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(200).build()).await,
+        );
+        assert!(matches!(
+            check.check(&ctx, &receipt).await,
+            Err(CheckError::Retryable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_price_list() {
+        let signer = PrivateKeySigner::random();
+        let document = sign(&signer, SystemTime::now() - Duration::from_secs(1));
+        let check = check(Prices::verify(&document, &HashSet::from([signer.address()])).unwrap());
+        assert!(!run(&check, QUERY, 200).await);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_value() {
+        let signer = PrivateKeySigner::random();
+        let document = sign(&signer, SystemTime::now() + Duration::from_secs(60));
+        let check = check(Prices::verify(&document, &HashSet::from([signer.address()])).unwrap());
+        assert!(!run(&check, QUERY, 100).await);
+        assert!(!run(&check, QUERY, 201).await);
+        assert!(!run(&check, "{ tokens { id } }", 200).await);
+    }
+}
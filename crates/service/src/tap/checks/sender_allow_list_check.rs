@@ -0,0 +1,106 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use alloy::primitives::Address;
+use anyhow::anyhow;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    Context, ReceiptWithState,
+};
+
+use crate::middleware::Sender;
+
+/// Refuses the receipts of every sender that isn't in the allow-list
+///
+/// The allow-list is shared with the admin routes, changes apply to the next
+/// receipt without rebuilding the checks.
+pub struct SenderAllowListCheck {
+    sender_allow_list: Arc<RwLock<HashSet<Address>>>,
+}
+
+impl SenderAllowListCheck {
+    pub fn new(sender_allow_list: Arc<RwLock<HashSet<Address>>>) -> Self {
+        Self { sender_allow_list }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for SenderAllowListCheck {
+    async fn check(&self, ctx: &Context, _: &ReceiptWithState<Checking>) -> CheckResult {
+        let Sender(receipt_sender) = ctx
+            .get::<Sender>()
+            .ok_or(CheckError::Failed(anyhow!("Could not find sender")))?;
+
+        if !self
+            .sender_allow_list
+            .read()
+            .unwrap()
+            .contains(receipt_sender)
+        {
+            return Err(CheckError::Failed(anyhow!(
+                "Received a receipt from a sender that is not allowed: {}",
+                receipt_sender
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, RwLock},
+    };
+
+    use alloy::primitives::Address;
+    use tap_core::receipt::{checks::Check, Context, ReceiptWithState};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_SENDER};
+
+    use super::SenderAllowListCheck;
+    use crate::middleware::Sender;
+
+    #[tokio::test]
+    async fn test_sender_allow_list() {
+        let sender_allow_list = Arc::new(RwLock::new(HashSet::from([TAP_SENDER.1])));
+        let check = SenderAllowListCheck::new(sender_allow_list.clone());
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().build()).await,
+        );
+
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+        check
+            .check(&ctx, &receipt)
+            .await
+            .expect("should accept allowed senders");
+
+        let mut ctx = Context::new();
+        ctx.insert(Sender(Address::ZERO));
+        assert!(
+            check.check(&ctx, &receipt).await.is_err(),
+            "Should deny senders that are not allowed"
+        );
+
+        // taken into account for the next receipt
+        sender_allow_list.write().unwrap().insert(Address::ZERO);
+        check
+            .check(&ctx, &receipt)
+            .await
+            .expect("should accept senders allowed at runtime");
+        sender_allow_list.write().unwrap().remove(&TAP_SENDER.1);
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+        assert!(
+            check.check(&ctx, &receipt).await.is_err(),
+            "Should deny senders no longer allowed"
+        );
+    }
+}
@@ -1,25 +1,239 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use alloy::primitives::U256;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    hex::ToHexExt,
+    primitives::{Address, U256},
+};
 use anyhow::anyhow;
-use indexer_monitor::EscrowAccounts;
+use bigdecimal::{num_bigint::BigInt, ToPrimitive};
+use indexer_monitor::{EscrowAccounts, EscrowReservations};
+use sqlx::{types::BigDecimal, PgPool};
 use tap_core::receipt::{
     checks::{Check, CheckError, CheckResult},
     state::Checking,
     ReceiptWithState,
 };
 use tokio::sync::watch::Receiver;
+use tracing::warn;
 
-use crate::middleware::Sender;
+use crate::{
+    middleware::{EscrowFreshnessState, ReceiptToken, Sender},
+    tap::PendingSettlements,
+};
 
 pub struct SenderBalanceCheck {
     escrow_accounts: Receiver<EscrowAccounts>,
+    /// balance held for quoted queries, not available to other receipts
+    reservations: EscrowReservations,
+    /// receipts tolerated from senders that ran out of balance
+    top_up_grace: Option<TopUpGrace>,
+    /// token the escrow balances are in
+    token: Option<Address>,
+    /// balances aren't trusted while the accounts are too stale for the
+    /// `escrow_unavailable_policy`
+    escrow_freshness: Option<EscrowFreshnessState>,
+}
+
+/// Accepts receipts from a sender whose balance doesn't cover them for a
+/// while, up to a total value, betting on a top-up the escrow subgraph doesn't
+/// show yet
+///
+/// A sender gets a single window each time it runs out, the next one opening
+/// only once its balance covered a receipt again.
+pub struct TopUpGrace {
+    max_value: U256,
+    duration: Duration,
+    windows: TopUpGraceWindows,
+}
+
+/// Grace windows of the senders, kept across check reloads and, with a
+/// database, across restarts
+///
+/// The value of a receipt is only added to its window once the receipt is
+/// accepted, through the [PendingSettlements] of the receipt.
+#[derive(Clone, Default)]
+pub struct TopUpGraceWindows {
+    windows: Arc<Mutex<HashMap<Address, GraceWindow>>>,
+    pgpool: Option<PgPool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GraceWindow {
+    /// nanoseconds since the epoch
+    opened_at_ns: u64,
+    /// value of the receipts accepted in the window so far
+    value: U256,
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+impl TopUpGraceWindows {
+    /// Windows stored in the database, kept there as they change
+    pub async fn load(pgpool: PgPool) -> Self {
+        let mut windows = HashMap::new();
+        match sqlx::query!(
+            r#"
+                SELECT sender_address, opened_at_ns, value
+                FROM tap_escrow_top_up_grace
+            "#
+        )
+        .fetch_all(&pgpool)
+        .await
+        {
+            Ok(rows) => {
+                for row in rows {
+                    let (Ok(sender), Some(opened_at_ns), Some(value)) = (
+                        Address::from_str(&row.sender_address),
+                        row.opened_at_ns.to_u64(),
+                        row.value.to_u128(),
+                    ) else {
+                        warn!(
+                            sender = row.sender_address,
+                            "Ignoring an invalid grace window"
+                        );
+                        continue;
+                    };
+                    windows.insert(
+                        sender,
+                        GraceWindow {
+                            opened_at_ns,
+                            value: U256::from(value),
+                        },
+                    );
+                }
+            }
+            Err(e) => warn!(error = %e, "Failed to load the escrow top-up grace windows"),
+        }
+        Self {
+            windows: Arc::new(Mutex::new(windows)),
+            pgpool: Some(pgpool),
+        }
+    }
+
+    fn get(&self, sender: &Address) -> Option<GraceWindow> {
+        self.windows.lock().unwrap().get(sender).copied()
+    }
+
+    /// Adds an accepted receipt to the window opened at `opened_at_ns`,
+    /// opening it if the sender has none
+    fn record(&self, sender: Address, opened_at_ns: u64, value: U256) {
+        let window = {
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows.entry(sender).or_insert_with(|| {
+                warn!(%sender, "Sender escrow balance is insufficient, accepting receipts in hope of a top-up");
+                GraceWindow {
+                    opened_at_ns,
+                    value: U256::ZERO,
+                }
+            });
+            window.value = window.value.saturating_add(value);
+            *window
+        };
+        if let Some(pgpool) = self.pgpool.clone() {
+            tokio::spawn(async move {
+                // a write overtaken by a later one leaves the later window
+                if let Err(e) = sqlx::query!(
+                    r#"
+                        INSERT INTO tap_escrow_top_up_grace (sender_address, opened_at_ns, value)
+                        VALUES ($1, $2, $3)
+                        ON CONFLICT (sender_address) DO UPDATE
+                        SET opened_at_ns = EXCLUDED.opened_at_ns, value = EXCLUDED.value
+                        WHERE tap_escrow_top_up_grace.opened_at_ns < EXCLUDED.opened_at_ns
+                            OR (tap_escrow_top_up_grace.opened_at_ns = EXCLUDED.opened_at_ns
+                                AND tap_escrow_top_up_grace.value < EXCLUDED.value)
+                    "#,
+                    sender.encode_hex(),
+                    BigDecimal::from(window.opened_at_ns),
+                    BigDecimal::from(BigInt::from(
+                        u128::try_from(window.value).unwrap_or(u128::MAX)
+                    )),
+                )
+                .execute(&pgpool)
+                .await
+                {
+                    warn!(error = %e, %sender, "Failed to store the escrow top-up grace window");
+                }
+            });
+        }
+    }
+
+    /// The balance covers the sender again, running out later opens a new window
+    fn close(&self, sender: &Address) {
+        if self.windows.lock().unwrap().remove(sender).is_none() {
+            return;
+        }
+        if let Some(pgpool) = self.pgpool.clone() {
+            let sender = *sender;
+            tokio::spawn(async move {
+                if let Err(e) = sqlx::query!(
+                    "DELETE FROM tap_escrow_top_up_grace WHERE sender_address = $1",
+                    sender.encode_hex(),
+                )
+                .execute(&pgpool)
+                .await
+                {
+                    warn!(error = %e, %sender, "Failed to remove the escrow top-up grace window");
+                }
+            });
+        }
+    }
+}
+
+impl TopUpGrace {
+    pub fn new(max_value: u128, duration: Duration, windows: TopUpGraceWindows) -> Self {
+        Self {
+            max_value: U256::from(max_value),
+            duration,
+            windows,
+        }
+    }
+
+    /// Whether the receipt fits in the window of the sender, or in a new one
+    /// if the sender has none. Returns when the window was opened
+    fn tolerates(&self, sender: &Address, value: U256) -> Option<u64> {
+        let now_ns = now_ns();
+        let window = self.windows.get(sender).unwrap_or(GraceWindow {
+            opened_at_ns: now_ns,
+            value: U256::ZERO,
+        });
+        let elapsed = Duration::from_nanos(now_ns.saturating_sub(window.opened_at_ns));
+        (elapsed <= self.duration && window.value.saturating_add(value) <= self.max_value)
+            .then_some(window.opened_at_ns)
+    }
 }
 
 impl SenderBalanceCheck {
-    pub fn new(escrow_accounts: Receiver<EscrowAccounts>) -> Self {
-        Self { escrow_accounts }
+    pub fn new(
+        escrow_accounts: Receiver<EscrowAccounts>,
+        reservations: EscrowReservations,
+        top_up_grace: Option<TopUpGrace>,
+        token: Option<Address>,
+    ) -> Self {
+        Self {
+            escrow_accounts,
+            reservations,
+            top_up_grace,
+            token,
+            escrow_freshness: None,
+        }
+    }
+
+    pub fn with_escrow_freshness(mut self, escrow_freshness: Option<EscrowFreshnessState>) -> Self {
+        self.escrow_freshness = escrow_freshness;
+        self
     }
 }
 
@@ -28,25 +242,361 @@ impl Check for SenderBalanceCheck {
     async fn check(
         &self,
         ctx: &tap_core::receipt::Context,
-        _: &ReceiptWithState<Checking>,
+        receipt: &ReceiptWithState<Checking>,
     ) -> CheckResult {
-        let escrow_accounts_snapshot = self.escrow_accounts.borrow();
-
         let Sender(receipt_sender) = ctx
             .get::<Sender>()
             .ok_or(CheckError::Failed(anyhow::anyhow!("Could not find sender")))?;
-
-        // Check that the sender has a non-zero balance -- more advanced accounting is done in
-        // `tap-agent`.
-        if !escrow_accounts_snapshot
-            .get_balance_for_sender(receipt_sender)
-            .map_or(false, |balance| balance > U256::ZERO)
+        // only the balance in the escrow token is known
+        if let Some(ReceiptToken(token)) = ctx.get::<ReceiptToken>() {
+            if self.token != Some(*token) {
+                return Err(CheckError::Failed(anyhow!(
+                    "Receipt token `{}` is not supported",
+                    token
+                )));
+            }
+        }
+        // the accounts may be read again soon, the receipt can be retried
+        if self
+            .escrow_freshness
+            .as_ref()
+            .is_some_and(|freshness| !freshness.serves_paid())
         {
-            return Err(CheckError::Failed(anyhow!(
-                "Receipt sender `{}` does not have a sufficient balance",
+            return Err(CheckError::Retryable(anyhow!(
+                "Escrow accounts are unavailable, the balance of `{}` can't be checked",
                 receipt_sender,
             )));
         }
-        Ok(())
+        let balance = self
+            .escrow_accounts
+            .borrow()
+            .get_balance_for_sender(receipt_sender)
+            .unwrap_or_default();
+
+        let value = U256::from(receipt.signed_receipt().message.value);
+
+        // a receipt paying for its quote uses the balance held for it, which
+        // is released once the receipt is accepted
+        if balance > U256::ZERO && self.reservations.holds(receipt_sender, value) {
+            let reservations = self.reservations.clone();
+            let sender = *receipt_sender;
+            PendingSettlements::defer_in(ctx, move || {
+                reservations.fulfill(&sender, value);
+            });
+            return Ok(());
+        }
+
+        // Check that the sender has a non-zero balance once the reservations
+        // of quoted queries are set aside, the value having to fit in what's
+        // left when there are any -- more advanced accounting is done in
+        // `tap-agent`.
+        let reserved = self.reservations.reserved(receipt_sender);
+        let available = balance.saturating_sub(reserved);
+        if available > U256::ZERO && (reserved == U256::ZERO || available >= value) {
+            if let Some(top_up_grace) = &self.top_up_grace {
+                top_up_grace.windows.close(receipt_sender);
+            }
+            return Ok(());
+        }
+
+        // a sender that ran out may have topped up without it showing yet, the
+        // receipt counts against its window once it is accepted
+        if let Some(top_up_grace) = &self.top_up_grace {
+            if let Some(opened_at_ns) = top_up_grace.tolerates(receipt_sender, value) {
+                let windows = top_up_grace.windows.clone();
+                let sender = *receipt_sender;
+                PendingSettlements::defer_in(ctx, move || {
+                    windows.record(sender, opened_at_ns, value);
+                });
+                return Ok(());
+            }
+        }
+
+        Err(CheckError::Failed(anyhow!(
+            "Receipt sender `{}` does not have a sufficient balance",
+            receipt_sender,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use alloy::primitives::{Address, U256};
+    use anyhow::anyhow;
+    use indexer_config::EscrowUnavailablePolicy;
+    use indexer_monitor::{
+        EscrowAccounts, EscrowReservations, EscrowSource, FreshnessTrackingSource,
+    };
+    use sqlx::PgPool;
+    use tap_core::receipt::{
+        checks::{Check, CheckError},
+        Context, ReceiptWithState,
+    };
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+    use tokio::sync::watch;
+
+    use super::{GraceWindow, SenderBalanceCheck, TopUpGrace, TopUpGraceWindows};
+    use crate::{
+        middleware::{EscrowFreshnessState, ReceiptToken, Sender},
+        tap::PendingSettlements,
+    };
+
+    const SENDER: Address = Address::repeat_byte(1);
+    const TOKEN: Address = Address::repeat_byte(2);
+
+    #[tokio::test]
+    async fn test_reserved_balance_is_not_double_committed() {
+        let escrow_accounts = watch::channel(EscrowAccounts::new(
+            HashMap::from([(SENDER, U256::from(100))]),
+            HashMap::from([(SENDER, vec![SENDER])]),
+        ))
+        .1;
+        let reservations = EscrowReservations::default();
+        let check = SenderBalanceCheck::new(escrow_accounts, reservations.clone(), None, None);
+
+        let settlements = PendingSettlements::default();
+        let mut ctx = Context::new();
+        ctx.insert(Sender(SENDER));
+        ctx.insert(settlements.clone());
+        let receipt = |value| async move {
+            ReceiptWithState::new(
+                create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+            )
+        };
+
+        // 80 of the 100 are held for a quote
+        reservations.reserve(SENDER, U256::from(80), Duration::from_secs(60));
+        assert!(check.check(&ctx, &receipt(30).await).await.is_err());
+        assert!(check.check(&ctx, &receipt(20).await).await.is_ok());
+
+        // the receipt paying for the quote goes through, the hold is kept
+        // until the receipt is accepted
+        assert!(check.check(&ctx, &receipt(80).await).await.is_ok());
+        assert_eq!(reservations.reserved(&SENDER), U256::from(80));
+        assert!(check.check(&ctx, &receipt(30).await).await.is_err());
+
+        settlements.settle();
+        assert_eq!(reservations.reserved(&SENDER), U256::ZERO);
+        assert!(check.check(&ctx, &receipt(30).await).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_receipt_token() {
+        let escrow_accounts = watch::channel(EscrowAccounts::new(
+            HashMap::from([(SENDER, U256::from(100))]),
+            HashMap::from([(SENDER, vec![SENDER])]),
+        ))
+        .1;
+        let check = SenderBalanceCheck::new(
+            escrow_accounts,
+            EscrowReservations::default(),
+            None,
+            Some(TOKEN),
+        );
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(10).build()).await,
+        );
+        let ctx = |token| {
+            let mut ctx = Context::new();
+            ctx.insert(Sender(SENDER));
+            ctx.insert(ReceiptToken(token));
+            ctx
+        };
+
+        assert!(check.check(&ctx(TOKEN), &receipt).await.is_ok());
+        assert!(check
+            .check(&ctx(Address::repeat_byte(3)), &receipt)
+            .await
+            .is_err());
+    }
+
+    /// Checks the receipt, settling it as if every other check accepted it
+    async fn accepted(check: &SenderBalanceCheck, value: u128) -> bool {
+        let settlements = PendingSettlements::default();
+        let mut ctx = Context::new();
+        ctx.insert(Sender(SENDER));
+        ctx.insert(settlements.clone());
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+        );
+        let accepted = check.check(&ctx, &receipt).await.is_ok();
+        settlements.settle();
+        accepted
+    }
+
+    fn accounts(balance: u64) -> EscrowAccounts {
+        EscrowAccounts::new(
+            HashMap::from([(SENDER, U256::from(balance))]),
+            HashMap::from([(SENDER, vec![SENDER])]),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_top_up_grace() {
+        let (escrow_tx, escrow_accounts) = watch::channel(accounts(0));
+        let windows = TopUpGraceWindows::default();
+        let check = SenderBalanceCheck::new(
+            escrow_accounts.clone(),
+            EscrowReservations::default(),
+            Some(TopUpGrace::new(
+                50,
+                Duration::from_millis(100),
+                windows.clone(),
+            )),
+            None,
+        );
+
+        // up to 50 are accepted without balance
+        assert!(accepted(&check, 30).await);
+        assert!(!accepted(&check, 30).await);
+
+        // a receipt refused by a later check doesn't count
+        let mut ctx = Context::new();
+        ctx.insert(Sender(SENDER));
+        ctx.insert(PendingSettlements::default());
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(20).build()).await,
+        );
+        assert!(check.check(&ctx, &receipt).await.is_ok());
+        assert!(accepted(&check, 20).await);
+
+        // the window is kept by the checks built on reload
+        let rebuilt = SenderBalanceCheck::new(
+            escrow_accounts,
+            EscrowReservations::default(),
+            Some(TopUpGrace::new(
+                50,
+                Duration::from_millis(100),
+                windows.clone(),
+            )),
+            None,
+        );
+        assert!(!accepted(&rebuilt, 1).await);
+
+        // and only lasts for its duration
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!accepted(&check, 1).await);
+
+        // the top-up shows up, running out again opens a new window
+        escrow_tx.send(accounts(100)).unwrap();
+        assert!(accepted(&check, 10).await);
+        escrow_tx.send(accounts(0)).unwrap();
+        assert!(accepted(&check, 10).await);
+    }
+
+    /// Serves the accounts until it is told the subgraph is down
+    struct FlakySource(AtomicBool);
+
+    #[async_trait::async_trait]
+    impl EscrowSource for FlakySource {
+        async fn escrow_accounts(&self) -> anyhow::Result<EscrowAccounts> {
+            if self.0.load(Ordering::SeqCst) {
+                Err(anyhow!("escrow subgraph is down"))
+            } else {
+                Ok(accounts(100))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_escrow_accounts() {
+        let source = Arc::new(FlakySource(AtomicBool::new(false)));
+        let (tracking, freshness) = FreshnessTrackingSource::new(source.clone());
+        let check = SenderBalanceCheck::new(
+            watch::channel(tracking.escrow_accounts().await.unwrap()).1,
+            EscrowReservations::default(),
+            None,
+            None,
+        )
+        .with_escrow_freshness(Some(EscrowFreshnessState {
+            freshness,
+            policy: EscrowUnavailablePolicy::RejectPaid,
+            max_staleness: None,
+        }));
+        let mut ctx = Context::new();
+        ctx.insert(Sender(SENDER));
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(10).build()).await,
+        );
+        assert!(check.check(&ctx, &receipt).await.is_ok());
+
+        // the last balance read isn't trusted once the policy refuses it
+        source.0.store(true, Ordering::SeqCst);
+        tracking.escrow_accounts().await.unwrap_err();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(matches!(
+            check.check(&ctx, &receipt).await,
+            Err(CheckError::Retryable(_))
+        ));
+
+        source.0.store(false, Ordering::SeqCst);
+        tracking.escrow_accounts().await.unwrap();
+        assert!(check.check(&ctx, &receipt).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_top_up_grace_for_insufficient_balance() {
+        let escrow_accounts = watch::channel(accounts(100)).1;
+        let reservations = EscrowReservations::default();
+        let check = SenderBalanceCheck::new(
+            escrow_accounts,
+            reservations.clone(),
+            Some(TopUpGrace::new(
+                50,
+                Duration::from_secs(60),
+                Default::default(),
+            )),
+            None,
+        );
+
+        // 90 of the 100 are held for a quote, the rest doesn't cover the receipt
+        reservations.reserve(SENDER, U256::from(90), Duration::from_secs(60));
+        assert!(accepted(&check, 40).await);
+        assert!(!accepted(&check, 40).await);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_top_up_grace_windows_are_stored(pgpool: PgPool) {
+        let escrow_accounts = watch::channel(accounts(0)).1;
+        let check = |windows| {
+            SenderBalanceCheck::new(
+                escrow_accounts.clone(),
+                EscrowReservations::default(),
+                Some(TopUpGrace::new(50, Duration::from_secs(60), windows)),
+                None,
+            )
+        };
+
+        let windows = TopUpGraceWindows::load(pgpool.clone()).await;
+        assert!(accepted(&check(windows.clone()), 40).await);
+        let opened_at_ns = windows.get(&SENDER).unwrap().opened_at_ns;
+
+        // written in the background
+        let stored = loop {
+            let stored = TopUpGraceWindows::load(pgpool.clone()).await;
+            if stored.get(&SENDER).is_some() {
+                break stored;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(
+            stored.get(&SENDER),
+            Some(GraceWindow {
+                opened_at_ns,
+                value: U256::from(40),
+            })
+        );
+
+        // a restart doesn't open a new window
+        assert!(!accepted(&check(stored), 40).await);
     }
 }
@@ -0,0 +1,93 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult, ReceiptCheck},
+    state::Checking,
+    Context, ReceiptWithState,
+};
+
+/// Bounds how long a check may take.
+///
+/// A check that doesn't finish in time fails with a retryable error, so the
+/// sender is asked to come back later instead of having its receipt rejected.
+pub struct TimeoutCheck {
+    name: &'static str,
+    timeout: Duration,
+    inner: ReceiptCheck,
+}
+
+impl TimeoutCheck {
+    pub fn wrap(name: &'static str, timeout: Duration, inner: ReceiptCheck) -> ReceiptCheck {
+        Arc::new(Self {
+            name,
+            timeout,
+            inner,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for TimeoutCheck {
+    async fn check(&self, ctx: &Context, receipt: &ReceiptWithState<Checking>) -> CheckResult {
+        match tokio::time::timeout(self.timeout, self.inner.check(ctx, receipt)).await {
+            Ok(result) => result,
+            Err(_) => Err(CheckError::Retryable(anyhow!(
+                "Check `{}` did not complete within {:?}",
+                self.name,
+                self.timeout
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tap_core::receipt::{
+        checks::{Check, CheckError, CheckResult},
+        state::Checking,
+        Context, ReceiptWithState,
+    };
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+
+    use super::TimeoutCheck;
+
+    struct SlowCheck(Duration);
+
+    #[async_trait::async_trait]
+    impl Check for SlowCheck {
+        async fn check(&self, _: &Context, _: &ReceiptWithState<Checking>) -> CheckResult {
+            tokio::time::sleep(self.0).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_timeout() {
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().build()).await,
+        );
+
+        let check = TimeoutCheck::wrap(
+            "slow",
+            Duration::from_millis(50),
+            Arc::new(SlowCheck(Duration::from_millis(10))),
+        );
+        assert!(check.check(&Context::new(), &receipt).await.is_ok());
+
+        let check = TimeoutCheck::wrap(
+            "slow",
+            Duration::from_millis(10),
+            Arc::new(SlowCheck(Duration::from_secs(10))),
+        );
+        let result = check.check(&Context::new(), &receipt).await;
+        assert!(
+            matches!(result, Err(CheckError::Retryable(ref e)) if e.to_string().contains("`slow`"))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use alloy::primitives::Address;
+use anyhow::anyhow;
+use indexer_config::TimestampGapAction;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    state::Checking,
+    ReceiptWithState,
+};
+use tracing::warn;
+
+use crate::{metrics::RECEIPT_TIMESTAMP_GAP, middleware::Sender};
+
+/// Flags senders whose receipt timestamps suddenly jump ahead of their history
+///
+/// This is complementary to [super::timestamp_check::TimestampCheck], which only
+/// compares each receipt with our own clock. Here the timestamp of a receipt is
+/// compared with the last one seen for the same sender: if it advanced more than
+/// the time that passed on our side plus `max_gap`, the gateway clock probably
+/// drifted or was tampered with. Being a heuristic, it can be set to only warn.
+pub struct TimestampGapCheck {
+    max_gap: Duration,
+    action: TimestampGapAction,
+    last_seen: TimestampHistory,
+}
+
+/// Last receipt timestamp of every sender and when it was seen, kept by the
+/// [crate::tap::CheckPipeline] so reloading the checks doesn't forget it
+#[derive(Clone, Default)]
+pub struct TimestampHistory(Arc<Mutex<HashMap<Address, (Duration, Instant)>>>);
+
+impl TimestampGapCheck {
+    pub fn new(max_gap: Duration, action: TimestampGapAction, last_seen: TimestampHistory) -> Self {
+        Self {
+            max_gap,
+            action,
+            last_seen,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for TimestampGapCheck {
+    async fn check(
+        &self,
+        ctx: &tap_core::receipt::Context,
+        receipt: &ReceiptWithState<Checking>,
+    ) -> CheckResult {
+        let Sender(sender) = ctx
+            .get::<Sender>()
+            .ok_or(CheckError::Failed(anyhow!("Could not find sender")))?;
+        let timestamp = Duration::from_nanos(receipt.signed_receipt().message.timestamp_ns);
+        let now = Instant::now();
+
+        let mut last_seen = self.last_seen.0.lock().unwrap();
+        if let Some((last_timestamp, last_seen_at)) = last_seen.get(sender) {
+            let elapsed = now.duration_since(*last_seen_at);
+            let gap = timestamp.saturating_sub(*last_timestamp);
+            if gap > elapsed + self.max_gap {
+                RECEIPT_TIMESTAMP_GAP
+                    .with_label_values(&[&sender.to_string()])
+                    .inc();
+                warn!(
+                    %sender,
+                    gap_secs = gap.as_secs_f64(),
+                    elapsed_secs = elapsed.as_secs_f64(),
+                    "Receipt timestamp jumped ahead of the sender's previous receipts"
+                );
+                if self.action == TimestampGapAction::Reject {
+                    return Err(CheckError::Failed(anyhow!(
+                        "Receipt timestamp `{}` jumped more than `{}` seconds ahead of the \
+                        previous receipts of sender `{}`",
+                        timestamp.as_secs(),
+                        self.max_gap.as_secs_f64(),
+                        sender,
+                    )));
+                }
+            }
+            if timestamp <= *last_timestamp {
+                return Ok(());
+            }
+        }
+        last_seen.insert(*sender, (timestamp, now));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use indexer_config::TimestampGapAction;
+    use tap_core::receipt::{checks::Check, Context, ReceiptWithState};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_SENDER};
+
+    use super::{TimestampGapCheck, TimestampHistory};
+    use crate::middleware::Sender;
+
+    async fn receipt_at(ahead: Duration) -> ReceiptWithState<tap_core::receipt::state::Checking> {
+        let timestamp_ns = (SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            + ahead)
+            .as_nanos() as u64;
+        ReceiptWithState::new(
+            create_signed_receipt(
+                SignedReceiptRequest::builder()
+                    .timestamp_ns(timestamp_ns)
+                    .build(),
+            )
+            .await,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_gap_reject() {
+        let last_seen = TimestampHistory::default();
+        let check = TimestampGapCheck::new(
+            Duration::from_secs(60),
+            TimestampGapAction::Reject,
+            last_seen.clone(),
+        );
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+
+        // first receipt of the sender, nothing to compare with
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::ZERO).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::from_secs(30)).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::from_secs(3600)).await)
+            .await
+            .is_err());
+
+        // a rebuilt check keeps the history of the sender
+        let check = TimestampGapCheck::new(
+            Duration::from_secs(60),
+            TimestampGapAction::Reject,
+            last_seen,
+        );
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::from_secs(3600)).await)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_gap_warn_only() {
+        let check = TimestampGapCheck::new(
+            Duration::from_secs(60),
+            TimestampGapAction::Warn,
+            Default::default(),
+        );
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::ZERO).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&ctx, &receipt_at(Duration::from_secs(3600)).await)
+            .await
+            .is_ok());
+    }
+}
@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use ::cost_model::CostModel;
+use alloy::primitives::U256;
 use anyhow::anyhow;
 use bigdecimal::ToPrimitive;
+use indexer_monitor::EscrowReservations;
 use sqlx::{
     postgres::{PgListener, PgNotification},
     PgPool,
@@ -12,10 +14,10 @@ use std::time::Duration;
 use std::{
     collections::HashMap,
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, PoisonError, RwLock},
     time::Instant,
 };
-use thegraph_core::DeploymentId;
+use thegraph_core::{Address, DeploymentId};
 use tracing::error;
 
 use tap_core::receipt::{
@@ -24,10 +26,16 @@ use tap_core::receipt::{
     Context, ReceiptWithState,
 };
 
-use crate::database::cost_model;
+use crate::{
+    database::cost_model,
+    middleware::{ReceiptToken, Sender},
+};
 
 // we only accept receipts with minimal 1 wei grt
 const MINIMAL_VALUE: u128 = 1;
+/// How long the escrow of a sender is held for the value its underpaid
+/// receipt was told to pay
+const QUOTE_RESERVATION_TTL: Duration = Duration::from_secs(30);
 
 /// Represents a query that can be checked against an agora model
 ///
@@ -39,6 +47,82 @@ pub struct AgoraQuery {
     pub variables: String,
 }
 
+/// Where the appraisal of a query comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppraisalSource {
+    /// the cost model of the deployment
+    Deployment,
+    /// the global cost model
+    Global,
+    /// [NoAppraisalPolicy::UseDefault], no cost model appraises the query
+    Default,
+}
+
+impl AppraisalSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppraisalSource::Deployment => "deployment",
+            AppraisalSource::Global => "global",
+            AppraisalSource::Default => "default",
+        }
+    }
+}
+
+/// Value the receipt of a query was checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Appraisal {
+    pub value: u128,
+    pub source: AppraisalSource,
+}
+
+/// Receives the [Appraisal] of the query from the [MinimumValue] check when it
+/// is in the receipt [Context]
+///
+/// Queries accepted without being appraised, during the grace period or above
+/// the floor of [NoAppraisalPolicy::AcceptAboveFloor], leave it empty.
+#[derive(Debug, Clone, Default)]
+pub struct AppraisalSlot(Arc<Mutex<Option<Appraisal>>>);
+
+impl AppraisalSlot {
+    pub fn set(&self, appraisal: Appraisal) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(appraisal);
+    }
+
+    pub fn get(&self) -> Option<Appraisal> {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// What to do with queries no cost model appraises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoAppraisalPolicy {
+    Reject,
+    /// accept receipts worth at least this much
+    AcceptAboveFloor(u128),
+    /// appraise the query at this value
+    UseDefault(u128),
+}
+
+impl Default for NoAppraisalPolicy {
+    fn default() -> Self {
+        Self::AcceptAboveFloor(MINIMAL_VALUE)
+    }
+}
+
+impl From<&indexer_config::NoAppraisalPolicy> for NoAppraisalPolicy {
+    fn from(policy: &indexer_config::NoAppraisalPolicy) -> Self {
+        match policy {
+            indexer_config::NoAppraisalPolicy::Reject => Self::Reject,
+            indexer_config::NoAppraisalPolicy::AcceptAboveFloor(floor) => {
+                Self::AcceptAboveFloor(floor.get_value())
+            }
+            indexer_config::NoAppraisalPolicy::UseDefault(value) => {
+                Self::UseDefault(value.get_value())
+            }
+        }
+    }
+}
+
 type CostModelMap = Arc<RwLock<HashMap<DeploymentId, CostModel>>>;
 type GlobalModel = Arc<RwLock<Option<CostModel>>>;
 type GracePeriod = Arc<RwLock<Instant>>;
@@ -53,6 +137,11 @@ pub struct MinimumValue {
     watcher_cancel_token: tokio_util::sync::CancellationToken,
     updated_at: GracePeriod,
     grace_period: Duration,
+    /// token the cost models are priced in
+    token: Option<Address>,
+    no_appraisal_policy: NoAppraisalPolicy,
+    /// escrow held for the value underpaid receipts are told to pay
+    reservations: Option<EscrowReservations>,
 
     #[cfg(test)]
     notify: std::sync::Arc<tokio::sync::Notify>,
@@ -127,11 +216,17 @@ impl CostModelWatcher {
 
         match deployment.as_str() {
             "global" => {
-                *self.global_model.write().unwrap() = Some(model);
+                *self
+                    .global_model
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = Some(model);
             }
             deployment_id => match DeploymentId::from_str(deployment_id) {
                 Ok(deployment_id) => {
-                    let mut cost_model_write = self.cost_models.write().unwrap();
+                    let mut cost_model_write = self
+                        .cost_models
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner);
                     cost_model_write.insert(deployment_id, model);
                 }
                 Err(_) => {
@@ -143,17 +238,26 @@ impl CostModelWatcher {
             },
         };
 
-        *self.updated_at.write().unwrap() = Instant::now();
+        *self
+            .updated_at
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
     }
 
     fn handle_delete(&self, deployment: String) {
         match deployment.as_str() {
             "global" => {
-                *self.global_model.write().unwrap() = None;
+                *self
+                    .global_model
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = None;
             }
             deployment_id => match DeploymentId::from_str(deployment_id) {
                 Ok(deployment_id) => {
-                    self.cost_models.write().unwrap().remove(&deployment_id);
+                    self.cost_models
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(&deployment_id);
                 }
                 Err(_) => {
                     error!(
@@ -163,7 +267,10 @@ impl CostModelWatcher {
                 }
             },
         };
-        *self.updated_at.write().unwrap() = Instant::now();
+        *self
+            .updated_at
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
     }
 
     async fn handle_unexpected_notification(&self, payload: &str) {
@@ -181,7 +288,10 @@ impl CostModelWatcher {
         .await
         .expect("should be able to reload cost models");
 
-        *self.updated_at.write().unwrap() = Instant::now();
+        *self
+            .updated_at
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
     }
 }
 
@@ -194,7 +304,12 @@ impl Drop for MinimumValue {
 }
 
 impl MinimumValue {
-    pub async fn new(pgpool: PgPool, grace_period: Duration) -> Self {
+    pub async fn new(
+        pgpool: PgPool,
+        grace_period: Duration,
+        token: Option<Address>,
+        no_appraisal_policy: NoAppraisalPolicy,
+    ) -> Self {
         let cost_model_map: CostModelMap = Default::default();
         let global_model: GlobalModel = Default::default();
         let updated_at: GracePeriod = Arc::new(RwLock::new(Instant::now()));
@@ -231,32 +346,55 @@ impl MinimumValue {
             watcher_cancel_token,
             updated_at,
             grace_period,
+            token,
+            no_appraisal_policy,
+            reservations: None,
             #[cfg(test)]
             notify,
         }
     }
 
+    /// Refusing an underpaid receipt quotes the value of the query: the
+    /// escrow of the sender is held for it until a receipt pays it, or for
+    /// [QUOTE_RESERVATION_TTL]
+    pub fn with_reservations(mut self, reservations: EscrowReservations) -> Self {
+        self.reservations = Some(reservations);
+        self
+    }
+
     fn inside_grace_period(&self) -> bool {
-        let time_elapsed = Instant::now().duration_since(*self.updated_at.read().unwrap());
+        let time_elapsed = Instant::now().duration_since(
+            *self
+                .updated_at
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
         time_elapsed < self.grace_period
     }
 
-    fn expected_value(&self, agora_query: &AgoraQuery) -> anyhow::Result<u128> {
+    /// Value the cost models appraise the query at, if any does
+    fn expected_value(&self, agora_query: &AgoraQuery) -> Option<(u128, AppraisalSource)> {
         // get agora model for the deployment_id
-        let model = self.cost_model_map.read().unwrap();
+        let model = self
+            .cost_model_map
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
         let subgraph_model = model.get(&agora_query.deployment_id);
-        let global_model = self.global_model.read().unwrap();
-
-        let expected_value = match (subgraph_model, global_model.as_ref()) {
-            (Some(model), _) | (_, Some(model)) => model
-                .cost(&agora_query.query, &agora_query.variables)
-                .map(|fee| fee.to_u128())
-                .ok()
-                .flatten(),
-            _ => None,
+        let global_model = self
+            .global_model
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let (model, source) = match (subgraph_model, global_model.as_ref()) {
+            (Some(model), _) => (model, AppraisalSource::Deployment),
+            (None, Some(model)) => (model, AppraisalSource::Global),
+            (None, None) => return None,
         };
-
-        Ok(expected_value.unwrap_or(MINIMAL_VALUE))
+        model
+            .cost(&agora_query.query, &agora_query.variables)
+            .ok()
+            .and_then(|fee| fee.to_u128())
+            .map(|fee| (fee, source))
     }
 
     async fn value_check_reload(
@@ -287,9 +425,11 @@ impl MinimumValue {
             })
             .collect::<HashMap<_, _>>();
 
-        *cost_model_map.write().unwrap() = models;
+        *cost_model_map
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = models;
 
-        *global_model.write().unwrap() =
+        *global_model.write().unwrap_or_else(PoisonError::into_inner) =
             cost_model::global_cost_model(pgpool)
                 .await?
                 .and_then(|model| {
@@ -310,6 +450,15 @@ impl Check for MinimumValue {
         let agora_query = ctx
             .get()
             .ok_or(CheckError::Failed(anyhow!("Could not find agora query")))?;
+        // a value in another token can't be compared to the appraisal
+        if let Some(ReceiptToken(token)) = ctx.get::<ReceiptToken>() {
+            if self.token != Some(*token) {
+                return Err(CheckError::Failed(anyhow!(
+                    "Receipt token `{}` is not supported",
+                    token
+                )));
+            }
+        }
         // get value
         let value = receipt.signed_receipt().message.value;
 
@@ -317,10 +466,32 @@ impl Check for MinimumValue {
             return Ok(());
         }
 
-        let expected_value = self
-            .expected_value(agora_query)
-            .map_err(CheckError::Failed)?;
+        let appraisal = match (self.expected_value(agora_query), self.no_appraisal_policy) {
+            (Some((value, source)), _) => Appraisal { value, source },
+            (None, NoAppraisalPolicy::UseDefault(value)) => Appraisal {
+                value,
+                source: AppraisalSource::Default,
+            },
+            (None, NoAppraisalPolicy::AcceptAboveFloor(floor)) if value >= floor => return Ok(()),
+            (None, NoAppraisalPolicy::AcceptAboveFloor(floor)) => {
+                return Err(CheckError::Failed(anyhow!(
+                    "Query receipt is below the value accepted for queries without appraisal. \
+                    Expected value: {}. Received value: {}.",
+                    floor,
+                    value,
+                )));
+            }
+            (None, NoAppraisalPolicy::Reject) => {
+                return Err(CheckError::Failed(anyhow!(
+                    "No cost model appraises the query"
+                )));
+            }
+        };
 
+        if let Some(slot) = ctx.get::<AppraisalSlot>() {
+            slot.set(appraisal);
+        }
+        let expected_value = appraisal.value;
         let should_accept = value >= expected_value;
 
         tracing::trace!(
@@ -333,6 +504,11 @@ impl Check for MinimumValue {
         if should_accept {
             Ok(())
         } else {
+            if let (Some(reservations), Some(Sender(sender))) =
+                (&self.reservations, ctx.get::<Sender>())
+            {
+                reservations.reserve(*sender, U256::from(expected_value), QUOTE_RESERVATION_TTL);
+            }
             return Err(CheckError::Failed(anyhow!(
                 "Query receipt does not have the minimum value. Expected value: {}. Received value: {}.",
                 expected_value, value,
@@ -364,23 +540,28 @@ enum CostModelNotification {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{str::FromStr, time::Duration};
     use test_assets::{create_signed_receipt, flush_messages, SignedReceiptRequest};
 
+    use alloy::primitives::U256;
+    use indexer_monitor::EscrowReservations;
     use sqlx::PgPool;
     use tap_core::receipt::{checks::Check, Context, ReceiptWithState};
+    use thegraph_core::{Address, DeploymentId};
     use tokio::time::sleep;
 
     use super::AgoraQuery;
-    use crate::database::cost_model::test::{
-        self, add_cost_models, global_cost_model, to_db_models,
+    use crate::{
+        database::cost_model::test::{self, add_cost_models, global_cost_model, to_db_models},
+        middleware::{ReceiptToken, Sender},
     };
 
-    use super::MinimumValue;
+    use super::{MinimumValue, NoAppraisalPolicy};
 
     #[sqlx::test(migrations = "../../migrations")]
     async fn initialize_check(pgpool: PgPool) {
-        let check = MinimumValue::new(pgpool, Duration::from_secs(0)).await;
+        let check =
+            MinimumValue::new(pgpool, Duration::from_secs(0), None, Default::default()).await;
         assert_eq!(check.cost_model_map.read().unwrap().len(), 0);
     }
 
@@ -391,7 +572,8 @@ mod tests {
 
         add_cost_models(&pgpool, to_db_models(test_models.clone())).await;
 
-        let check = MinimumValue::new(pgpool, Duration::from_secs(0)).await;
+        let check =
+            MinimumValue::new(pgpool, Duration::from_secs(0), None, Default::default()).await;
         assert_eq!(check.cost_model_map.read().unwrap().len(), 2);
 
         // no global model
@@ -400,7 +582,13 @@ mod tests {
 
     #[sqlx::test(migrations = "../../migrations")]
     async fn should_watch_model_insert(pgpool: PgPool) {
-        let check = MinimumValue::new(pgpool.clone(), Duration::from_secs(0)).await;
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            Default::default(),
+        )
+        .await;
         assert_eq!(check.cost_model_map.read().unwrap().len(), 0);
 
         // insert 2 cost models for different deployment_id
@@ -421,7 +609,13 @@ mod tests {
         let test_models = test::test_data();
         add_cost_models(&pgpool, to_db_models(test_models.clone())).await;
 
-        let check = MinimumValue::new(pgpool.clone(), Duration::from_secs(0)).await;
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            Default::default(),
+        )
+        .await;
         assert_eq!(check.cost_model_map.read().unwrap().len(), 2);
 
         // remove
@@ -440,13 +634,25 @@ mod tests {
         let global_model = global_cost_model();
         add_cost_models(&pgpool, vec![global_model.clone()]).await;
 
-        let check = MinimumValue::new(pgpool.clone(), Duration::from_secs(0)).await;
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            Default::default(),
+        )
+        .await;
         assert!(check.global_model.read().unwrap().is_some());
     }
 
     #[sqlx::test(migrations = "../../migrations")]
     async fn should_watch_global_model(pgpool: PgPool) {
-        let check = MinimumValue::new(pgpool.clone(), Duration::from_secs(0)).await;
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            Default::default(),
+        )
+        .await;
 
         let global_model = global_cost_model();
         add_cost_models(&pgpool, vec![global_model.clone()]).await;
@@ -461,7 +667,13 @@ mod tests {
         let global_model = global_cost_model();
         add_cost_models(&pgpool, vec![global_model.clone()]).await;
 
-        let check = MinimumValue::new(pgpool.clone(), Duration::from_secs(0)).await;
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            Default::default(),
+        )
+        .await;
         assert!(check.global_model.read().unwrap().is_some());
 
         sqlx::query!(r#"DELETE FROM "CostModels""#)
@@ -483,7 +695,7 @@ mod tests {
 
         let grace_period = Duration::from_secs(1);
 
-        let check = MinimumValue::new(pgpool, grace_period).await;
+        let check = MinimumValue::new(pgpool, grace_period, None, Default::default()).await;
 
         let deployment_id = test_models[0].deployment;
         let mut ctx = Context::new();
@@ -573,7 +785,8 @@ mod tests {
         add_cost_models(&pgpool, vec![global_model.clone()]).await;
         add_cost_models(&pgpool, to_db_models(test_models.clone())).await;
 
-        let check = MinimumValue::new(pgpool, Duration::from_secs(0)).await;
+        let check =
+            MinimumValue::new(pgpool, Duration::from_secs(0), None, Default::default()).await;
 
         let deployment_id = test_models[0].deployment;
         let mut ctx = Context::new();
@@ -623,4 +836,132 @@ mod tests {
             .await
             .expect("should accept more than global");
     }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_reserve_the_quoted_value(pgpool: PgPool) {
+        add_cost_models(&pgpool, vec![global_cost_model()]).await;
+        let reservations = EscrowReservations::default();
+        let check = MinimumValue::new(pgpool, Duration::from_secs(0), None, Default::default())
+            .await
+            .with_reservations(reservations.clone());
+        let sender = Address::repeat_byte(1);
+
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: test::test_data()[0].deployment,
+            query: "query { a(skip: 10), b(bob: 5) }".into(),
+            variables: "".into(),
+        });
+        ctx.insert(Sender(sender));
+        let minimal_global_value = 20000000000000;
+        let receipt = |value| async move {
+            ReceiptWithState::new(
+                create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+            )
+        };
+
+        // the underpaid receipt is refused, the escrow held for its quote
+        assert!(check
+            .check(&ctx, &receipt(minimal_global_value - 1).await)
+            .await
+            .is_err());
+        assert!(reservations.holds(&sender, U256::from(minimal_global_value)));
+
+        // an accepted receipt holds nothing more
+        check
+            .check(&ctx, &receipt(minimal_global_value).await)
+            .await
+            .expect("should accept the quoted value");
+        assert_eq!(
+            reservations.reserved(&sender),
+            U256::from(minimal_global_value)
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_check_receipt_token(pgpool: PgPool) {
+        add_cost_models(&pgpool, vec![global_cost_model()]).await;
+        let token = Address::repeat_byte(1);
+        let check = MinimumValue::new(
+            pgpool,
+            Duration::from_secs(0),
+            Some(token),
+            Default::default(),
+        )
+        .await;
+
+        let ctx = |receipt_token| {
+            let mut ctx = Context::new();
+            ctx.insert(AgoraQuery {
+                deployment_id: test::test_data()[0].deployment,
+                query: "query { a(skip: 10), b(bob: 5) }".into(),
+                variables: "".into(),
+            });
+            ctx.insert(ReceiptToken(receipt_token));
+            ctx
+        };
+        let signed_receipt = create_signed_receipt(
+            SignedReceiptRequest::builder()
+                .value(20000000000000)
+                .build(),
+        )
+        .await;
+        let receipt = ReceiptWithState::new(signed_receipt);
+
+        check
+            .check(&ctx(token), &receipt)
+            .await
+            .expect("should accept the configured token");
+        assert!(
+            check
+                .check(&ctx(Address::repeat_byte(2)), &receipt)
+                .await
+                .is_err(),
+            "Should deny other tokens"
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_apply_no_appraisal_policy(pgpool: PgPool) {
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: DeploymentId::from_str("Qmnononononononononononononononononononononono")
+                .unwrap(),
+            query: "query { a(skip: 10), b(bob: 5) }".into(),
+            variables: "".into(),
+        });
+        let receipt = |value| async move {
+            ReceiptWithState::new(
+                create_signed_receipt(SignedReceiptRequest::builder().value(value).build()).await,
+            )
+        };
+
+        let check = MinimumValue::new(
+            pgpool.clone(),
+            Duration::from_secs(0),
+            None,
+            NoAppraisalPolicy::Reject,
+        )
+        .await;
+        assert!(
+            check.check(&ctx, &receipt(u128::MAX).await).await.is_err(),
+            "Should deny queries without appraisal"
+        );
+
+        for policy in [
+            NoAppraisalPolicy::AcceptAboveFloor(100),
+            NoAppraisalPolicy::UseDefault(100),
+        ] {
+            let check =
+                MinimumValue::new(pgpool.clone(), Duration::from_secs(0), None, policy).await;
+            assert!(
+                check.check(&ctx, &receipt(99).await).await.is_err(),
+                "Should deny less than the value of {policy:?}"
+            );
+            check
+                .check(&ctx, &receipt(100).await)
+                .await
+                .expect("should accept the value of the policy");
+        }
+    }
 }
@@ -0,0 +1,126 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keeps the receipts that paid for queries that can never be served
+//!
+//! Unlike the receipts of the [ReceiptLog](super::ReceiptLog), these passed the
+//! checks and are collected like any other. They are recorded with why their
+//! query failed, so operators can tell which senders keep paying for queries
+//! that will never succeed.
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use bigdecimal::{num_bigint::BigInt, ToPrimitive};
+use serde::Serialize;
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::DeploymentId;
+
+/// A dead-lettered receipt, as listed by the admin route
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetterRecord {
+    pub id: i64,
+    pub allocation_id: String,
+    pub sender: Option<String>,
+    pub signature: String,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    pub value: String,
+    pub deployment_id: Option<String>,
+    pub failure: String,
+    /// when the receipt was dead-lettered, in RFC 3339
+    pub created_at: String,
+}
+
+#[derive(Clone)]
+pub struct DeadLetterStore {
+    pgpool: PgPool,
+}
+
+impl DeadLetterStore {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self { pgpool }
+    }
+
+    pub async fn record(
+        &self,
+        receipt: &SignedReceipt,
+        sender: Option<Address>,
+        deployment_id: Option<DeploymentId>,
+        failure: String,
+    ) -> anyhow::Result<()> {
+        let message = &receipt.message;
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_dead_letter_receipts (
+                    allocation_id,
+                    sender_address,
+                    signature,
+                    timestamp_ns,
+                    nonce,
+                    value,
+                    deployment_id,
+                    failure
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+            message.allocation_id.encode_hex(),
+            sender.map(|sender| sender.encode_hex()),
+            receipt.signature.as_bytes().to_vec(),
+            BigDecimal::from(message.timestamp_ns),
+            BigDecimal::from(message.nonce),
+            BigDecimal::from(BigInt::from(message.value)),
+            deployment_id.map(|deployment| deployment.to_string()),
+            failure,
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    /// Latest dead-lettered receipts first
+    pub async fn list(&self, limit: i64) -> anyhow::Result<Vec<DeadLetterRecord>> {
+        let rows = sqlx::query!(
+            r#"
+                SELECT
+                    id,
+                    allocation_id,
+                    sender_address,
+                    signature,
+                    timestamp_ns,
+                    nonce,
+                    value,
+                    deployment_id,
+                    failure,
+                    created_at
+                FROM scalar_tap_dead_letter_receipts
+                ORDER BY id DESC
+                LIMIT $1
+            "#,
+            limit,
+        )
+        .fetch_all(&self.pgpool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(DeadLetterRecord {
+                    id: row.id,
+                    allocation_id: format!("0x{}", row.allocation_id),
+                    sender: row.sender_address.map(|sender| format!("0x{sender}")),
+                    signature: row.signature.encode_hex_with_prefix(),
+                    timestamp_ns: row
+                        .timestamp_ns
+                        .to_u64()
+                        .ok_or_else(|| anyhow!("Invalid timestamp_ns {}", row.timestamp_ns))?,
+                    nonce: row
+                        .nonce
+                        .to_u64()
+                        .ok_or_else(|| anyhow!("Invalid nonce {}", row.nonce))?,
+                    value: row.value.to_string(),
+                    deployment_id: row.deployment_id,
+                    failure: row.failure,
+                    created_at: row.created_at.to_rfc3339(),
+                })
+            })
+            .collect()
+    }
+}
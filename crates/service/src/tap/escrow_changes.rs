@@ -0,0 +1,195 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recent changes of the escrow balances, served by the TAP stats endpoint
+//!
+//! Every new snapshot of the escrow accounts is compared to the previous one
+//! and the balances that moved are recorded, so operators can tell top-ups and
+//! withdrawals apart from receipts being refused. Only the latest changes are
+//! kept, up to [MAX_CHANGES_PER_SENDER] per sender and [MAX_CHANGES] overall.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use alloy::primitives::{Address, U256};
+use indexer_monitor::EscrowAccounts;
+use serde::Serialize;
+use tokio::sync::watch;
+
+pub const MAX_CHANGES_PER_SENDER: usize = 20;
+pub const MAX_CHANGES: usize = 1000;
+
+/// Balance of a sender that moved between two snapshots of the escrow accounts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EscrowChange {
+    pub sender: Address,
+    /// seconds since the UNIX epoch
+    pub timestamp: u64,
+    #[serde(serialize_with = "serialize_balance")]
+    pub previous_balance: U256,
+    #[serde(serialize_with = "serialize_balance")]
+    pub balance: U256,
+}
+
+/// Balances in GRT wei don't fit in JSON numbers
+fn serialize_balance<S: serde::Serializer>(
+    balance: &U256,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&balance.to_string())
+}
+
+/// Balances that differ between both snapshots, a sender missing from one of
+/// them having no balance in it
+fn diff(previous: &EscrowAccounts, current: &EscrowAccounts, timestamp: u64) -> Vec<EscrowChange> {
+    let mut senders: Vec<_> = previous
+        .get_senders()
+        .union(&current.get_senders())
+        .copied()
+        .collect();
+    senders.sort();
+    senders
+        .into_iter()
+        .filter_map(|sender| {
+            let previous_balance = previous.get_balance_for_sender(&sender).unwrap_or_default();
+            let balance = current.get_balance_for_sender(&sender).unwrap_or_default();
+            (previous_balance != balance).then_some(EscrowChange {
+                sender,
+                timestamp,
+                previous_balance,
+                balance,
+            })
+        })
+        .collect()
+}
+
+/// Latest escrow changes, oldest first
+#[derive(Clone, Default)]
+pub struct EscrowChanges(Arc<Mutex<VecDeque<EscrowChange>>>);
+
+impl EscrowChanges {
+    /// Records the changes between the snapshots the escrow accounts go through
+    pub fn spawn(mut escrow_accounts: watch::Receiver<EscrowAccounts>) -> Self {
+        let changes = Self::default();
+        let recorder = changes.clone();
+        let mut previous = escrow_accounts.borrow_and_update().clone();
+        tokio::spawn(async move {
+            while escrow_accounts.changed().await.is_ok() {
+                let current = escrow_accounts.borrow_and_update().clone();
+                let timestamp = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                recorder.record(diff(&previous, &current, timestamp));
+                previous = current;
+            }
+        });
+        changes
+    }
+
+    fn record(&self, new_changes: Vec<EscrowChange>) {
+        let mut changes = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        for change in new_changes {
+            let sender = change.sender;
+            changes.push_back(change);
+            let from_sender = changes.iter().filter(|change| change.sender == sender);
+            if from_sender.count() > MAX_CHANGES_PER_SENDER {
+                let oldest = changes
+                    .iter()
+                    .position(|change| change.sender == sender)
+                    .expect("sender has changes");
+                changes.remove(oldest);
+            }
+            if changes.len() > MAX_CHANGES {
+                changes.pop_front();
+            }
+        }
+    }
+
+    /// Latest changes, newest first
+    pub fn latest(&self) -> Vec<EscrowChange> {
+        let changes = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        changes.iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use alloy::primitives::{Address, U256};
+    use indexer_monitor::EscrowAccounts;
+    use tokio::sync::watch;
+
+    use super::{EscrowChange, EscrowChanges, MAX_CHANGES_PER_SENDER};
+
+    fn accounts(balances: &[(Address, u64)]) -> EscrowAccounts {
+        EscrowAccounts::new(
+            balances
+                .iter()
+                .map(|(sender, balance)| (*sender, U256::from(*balance)))
+                .collect(),
+            HashMap::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_balance_changes_are_recorded() {
+        let sender_1 = Address::repeat_byte(1);
+        let sender_2 = Address::repeat_byte(2);
+        let (escrow_tx, escrow_rx) = watch::channel(accounts(&[(sender_1, 100), (sender_2, 50)]));
+        let changes = EscrowChanges::spawn(escrow_rx);
+
+        // sender 1 tops up, sender 2 withdraws everything
+        escrow_tx.send_replace(accounts(&[(sender_1, 150)]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let latest = changes.latest();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(
+            latest
+                .iter()
+                .map(|change| (change.sender, change.previous_balance, change.balance))
+                .collect::<Vec<_>>(),
+            vec![
+                (sender_2, U256::from(50), U256::ZERO),
+                (sender_1, U256::from(100), U256::from(150)),
+            ]
+        );
+
+        // snapshots without changes record nothing
+        escrow_tx.send_replace(accounts(&[(sender_1, 150)]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(changes.latest().len(), 2);
+    }
+
+    #[test]
+    fn test_changes_are_bounded_per_sender() {
+        let changes = EscrowChanges::default();
+        let change = |sender, balance: u64| EscrowChange {
+            sender,
+            timestamp: 0,
+            previous_balance: U256::ZERO,
+            balance: U256::from(balance),
+        };
+        changes.record(vec![change(Address::ZERO, 0)]);
+        changes.record(
+            (1..=MAX_CHANGES_PER_SENDER as u64 + 5)
+                .map(|balance| change(Address::repeat_byte(1), balance))
+                .collect(),
+        );
+
+        let latest = changes.latest();
+        assert_eq!(latest.len(), MAX_CHANGES_PER_SENDER + 1);
+        // the oldest changes of the sender are dropped, not those of others
+        assert_eq!(
+            latest[0].balance,
+            U256::from(MAX_CHANGES_PER_SENDER as u64 + 5)
+        );
+        assert_eq!(latest.last().unwrap().sender, Address::ZERO);
+    }
+}
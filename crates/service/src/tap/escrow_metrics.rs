@@ -0,0 +1,310 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-sender escrow balance and the part of it committed by receipts that
+//! have not been aggregated into a RAV yet.
+//!
+//! Both gauges are refreshed whenever the escrow accounts change and on a
+//! fixed interval, so the committed value follows the receipts being stored.
+//! Only the senders with the largest balances get their own label, the rest
+//! are summed into a single `other` series.
+//!
+//! Senders whose balance falls below the optional low balance threshold are
+//! logged once and flagged in their own gauge until they top up. This is only
+//! a warning, receipts keep being accepted until the balance check refuses them.
+//!
+//! The headroom of each sender, the fraction of its balance not committed, is
+//! published along for the escrow admission of queries.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use alloy::primitives::{Address, U256};
+use bigdecimal::ToPrimitive;
+use indexer_monitor::EscrowAccounts;
+use sqlx::PgPool;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::warn;
+
+use crate::metrics::{ESCROW_BALANCE, ESCROW_COMMITTED, ESCROW_LOW_BALANCE};
+
+/// Senders with their own series, beyond that they are grouped as `other`
+const MAX_SENDER_LABELS: usize = 100;
+const OTHER_SENDERS_LABEL: &str = "other";
+
+/// Fraction of each sender's escrow balance not committed by receipts, from
+/// 0 to 1. Senders without balance are left out
+pub type EscrowHeadroom = HashMap<Address, f64>;
+
+pub fn spawn_escrow_metrics(
+    pgpool: PgPool,
+    mut escrow_accounts: watch::Receiver<EscrowAccounts>,
+    refresh_interval: Duration,
+    low_balance_threshold: Option<u128>,
+    headroom: watch::Sender<EscrowHeadroom>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut low_balance = HashSet::new();
+        let mut interval = tokio::time::interval(refresh_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                changed = escrow_accounts.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+            let accounts = escrow_accounts.borrow().clone();
+            if let Some(threshold) = low_balance_threshold {
+                update_low_balance(&accounts, threshold, &mut low_balance);
+            }
+            match update_escrow_metrics(&pgpool, &accounts).await {
+                Ok(sender_headroom) => {
+                    headroom.send_replace(sender_headroom);
+                }
+                Err(e) => warn!(error = %e, "Failed to update escrow metrics"),
+            }
+        }
+    })
+}
+
+async fn update_escrow_metrics(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+) -> anyhow::Result<EscrowHeadroom> {
+    let committed_per_signer = sqlx::query!(
+        r#"
+            SELECT signer_address, SUM(value) AS "value!"
+            FROM scalar_tap_receipts
+            GROUP BY signer_address
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    let mut committed: HashMap<Address, f64> = HashMap::new();
+    for row in committed_per_signer {
+        let Ok(signer) = row.signer_address.parse::<Address>() else {
+            continue;
+        };
+        // receipts of signers no longer in escrow don't commit any balance
+        let Ok(sender) = escrow_accounts.get_sender_for_signer(&signer) else {
+            continue;
+        };
+        *committed.entry(sender).or_default() += row.value.to_f64().unwrap_or_default();
+    }
+
+    let balances = escrow_accounts
+        .get_senders()
+        .into_iter()
+        .map(|sender| {
+            let balance = escrow_accounts
+                .get_balance_for_sender(&sender)
+                .unwrap_or_default();
+            (sender, balance)
+        })
+        .collect::<Vec<_>>();
+
+    let headroom = sender_headroom(&balances, &committed);
+    ESCROW_BALANCE.reset();
+    ESCROW_COMMITTED.reset();
+    for (label, balance, committed) in labeled_senders(balances, &committed) {
+        ESCROW_BALANCE.with_label_values(&[&label]).set(balance);
+        ESCROW_COMMITTED.with_label_values(&[&label]).set(committed);
+    }
+    Ok(headroom)
+}
+
+fn sender_headroom(
+    balances: &[(Address, U256)],
+    committed: &HashMap<Address, f64>,
+) -> EscrowHeadroom {
+    balances
+        .iter()
+        .filter(|(_, balance)| *balance > U256::ZERO)
+        .map(|(sender, balance)| {
+            let balance = u128::try_from(*balance).map_or(f64::MAX, |balance| balance as f64);
+            let committed = committed.get(sender).copied().unwrap_or_default();
+            (*sender, ((balance - committed) / balance).clamp(0.0, 1.0))
+        })
+        .collect()
+}
+
+/// Flags the senders whose balance is below `threshold`, warning about the ones
+/// that weren't already below it. Returns those newly flagged
+fn update_low_balance(
+    escrow_accounts: &EscrowAccounts,
+    threshold: u128,
+    low_balance: &mut HashSet<Address>,
+) -> Vec<Address> {
+    let threshold = U256::from(threshold);
+    let mut newly_low = Vec::new();
+    let mut still_low = HashSet::new();
+    for sender in escrow_accounts.get_senders() {
+        let balance = escrow_accounts
+            .get_balance_for_sender(&sender)
+            .unwrap_or_default();
+        if balance >= threshold {
+            continue;
+        }
+        if !low_balance.contains(&sender) {
+            warn!(
+                %sender,
+                %balance,
+                %threshold,
+                "Sender escrow balance is running low, receipts will be refused once it runs out"
+            );
+            newly_low.push(sender);
+        }
+        still_low.insert(sender);
+    }
+    // senders that topped up or left the escrow lose their series
+    for sender in low_balance.difference(&still_low) {
+        let _ = ESCROW_LOW_BALANCE.remove_label_values(&[&sender.to_string()]);
+    }
+    for sender in &newly_low {
+        ESCROW_LOW_BALANCE
+            .with_label_values(&[&sender.to_string()])
+            .set(1.0);
+    }
+    *low_balance = still_low;
+    newly_low
+}
+
+/// Balance and committed value per label, the senders with the largest
+/// balances first and every other sender summed into [OTHER_SENDERS_LABEL]
+fn labeled_senders(
+    mut balances: Vec<(Address, U256)>,
+    committed: &HashMap<Address, f64>,
+) -> Vec<(String, f64, f64)> {
+    balances.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    let mut labels = Vec::new();
+    let mut other: Option<(f64, f64)> = None;
+    for (i, (sender, balance)) in balances.into_iter().enumerate() {
+        let balance = u128::try_from(balance).map_or(f64::MAX, |balance| balance as f64);
+        let committed = committed.get(&sender).copied().unwrap_or_default();
+        if i < MAX_SENDER_LABELS {
+            labels.push((sender.to_string(), balance, committed));
+        } else {
+            let (other_balance, other_committed) = other.get_or_insert((0.0, 0.0));
+            *other_balance += balance;
+            *other_committed += committed;
+        }
+    }
+    if let Some((balance, committed)) = other {
+        labels.push((OTHER_SENDERS_LABEL.to_string(), balance, committed));
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use alloy::primitives::{Address, U256};
+    use indexer_monitor::EscrowAccounts;
+    use prometheus::core::Collector;
+
+    use super::{
+        labeled_senders, sender_headroom, update_low_balance, MAX_SENDER_LABELS,
+        OTHER_SENDERS_LABEL,
+    };
+    use crate::metrics::ESCROW_LOW_BALANCE;
+
+    #[test]
+    fn test_sender_labels_are_capped() {
+        let balances = (0..MAX_SENDER_LABELS as u64 + 5)
+            .map(|i| (Address::left_padding_from(&i.to_be_bytes()), U256::from(i)))
+            .collect::<Vec<_>>();
+        let committed = balances
+            .iter()
+            .map(|(sender, _)| (*sender, 1.0))
+            .collect::<HashMap<_, _>>();
+
+        let labels = labeled_senders(balances, &committed);
+        assert_eq!(labels.len(), MAX_SENDER_LABELS + 1);
+
+        // largest balances keep their own label
+        let expected_first =
+            Address::left_padding_from(&(MAX_SENDER_LABELS as u64 + 4).to_be_bytes());
+        assert_eq!(labels[0].0, expected_first.to_string());
+
+        // the 5 smallest balances (0 to 4) are grouped together
+        let (label, balance, committed) = labels.last().unwrap();
+        assert_eq!(label, OTHER_SENDERS_LABEL);
+        assert_eq!(*balance, 10.0);
+        assert_eq!(*committed, 5.0);
+    }
+
+    #[test]
+    fn test_sender_headroom() {
+        let [full, half, spent, empty] = [1, 2, 3, 4].map(Address::repeat_byte);
+        let balances = [
+            (full, U256::from(100)),
+            (half, U256::from(100)),
+            (spent, U256::from(100)),
+            (empty, U256::ZERO),
+        ];
+        let committed = HashMap::from([(half, 50.0), (spent, 150.0)]);
+
+        let headroom = sender_headroom(&balances, &committed);
+        assert_eq!(
+            headroom,
+            HashMap::from([(full, 1.0), (half, 0.5), (spent, 0.0)])
+        );
+    }
+
+    #[test]
+    fn test_low_balance_is_flagged_once() {
+        let sender = Address::repeat_byte(1);
+        let accounts = |balance: u64| {
+            EscrowAccounts::new(
+                HashMap::from([(sender, U256::from(balance))]),
+                HashMap::from([(sender, vec![sender])]),
+            )
+        };
+        let mut low_balance = HashSet::new();
+        // value of the sender's series, if it has one
+        let gauge = || {
+            ESCROW_LOW_BALANCE
+                .collect()
+                .iter()
+                .flat_map(|family| family.get_metric())
+                .find(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|label| label.get_value() == sender.to_string())
+                })
+                .map(|metric| metric.get_gauge().get_value())
+        };
+
+        assert!(update_low_balance(&accounts(200), 100, &mut low_balance).is_empty());
+        assert_eq!(gauge(), None);
+
+        // only warned about when crossing the threshold
+        assert_eq!(
+            update_low_balance(&accounts(50), 100, &mut low_balance),
+            vec![sender]
+        );
+        assert!(update_low_balance(&accounts(40), 100, &mut low_balance).is_empty());
+        assert_eq!(gauge(), Some(1.0));
+
+        // topped up
+        assert!(update_low_balance(&accounts(500), 100, &mut low_balance).is_empty());
+        assert!(low_balance.is_empty());
+        assert_eq!(gauge(), None);
+
+        // left the escrow while running low
+        update_low_balance(&accounts(50), 100, &mut low_balance);
+        assert_eq!(gauge(), Some(1.0));
+        assert!(update_low_balance(&EscrowAccounts::default(), 100, &mut low_balance).is_empty());
+        assert!(low_balance.is_empty());
+        assert_eq!(gauge(), None);
+    }
+}
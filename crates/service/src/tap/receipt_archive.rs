@@ -0,0 +1,300 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Archives a sample of the paid queries served, for audits
+//!
+//! The receipts of `scalar_tap_receipts` are deleted once a RAV covers them,
+//! and their query was never kept. Archived queries are kept with their
+//! request, response and attestation for the configured retention, in the
+//! `scalar_tap_receipt_archive` table or a directory of JSON lines files, one
+//! per UTC day. Queries are archived from a background task, they are left out
+//! rather than slowing down serving when it falls behind.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    hex::ToHexExt,
+    primitives::{Address, Bytes},
+};
+use bigdecimal::num_bigint::BigInt;
+use indexer_config::{ReceiptArchiveConfig, ReceiptArchiveSink};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::{
+        chrono::{self, DateTime, NaiveDate, Utc},
+        BigDecimal,
+    },
+    PgPool,
+};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::{Attestation, DeploymentId};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
+use tracing::{error, warn};
+
+/// Queries waiting to be archived before new ones are left out
+const BUFFER_SIZE: usize = 1000;
+/// How often queries past their retention are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A paid query, as archived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedQuery {
+    /// when the query was served, in milliseconds since the unix epoch
+    pub archived_at_ms: u64,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    pub value: u128,
+    pub signature: Bytes,
+    pub sender: Option<Address>,
+    pub deployment_id: Option<DeploymentId>,
+    pub request: String,
+    /// the GraphQL response, as attested
+    pub response: String,
+    pub attestation: Option<Attestation>,
+}
+
+impl ArchivedQuery {
+    pub fn new(
+        receipt: &SignedReceipt,
+        sender: Option<Address>,
+        deployment_id: Option<DeploymentId>,
+        request: String,
+        response: String,
+        attestation: Option<Attestation>,
+    ) -> Self {
+        let archived_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            archived_at_ms,
+            allocation_id: receipt.message.allocation_id,
+            timestamp_ns: receipt.message.timestamp_ns,
+            nonce: receipt.message.nonce,
+            value: receipt.message.value,
+            signature: Bytes::copy_from_slice(&receipt.signature.as_bytes()),
+            sender,
+            deployment_id,
+            request,
+            response,
+            attestation,
+        }
+    }
+
+    fn archived_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.archived_at_ms as i64).unwrap_or_default()
+    }
+}
+
+enum Sink {
+    Database(PgPool),
+    Directory(PathBuf),
+}
+
+impl Sink {
+    async fn write(&self, query: &ArchivedQuery) -> anyhow::Result<()> {
+        match self {
+            Sink::Database(pgpool) => {
+                sqlx::query!(
+                    r#"
+                        INSERT INTO scalar_tap_receipt_archive (
+                            allocation_id,
+                            sender_address,
+                            signature,
+                            timestamp_ns,
+                            nonce,
+                            value,
+                            deployment_id,
+                            request,
+                            response,
+                            attestation,
+                            archived_at
+                        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                    "#,
+                    query.allocation_id.encode_hex(),
+                    query.sender.map(|sender| sender.encode_hex()),
+                    query.signature.to_vec(),
+                    BigDecimal::from(query.timestamp_ns),
+                    BigDecimal::from(query.nonce),
+                    BigDecimal::from(BigInt::from(query.value)),
+                    query.deployment_id.map(|deployment| deployment.to_string()),
+                    query.request,
+                    query.response,
+                    query
+                        .attestation
+                        .as_ref()
+                        .map(serde_json::to_value)
+                        .transpose()?,
+                    query.archived_at(),
+                )
+                .execute(pgpool)
+                .await?;
+            }
+            Sink::Directory(path) => {
+                let file = path.join(format!(
+                    "receipts-{}.ndjson",
+                    query.archived_at().format("%Y-%m-%d")
+                ));
+                let mut line = serde_json::to_vec(query)?;
+                line.push(b'\n');
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .await?;
+                file.write_all(&line).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the queries archived before `before`, whole days at a time for
+    /// the directory
+    async fn prune(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        match self {
+            Sink::Database(pgpool) => {
+                sqlx::query!(
+                    r#"
+                        DELETE FROM scalar_tap_receipt_archive
+                        WHERE archived_at < $1
+                    "#,
+                    before,
+                )
+                .execute(pgpool)
+                .await?;
+            }
+            Sink::Directory(path) => {
+                let mut entries = tokio::fs::read_dir(path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = entry.file_name();
+                    let Some(day) = name
+                        .to_str()
+                        .and_then(|name| name.strip_prefix("receipts-"))
+                        .and_then(|name| name.strip_suffix(".ndjson"))
+                        .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok())
+                    else {
+                        continue;
+                    };
+                    // the whole day is past the retention
+                    if day < before.date_naive() {
+                        tokio::fs::remove_file(entry.path()).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Samples paid queries and archives them from a background task
+#[derive(Clone)]
+pub struct ReceiptArchive {
+    queries: mpsc::Sender<ArchivedQuery>,
+    sample_rate: f64,
+}
+
+impl ReceiptArchive {
+    pub async fn new(config: &ReceiptArchiveConfig, pgpool: PgPool) -> anyhow::Result<Self> {
+        let sink = match &config.sink {
+            ReceiptArchiveSink::Database => Sink::Database(pgpool),
+            ReceiptArchiveSink::Directory { path } => {
+                tokio::fs::create_dir_all(path).await?;
+                Sink::Directory(path.clone())
+            }
+        };
+        let (queries, receiver) = mpsc::channel(BUFFER_SIZE);
+        tokio::spawn(archive_queries(sink, config.retention_secs, receiver));
+        Ok(Self {
+            queries,
+            sample_rate: config.sample_rate,
+        })
+    }
+
+    /// Whether the next paid query should be archived
+    pub fn sampled(&self) -> bool {
+        self.sample_rate > 0.0 && rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Queues the query, leaving it out rather than slowing down serving
+    pub fn archive(&self, query: ArchivedQuery) {
+        if self.queries.try_send(query).is_err() {
+            warn!("Receipt archive is falling behind, leaving a query out");
+        }
+    }
+}
+
+async fn archive_queries(
+    sink: Sink,
+    retention: Duration,
+    mut receiver: mpsc::Receiver<ArchivedQuery>,
+) {
+    // nothing is ever old enough to be deleted past what chrono represents
+    let retention = chrono::Duration::from_std(retention).ok();
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    prune.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            query = receiver.recv() => {
+                let Some(query) = query else {
+                    break;
+                };
+                if let Err(e) = sink.write(&query).await {
+                    error!(error = %e, "Failed to archive a query");
+                }
+            }
+            _ = prune.tick() => {
+                let Some(before) =
+                    retention.and_then(|retention| Utc::now().checked_sub_signed(retention))
+                else {
+                    continue;
+                };
+                if let Err(e) = sink.prune(before).await {
+                    error!(error = %e, "Failed to delete the queries archived past their retention");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::chrono::{Duration, Utc};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+
+    use super::{ArchivedQuery, Sink};
+
+    #[tokio::test]
+    async fn test_directory_sink() {
+        let path = std::env::temp_dir().join(format!("receipt-archive-{}", uuid::Uuid::now_v7()));
+        tokio::fs::create_dir_all(&path).await.unwrap();
+        let sink = Sink::Directory(path.clone());
+
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let query = |archived_at_ms| ArchivedQuery {
+            archived_at_ms,
+            ..ArchivedQuery::new(&receipt, None, None, "{}".into(), "{}".into(), None)
+        };
+        let today = query(Utc::now().timestamp_millis() as u64);
+        sink.write(&today).await.unwrap();
+        sink.write(&today).await.unwrap();
+        // 2024-01-01
+        sink.write(&query(1_704_067_200_000)).await.unwrap();
+
+        let today_file = path.join(format!("receipts-{}.ndjson", Utc::now().format("%Y-%m-%d")));
+        let old_file = path.join("receipts-2024-01-01.ndjson");
+        let lines = tokio::fs::read_to_string(&today_file).await.unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        let archived: ArchivedQuery = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(archived.nonce, receipt.message.nonce);
+        assert!(old_file.exists());
+
+        sink.prune(Utc::now() - Duration::days(1)).await.unwrap();
+        assert!(today_file.exists());
+        assert!(!old_file.exists());
+        tokio::fs::remove_dir_all(path).await.unwrap();
+    }
+}
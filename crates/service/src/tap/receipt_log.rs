@@ -0,0 +1,198 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the receipts refused by the checks to a file
+//!
+//! Records are serialized in the configured [ReceiptLogFormat], their fields
+//! always in the same order, for downstream tooling to parse. The query paid by
+//! the receipt is left out: the receipt, who sent it and why it was refused is
+//! enough to audit it.
+
+use std::{
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::primitives::{Address, Bytes};
+use indexer_config::ReceiptLogFormat;
+use serde::{Deserialize, Serialize};
+use tap_core::receipt::SignedReceipt;
+use thegraph_core::DeploymentId;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{error, warn};
+
+/// Records waiting to be written before new ones are dropped
+const BUFFER_SIZE: usize = 1000;
+
+/// A refused receipt, as written to the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReceiptLogRecord {
+    /// when the receipt was refused, in milliseconds since the unix epoch
+    pub rejected_at_ms: u64,
+    pub allocation_id: Address,
+    pub timestamp_ns: u64,
+    pub nonce: u64,
+    pub value: u128,
+    pub signature: Bytes,
+    /// sender the signer belongs to, if it could be recovered
+    pub sender: Option<Address>,
+    pub deployment_id: Option<DeploymentId>,
+    /// error of the failing checks
+    pub failure: String,
+}
+
+impl ReceiptLogRecord {
+    pub fn new(
+        receipt: &SignedReceipt,
+        sender: Option<Address>,
+        deployment_id: Option<DeploymentId>,
+        failure: String,
+    ) -> Self {
+        let rejected_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            rejected_at_ms,
+            allocation_id: receipt.message.allocation_id,
+            timestamp_ns: receipt.message.timestamp_ns,
+            nonce: receipt.message.nonce,
+            value: receipt.message.value,
+            signature: Bytes::copy_from_slice(&receipt.signature.as_bytes()),
+            sender,
+            deployment_id,
+            failure,
+        }
+    }
+
+    pub fn encode(&self, format: ReceiptLogFormat) -> anyhow::Result<Vec<u8>> {
+        Ok(match format {
+            ReceiptLogFormat::Json => {
+                let mut record = serde_json::to_vec_pretty(self)?;
+                record.push(b'\n');
+                record
+            }
+            ReceiptLogFormat::Ndjson => {
+                let mut record = serde_json::to_vec(self)?;
+                record.push(b'\n');
+                record
+            }
+            ReceiptLogFormat::Binary => {
+                let encoded = bincode::serialize(self)?;
+                let mut record = (encoded.len() as u32).to_le_bytes().to_vec();
+                record.extend(encoded);
+                record
+            }
+        })
+    }
+}
+
+/// Appends refused receipts to the log file from a background task
+#[derive(Clone)]
+pub struct ReceiptLog {
+    records: mpsc::Sender<ReceiptLogRecord>,
+}
+
+impl ReceiptLog {
+    pub async fn open(path: &Path, format: ReceiptLogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self::new(file, format))
+    }
+
+    fn new(writer: impl AsyncWrite + Unpin + Send + 'static, format: ReceiptLogFormat) -> Self {
+        let (records, receiver) = mpsc::channel(BUFFER_SIZE);
+        tokio::spawn(write_records(writer, format, receiver));
+        Self { records }
+    }
+
+    /// Queues the record, dropping it rather than slowing down the query
+    pub fn log(&self, record: ReceiptLogRecord) {
+        if self.records.try_send(record).is_err() {
+            warn!("Receipt log is falling behind, dropping a rejected receipt");
+        }
+    }
+}
+
+async fn write_records(
+    writer: impl AsyncWrite + Unpin,
+    format: ReceiptLogFormat,
+    mut receiver: mpsc::Receiver<ReceiptLogRecord>,
+) {
+    let mut writer = BufWriter::new(writer);
+    while let Some(record) = receiver.recv().await {
+        let result = async {
+            writer.write_all(&record.encode(format)?).await?;
+            // only hold records back while more are waiting
+            if receiver.is_empty() {
+                writer.flush().await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(e) = result.await {
+            error!(error = %e, "Failed to write to the receipt log");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_config::ReceiptLogFormat;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, ESCROW_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::Address;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    use super::{ReceiptLog, ReceiptLogRecord};
+
+    #[tokio::test]
+    async fn test_formats_round_trip() {
+        let receipt =
+            create_signed_receipt(SignedReceiptRequest::builder().value(42).build()).await;
+        let record = ReceiptLogRecord::new(
+            &receipt,
+            Some(Address::repeat_byte(1)),
+            Some(*ESCROW_SUBGRAPH_DEPLOYMENT),
+            "Receipt sender does not have a sufficient balance".to_string(),
+        );
+        assert_eq!(record.signature.as_ref(), &receipt.signature.as_bytes()[..]);
+
+        for format in [ReceiptLogFormat::Json, ReceiptLogFormat::Ndjson] {
+            let encoded = record.encode(format).unwrap();
+            assert_eq!(encoded.last(), Some(&b'\n'));
+            let decoded: ReceiptLogRecord = serde_json::from_slice(&encoded).unwrap();
+            assert_eq!(decoded, record);
+            // serialization is deterministic
+            assert_eq!(decoded.encode(format).unwrap(), encoded);
+        }
+        let encoded = record.encode(ReceiptLogFormat::Ndjson).unwrap();
+        assert_eq!(encoded.iter().filter(|byte| **byte == b'\n').count(), 1);
+
+        let encoded = record.encode(ReceiptLogFormat::Binary).unwrap();
+        let length = u32::from_le_bytes(encoded[..4].try_into().unwrap()) as usize;
+        assert_eq!(length, encoded.len() - 4);
+        let decoded: ReceiptLogRecord = bincode::deserialize(&encoded[4..]).unwrap();
+        assert_eq!(decoded, record);
+    }
+
+    #[tokio::test]
+    async fn test_records_are_written() {
+        let (writer, mut reader) = duplex(4096);
+        let log = ReceiptLog::new(writer, ReceiptLogFormat::Ndjson);
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let record = ReceiptLogRecord::new(&receipt, None, None, "failed".to_string());
+        log.log(record.clone());
+
+        let expected = record.encode(ReceiptLogFormat::Ndjson).unwrap();
+        let mut written = vec![0; expected.len()];
+        reader.read_exact(&mut written).await.unwrap();
+        assert_eq!(written, expected);
+    }
+}
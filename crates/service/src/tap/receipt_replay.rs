@@ -0,0 +1,335 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs the receipts stored in the database through the current checks
+//!
+//! This is a dry run meant to validate a check configuration before relying on
+//! it: the checks are built fresh from the current settings, so stateful checks
+//! and escrow reservations of live traffic are left untouched, and nothing is
+//! written to the database. The report is streamed as newline delimited JSON,
+//! one line per failing receipt and a summary at the end.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use alloy::primitives::Address;
+use anyhow::anyhow;
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use indexer_allocation::Allocation;
+use indexer_config::CheckName;
+use indexer_monitor::EscrowAccounts;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use tap_core::receipt::{
+    checks::{CheckError, ReceiptCheck},
+    Context, Receipt, ReceiptWithState, SignedReceipt,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::watch,
+};
+
+use super::AgoraQuery;
+use crate::middleware::Sender;
+
+/// Receipts read from the database at once
+const BATCH_SIZE: i64 = 1000;
+
+/// Checks that can't judge a stored receipt: its timestamp is long past and
+/// the query it paid for isn't stored
+pub const SKIPPED_CHECKS: [CheckName; 3] = [
+    CheckName::Timestamp,
+    CheckName::MinimumValue,
+    CheckName::PriceList,
+];
+
+/// Stored receipts to run through a fresh set of checks
+pub struct ReceiptReplay {
+    pub(super) pgpool: PgPool,
+    pub(super) checks: Vec<(CheckName, ReceiptCheck)>,
+    pub(super) indexer_allocations: watch::Receiver<HashMap<Address, Allocation>>,
+    pub(super) escrow_accounts: watch::Receiver<EscrowAccounts>,
+}
+
+#[derive(Serialize)]
+struct ReplayFailure {
+    id: i64,
+    allocation_id: String,
+    signer: String,
+    /// name of each failing check with its error
+    failures: BTreeMap<&'static str, String>,
+}
+
+#[derive(Default, Serialize)]
+struct ReplaySummary {
+    passed: u64,
+    failed: u64,
+    /// receipts failing each check
+    failures_by_check: BTreeMap<&'static str, u64>,
+    skipped_checks: &'static [CheckName],
+}
+
+impl ReceiptReplay {
+    /// Replays every stored receipt, writing the report as it goes.
+    ///
+    /// Stops at the first error reading the database or writing the report
+    pub async fn run(self, mut writer: impl AsyncWrite + Unpin) -> anyhow::Result<()> {
+        let mut summary = ReplaySummary {
+            skipped_checks: &SKIPPED_CHECKS,
+            ..Default::default()
+        };
+        let mut last_id = 0;
+        loop {
+            let records = match sqlx::query!(
+                r#"
+                    SELECT id, signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                    FROM scalar_tap_receipts
+                    WHERE id > $1
+                    ORDER BY id
+                    LIMIT $2
+                "#,
+                last_id,
+                BATCH_SIZE,
+            )
+            .fetch_all(&self.pgpool)
+            .await
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    write_line(&mut writer, &json!({ "error": e.to_string() })).await?;
+                    return Err(e.into());
+                }
+            };
+            let Some(last) = records.last() else {
+                break;
+            };
+            last_id = last.id;
+
+            for record in records {
+                let mut failures = BTreeMap::new();
+                match decode_receipt(
+                    &record.signature,
+                    &record.allocation_id,
+                    &record.timestamp_ns,
+                    &record.nonce,
+                    &record.value,
+                ) {
+                    Ok(receipt) => {
+                        let ctx = self.context(&record.signer_address, &receipt);
+                        let receipt = ReceiptWithState::new(receipt);
+                        for (name, check) in &self.checks {
+                            if let Err(CheckError::Failed(e) | CheckError::Retryable(e)) =
+                                check.check(&ctx, &receipt).await
+                            {
+                                failures.insert(name.as_str(), e.to_string());
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        failures.insert("decode", e.to_string());
+                    }
+                }
+
+                if failures.is_empty() {
+                    summary.passed += 1;
+                    continue;
+                }
+                summary.failed += 1;
+                for name in failures.keys() {
+                    *summary.failures_by_check.entry(*name).or_default() += 1;
+                }
+                let failure = ReplayFailure {
+                    id: record.id,
+                    allocation_id: record.allocation_id,
+                    signer: record.signer_address,
+                    failures,
+                };
+                write_line(&mut writer, &failure).await?;
+            }
+        }
+
+        write_line(&mut writer, &json!({ "summary": summary })).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// What the middlewares would have provided to the checks, as far as it
+    /// can be recovered from the receipt
+    fn context(&self, signer: &str, receipt: &SignedReceipt) -> Context {
+        let mut ctx = Context::new();
+        let sender = Address::from_str(signer).ok().and_then(|signer| {
+            self.escrow_accounts
+                .borrow()
+                .get_sender_for_signer(&signer)
+                .ok()
+        });
+        if let Some(sender) = sender {
+            ctx.insert(Sender(sender));
+        }
+        let deployment_id = self
+            .indexer_allocations
+            .borrow()
+            .get(&receipt.message.allocation_id)
+            .map(|allocation| allocation.subgraph_deployment.id);
+        if let Some(deployment_id) = deployment_id {
+            ctx.insert(AgoraQuery {
+                deployment_id,
+                query: Default::default(),
+                variables: Default::default(),
+            });
+        }
+        ctx
+    }
+}
+
+fn decode_receipt(
+    signature: &[u8],
+    allocation_id: &str,
+    timestamp_ns: &sqlx::types::BigDecimal,
+    nonce: &sqlx::types::BigDecimal,
+    value: &sqlx::types::BigDecimal,
+) -> anyhow::Result<SignedReceipt> {
+    Ok(SignedReceipt {
+        message: Receipt {
+            allocation_id: Address::from_str(allocation_id)?,
+            timestamp_ns: timestamp_ns
+                .to_u64()
+                .ok_or_else(|| anyhow!("Invalid timestamp_ns `{timestamp_ns}`"))?,
+            nonce: nonce
+                .to_u64()
+                .ok_or_else(|| anyhow!("Invalid nonce `{nonce}`"))?,
+            // BigDecimal::to_u128() goes through u64, BigInt doesn't
+            value: value
+                .to_bigint()
+                .and_then(|value| value.to_u128())
+                .ok_or_else(|| anyhow!("Invalid value `{value}`"))?,
+        },
+        signature: signature.try_into()?,
+    })
+}
+
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    line: &impl Serialize,
+) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(line)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use indexer_config::{CheckMode, UnknownAllocationPolicy};
+    use indexer_monitor::EscrowAccounts;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tap_core::{manager::adapters::ReceiptStore, receipt::ReceiptWithState};
+    use test_assets::{
+        assert_while_retry, create_signed_receipt, SignedReceiptRequest, ESCROW_ACCOUNTS_BALANCES,
+        ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, INDEXER_ALLOCATIONS, TAP_EIP712_DOMAIN,
+    };
+    use tokio::sync::watch;
+
+    use crate::tap::{CheckPipeline, CheckSettings, CheckState, IndexerTapContext};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_replay_reports_failures(pgpool: PgPool) {
+        let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        for value in [10, 100] {
+            let receipt = create_signed_receipt(
+                SignedReceiptRequest::builder()
+                    .allocation_id(allocation.id)
+                    .value(value)
+                    .build(),
+            )
+            .await;
+            context
+                .store_receipt(ReceiptWithState::new(receipt))
+                .await
+                .unwrap();
+        }
+        assert_while_retry!({
+            sqlx::query!("SELECT * FROM scalar_tap_receipts")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap()
+                .len()
+                < 2
+        });
+
+        let pipeline = CheckPipeline::new(
+            pgpool.clone(),
+            watch::channel(INDEXER_ALLOCATIONS.clone()).1,
+            watch::channel(EscrowAccounts::new(
+                ESCROW_ACCOUNTS_BALANCES.clone(),
+                ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+            ))
+            .1,
+            Default::default(),
+            CheckState::default(),
+            CheckSettings {
+                timestamp_error_tolerance: Duration::from_secs(30),
+                receipt_max_value: 50,
+                min_price: 0,
+                min_price_per_deployment: HashMap::new(),
+                no_appraisal_policy: Default::default(),
+                sender_allow_list: None,
+                deny_list: true,
+                max_pending_value_per_sender: None,
+                enforce_allocation_cap: false,
+                unknown_allocation: (UnknownAllocationPolicy::Reject, Duration::ZERO),
+                escrow_top_up_grace: None,
+                token: None,
+                max_timestamp_gap: None,
+                default_check_timeout: Duration::from_secs(5),
+                check_timeouts: HashMap::new(),
+                check_order: vec![],
+                check_mode: CheckMode::FirstFailure,
+                price_list: None,
+            },
+        )
+        .await;
+
+        let mut report = Vec::new();
+        pipeline.replay().await.run(&mut report).await.unwrap();
+        let lines = String::from_utf8(report)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        // only the receipt above the max value fails
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0]["failures"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["receipt_max_value"]
+        );
+        assert_eq!(
+            lines[1]["summary"],
+            json!({
+                "passed": 1,
+                "failed": 1,
+                "failures_by_check": { "receipt_max_value": 1 },
+                "skipped_checks": ["timestamp", "minimum_value", "price_list"],
+            })
+        );
+
+        // nothing was written by the replay
+        let stored = sqlx::query!("SELECT * FROM scalar_tap_receipts")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+    }
+}
@@ -1,23 +1,42 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use alloy::{dyn_abi::Eip712Domain, hex::ToHexExt};
 use anyhow::anyhow;
 use bigdecimal::num_bigint::BigInt;
+use indexer_config::{ReceiptQueueOverflowPolicy, ReceiptTimeSource};
 use sqlx::{types::BigDecimal, PgPool};
 use tap_core::{
     manager::adapters::ReceiptStore,
     receipt::{state::Checking, ReceiptWithState},
 };
-use tokio::{select, sync::mpsc::Receiver, task::JoinHandle};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{error::TrySendError, Receiver, Sender},
+        watch,
+    },
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{error, warn};
 
 use super::{AdapterError, IndexerTapContext};
+use crate::metrics::{RECEIPT_QUEUE_BACKPRESSURE, RECEIPT_QUEUE_DEPTH};
+
+/// How often the writer checks whether the database is reachable again
+const DATABASE_PROBE_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct InnerContext {
     pub pgpool: PgPool,
+    pub database_available: Arc<watch::Sender<bool>>,
 }
 
 impl InnerContext {
@@ -29,6 +48,7 @@ impl InnerContext {
         let mut timestamps = Vec::with_capacity(receipts_len);
         let mut nonces = Vec::with_capacity(receipts_len);
         let mut values = Vec::with_capacity(receipts_len);
+        let mut received_ats = Vec::with_capacity(receipts_len);
 
         for receipt in receipts {
             signers.push(receipt.signer_address);
@@ -37,6 +57,7 @@ impl InnerContext {
             timestamps.push(receipt.timestamp_ns);
             nonces.push(receipt.nonce);
             values.push(receipt.value);
+            received_ats.push(receipt.received_at_ns);
         }
         sqlx::query!(
             r#"INSERT INTO scalar_tap_receipts (
@@ -45,14 +66,16 @@ impl InnerContext {
                 allocation_id,
                 timestamp_ns,
                 nonce,
-                value
+                value,
+                received_at_ns
             ) SELECT * FROM UNNEST(
                 $1::CHAR(40)[],
                 $2::BYTEA[],
                 $3::CHAR(40)[],
                 $4::NUMERIC(20)[],
                 $5::NUMERIC(20)[],
-                $6::NUMERIC(40)[]
+                $6::NUMERIC(40)[],
+                $7::NUMERIC(20)[]
             )"#,
             &signers,
             &signatures,
@@ -60,12 +83,17 @@ impl InnerContext {
             &timestamps,
             &nonces,
             &values,
+            &received_ats as &[Option<BigDecimal>],
         )
         .execute(&self.pgpool)
         .await
         .map_err(|e| {
             error!("Failed to store receipt: {}", e);
-            anyhow!(e)
+            if AdapterError::is_connection_error(&e) {
+                AdapterError::DatabaseUnavailable(e)
+            } else {
+                AdapterError::AnyhowError(anyhow!(e))
+            }
         })?;
 
         Ok(())
@@ -82,12 +110,25 @@ impl IndexerTapContext {
         tokio::spawn(async move {
             loop {
                 let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+                let database_available = *inner_context.database_available.borrow();
                 select! {
                     biased;
                     _ = receiver.recv_many(&mut buffer, BUFFER_SIZE) => {
-                        if let Err(e) = inner_context.store_receipts(buffer).await {
+                        RECEIPT_QUEUE_DEPTH.set(receiver.len() as i64);
+                        let result = inner_context.store_receipts(buffer).await;
+                        if let Err(e) = &result {
                             error!("Failed to store receipts: {}", e);
                         }
+                        inner_context.database_available.send_replace(
+                            !matches!(result, Err(AdapterError::DatabaseUnavailable(_))),
+                        );
+                    }
+                    // receipts are refused while the database is down, check
+                    // for it to come back instead
+                    _ = sleep(DATABASE_PROBE_INTERVAL), if !database_available => {
+                        if inner_context.pgpool.acquire().await.is_ok() {
+                            inner_context.database_available.send_replace(true);
+                        }
                     }
                     _ = cancelation_token.cancelled() => { break },
                 }
@@ -104,17 +145,69 @@ impl ReceiptStore for IndexerTapContext {
         &self,
         receipt: ReceiptWithState<Checking>,
     ) -> Result<u64, Self::AdapterError> {
-        let db_receipt = DatabaseReceipt::from_receipt(receipt, &self.domain_separator)?;
-        self.receipt_producer.send(db_receipt).await.map_err(|e| {
-            error!("Failed to queue receipt for storage: {}", e);
-            anyhow!(e)
-        })?;
+        // Once a write failed to reach the database, don't queue receipts that
+        // would be lost until the writer finds it reachable again
+        if !*self.database_available.borrow() {
+            return Err(AdapterError::DatabaseDown);
+        }
+
+        let received_at_ns = match self.receipt_time_source {
+            ReceiptTimeSource::ReceiptField => None,
+            ReceiptTimeSource::ServerReceive => Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| anyhow!(e))?
+                    .as_nanos() as u64,
+            ),
+        };
+        let db_receipt =
+            DatabaseReceipt::from_receipt(receipt, &self.domain_separator, received_at_ns)?;
+        let permit = match self.receipt_producer.try_reserve() {
+            Ok(permit) => permit,
+            Err(TrySendError::Full(())) => {
+                let max_block = match self.receipt_queue_overflow {
+                    ReceiptQueueOverflowPolicy::Backpressure { max_block_secs } => max_block_secs,
+                    ReceiptQueueOverflowPolicy::Reject => Duration::ZERO,
+                };
+                match timeout(max_block, self.receipt_producer.reserve()).await {
+                    Ok(permit) => {
+                        RECEIPT_QUEUE_BACKPRESSURE
+                            .with_label_values(&["queued"])
+                            .inc();
+                        permit.map_err(|e| anyhow!(e))?
+                    }
+                    Err(_) => {
+                        RECEIPT_QUEUE_BACKPRESSURE
+                            .with_label_values(&["rejected"])
+                            .inc();
+                        warn!("Receipt queue is full, refusing the receipt");
+                        return Err(AdapterError::ReceiptQueueFull);
+                    }
+                }
+            }
+            Err(TrySendError::Closed(())) => {
+                error!("Failed to queue receipt for storage: receipt queue is closed");
+                return Err(anyhow!("Receipt queue is closed").into());
+            }
+        };
+        permit.send(db_receipt);
+        RECEIPT_QUEUE_DEPTH.set(self.receipt_queue().depth() as i64);
 
         // We don't need receipt_ids
         Ok(0)
     }
 }
 
+/// Receipts queued for storage but not yet written to the database
+#[derive(Clone)]
+pub struct ReceiptQueue(pub(super) Sender<DatabaseReceipt>);
+
+impl ReceiptQueue {
+    pub fn depth(&self) -> usize {
+        self.0.max_capacity() - self.0.capacity()
+    }
+}
+
 pub struct DatabaseReceipt {
     signer_address: String,
     signature: Vec<u8>,
@@ -122,12 +215,15 @@ pub struct DatabaseReceipt {
     timestamp_ns: BigDecimal,
     nonce: BigDecimal,
     value: BigDecimal,
+    /// when the service received the receipt, only with [ReceiptTimeSource::ServerReceive]
+    received_at_ns: Option<BigDecimal>,
 }
 
 impl DatabaseReceipt {
     fn from_receipt(
         receipt: ReceiptWithState<Checking>,
         separator: &Eip712Domain,
+        received_at_ns: Option<u64>,
     ) -> anyhow::Result<Self> {
         let receipt = receipt.signed_receipt();
         let allocation_id = receipt.message.allocation_id.encode_hex();
@@ -151,6 +247,181 @@ impl DatabaseReceipt {
             signer_address,
             timestamp_ns,
             value,
+            received_at_ns: received_at_ns.map(BigDecimal::from),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use axum::response::IntoResponse;
+    use indexer_config::{ReceiptQueueOverflowPolicy, ReceiptTimeSource};
+    use reqwest::StatusCode;
+    use sqlx::{types::BigDecimal, PgPool};
+    use tap_core::{manager::adapters::ReceiptStore, receipt::ReceiptWithState, Error as TapError};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_EIP712_DOMAIN};
+    use tokio::sync::{mpsc, watch};
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        error::IndexerServiceError,
+        tap::{AdapterError, IndexerTapContext},
+    };
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_stalled_writer(pgpool: PgPool) {
+        // nothing reads the queue, as if the writes were stuck
+        let (receipt_producer, mut stalled) = mpsc::channel(1);
+        let (_database_available_tx, database_available) = watch::channel(true);
+        let context = |policy| IndexerTapContext {
+            domain_separator: Arc::new(TAP_EIP712_DOMAIN.clone()),
+            receipt_producer: receipt_producer.clone(),
+            cancelation_token: CancellationToken::new(),
+            pgpool: pgpool.clone(),
+            database_available: database_available.clone(),
+            receipt_queue_overflow: policy,
+            receipt_time_source: Default::default(),
+        };
+        let backpressure = context(ReceiptQueueOverflowPolicy::Backpressure {
+            max_block_secs: Duration::from_millis(100),
+        });
+        let reject = context(ReceiptQueueOverflowPolicy::Reject);
+        let receipt = || async {
+            ReceiptWithState::new(
+                create_signed_receipt(SignedReceiptRequest::builder().build()).await,
+            )
+        };
+
+        backpressure.store_receipt(receipt().await).await.unwrap();
+
+        let receipt_ = receipt().await;
+        let start = tokio::time::Instant::now();
+        let error = backpressure.store_receipt(receipt_).await.unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(error, AdapterError::ReceiptQueueFull));
+        assert!(matches!(
+            reject.store_receipt(receipt().await).await,
+            Err(AdapterError::ReceiptQueueFull)
+        ));
+
+        // the client is told to come back later
+        let error = IndexerServiceError::from(TapError::AdapterError {
+            source_error: anyhow::Error::new(error),
+        });
+        assert_eq!(
+            error.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // queued as soon as the writer catches up, within the block time
+        let blocked = tokio::spawn({
+            let backpressure = backpressure.clone();
+            let receipt = receipt().await;
+            async move { backpressure.store_receipt(receipt).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stalled.recv().await.unwrap();
+        blocked.await.unwrap().unwrap();
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_refused_until_database_is_back(pgpool: PgPool) {
+        let (database_available_tx, database_available) = watch::channel(false);
+        let database_available_tx = Arc::new(database_available_tx);
+        let (receipt_producer, receiver) = mpsc::channel(1);
+        let cancelation_token = CancellationToken::new();
+        let context = IndexerTapContext {
+            domain_separator: Arc::new(TAP_EIP712_DOMAIN.clone()),
+            receipt_producer,
+            cancelation_token: cancelation_token.clone(),
+            pgpool: pgpool.clone(),
+            database_available: database_available.clone(),
+            receipt_queue_overflow: Default::default(),
+            receipt_time_source: Default::default(),
+        };
+        let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+
+        // refused without reaching for the database
+        assert!(matches!(
+            context.store_receipt(ReceiptWithState::new(receipt)).await,
+            Err(AdapterError::DatabaseDown)
+        ));
+
+        // the writer finds it reachable and lets receipts in again
+        IndexerTapContext::spawn_store_receipt_task(
+            super::InnerContext {
+                pgpool,
+                database_available: database_available_tx,
+            },
+            receiver,
+            cancelation_token.clone(),
+        );
+        let mut database_available = database_available;
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            database_available.wait_for(|available| *available),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        cancelation_token.cancel();
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_server_receive_time_stored(pgpool: PgPool) {
+        let stored = |source| {
+            let pgpool = pgpool.clone();
+            async move {
+                let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone())
+                    .await
+                    .with_receipt_time_source(source);
+                // stamped long before it's received
+                let timestamp_ns = 1_000;
+                let receipt = create_signed_receipt(
+                    SignedReceiptRequest::builder()
+                        .timestamp_ns(timestamp_ns)
+                        .build(),
+                )
+                .await;
+                context
+                    .store_receipt(ReceiptWithState::new(receipt))
+                    .await
+                    .unwrap();
+                // the receipt is written by the background task
+                loop {
+                    let row: Option<(BigDecimal, Option<BigDecimal>)> = sqlx::query_as(
+                        "SELECT timestamp_ns, received_at_ns FROM scalar_tap_receipts \
+                         WHERE timestamp_ns = $1",
+                    )
+                    .bind(BigDecimal::from(timestamp_ns))
+                    .fetch_optional(&pgpool)
+                    .await
+                    .unwrap();
+                    match row {
+                        Some(row) => break row,
+                        None => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            }
+        };
+
+        let (_, received_at_ns) = stored(ReceiptTimeSource::ReceiptField).await;
+        assert_eq!(received_at_ns, None);
+
+        sqlx::query("DELETE FROM scalar_tap_receipts")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (timestamp_ns, received_at_ns) = stored(ReceiptTimeSource::ServerReceive).await;
+        // both the claimed and observed times are kept
+        let received_at_ns = received_at_ns.unwrap();
+        assert!(received_at_ns >= BigDecimal::from(before.as_nanos() as u64));
+        assert_eq!(timestamp_ns, BigDecimal::from(1_000));
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! State changes of the checks, applied once the receipt is accepted
+//!
+//! A check passing doesn't mean the receipt will be accepted, a later check
+//! may still refuse it. Checks taking something from state shared between
+//! receipts, like an escrow reservation, defer the change to the
+//! [PendingSettlements] of the receipt [Context](tap_core::receipt::Context).
+//! The changes are applied once every check passed and the receipt was
+//! stored, and dropped with the context otherwise.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tap_core::receipt::Context;
+
+type Settlement = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone, Default)]
+pub struct PendingSettlements(Arc<Mutex<Vec<Settlement>>>);
+
+impl PendingSettlements {
+    /// Runs `settle` once the receipt is accepted
+    pub fn defer(&self, settle: impl FnOnce() + Send + 'static) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Box::new(settle));
+    }
+
+    /// Applies the changes deferred by the checks
+    pub fn settle(&self) {
+        let settlements =
+            std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner));
+        for settle in settlements {
+            settle();
+        }
+    }
+
+    /// Defers `settle` to the settlements of the context. Without any, the
+    /// receipt is only being checked and nothing is settled
+    pub fn defer_in(ctx: &Context, settle: impl FnOnce() + Send + 'static) {
+        if let Some(settlements) = ctx.get::<PendingSettlements>() {
+            settlements.defer(settle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tap_core::receipt::Context;
+
+    use super::PendingSettlements;
+
+    #[test]
+    fn test_settled_once() {
+        let settled = Arc::new(AtomicUsize::new(0));
+        let settlements = PendingSettlements::default();
+        let mut ctx = Context::new();
+        ctx.insert(settlements.clone());
+
+        PendingSettlements::defer_in(&ctx, {
+            let settled = settled.clone();
+            move || {
+                settled.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // nothing happens until the receipt is accepted
+        assert_eq!(settled.load(Ordering::SeqCst), 0);
+
+        settlements.settle();
+        settlements.settle();
+        assert_eq!(settled.load(Ordering::SeqCst), 1);
+
+        // without settlements in the context, the change is dropped
+        PendingSettlements::defer_in(&Context::new(), move || {
+            settled.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Waits on startup for the escrow and network subgraphs to catch up.
+//!
+//! Right after a restart of graph-node the subgraphs can be far behind the
+//! chain, and receipts checked against them would see outdated escrow balances
+//! and allocations. How far behind the chain head each subgraph is comes from
+//! the graph-node status endpoint, so only subgraphs with a known deployment
+//! are waited for.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use indexer_config::WaitForSyncConfig;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use thegraph_core::DeploymentId;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const INDEXING_STATUSES_QUERY: &str = r#"query indexingStatuses($ids: [String!]!) {
+    indexingStatuses(subgraphs: $ids) {
+        subgraph
+        chains {
+            chainHeadBlock { number }
+            latestBlock { number }
+        }
+    }
+}"#;
+
+#[derive(Deserialize)]
+struct Response {
+    data: Data,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Data {
+    indexing_statuses: Vec<IndexingStatus>,
+}
+
+#[derive(Deserialize)]
+struct IndexingStatus {
+    subgraph: DeploymentId,
+    chains: Vec<ChainStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChainStatus {
+    chain_head_block: Option<Block>,
+    latest_block: Option<Block>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    number: String,
+}
+
+/// Blocks behind the chain head of each deployment, none until it indexed a block
+async fn block_lags(
+    http_client: &reqwest::Client,
+    status_url: &Url,
+    deployments: &[DeploymentId],
+) -> anyhow::Result<Vec<(DeploymentId, Option<u64>)>> {
+    let response: Response = http_client
+        .post(status_url.clone())
+        .json(&json!({
+            "query": INDEXING_STATUSES_QUERY,
+            "variables": { "ids": deployments },
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    deployments
+        .iter()
+        .map(|deployment| {
+            let status = response
+                .data
+                .indexing_statuses
+                .iter()
+                .find(|status| status.subgraph == *deployment)
+                .ok_or_else(|| anyhow!("Deployment `{deployment}` is not indexed"))?;
+            let lag = match status.chains.first() {
+                Some(ChainStatus {
+                    chain_head_block: Some(head),
+                    latest_block: Some(latest),
+                }) => Some(
+                    head.number
+                        .parse::<u64>()?
+                        .saturating_sub(latest.number.parse()?),
+                ),
+                _ => None,
+            };
+            Ok((*deployment, lag))
+        })
+        .collect()
+}
+
+/// Polls the lag of the deployments until all are within `min_block_lag` of the
+/// chain head or `timeout_secs` elapsed. Returns whether they caught up
+pub async fn wait_for_sync(
+    http_client: &reqwest::Client,
+    status_url: &Url,
+    deployments: &[DeploymentId],
+    config: &WaitForSyncConfig,
+) -> bool {
+    if deployments.is_empty() {
+        warn!("No subgraph deployment is known, not waiting for the subgraphs to sync");
+        return true;
+    }
+    let deadline = Instant::now() + config.timeout_secs;
+    loop {
+        match block_lags(http_client, status_url, deployments).await {
+            Ok(lags) => {
+                let behind = lags
+                    .iter()
+                    .filter(|(_, lag)| !matches!(lag, Some(lag) if *lag <= config.min_block_lag))
+                    .collect::<Vec<_>>();
+                if behind.is_empty() {
+                    info!("Subgraphs are synced");
+                    return true;
+                }
+                info!(?behind, "Waiting for the subgraphs to sync");
+            }
+            Err(e) => warn!(error = %e, "Failed to query the indexing status of the subgraphs"),
+        }
+        if Instant::now() + POLL_INTERVAL > deadline {
+            warn!(
+                timeout = ?config.timeout_secs,
+                "Subgraphs are still behind the chain head, serving anyway"
+            );
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_config::WaitForSyncConfig;
+    use reqwest::Url;
+    use serde_json::json;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{wait_for_sync, POLL_INTERVAL};
+
+    /// Status endpoint reporting the escrow subgraph at each of `escrow_blocks`
+    /// in turn, the last one for good
+    async fn status_url(escrow_blocks: &[u64]) -> (MockServer, Url) {
+        let mock_server = MockServer::start().await;
+        let status = |deployment, block: u64| {
+            json!({
+                "subgraph": deployment,
+                "chains": [{
+                    "chainHeadBlock": { "number": "1000" },
+                    "latestBlock": { "number": block.to_string() },
+                }],
+            })
+        };
+        for (i, escrow_block) in escrow_blocks.iter().enumerate() {
+            let mock = Mock::given(method("POST"))
+                .and(path("/status"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "data": { "indexingStatuses": [
+                        status(*NETWORK_SUBGRAPH_DEPLOYMENT, 995),
+                        status(*ESCROW_SUBGRAPH_DEPLOYMENT, *escrow_block),
+                    ]}
+                })));
+            let mock = if i + 1 < escrow_blocks.len() {
+                mock.up_to_n_times(1).with_priority(1)
+            } else {
+                mock
+            };
+            mock.mount(&mock_server).await;
+        }
+        let url = format!("{}/status", mock_server.uri()).parse().unwrap();
+        (mock_server, url)
+    }
+
+    async fn polls(mock_server: &MockServer) -> usize {
+        mock_server.received_requests().await.unwrap().len()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_for_sync() {
+        // no idle connections, whose timers would move the paused clock
+        let http_client = reqwest::Client::builder()
+            .pool_max_idle_per_host(0)
+            .build()
+            .unwrap();
+        let deployments = [*NETWORK_SUBGRAPH_DEPLOYMENT, *ESCROW_SUBGRAPH_DEPLOYMENT];
+        let config = WaitForSyncConfig {
+            min_block_lag: 10,
+            timeout_secs: POLL_INTERVAL * 5,
+        };
+
+        let (mock_server, url) = status_url(&[990]).await;
+        assert!(wait_for_sync(&http_client, &url, &deployments, &config).await);
+        assert_eq!(polls(&mock_server).await, 1);
+
+        // the escrow subgraph catches up by the second poll
+        let (mock_server, url) = status_url(&[900, 990]).await;
+        let start = tokio::time::Instant::now();
+        assert!(wait_for_sync(&http_client, &url, &deployments, &config).await);
+        assert_eq!(polls(&mock_server).await, 2);
+        assert!(start.elapsed() >= POLL_INTERVAL);
+
+        // the escrow subgraph lags behind until the timeout
+        let config = WaitForSyncConfig {
+            timeout_secs: POLL_INTERVAL + POLL_INTERVAL / 2,
+            ..config
+        };
+        let (mock_server, url) = status_url(&[900]).await;
+        assert!(!wait_for_sync(&http_client, &url, &deployments, &config).await);
+        assert_eq!(polls(&mock_server).await, 2);
+    }
+}
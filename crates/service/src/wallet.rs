@@ -1,6 +1,7 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use alloy::primitives::Address;
 use alloy::signers::local::{
     coins_bip39::English, LocalSignerError, MnemonicBuilder, PrivateKeySigner,
 };
@@ -13,9 +14,8 @@ pub fn build_wallet(value: &str) -> Result<PrivateKeySigner, LocalSignerError> {
         .or(MnemonicBuilder::<English>::default().phrase(value).build())
 }
 
-// Format public key to a String
-pub fn public_key(value: &Mnemonic) -> Result<String, LocalSignerError> {
+// Address of the wallet built from the mnemonic
+pub fn public_key(value: &Mnemonic) -> Result<Address, LocalSignerError> {
     let wallet = build_wallet(&value.to_string())?;
-    let addr = format!("{:?}", wallet.address());
-    Ok(addr)
+    Ok(wallet.address())
 }
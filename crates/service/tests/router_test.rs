@@ -4,12 +4,23 @@
 use std::time::Duration;
 
 use alloy::primitives::Address;
-use axum::{body::to_bytes, http::Request};
+use axum::{
+    body::{to_bytes, Body},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Request,
+    },
+};
 use axum_extra::headers::Header;
-use indexer_config::{BlockchainConfig, GraphNodeConfig, IndexerConfig, NonZeroGRT};
+use indexer_config::{
+    AddressFormat, BlockchainConfig, CheckMode, CheckPolicy, GraphNodeConfig, IndexerConfig,
+    NonZeroGRT, RequestFeaturesConfig, RequestIdConfig, RequestPanicAction,
+    ResponseCompressionConfig, TimestampGapAction, UnknownAllocationPolicy, UnknownFeatureAction,
+    VersionHeadersConfig,
+};
 use indexer_monitor::EscrowAccounts;
 use indexer_service_rs::{
-    service::{ServiceRouter, TapReceipt},
+    service::{ServiceRouter, ServiceRouters, TapReceipt},
     QueryBody,
 };
 use reqwest::{Method, StatusCode, Url};
@@ -24,6 +35,114 @@ use wiremock::{
     Mock, MockServer, ResponseTemplate,
 };
 
+fn service_config() -> indexer_config::ServiceConfig {
+    indexer_config::ServiceConfig {
+        serve_network_subgraph: false,
+        serve_escrow_subgraph: false,
+        serve_auth_token: None,
+        host_and_port: "0.0.0.0:0".parse().unwrap(),
+        listen: vec![],
+        grpc: None,
+        listen_backlog: 1024,
+        reuse_address: true,
+        reuse_port: false,
+        url_prefix: "/".into(),
+        tap: indexer_config::ServiceTapConfig {
+            max_receipt_value_grt: NonZeroGRT::new(1000000000000).unwrap(),
+            min_price_grt: None,
+            min_price_per_deployment_grt: Default::default(),
+            max_pending_value_per_sender_grt: None,
+            enforce_allocation_cap: false,
+            low_escrow_balance_grt: None,
+            escrow_top_up_grace: None,
+            escrow_admission: None,
+            refresh_escrow_on_unknown_sender: false,
+            token_address: None,
+            max_timestamp_gap_secs: None,
+            timestamp_gap_action: TimestampGapAction::Warn,
+            unknown_allocation_policy: UnknownAllocationPolicy::RetryableUnavailable,
+            unknown_allocation_grace_secs: Duration::from_secs(60),
+            check_timeout_secs: Duration::from_secs(5),
+            check_timeouts_secs: Default::default(),
+            check_policy: CheckPolicy {
+                order: vec![],
+                mode: CheckMode::FirstFailure,
+            },
+            receipt_log: None,
+            no_appraisal_policy: None,
+            normalize_variable_numbers: false,
+            strict_receipt_validation: false,
+            sender_allow_list: None,
+            enable_deny_list: None,
+            check_failure_logging: None,
+            price_list: None,
+            receipt_queue_overflow: Default::default(),
+            dead_letter_receipts: false,
+            receipt_archive: None,
+        },
+        free_query_auth_token: None,
+        attest_error_responses: true,
+        unattested_free_queries: false,
+        cache_attestations: false,
+        attestation_scope: Default::default(),
+        remote_signer: None,
+        attestation_failure_policy: Default::default(),
+        max_concurrent_signings: None,
+        signing_queue_warning_threshold: None,
+        load_shedding_receipt_queue_threshold: 500,
+        safe_mode: false,
+        clock_drift: None,
+        wait_for_sync: None,
+        verbose_errors: false,
+        accepted_content_types: vec!["application/json".into()],
+        request_panic_action: RequestPanicAction::Respond,
+        address_format: Default::default(),
+        deployment_id_format: Default::default(),
+        request_log_sample_rate: 0.0,
+        max_deployment_metric_labels: 500,
+        request_id: RequestIdConfig {
+            header: "x-request-id".into(),
+            honor_inbound: true,
+            propagate: false,
+        },
+        version_headers: VersionHeadersConfig {
+            advertise_version: true,
+            server: None,
+            min_gateway_version: None,
+        },
+        request_features: RequestFeaturesConfig {
+            header: "x-indexer-features".into(),
+            allowed: vec![],
+            unknown_action: UnknownFeatureAction::Ignore,
+        },
+        response_compression: ResponseCompressionConfig { enabled: false },
+        allowed_operations: Default::default(),
+        max_query_depth: None,
+        max_query_fields: None,
+        query_limits_per_deployment: Default::default(),
+        max_response_body_bytes: None,
+        coalesce_identical_queries: false,
+        cost_metadata: Default::default(),
+        deployment_aliases: Default::default(),
+        monitored_allocations: Default::default(),
+        monitored_deployments: Default::default(),
+        served_deployments: Default::default(),
+        denied_deployments: Default::default(),
+        allocation_quotas: Default::default(),
+        max_lazy_signers: None,
+        warm_allocations: Default::default(),
+        max_concurrent_requests: None,
+        request_queue_length: 100,
+        request_queue_max_wait_secs: Duration::from_secs(1),
+        request_timeout_secs: None,
+        shutdown_grace_period_secs: Duration::from_secs(30),
+        connection_idle_timeout_secs: None,
+        database_statement_timeout_secs: None,
+        admin_auth_token: None,
+        admin_signature: None,
+    }
+}
+
 #[sqlx::test(migrations = "../../migrations")]
 async fn full_integration_test(database: PgPool) {
     let http_client = reqwest::Client::builder()
@@ -72,19 +191,11 @@ async fn full_integration_test(database: PgPool) {
         })
         .indexer(IndexerConfig {
             indexer_address: *test_assets::INDEXER_ADDRESS,
-            operator_mnemonic: test_assets::INDEXER_MNEMONIC.clone(),
-        })
-        .service(indexer_config::ServiceConfig {
-            serve_network_subgraph: false,
-            serve_escrow_subgraph: false,
-            serve_auth_token: None,
-            host_and_port: "0.0.0.0:0".parse().unwrap(),
-            url_prefix: "/".into(),
-            tap: indexer_config::ServiceTapConfig {
-                max_receipt_value_grt: NonZeroGRT::new(1000000000000).unwrap(),
-            },
-            free_query_auth_token: None,
+            operator_mnemonic: Some(test_assets::INDEXER_MNEMONIC.clone()),
+            previous_operator_mnemonics: vec![],
+            operator_priority: vec![],
         })
+        .service(service_config())
         .blockchain(BlockchainConfig {
             chain_id: indexer_config::TheGraphChainId::Test,
             receipts_verifier_address: *test_assets::VERIFIER_ADDRESS,
@@ -113,6 +224,7 @@ async fn full_integration_test(database: PgPool) {
     let request = Request::builder()
         .method(Method::POST)
         .uri(format!("/subgraphs/id/{deployment}"))
+        .header(CONTENT_TYPE, "application/json")
         .header(TapReceipt::name(), serde_json::to_string(&receipt).unwrap())
         .body(serde_json::to_string(&query).unwrap())
         .unwrap();
@@ -130,6 +242,7 @@ async fn full_integration_test(database: PgPool) {
     let request = Request::builder()
         .method(Method::POST)
         .uri(format!("/subgraphs/id/{deployment}"))
+        .header(CONTENT_TYPE, "application/json")
         .body(serde_json::to_string(&query).unwrap())
         .unwrap();
 
@@ -144,3 +257,403 @@ async fn full_integration_test(database: PgPool) {
 
     insta::assert_snapshot!(res);
 }
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn admin_routes_only_on_admin_router(database: PgPool) {
+    let (_escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::default());
+    let (_dispute_tx, dispute_manager) = watch::channel(Address::ZERO);
+    let (_allocations_tx, allocations) = watch::channel(Default::default());
+    let graph_node_url = Url::parse("http://localhost:8000").unwrap();
+
+    let router = ServiceRouter::builder()
+        .database(database)
+        .domain_separator(TAP_EIP712_DOMAIN.clone())
+        .http_client(reqwest::Client::new())
+        .graph_node(GraphNodeConfig {
+            query_url: graph_node_url.clone(),
+            status_url: graph_node_url,
+        })
+        .indexer(IndexerConfig {
+            indexer_address: *test_assets::INDEXER_ADDRESS,
+            operator_mnemonic: Some(test_assets::INDEXER_MNEMONIC.clone()),
+            previous_operator_mnemonics: vec![],
+            operator_priority: vec![],
+        })
+        .service(indexer_config::ServiceConfig {
+            admin_auth_token: Some("admin".into()),
+            ..service_config()
+        })
+        .blockchain(BlockchainConfig {
+            chain_id: indexer_config::TheGraphChainId::Test,
+            receipts_verifier_address: *test_assets::VERIFIER_ADDRESS,
+        })
+        .timestamp_buffer_secs(Duration::from_secs(10))
+        .escrow_accounts(escrow_accounts)
+        .dispute_manager(dispute_manager)
+        .allocations(allocations)
+        .build();
+
+    let ServiceRouters {
+        mut public,
+        mut admin,
+    } = router.create_routers().await.unwrap();
+
+    let request = || {
+        Request::builder()
+            .method(Method::GET)
+            .uri("/admin/safe-mode")
+            .header(AUTHORIZATION, "Bearer admin")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let res = public.call(request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = admin.call(request()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn info_address_format(database: PgPool) {
+    let public_key = |address_format| {
+        let database = database.clone();
+        async move {
+            let (_escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::default());
+            let (_dispute_tx, dispute_manager) = watch::channel(Address::ZERO);
+            let (_allocations_tx, allocations) = watch::channel(Default::default());
+            let graph_node_url = Url::parse("http://localhost:8000").unwrap();
+
+            let router = ServiceRouter::builder()
+                .database(database)
+                .domain_separator(TAP_EIP712_DOMAIN.clone())
+                .http_client(reqwest::Client::new())
+                .graph_node(GraphNodeConfig {
+                    query_url: graph_node_url.clone(),
+                    status_url: graph_node_url,
+                })
+                .indexer(IndexerConfig {
+                    indexer_address: *test_assets::INDEXER_ADDRESS,
+                    operator_mnemonic: Some(test_assets::INDEXER_MNEMONIC.clone()),
+                    previous_operator_mnemonics: vec![],
+                    operator_priority: vec![],
+                })
+                .service(indexer_config::ServiceConfig {
+                    address_format,
+                    ..service_config()
+                })
+                .blockchain(BlockchainConfig {
+                    chain_id: indexer_config::TheGraphChainId::Test,
+                    receipts_verifier_address: *test_assets::VERIFIER_ADDRESS,
+                })
+                .timestamp_buffer_secs(Duration::from_secs(10))
+                .escrow_accounts(escrow_accounts)
+                .dispute_manager(dispute_manager)
+                .allocations(allocations)
+                .build();
+            let ServiceRouters { mut public, .. } = router.create_routers().await.unwrap();
+
+            let request = Request::builder()
+                .method(Method::GET)
+                .uri("/info")
+                .body(Body::empty())
+                .unwrap();
+            let res = public.call(request).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            info["publicKey"].as_str().unwrap().to_string()
+        }
+    };
+
+    // lowercase unless configured otherwise
+    let lowercase = public_key(Default::default()).await;
+    assert_eq!(lowercase, lowercase.to_lowercase());
+
+    let checksummed = public_key(AddressFormat::Checksummed).await;
+    let address: Address = checksummed.parse().unwrap();
+    assert_eq!(checksummed, address.to_checksum(None));
+    assert_ne!(checksummed, lowercase);
+    assert_eq!(checksummed.to_lowercase(), lowercase);
+}
+
+/// Builds and signs a receipt with the sender's wallet, sends it through the
+/// router and checks that it was stored and that the attestation returned
+/// verifies against the signer derived for the allocation.
+#[cfg(feature = "test-util")]
+#[sqlx::test(migrations = "../../migrations")]
+async fn receipt_to_attestation_test(database: PgPool) {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use alloy::hex::ToHexExt;
+    use indexer_attestation::AttestationSigner;
+    use tap_core::{receipt::Receipt, signed_message::EIP712SignedMessage};
+    use test_assets::{assert_while_retry, TAP_SIGNER};
+    use thegraph_core::Attestation;
+
+    let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+    let deployment = allocation.subgraph_deployment.id;
+    let response_body = r#"{"data":{"graphNetwork":{"currentEpoch":960}}}"#;
+
+    let mock_server = MockServer::start().await;
+    mock_server
+        .register(
+            Mock::given(method("POST"))
+                .and(path(format!("/subgraphs/id/{deployment}")))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("graph-attestable", "true")
+                        .set_body_raw(response_body, "application/json"),
+                ),
+        )
+        .await;
+    let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+
+    let (_escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::new(
+        test_assets::ESCROW_ACCOUNTS_BALANCES.clone(),
+        test_assets::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+    ));
+    let dispute_manager_address = *test_assets::DISPUTE_MANAGER_ADDRESS;
+    let (_dispute_tx, dispute_manager) = watch::channel(dispute_manager_address);
+    let (_allocations_tx, allocations) = watch::channel(INDEXER_ALLOCATIONS.clone());
+    let chain_id = indexer_config::TheGraphChainId::Test;
+
+    let router = ServiceRouter::builder()
+        .database(database.clone())
+        .domain_separator(TAP_EIP712_DOMAIN.clone())
+        .http_client(reqwest::Client::new())
+        .graph_node(GraphNodeConfig {
+            query_url: graph_node_url.clone(),
+            status_url: graph_node_url,
+        })
+        .indexer(IndexerConfig {
+            indexer_address: *test_assets::INDEXER_ADDRESS,
+            operator_mnemonic: Some(test_assets::INDEXER_MNEMONIC.clone()),
+            previous_operator_mnemonics: vec![],
+            operator_priority: vec![],
+        })
+        .service(service_config())
+        .blockchain(BlockchainConfig {
+            chain_id,
+            receipts_verifier_address: *test_assets::VERIFIER_ADDRESS,
+        })
+        .timestamp_buffer_secs(Duration::from_secs(10))
+        .escrow_accounts(escrow_accounts)
+        .dispute_manager(dispute_manager)
+        .allocations(allocations)
+        .build();
+    let mut app = router.create_router().await.unwrap();
+
+    // signed against the same EIP-712 domain the router verifies receipts with
+    let (wallet, signer_address) = &*TAP_SIGNER;
+    let receipt = EIP712SignedMessage::new(
+        &TAP_EIP712_DOMAIN,
+        Receipt {
+            allocation_id: allocation.id,
+            nonce: 1,
+            timestamp_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            value: 100,
+        },
+        wallet,
+    )
+    .unwrap();
+
+    let request_body = serde_json::to_string(&QueryBody {
+        query: "{ graphNetwork(id: 1) { currentEpoch } }".into(),
+        variables: None,
+    })
+    .unwrap();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/subgraphs/id/{deployment}"))
+        .header(CONTENT_TYPE, "application/json")
+        .header(TapReceipt::name(), serde_json::to_string(&receipt).unwrap())
+        .body(request_body.clone())
+        .unwrap();
+
+    let res = app.call(request).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let payload: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+    // the receipt passed the checks and gets stored
+    assert_while_retry!(
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM scalar_tap_receipts WHERE signer_address = $1"
+        )
+        .bind(signer_address.encode_hex())
+        .fetch_one(&database)
+        .await
+        .unwrap()
+            == 0
+    );
+
+    let graphql_response = payload["graphQLResponse"].as_str().unwrap();
+    assert_eq!(graphql_response, response_body);
+    let attestation: Attestation = serde_json::from_value(payload["attestation"].clone()).unwrap();
+
+    let attestation_signer = AttestationSigner::new(
+        &test_assets::INDEXER_MNEMONIC.to_string(),
+        &allocation,
+        chain_id as u64,
+        dispute_manager_address,
+    )
+    .unwrap();
+    attestation_signer
+        .verify(
+            &attestation,
+            &request_body,
+            graphql_response,
+            &allocation.id,
+        )
+        .unwrap();
+}
+
+/// Sends a paid query and one without receipt over gRPC, going through the
+/// same checks and attestation as the query route
+#[cfg(feature = "grpc")]
+#[sqlx::test(migrations = "../../migrations")]
+async fn query_over_grpc(database: PgPool) {
+    use alloy::{hex::ToHexExt, primitives::B256};
+    use indexer_attestation::AttestationSigner;
+    use indexer_service_rs::grpc::{
+        proto::{query_client::QueryClient, QueryRequest},
+        serve_grpc,
+    };
+    use test_assets::{assert_while_retry, TAP_SIGNER};
+    use thegraph_core::Attestation;
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+    use tonic::Code;
+
+    let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+    let deployment = allocation.subgraph_deployment.id;
+    let response_body = r#"{"data":{"graphNetwork":{"currentEpoch":960}}}"#;
+
+    let mock_server = MockServer::start().await;
+    mock_server
+        .register(
+            Mock::given(method("POST"))
+                .and(path(format!("/subgraphs/id/{deployment}")))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("graph-attestable", "true")
+                        .set_body_raw(response_body, "application/json"),
+                ),
+        )
+        .await;
+    let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+
+    let (_escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::new(
+        test_assets::ESCROW_ACCOUNTS_BALANCES.clone(),
+        test_assets::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
+    ));
+    let (_dispute_tx, dispute_manager) = watch::channel(Address::ZERO);
+    let (_allocations_tx, allocations) = watch::channel(INDEXER_ALLOCATIONS.clone());
+    let chain_id = indexer_config::TheGraphChainId::Test;
+
+    let router = ServiceRouter::builder()
+        .database(database.clone())
+        .domain_separator(TAP_EIP712_DOMAIN.clone())
+        .http_client(reqwest::Client::new())
+        .graph_node(GraphNodeConfig {
+            query_url: graph_node_url.clone(),
+            status_url: graph_node_url,
+        })
+        .indexer(IndexerConfig {
+            indexer_address: *test_assets::INDEXER_ADDRESS,
+            operator_mnemonic: Some(test_assets::INDEXER_MNEMONIC.clone()),
+            previous_operator_mnemonics: vec![],
+            operator_priority: vec![],
+        })
+        .service(service_config())
+        .blockchain(BlockchainConfig {
+            chain_id,
+            receipts_verifier_address: *test_assets::VERIFIER_ADDRESS,
+        })
+        .timestamp_buffer_secs(Duration::from_secs(10))
+        .escrow_accounts(escrow_accounts)
+        .dispute_manager(dispute_manager)
+        .allocations(allocations)
+        .build();
+    let app = router.create_router().await.unwrap();
+
+    // the listener is bound before the client connects, and stays bound
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(serve_grpc(listener, app, "/", None, shutdown.clone()));
+    let mut client = tokio::time::timeout(
+        Duration::from_secs(5),
+        QueryClient::connect(format!("http://{address}")),
+    )
+    .await
+    .expect("Timed out connecting to the gRPC server")
+    .unwrap();
+
+    let receipt = create_signed_receipt(
+        SignedReceiptRequest::builder()
+            .allocation_id(allocation.id)
+            .value(100)
+            .build(),
+    )
+    .await;
+    let request_body = serde_json::to_string(&QueryBody {
+        query: "{ graphNetwork(id: 1) { currentEpoch } }".into(),
+        variables: None,
+    })
+    .unwrap();
+    let request = |receipt: String| QueryRequest {
+        deployment_id: deployment.to_string(),
+        request: request_body.clone(),
+        receipt,
+    };
+
+    let response = client
+        .query(request(serde_json::to_string(&receipt).unwrap()))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.graphql_response, response_body);
+
+    // the receipt passed the checks and gets stored
+    let (_, signer_address) = &*TAP_SIGNER;
+    assert_while_retry!(
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM scalar_tap_receipts WHERE signer_address = $1"
+        )
+        .bind(signer_address.encode_hex())
+        .fetch_one(&database)
+        .await
+        .unwrap()
+            == 0
+    );
+
+    let attestation = response.attestation.unwrap();
+    let attestation = Attestation {
+        request_cid: B256::from_slice(&attestation.request_cid),
+        response_cid: B256::from_slice(&attestation.response_cid),
+        deployment: B256::from_slice(&attestation.deployment),
+        r: B256::from_slice(&attestation.r),
+        s: B256::from_slice(&attestation.s),
+        v: attestation.v as u8,
+    };
+    AttestationSigner::new(
+        &test_assets::INDEXER_MNEMONIC.to_string(),
+        &allocation,
+        chain_id as u64,
+        Address::ZERO,
+    )
+    .unwrap()
+    .verify(&attestation, &request_body, response_body, &allocation.id)
+    .unwrap();
+
+    // without receipt, the query route refuses the request
+    let status = client.query(request(String::new())).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    assert_eq!(status.message(), "No Tap receipt was found in the request");
+    shutdown.cancel();
+}
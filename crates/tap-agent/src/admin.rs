@@ -0,0 +1,408 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Admin routes of tap-agent, served on the metrics port
+//!
+//! Appraisals are keyed by the [canonical_receipt_hash] of the receipt paying
+//! for the query, and are read by the [Value] check. Those set for a sender
+//! take precedence over the global ones, set without a sender.
+//!
+//! [canonical_receipt_hash]: crate::tap::canonical_receipt_hash
+//! [Value]: crate::tap::context::checks::Value
+
+use std::{collections::HashMap, str::FromStr};
+
+use alloy::primitives::{Address, B256};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use indexer_admin_auth::{admin_auth_middleware, AdminAuthState};
+use indexer_config::AdminSignatureConfig;
+use serde::Deserialize;
+use serde_json::json;
+use tap_core::signed_message::MessageId;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::tap::context::checks::QueryAppraisals;
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("Invalid receipt hash `{0}`")]
+    InvalidHash(String),
+    #[error("Invalid appraised value `{0}`, expected a positive amount of GRT wei")]
+    InvalidValue(String),
+    #[error("No appraisal for receipt hash {0}")]
+    AppraisalNotFound(B256),
+    #[error("Batch of {0} appraisals is over the maximum of {1}")]
+    BatchTooLarge(usize, usize),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        warn!(%self, "Admin request rejected");
+        let status = match self {
+            AdminError::InvalidHash(_)
+            | AdminError::InvalidValue(_)
+            | AdminError::BatchTooLarge(..) => StatusCode::BAD_REQUEST,
+            AdminError::AppraisalNotFound(_) => StatusCode::NOT_FOUND,
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+#[derive(Clone)]
+struct AppraisalsState {
+    query_appraisals: QueryAppraisals,
+    /// appraisals set by a single request
+    max_batch_size: usize,
+}
+
+/// Routes to set and read the query appraisals, for requests bearing
+/// `auth_token` or signed by the key of `signature`
+pub fn admin_routes(
+    query_appraisals: QueryAppraisals,
+    max_batch_size: usize,
+    auth_token: Option<String>,
+    signature: Option<AdminSignatureConfig>,
+) -> Router {
+    let auth = AdminAuthState::new(auth_token.as_deref(), signature);
+    Router::new()
+        .route("/admin/appraisals", post(set_appraisals))
+        .route("/admin/appraisals/:hash", get(get_appraisal))
+        .with_state(AppraisalsState {
+            query_appraisals,
+            max_batch_size,
+        })
+        .layer(from_fn_with_state(auth, admin_auth_middleware))
+}
+
+fn parse_hash(hash: &str) -> Result<B256, AdminError> {
+    B256::from_str(hash).map_err(|_| AdminError::InvalidHash(hash.to_string()))
+}
+
+#[derive(Deserialize)]
+struct SetAppraisalsRequest {
+    /// global appraisals if not set
+    sender: Option<Address>,
+    /// GRT wei by receipt hash, as decimal strings
+    appraisals: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct GetAppraisalQuery {
+    /// only the global appraisal if not set
+    sender: Option<Address>,
+}
+
+/// Sets a batch of appraisals, none of them if any is invalid
+///
+/// The whole batch is parsed before any of it is set, for the lock on the
+/// appraisals to only be held while they are inserted.
+async fn set_appraisals(
+    State(state): State<AppraisalsState>,
+    Json(SetAppraisalsRequest { sender, appraisals }): Json<SetAppraisalsRequest>,
+) -> Result<impl IntoResponse, AdminError> {
+    if appraisals.len() > state.max_batch_size {
+        return Err(AdminError::BatchTooLarge(
+            appraisals.len(),
+            state.max_batch_size,
+        ));
+    }
+    let appraisals = appraisals
+        .iter()
+        .map(|(hash, value)| {
+            let value = value
+                .parse::<u128>()
+                .ok()
+                .filter(|value| *value > 0)
+                .ok_or_else(|| AdminError::InvalidValue(value.clone()))?;
+            Ok((MessageId(parse_hash(hash)?.0), value))
+        })
+        .collect::<Result<Vec<_>, AdminError>>()?;
+
+    let updated = appraisals.len();
+    state.query_appraisals.extend(sender, appraisals);
+    info!(updated, ?sender, "Query appraisals set");
+    Ok(Json(json!({ "updated": updated })))
+}
+
+async fn get_appraisal(
+    State(AppraisalsState {
+        query_appraisals, ..
+    }): State<AppraisalsState>,
+    Path(hash): Path<String>,
+    Query(GetAppraisalQuery { sender }): Query<GetAppraisalQuery>,
+) -> Result<impl IntoResponse, AdminError> {
+    let hash = parse_hash(&hash)?;
+    let (sender, value) = query_appraisals
+        .get(sender, &MessageId(hash.0))
+        .ok_or(AdminError::AppraisalNotFound(hash))?;
+    Ok(Json(
+        json!({ "hash": hash, "sender": sender, "value": value.to_string() }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use alloy::{
+        hex::ToHexExt,
+        primitives::{Address, B256},
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::AUTHORIZATION, Method, Request, StatusCode},
+        Router,
+    };
+    use indexer_admin_auth::{
+        admin_request_message, ADMIN_NONCE, ADMIN_SIGNATURE, ADMIN_TIMESTAMP,
+    };
+    use indexer_config::AdminSignatureConfig;
+    use serde_json::{json, Value as JsonValue};
+    use tap_core::receipt::{checks::Check, Context, ReceiptWithState};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_SENDER};
+    use tower::ServiceExt;
+
+    use super::admin_routes;
+    use crate::tap::{
+        canonical_receipt_hash,
+        context::checks::{QueryAppraisals, Value},
+    };
+
+    const TOKEN: &str = "admin-token";
+    const MAX_BATCH_SIZE: usize = 100;
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, JsonValue) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn set(body: JsonValue) -> Request<Body> {
+        Request::post("/admin/appraisals")
+            .header(AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn get(hash: &str) -> Request<Body> {
+        Request::get(format!("/admin/appraisals/{hash}"))
+            .header(AUTHORIZATION, format!("Bearer {TOKEN}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_set_then_get() {
+        let app = admin_routes(
+            QueryAppraisals::default(),
+            MAX_BATCH_SIZE,
+            Some(TOKEN.to_string()),
+            None,
+        );
+        let hash = B256::repeat_byte(1).to_string();
+
+        let (status, _) = send(&app, get(&hash)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(&app, set(json!({ "appraisals": { &hash: "1000" } }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "updated": 1 }));
+
+        let (status, body) = send(&app, get(&hash)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "hash": hash, "sender": null, "value": "1000" })
+        );
+
+        // nothing is set from an invalid batch
+        let other = B256::repeat_byte(2).to_string();
+        for appraisals in [
+            json!({ &other: "10", "0x1234": "10" }),
+            json!({ &other: "10", &hash: "0" }),
+            json!({ &other: "ten" }),
+        ] {
+            let (status, _) = send(&app, set(json!({ "appraisals": appraisals }))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(send(&app, get(&other)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, get(&hash)).await.1["value"], "1000");
+        assert_eq!(send(&app, get("0x1234")).await.0, StatusCode::BAD_REQUEST);
+
+        let request = Request::get(format!("/admin/appraisals/{hash}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_value_check_sees_appraisals() {
+        let query_appraisals = QueryAppraisals::default();
+        let app = admin_routes(
+            query_appraisals.clone(),
+            MAX_BATCH_SIZE,
+            Some(TOKEN.to_string()),
+            None,
+        );
+        let check = Value::new(query_appraisals, TAP_SENDER.1);
+
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(1000).build()).await,
+        );
+        let hash = B256::from(canonical_receipt_hash(receipt.signed_receipt()).0).to_string();
+        let ctx = Context::new();
+        assert!(check.check(&ctx, &receipt).await.is_err());
+
+        send(&app, set(json!({ "appraisals": { &hash: "1000" } }))).await;
+        assert!(check.check(&ctx, &receipt).await.is_ok());
+
+        send(&app, set(json!({ "appraisals": { &hash: "999" } }))).await;
+        assert!(check.check(&ctx, &receipt).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sender_appraisals() {
+        let query_appraisals = QueryAppraisals::default();
+        let app = admin_routes(
+            query_appraisals.clone(),
+            MAX_BATCH_SIZE,
+            Some(TOKEN.to_string()),
+            None,
+        );
+        let sender = TAP_SENDER.1;
+        let other_sender = Address::repeat_byte(9);
+        let check = Value::new(query_appraisals.clone(), sender);
+        let other_check = Value::new(query_appraisals, other_sender);
+
+        let receipt = ReceiptWithState::new(
+            create_signed_receipt(SignedReceiptRequest::builder().value(1000).build()).await,
+        );
+        let hash = B256::from(canonical_receipt_hash(receipt.signed_receipt()).0).to_string();
+        let ctx = Context::new();
+
+        // global appraisals hold for every sender
+        send(&app, set(json!({ "appraisals": { &hash: "1000" } }))).await;
+        assert!(check.check(&ctx, &receipt).await.is_ok());
+        assert!(other_check.check(&ctx, &receipt).await.is_ok());
+
+        // until the sender has its own
+        let (status, _) = send(
+            &app,
+            set(json!({ "sender": sender, "appraisals": { &hash: "900" } })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(check.check(&ctx, &receipt).await.is_err());
+        assert!(other_check.check(&ctx, &receipt).await.is_ok());
+
+        let (_, body) = send(&app, get(&format!("{hash}?sender={sender}"))).await;
+        assert_eq!(
+            body,
+            json!({ "hash": hash, "sender": sender, "value": "900" })
+        );
+        let (_, body) = send(&app, get(&format!("{hash}?sender={other_sender}"))).await;
+        assert_eq!(
+            body,
+            json!({ "hash": hash, "sender": null, "value": "1000" })
+        );
+        let (_, body) = send(&app, get(&hash)).await;
+        assert_eq!(body["value"], "1000");
+    }
+
+    #[tokio::test]
+    async fn test_signed_requests() {
+        let admin = PrivateKeySigner::random();
+        let app = admin_routes(
+            QueryAppraisals::default(),
+            MAX_BATCH_SIZE,
+            None,
+            Some(AdminSignatureConfig {
+                signer: admin.address(),
+                max_age_secs: Duration::from_secs(30),
+            }),
+        );
+        let body = json!({ "appraisals": { B256::repeat_byte(1).to_string(): "10" } }).to_string();
+        let signed = |signer: &PrivateKeySigner, signed_ago: Duration, nonce: &str| {
+            let timestamp = (SystemTime::now() - signed_ago)
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let message = admin_request_message(
+                timestamp,
+                nonce,
+                &Method::POST,
+                "/admin/appraisals",
+                body.as_bytes(),
+            );
+            let signature = signer.sign_message_sync(&message).unwrap();
+            Request::post("/admin/appraisals")
+                .header("content-type", "application/json")
+                .header(ADMIN_TIMESTAMP, timestamp)
+                .header(ADMIN_NONCE, nonce)
+                .header(
+                    ADMIN_SIGNATURE,
+                    signature.as_bytes().encode_hex_with_prefix(),
+                )
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+
+        let (status, body) = send(&app, signed(&admin, Duration::ZERO, "1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "updated": 1 }));
+
+        let replayed = signed(&admin, Duration::ZERO, "1");
+        assert_eq!(send(&app, replayed).await.0, StatusCode::UNAUTHORIZED);
+        let stale = signed(&admin, Duration::from_secs(60), "2");
+        assert_eq!(send(&app, stale).await.0, StatusCode::UNAUTHORIZED);
+        let wrong_key = signed(&PrivateKeySigner::random(), Duration::ZERO, "3");
+        assert_eq!(send(&app, wrong_key).await.0, StatusCode::UNAUTHORIZED);
+        // no token is configured
+        assert_eq!(send(&app, set(json!({}))).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_batch_size_limit() {
+        let query_appraisals = QueryAppraisals::default();
+        let app = admin_routes(
+            query_appraisals.clone(),
+            MAX_BATCH_SIZE,
+            Some(TOKEN.to_string()),
+            None,
+        );
+        let batch = |size: usize| {
+            let appraisals: serde_json::Map<String, JsonValue> = (0..size)
+                .map(|i| {
+                    let mut hash = B256::ZERO;
+                    hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                    (hash.to_string(), json!("10"))
+                })
+                .collect();
+            set(json!({ "appraisals": appraisals }))
+        };
+
+        let (status, body) = send(&app, batch(MAX_BATCH_SIZE + 1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({ "error": "Batch of 101 appraisals is over the maximum of 100" })
+        );
+        assert!(query_appraisals.is_empty());
+
+        let (status, body) = send(&app, batch(MAX_BATCH_SIZE)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "updated": MAX_BATCH_SIZE }));
+        assert_eq!(query_appraisals.len(), MAX_BATCH_SIZE);
+    }
+}
@@ -5,7 +5,9 @@ use indexer_config::{
     Config, EscrowSubgraphConfig, GraphNodeConfig, IndexerConfig, NetworkSubgraphConfig,
     SubgraphConfig, SubgraphsConfig, TapConfig,
 };
-use indexer_monitor::{escrow_accounts, indexer_allocations, DeploymentDetails, SubgraphClient};
+use indexer_monitor::{
+    escrow_accounts, indexer_allocations, DeploymentDetails, SubgraphClient, SubgraphQueryLimit,
+};
 use ractor::concurrency::JoinHandle;
 use ractor::{Actor, ActorRef};
 use sender_account::SenderAccountConfig;
@@ -13,15 +15,23 @@ use sender_account::SenderAccountConfig;
 use crate::agent::sender_accounts_manager::{
     SenderAccountsManagerArgs, SenderAccountsManagerMessage,
 };
-use crate::{database, CONFIG, EIP_712_DOMAIN};
+use crate::{database, tap::context::checks::QueryAppraisals, CONFIG, EIP_712_DOMAIN};
+use rav_redemption::spawn_rav_redemption_poller;
+use receipt_pruning::spawn_receipt_pruner;
 use sender_accounts_manager::SenderAccountsManager;
 
+pub mod rav_redemption;
+pub mod receipt_pruning;
 pub mod sender_account;
 pub mod sender_accounts_manager;
 pub mod sender_allocation;
 pub mod unaggregated_receipts;
 
-pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>) {
+/// Starts the sender accounts manager, checking receipts against
+/// `query_appraisals` if set
+pub async fn start_agent(
+    query_appraisals: Option<QueryAppraisals>,
+) -> (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>) {
     let Config {
         indexer: IndexerConfig {
             indexer_address, ..
@@ -54,12 +64,16 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                                 deployment_id: escrow_deployment_id,
                                 syncing_interval_secs: escrow_sync_interval,
                             },
+                        ..
                     },
+                max_concurrent_queries,
             },
         tap:
             TapConfig {
                 // TODO: replace with a proper implementation once the gateway registry contract is ready
                 sender_aggregator_endpoints,
+                rav_redemption,
+                receipt_pruning,
                 ..
             },
         ..
@@ -68,7 +82,14 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
 
     let http_client = reqwest::Client::new();
 
-    let network_subgraph = Box::leak(Box::new(
+    // both subgraphs share the limit, they are usually served by the same gateway
+    let query_limit = max_concurrent_queries.map(SubgraphQueryLimit::new);
+    let with_query_limit = |client: SubgraphClient| match &query_limit {
+        Some(query_limit) => client.with_query_limit(query_limit.clone()),
+        None => client,
+    };
+
+    let network_subgraph = Box::leak(Box::new(with_query_limit(
         SubgraphClient::new(
             http_client.clone(),
             network_deployment_id.map(|deployment| {
@@ -84,7 +105,7 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
             ),
         )
         .await,
-    ));
+    )));
 
     let indexer_allocations = indexer_allocations(
         network_subgraph,
@@ -95,7 +116,7 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
     .await
     .expect("Failed to initialize indexer_allocations watcher");
 
-    let escrow_subgraph = Box::leak(Box::new(
+    let escrow_subgraph = Box::leak(Box::new(with_query_limit(
         SubgraphClient::new(
             http_client.clone(),
             escrow_deployment_id.map(|deployment| {
@@ -111,7 +132,7 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
             ),
         )
         .await,
-    ));
+    )));
 
     let escrow_accounts = escrow_accounts(
         escrow_subgraph,
@@ -122,7 +143,25 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
     .await
     .expect("Error creating escrow_accounts channel");
 
-    let config = Box::leak(Box::new(SenderAccountConfig::from_config(&CONFIG)));
+    spawn_rav_redemption_poller(
+        pgpool.clone(),
+        escrow_subgraph,
+        *indexer_address,
+        rav_redemption.polling_interval_secs,
+        rav_redemption.confirmation_depth,
+    );
+
+    spawn_receipt_pruner(
+        pgpool.clone(),
+        escrow_accounts.clone(),
+        receipt_pruning.interval_secs,
+        receipt_pruning.retention_secs,
+    );
+
+    let config = Box::leak(Box::new(SenderAccountConfig {
+        query_appraisals,
+        ..SenderAccountConfig::from_config(&CONFIG)
+    }));
 
     let args = SenderAccountsManagerArgs {
         config,
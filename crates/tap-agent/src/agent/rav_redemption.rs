@@ -0,0 +1,396 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Background poller that follows the last RAV of every allocation until it
+//! is redeemed on-chain.
+//!
+//! A redemption is first recorded with the escrow subgraph block at which it
+//! was observed. Only once the subgraph has advanced `confirmation_depth`
+//! blocks past that point is the RAV marked as redeemed. If the redeem
+//! transaction disappears in the meantime (re-org), the observation is
+//! cleared and the RAV goes back to pending.
+
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+use alloy::{hex::ToHexExt, primitives::Address};
+use anyhow::anyhow;
+use indexer_monitor::SubgraphClient;
+use indexer_query::rav_redemptions::{self, RavRedemptions};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// Redeem transactions read from the escrow subgraph at once
+const REDEMPTIONS_PAGE_SIZE: i64 = 200;
+
+lazy_static! {
+    static ref RAVS_PENDING_REDEMPTION: IntGauge = register_int_gauge!(
+        "tap_ravs_pending_redemption_total",
+        "Last RAVs that are not yet confirmed as redeemed on-chain"
+    )
+    .unwrap();
+}
+
+pub fn spawn_rav_redemption_poller(
+    pgpool: PgPool,
+    escrow_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    polling_interval: Duration,
+    confirmation_depth: u64,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(polling_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = check_rav_redemptions(
+                &pgpool,
+                escrow_subgraph,
+                indexer_address,
+                confirmation_depth,
+            )
+            .await
+            {
+                error!(error = %e, "Failed to update RAV redemption status");
+            }
+        }
+    })
+}
+
+/// Runs a single pass over all the last RAVs that are not yet confirmed as redeemed.
+pub async fn check_rav_redemptions(
+    pgpool: &PgPool,
+    escrow_subgraph: &SubgraphClient,
+    indexer_address: Address,
+    confirmation_depth: u64,
+) -> anyhow::Result<()> {
+    let pending = sqlx::query!(
+        r#"
+            SELECT sender_address, allocation_id, redemption_block_number
+            FROM scalar_tap_ravs
+            WHERE last AND redeemed_at IS NULL
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    RAVS_PENDING_REDEMPTION.set(pending.len() as i64);
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let allocation_ids = pending
+        .iter()
+        .filter_map(|rav| Address::from_str(&rav.allocation_id).ok())
+        .map(|allocation_id| format!("{:x?}", allocation_id))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    // every page is read at the block of the first one
+    let mut block: Option<rav_redemptions::Block_height> = None;
+    let mut current_block = None;
+    let mut last = String::new();
+    let mut redeemed = HashSet::new();
+    loop {
+        let response = escrow_subgraph
+            .query::<RavRedemptions, _>(rav_redemptions::Variables {
+                receiver: format!("{:x?}", indexer_address),
+                allocation_ids: allocation_ids.clone(),
+                block: block.clone(),
+                first: REDEMPTIONS_PAGE_SIZE,
+                last: last.clone(),
+            })
+            .await?
+            .map_err(|e| anyhow!(e))?;
+
+        let meta = response
+            .meta
+            .ok_or_else(|| anyhow!("Escrow subgraph did not report its latest block number"))?;
+        current_block.get_or_insert(meta.block.number);
+        if block.is_none() {
+            block = meta.block.hash.map(|hash| rav_redemptions::Block_height {
+                hash: Some(hash),
+                number: None,
+                number_gte: None,
+            });
+        }
+
+        let page_len = response.transactions.len();
+        if let Some(tx) = response.transactions.last() {
+            last = tx.id.clone();
+        }
+        redeemed.extend(response.transactions.into_iter().filter_map(|tx| {
+            let allocation_id = Address::from_str(tx.allocation_id.as_deref()?).ok()?;
+            let sender = Address::from_str(&tx.sender.id).ok()?;
+            Some((sender, allocation_id))
+        }));
+        if (page_len as i64) < REDEMPTIONS_PAGE_SIZE {
+            break;
+        }
+    }
+    let current_block = current_block.expect("at least one page is read");
+
+    let mut pending_count = 0;
+    for rav in pending {
+        let (Ok(sender), Ok(allocation_id)) = (
+            Address::from_str(&rav.sender_address),
+            Address::from_str(&rav.allocation_id),
+        ) else {
+            continue;
+        };
+
+        match (
+            redeemed.contains(&(sender, allocation_id)),
+            rav.redemption_block_number,
+        ) {
+            // First time we see the redeem transaction
+            (true, None) => {
+                sqlx::query!(
+                    r#"
+                        UPDATE scalar_tap_ravs
+                        SET redemption_block_number = $3
+                        WHERE allocation_id = $1 AND sender_address = $2
+                    "#,
+                    allocation_id.encode_hex(),
+                    sender.encode_hex(),
+                    current_block,
+                )
+                .execute(pgpool)
+                .await?;
+                pending_count += 1;
+            }
+            // Deep enough to survive a re-org
+            (true, Some(observed_block))
+                if current_block.saturating_sub(observed_block) >= confirmation_depth as i64 =>
+            {
+                sqlx::query!(
+                    r#"
+                        UPDATE scalar_tap_ravs
+                        SET redeemed_at = NOW()
+                        WHERE allocation_id = $1 AND sender_address = $2
+                    "#,
+                    allocation_id.encode_hex(),
+                    sender.encode_hex(),
+                )
+                .execute(pgpool)
+                .await?;
+                info!(
+                    %sender,
+                    %allocation_id,
+                    observed_block,
+                    current_block,
+                    "RAV redemption confirmed"
+                );
+            }
+            (true, Some(_)) => pending_count += 1,
+            // The redeem transaction was rolled back before it was confirmed
+            (false, Some(observed_block)) => {
+                sqlx::query!(
+                    r#"
+                        UPDATE scalar_tap_ravs
+                        SET redemption_block_number = NULL
+                        WHERE allocation_id = $1 AND sender_address = $2
+                    "#,
+                    allocation_id.encode_hex(),
+                    sender.encode_hex(),
+                )
+                .execute(pgpool)
+                .await?;
+                info!(
+                    %sender,
+                    %allocation_id,
+                    observed_block,
+                    "RAV redemption disappeared from the escrow subgraph, probably a re-org"
+                );
+                pending_count += 1;
+            }
+            (false, None) => pending_count += 1,
+        }
+    }
+    RAVS_PENDING_REDEMPTION.set(pending_count);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::hex::ToHexExt;
+    use indexer_monitor::{DeploymentDetails, SubgraphClient};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::check_rav_redemptions;
+    use crate::test::{create_rav, store_rav_with_options, INDEXER};
+    use test_assets::{ALLOCATION_ID_0, TAP_SENDER as SENDER, TAP_SIGNER as SIGNER};
+
+    const CONFIRMATION_DEPTH: u64 = 5;
+
+    async fn mock_redemptions(mock_server: &MockServer, block: i64, redeemed: bool) {
+        let transactions = if redeemed {
+            json!([{
+                "id": "0x01",
+                "allocationID": format!("{:x?}", *ALLOCATION_ID_0),
+                "sender": { "id": format!("{:x?}", SENDER.1) }
+            }])
+        } else {
+            json!([])
+        };
+        mock_server.reset().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("RavRedemptions"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                        "meta": { "block": { "number": block, "hash": null } },
+                        "transactions": transactions,
+                    }}))),
+            )
+            .await;
+    }
+
+    async fn redemption_status(pgpool: &PgPool) -> (Option<i64>, bool) {
+        let row = sqlx::query!(
+            r#"
+                SELECT redemption_block_number, redeemed_at IS NOT NULL AS "redeemed!"
+                FROM scalar_tap_ravs
+                WHERE allocation_id = $1
+            "#,
+            ALLOCATION_ID_0.encode_hex(),
+        )
+        .fetch_one(pgpool)
+        .await
+        .unwrap();
+        (row.redemption_block_number, row.redeemed)
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_redemption_confirmed_after_depth(pgpool: PgPool) {
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100);
+        store_rav_with_options(&pgpool, rav, SENDER.1, true, false)
+            .await
+            .unwrap();
+
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .await;
+
+        mock_redemptions(&mock_server, 100, true).await;
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (Some(100), false));
+
+        // not deep enough yet
+        mock_redemptions(&mock_server, 104, true).await;
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (Some(100), false));
+
+        mock_redemptions(&mock_server, 105, true).await;
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (Some(100), true));
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_redemption_rolled_back_by_reorg(pgpool: PgPool) {
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100);
+        store_rav_with_options(&pgpool, rav, SENDER.1, true, false)
+            .await
+            .unwrap();
+
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .await;
+
+        mock_redemptions(&mock_server, 100, true).await;
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (Some(100), false));
+
+        mock_redemptions(&mock_server, 102, false).await;
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (None, false));
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_redemption_found_past_the_first_page(pgpool: PgPool) {
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 100);
+        store_rav_with_options(&pgpool, rav, SENDER.1, true, false)
+            .await
+            .unwrap();
+
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = SubgraphClient::new(
+            reqwest::Client::new(),
+            None,
+            DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+        )
+        .await;
+
+        // a full first page of redemptions of other senders
+        let first_page = (0..super::REDEMPTIONS_PAGE_SIZE)
+            .map(|i| {
+                json!({
+                    "id": format!("0x{i:04x}"),
+                    "allocationID": format!("{:x?}", *ALLOCATION_ID_0),
+                    "sender": { "id": format!("{:x?}", SIGNER.1) }
+                })
+            })
+            .collect::<Vec<_>>();
+        let meta = json!({ "block": { "number": 100, "hash": "0xabcd" } });
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains(r#""last":"""#))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                        "meta": meta,
+                        "transactions": first_page,
+                    }}))),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains(format!(
+                        r#""last":"0x{:04x}""#,
+                        super::REDEMPTIONS_PAGE_SIZE - 1
+                    )))
+                    .and(body_string_contains("0xabcd"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                        "meta": meta,
+                        "transactions": [{
+                            "id": "0xffff",
+                            "allocationID": format!("{:x?}", *ALLOCATION_ID_0),
+                            "sender": { "id": format!("{:x?}", SENDER.1) }
+                        }],
+                    }}))),
+            )
+            .await;
+
+        check_rav_redemptions(&pgpool, &escrow_subgraph, INDEXER.1, CONFIRMATION_DEPTH)
+            .await
+            .unwrap();
+        assert_eq!(redemption_status(&pgpool).await, (Some(100), false));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Background pruner deleting the receipts of redeemed RAVs.
+//!
+//! Receipts are usually removed once aggregated, but some are left behind, e.g.
+//! when the agent stops in between. A receipt is only deleted once it's older
+//! than the retention window and covered by a RAV of its sender and allocation
+//! that is confirmed as redeemed, see [super::rav_redemption]. Receipts that
+//! aren't aggregated, or whose RAV isn't redeemed yet, are never deleted.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use alloy::hex::ToHexExt;
+use bigdecimal::num_bigint::BigInt;
+use indexer_monitor::EscrowAccounts;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use sqlx::{types::BigDecimal, PgPool};
+use tokio::{sync::watch::Receiver, task::JoinHandle};
+use tracing::{error, info};
+
+lazy_static! {
+    static ref RECEIPTS_PRUNED: IntCounter = register_int_counter!(
+        "tap_receipts_pruned_total",
+        "Receipts deleted because they are covered by a redeemed RAV"
+    )
+    .unwrap();
+}
+
+pub fn spawn_receipt_pruner(
+    pgpool: PgPool,
+    escrow_accounts: Receiver<EscrowAccounts>,
+    interval: Duration,
+    retention: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let escrow_accounts = escrow_accounts.borrow().clone();
+            match prune_receipts(&pgpool, &escrow_accounts, retention).await {
+                Ok(pruned) => {
+                    RECEIPTS_PRUNED.inc_by(pruned);
+                    info!(
+                        pruned,
+                        retention_secs = retention.as_secs(),
+                        "Pruned receipts of redeemed RAVs"
+                    );
+                }
+                Err(e) => error!(error = %e, "Failed to prune receipts"),
+            }
+        }
+    })
+}
+
+/// Deletes the receipts older than `retention` covered by a redeemed RAV,
+/// returning how many were deleted
+pub async fn prune_receipts(
+    pgpool: &PgPool,
+    escrow_accounts: &EscrowAccounts,
+    retention: Duration,
+) -> anyhow::Result<u64> {
+    // RAVs are stored by sender, receipts by signer
+    let (senders, signers): (Vec<_>, Vec<_>) = escrow_accounts
+        .get_senders()
+        .into_iter()
+        .flat_map(|sender| {
+            escrow_accounts
+                .get_signers_for_sender(&sender)
+                .into_iter()
+                .map(move |signer| (sender.encode_hex(), signer.encode_hex()))
+        })
+        .unzip();
+    if signers.is_empty() {
+        return Ok(0);
+    }
+
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)?
+        .as_nanos();
+
+    let pruned = sqlx::query!(
+        r#"
+            DELETE FROM scalar_tap_receipts AS receipts
+            USING scalar_tap_ravs AS ravs,
+                unnest($1::text[], $2::text[]) AS signers(sender_address, signer_address)
+            WHERE ravs.redeemed_at IS NOT NULL
+                AND ravs.allocation_id = receipts.allocation_id
+                AND ravs.sender_address = signers.sender_address
+                AND receipts.signer_address = signers.signer_address
+                AND receipts.timestamp_ns <= ravs.timestamp_ns
+                AND receipts.timestamp_ns < $3
+        "#,
+        &senders,
+        &signers,
+        BigDecimal::from(BigInt::from(cutoff)),
+    )
+    .execute(pgpool)
+    .await?
+    .rows_affected();
+
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use alloy::hex::ToHexExt;
+    use indexer_monitor::EscrowAccounts;
+    use sqlx::PgPool;
+    use test_assets::{
+        ALLOCATION_ID_0, ALLOCATION_ID_1, TAP_SENDER as SENDER, TAP_SIGNER as SIGNER,
+    };
+
+    use super::prune_receipts;
+    use crate::test::{create_rav, create_received_receipt, store_rav_with_options, store_receipt};
+
+    const RETENTION: Duration = Duration::from_secs(3600);
+
+    async fn remaining_nonces(pgpool: &PgPool) -> Vec<i64> {
+        sqlx::query_scalar("SELECT nonce::BIGINT FROM scalar_tap_receipts ORDER BY nonce")
+            .fetch_all(pgpool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_only_redeemed_receipts_are_pruned(pgpool: PgPool) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let old = now - 2 * RETENTION.as_nanos() as u64;
+
+        for (nonce, allocation_id, timestamp_ns) in [
+            // covered by the redeemed RAV of allocation 0
+            (0, *ALLOCATION_ID_0, old),
+            (1, *ALLOCATION_ID_0, old + 10),
+            // covered too, but still within the retention window
+            (2, *ALLOCATION_ID_0, now - 10),
+            // not aggregated yet
+            (3, *ALLOCATION_ID_0, now + 10),
+            // covered by the RAV of allocation 1, which isn't redeemed yet
+            (4, *ALLOCATION_ID_1, old),
+        ] {
+            let receipt =
+                create_received_receipt(&allocation_id, &SIGNER.0, nonce, timestamp_ns, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let redeemed = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), now, 30);
+        store_rav_with_options(&pgpool, redeemed, SENDER.1, true, false)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE scalar_tap_ravs SET redeemed_at = NOW() WHERE allocation_id = $1")
+            .bind(ALLOCATION_ID_0.encode_hex())
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let pending = create_rav(*ALLOCATION_ID_1, SIGNER.0.clone(), old + 10, 10);
+        store_rav_with_options(&pgpool, pending, SENDER.1, true, false)
+            .await
+            .unwrap();
+
+        // receipts of signers that aren't known are kept
+        let pruned = prune_receipts(&pgpool, &EscrowAccounts::default(), RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 0);
+
+        let escrow_accounts =
+            EscrowAccounts::new(HashMap::new(), HashMap::from([(SENDER.1, vec![SIGNER.1])]));
+        let pruned = prune_receipts(&pgpool, &escrow_accounts, RETENTION)
+            .await
+            .unwrap();
+        assert_eq!(pruned, 2);
+        assert_eq!(remaining_nonces(&pgpool).await, vec![2, 3, 4]);
+    }
+}
@@ -37,6 +37,7 @@ use crate::adaptative_concurrency::AdaptiveLimiter;
 use crate::agent::sender_allocation::{AllocationConfig, SenderAllocationMessage};
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
 use crate::backoff::BackoffInfo;
+use crate::tap::context::checks::QueryAppraisals;
 use crate::tracker::{SenderFeeTracker, SimpleFeeTracker};
 use lazy_static::lazy_static;
 
@@ -108,6 +109,9 @@ pub enum SenderAccountMessage {
     UpdateReceiptFees(Address, ReceiptFees),
     UpdateInvalidReceiptFees(Address, UnaggregatedReceipts),
     UpdateRav(SignedRAV),
+    /// Sent by an allocation whose RAV request failed to reach the aggregator,
+    /// once its backoff is over
+    RetryRavRequest(Address),
     #[cfg(test)]
     GetSenderFeeTracker(ractor::RpcReplyPort<SenderFeeTracker>),
     #[cfg(test)]
@@ -188,8 +192,13 @@ pub struct SenderAccountConfig {
     // allocation config
     pub rav_request_timeout: Duration,
     pub rav_request_receipt_limit: u64,
+    pub persist_rav_requests: bool,
+    pub max_rav_request_attempts: u32,
+    pub rav_request_retry_backoff: Duration,
     pub indexer_address: Address,
     pub escrow_polling_interval: Duration,
+    /// appraisals receipts are checked against, set through the admin routes
+    pub query_appraisals: Option<QueryAppraisals>,
 }
 
 impl SenderAccountConfig {
@@ -197,11 +206,15 @@ impl SenderAccountConfig {
         Self {
             rav_request_buffer: config.tap.rav_request.timestamp_buffer_secs,
             rav_request_receipt_limit: config.tap.rav_request.max_receipts_per_request,
+            persist_rav_requests: config.tap.rav_request.persist_requests,
+            max_rav_request_attempts: config.tap.rav_request.max_retry_attempts,
+            rav_request_retry_backoff: config.tap.rav_request.retry_backoff_secs,
             indexer_address: config.indexer.indexer_address,
             escrow_polling_interval: config.subgraphs.escrow.config.syncing_interval_secs,
             max_amount_willing_to_lose_grt: config.tap.max_amount_willing_to_lose_grt.get_value(),
             trigger_value: config.tap.get_trigger_value(),
             rav_request_timeout: config.tap.rav_request.request_timeout_secs,
+            query_appraisals: None,
         }
     }
 }
@@ -796,6 +809,33 @@ impl Actor for SenderAccount {
                     _ => {}
                 }
             }
+            SenderAccountMessage::RetryRavRequest(allocation_id) => {
+                let has_unaggregated_fees = state
+                    .sender_fee_tracker
+                    .get_total_fee_for_allocation(&allocation_id)
+                    .is_some_and(|fees| fees.value > 0);
+                // a RAV may have been requested successfully since, or the
+                // allocation closed, which requests its last RAV anyway
+                if !state.allocation_ids.contains(&allocation_id) || !has_unaggregated_fees {
+                    return Ok(());
+                }
+                if state.adaptive_limiter.has_limit()
+                    && state.sender_fee_tracker.can_trigger_rav(allocation_id)
+                {
+                    tracing::debug!(%allocation_id, "Retrying failed RAV request");
+                    if let Err(err) = state.rav_request_for_allocation(allocation_id).await {
+                        tracing::error!(
+                            error = %err,
+                            "There was an error while retrying a RAV request."
+                        );
+                    }
+                } else {
+                    // a request is in flight or the limit is reached, retry in a moment
+                    myself.send_after(state.retry_interval, move || {
+                        SenderAccountMessage::RetryRavRequest(allocation_id)
+                    });
+                }
+            }
             SenderAccountMessage::UpdateAllocationIds(allocation_ids) => {
                 // Create new sender allocations
                 let mut new_allocation_ids = state.allocation_ids.clone();
@@ -1089,6 +1129,7 @@ pub mod tests {
                     Self::UpdateInvalidReceiptFees(r0, r1),
                 ) => l0 == r0 && l1 == r1,
                 (Self::NewAllocationId(l0), Self::NewAllocationId(r0)) => l0 == r0,
+                (Self::RetryRavRequest(l0), Self::RetryRavRequest(r0)) => l0 == r0,
                 (a, b) => match (
                     core::mem::discriminant(self),
                     core::mem::discriminant(other),
@@ -1145,8 +1186,12 @@ pub mod tests {
             trigger_value: rav_request_trigger_value,
             rav_request_timeout: Duration::default(),
             rav_request_receipt_limit,
+            persist_rav_requests: true,
+            max_rav_request_attempts: 5,
+            rav_request_retry_backoff: Duration::default(),
             indexer_address: INDEXER.1,
             escrow_polling_interval: Duration::default(),
+            query_appraisals: None,
         }));
 
         let network_subgraph = Box::leak(Box::new(
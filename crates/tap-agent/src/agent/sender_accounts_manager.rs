@@ -641,8 +641,12 @@ mod tests {
             trigger_value: 100,
             rav_request_timeout: Duration::from_millis(1),
             rav_request_receipt_limit: 1000,
+            persist_rav_requests: true,
+            max_rav_request_attempts: 5,
+            rav_request_retry_backoff: Duration::from_millis(1),
             indexer_address: INDEXER.1,
             escrow_polling_interval: Duration::default(),
+            query_appraisals: None,
         }))
     }
 
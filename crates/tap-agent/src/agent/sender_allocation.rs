@@ -6,14 +6,14 @@ use std::{
     time::{Duration, Instant},
 };
 
-use alloy::primitives::Address;
+use alloy::primitives::{keccak256, Address};
 use alloy::{dyn_abi::Eip712Domain, hex::ToHexExt};
 use anyhow::{anyhow, ensure, Result};
 use bigdecimal::{num_bigint::BigInt, ToPrimitive};
 use indexer_monitor::{EscrowAccounts, SubgraphClient};
 use jsonrpsee::{core::client::ClientT, rpc_params};
 use prometheus::{register_counter_vec, register_histogram_vec, CounterVec, HistogramVec};
-use ractor::{Actor, ActorProcessingErr, ActorRef};
+use ractor::{Actor, ActorProcessingErr, ActorRef, MessagingErr};
 use sqlx::{types::BigDecimal, PgPool};
 use tap_aggregator::jsonrpsee_helpers::JsonRpcResponse;
 use tap_core::{
@@ -22,12 +22,12 @@ use tap_core::{
     receipt::{
         checks::{Check, CheckList},
         state::Failed,
-        Context, ReceiptWithState,
+        Context, ReceiptWithState, SignedReceipt,
     },
     signed_message::EIP712SignedMessage,
 };
-use tokio::sync::watch::Receiver;
-use tracing::{debug, error, warn};
+use tokio::{sync::watch::Receiver, task::JoinHandle};
+use tracing::{debug, error, info, warn};
 
 use crate::{agent::sender_account::ReceiptFees, lazy_static};
 
@@ -35,9 +35,9 @@ use crate::agent::sender_account::SenderAccountMessage;
 use crate::agent::sender_accounts_manager::NewReceiptNotification;
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
 use crate::{
-    tap::context::checks::AllocationId,
+    tap::context::checks::{AllocationId, QueryAppraisals, Value},
     tap::context::{checks::Signature, TapAgentContext},
-    tap::signers_trimmed,
+    tap::{canonical_receipt_hash, signers_trimmed},
 };
 use thiserror::Error;
 
@@ -62,6 +62,12 @@ lazy_static! {
         &["sender", "allocation"]
     )
     .unwrap();
+    static ref RAV_REQUEST_RETRIES_EXHAUSTED: CounterVec = register_counter_vec!(
+        "tap_rav_request_retries_exhausted_total",
+        "RAV requests that failed to reach the aggregator on every attempt, needing an operator",
+        &["sender", "allocation"]
+    )
+    .unwrap();
     static ref RAV_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
         "tap_rav_response_time_seconds",
         "RAV response time per sender",
@@ -90,6 +96,9 @@ pub enum RavError {
 
 type TapManager = tap_core::manager::Manager<TapAgentContext>;
 
+/// Longest backoff between two retries of a failed RAV request
+const MAX_RAV_REQUEST_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
 /// Manages unaggregated fees and the TAP lifecyle for a specific (allocation, sender) pair.
 pub struct SenderAllocation;
 
@@ -107,17 +116,28 @@ pub struct SenderAllocationState {
 
     sender_aggregator: jsonrpsee::http_client::HttpClient,
 
+    /// failed attempts at sending the pending RAV request to the aggregator
+    rav_request_attempts: u32,
+    scheduled_rav_request_retry: Option<JoinHandle<Result<(), MessagingErr<SenderAccountMessage>>>>,
+
     //config
     timestamp_buffer_ns: u64,
     rav_request_receipt_limit: u64,
+    persist_rav_requests: bool,
+    max_rav_request_attempts: u32,
+    rav_request_retry_backoff: Duration,
 }
 
 #[derive(Clone)]
 pub struct AllocationConfig {
     pub timestamp_buffer_ns: u64,
     pub rav_request_receipt_limit: u64,
+    pub persist_rav_requests: bool,
+    pub max_rav_request_attempts: u32,
+    pub rav_request_retry_backoff: Duration,
     pub indexer_address: Address,
     pub escrow_polling_interval: Duration,
+    pub query_appraisals: Option<QueryAppraisals>,
 }
 
 impl AllocationConfig {
@@ -125,8 +145,12 @@ impl AllocationConfig {
         Self {
             timestamp_buffer_ns: config.rav_request_buffer.as_nanos() as u64,
             rav_request_receipt_limit: config.rav_request_receipt_limit,
+            persist_rav_requests: config.persist_rav_requests,
+            max_rav_request_attempts: config.max_rav_request_attempts,
+            rav_request_retry_backoff: config.rav_request_retry_backoff,
             indexer_address: config.indexer_address,
             escrow_polling_interval: config.escrow_polling_interval,
+            query_appraisals: config.query_appraisals.clone(),
         }
     }
 }
@@ -190,6 +214,13 @@ impl Actor for SenderAllocation {
             sender_account_ref.cast(SenderAccountMessage::UpdateRav(rav.clone()))?;
         }
 
+        // resume the retries of a request that kept failing before the restart
+        if state.rav_request_attempts > 0
+            && state.rav_request_attempts < state.max_rav_request_attempts
+        {
+            state.schedule_rav_request_retry();
+        }
+
         tracing::info!(
             sender = %state.sender,
             allocation_id = %state.allocation_id,
@@ -345,7 +376,7 @@ impl SenderAllocationState {
             config,
         }: SenderAllocationArgs,
     ) -> anyhow::Result<Self> {
-        let required_checks: Vec<Arc<dyn Check + Send + Sync>> = vec![
+        let mut required_checks: Vec<Arc<dyn Check + Send + Sync>> = vec![
             Arc::new(
                 AllocationId::new(
                     config.indexer_address,
@@ -361,6 +392,9 @@ impl SenderAllocationState {
                 escrow_accounts.clone(),
             )),
         ];
+        if let Some(query_appraisals) = &config.query_appraisals {
+            required_checks.push(Arc::new(Value::new(query_appraisals.clone(), sender)));
+        }
         let context = TapAgentContext::new(
             pgpool.clone(),
             allocation_id,
@@ -368,6 +402,22 @@ impl SenderAllocationState {
             escrow_accounts.clone(),
         );
         let latest_rav = context.last_rav().await.unwrap_or_default();
+        let rav_request_attempts = if config.persist_rav_requests {
+            sqlx::query_scalar!(
+                r#"
+                    SELECT attempts
+                    FROM scalar_tap_rav_requests
+                    WHERE allocation_id = $1 AND sender_address = $2
+                "#,
+                allocation_id.encode_hex(),
+                sender.encode_hex(),
+            )
+            .fetch_optional(&pgpool)
+            .await?
+            .unwrap_or_default() as u32
+        } else {
+            0
+        };
         let tap_manager = TapManager::new(
             domain_separator.clone(),
             context,
@@ -387,8 +437,13 @@ impl SenderAllocationState {
             invalid_receipts_fees: UnaggregatedReceipts::default(),
             latest_rav,
             sender_aggregator,
+            rav_request_attempts,
+            scheduled_rav_request_retry: None,
             rav_request_receipt_limit: config.rav_request_receipt_limit,
             timestamp_buffer_ns: config.timestamp_buffer_ns,
+            persist_rav_requests: config.persist_rav_requests,
+            max_rav_request_attempts: config.max_rav_request_attempts,
+            rav_request_retry_backoff: config.rav_request_retry_backoff,
         })
     }
 
@@ -502,6 +557,10 @@ impl SenderAllocationState {
     async fn request_rav(&mut self) -> Result<()> {
         match self.rav_requester_single().await {
             Ok(rav) => {
+                self.rav_request_attempts = 0;
+                if let Some(retry) = self.scheduled_rav_request_retry.take() {
+                    retry.abort();
+                }
                 self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
                 self.latest_rav = Some(rav);
                 RAVS_CREATED
@@ -587,32 +646,41 @@ impl SenderAllocationState {
                     .into_iter()
                     .map(|r| r.signed_receipt().clone())
                     .collect();
-                let rav_response_time_start = Instant::now();
-                let response: JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = self
-                    .sender_aggregator
-                    .request(
-                        "aggregate_receipts",
-                        rpc_params!(
-                            "0.0", // TODO: Set the version in a smarter place.
-                            valid_receipts,
-                            previous_rav
-                        ),
-                    )
-                    .await
-                    .inspect_err(|err| {
-                        if let jsonrpsee::core::ClientError::RequestTimeout = &err {
-                            warn!(
-                                "Rav request is timing out, maybe request_timeout_secs is too \
-                                low in your config file, try adding more secs to the value. \
-                                If the problem persists after doing so please open an issue"
-                            );
+                let request_key = rav_request_key(&expected_rav);
+                let interrupted_rav = if self.persist_rav_requests {
+                    self.interrupted_rav_request(&request_key).await?
+                } else {
+                    None
+                };
+                let rav = match interrupted_rav {
+                    Some(rav) => {
+                        info!(
+                            sender = %self.sender,
+                            allocation_id = %self.allocation_id,
+                            "Storing the RAV received before the last restart instead of \
+                            requesting it again",
+                        );
+                        rav
+                    }
+                    None => {
+                        if self.persist_rav_requests {
+                            self.record_rav_request(&request_key).await?;
                         }
-                    })?;
+                        let rav = match self.aggregate_receipts(valid_receipts, previous_rav).await
+                        {
+                            Ok(rav) => rav,
+                            Err(e) => {
+                                self.failed_rav_request_attempt().await;
+                                return Err(e);
+                            }
+                        };
+                        if self.persist_rav_requests {
+                            self.record_rav_response(&request_key, &rav).await?;
+                        }
+                        rav
+                    }
+                };
 
-                let rav_response_time = rav_response_time_start.elapsed();
-                RAV_RESPONSE_TIME
-                    .with_label_values(&[&self.sender.to_string()])
-                    .observe(rav_response_time.as_secs_f64());
                 // we only save invalid receipts when we are about to store our rav
                 //
                 // store them before we call remove_obsolete_receipts()
@@ -630,12 +698,9 @@ impl SenderAllocationState {
                         .await?;
                 }
 
-                if let Some(warnings) = response.warnings {
-                    warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
-                }
                 match self
                     .tap_manager
-                    .verify_and_store_rav(expected_rav.clone(), response.data.clone())
+                    .verify_and_store_rav(expected_rav.clone(), rav.clone())
                     .await
                 {
                     Ok(_) => {}
@@ -657,8 +722,8 @@ impl SenderAllocationState {
                         | e @ tap_core::Error::SignatureError(_)
                         | e @ tap_core::Error::InvalidRecoveredSigner { address: _ },
                     ) => {
-                        Self::store_failed_rav(self, &expected_rav, &response.data, &e.to_string())
-                            .await?;
+                        Self::store_failed_rav(self, &expected_rav, &rav, &e.to_string()).await?;
+                        self.clear_rav_request().await?;
                         return Err(anyhow::anyhow!(
                             "Invalid RAV, sender could be malicious: {:?}.",
                             e
@@ -676,7 +741,8 @@ impl SenderAllocationState {
                         .into());
                     }
                 }
-                Ok(response.data)
+                self.clear_rav_request().await?;
+                Ok(rav)
             }
             (Err(tap_core::Error::NoValidReceiptsForRAVRequest), true, true) => Err(anyhow!(
                 "It looks like there are no valid receipts for the RAV request.\
@@ -689,6 +755,193 @@ impl SenderAllocationState {
         }
     }
 
+    async fn aggregate_receipts(
+        &self,
+        valid_receipts: Vec<SignedReceipt>,
+        previous_rav: Option<SignedRAV>,
+    ) -> Result<SignedRAV, RavError> {
+        let rav_response_time_start = Instant::now();
+        let response: JsonRpcResponse<EIP712SignedMessage<ReceiptAggregateVoucher>> = self
+            .sender_aggregator
+            .request(
+                "aggregate_receipts",
+                rpc_params!(
+                    "0.0", // TODO: Set the version in a smarter place.
+                    valid_receipts,
+                    previous_rav
+                ),
+            )
+            .await
+            .inspect_err(|err| {
+                if let jsonrpsee::core::ClientError::RequestTimeout = &err {
+                    warn!(
+                        "Rav request is timing out, maybe request_timeout_secs is too \
+                        low in your config file, try adding more secs to the value. \
+                        If the problem persists after doing so please open an issue"
+                    );
+                }
+            })?;
+
+        let rav_response_time = rav_response_time_start.elapsed();
+        RAV_RESPONSE_TIME
+            .with_label_values(&[&self.sender.to_string()])
+            .observe(rav_response_time.as_secs_f64());
+        if let Some(warnings) = response.warnings {
+            warn!("Warnings from sender's TAP aggregator: {:?}", warnings);
+        }
+        Ok(response.data)
+    }
+
+    /// Counts a failed attempt at reaching the aggregator and schedules a retry,
+    /// or alerts once all attempts failed
+    async fn failed_rav_request_attempt(&mut self) {
+        self.rav_request_attempts += 1;
+        if self.persist_rav_requests {
+            if let Err(e) = sqlx::query!(
+                r#"
+                    UPDATE scalar_tap_rav_requests
+                    SET attempts = $3
+                    WHERE allocation_id = $1 AND sender_address = $2
+                "#,
+                self.allocation_id.encode_hex(),
+                self.sender.encode_hex(),
+                self.rav_request_attempts as i32,
+            )
+            .execute(&self.pgpool)
+            .await
+            {
+                warn!(error = %e, "Failed to record the failed RAV request attempt");
+            }
+        }
+
+        if self.rav_request_attempts < self.max_rav_request_attempts {
+            self.schedule_rav_request_retry();
+        } else if self.rav_request_attempts == self.max_rav_request_attempts {
+            error!(
+                sender = %self.sender,
+                allocation_id = %self.allocation_id,
+                attempts = self.rav_request_attempts,
+                "RAV request failed on every attempt, not retrying it anymore. \
+                Check the sender's aggregator, receipts keep accumulating until a RAV \
+                request succeeds",
+            );
+            RAV_REQUEST_RETRIES_EXHAUSTED
+                .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+                .inc();
+        }
+    }
+
+    /// Asks the sender account to retry the RAV request once the backoff of
+    /// the failed attempts is over
+    fn schedule_rav_request_retry(&mut self) {
+        // backoff = min(retry_backoff * 2 ^ (attempts - 1), 10min)
+        let backoff = 2u32
+            .checked_pow(self.rav_request_attempts.saturating_sub(1))
+            .and_then(|factor| self.rav_request_retry_backoff.checked_mul(factor))
+            .unwrap_or(MAX_RAV_REQUEST_RETRY_BACKOFF)
+            .min(MAX_RAV_REQUEST_RETRY_BACKOFF);
+        let allocation_id = self.allocation_id;
+        if let Some(retry) = self.scheduled_rav_request_retry.take() {
+            retry.abort();
+        }
+        self.scheduled_rav_request_retry =
+            Some(self.sender_account_ref.send_after(backoff, move || {
+                SenderAccountMessage::RetryRavRequest(allocation_id)
+            }));
+    }
+
+    /// RAV the aggregator sent for this request before the agent stopped,
+    /// if it was received but never stored
+    async fn interrupted_rav_request(&self, request_key: &str) -> Result<Option<SignedRAV>> {
+        let Some(record) = sqlx::query!(
+            r#"
+                SELECT request_key, rav
+                FROM scalar_tap_rav_requests
+                WHERE allocation_id = $1 AND sender_address = $2
+            "#,
+            self.allocation_id.encode_hex(),
+            self.sender.encode_hex(),
+        )
+        .fetch_optional(&self.pgpool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        // receipts came in since, the receipts of the old request are part of the new one
+        if record.request_key != request_key {
+            return Ok(None);
+        }
+        match record.rav.map(serde_json::from_value) {
+            Some(Ok(rav)) => Ok(Some(rav)),
+            Some(Err(e)) => {
+                warn!(error = %e, "Failed to read the RAV of an interrupted request");
+                Ok(None)
+            }
+            None => {
+                // the aggregator might have answered, it keeps no state to
+                // ask about, but the same request yields the same RAV
+                warn!(
+                    sender = %self.sender,
+                    allocation_id = %self.allocation_id,
+                    "A RAV request for the same receipts was interrupted before \
+                    its answer was recorded, sending it again",
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn record_rav_request(&self, request_key: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_rav_requests (allocation_id, sender_address, request_key)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (allocation_id, sender_address)
+                DO UPDATE SET request_key = EXCLUDED.request_key, rav = NULL, created_at = NOW()
+            "#,
+            self.allocation_id.encode_hex(),
+            self.sender.encode_hex(),
+            request_key,
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_rav_response(&self, request_key: &str, rav: &SignedRAV) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE scalar_tap_rav_requests
+                SET rav = $4
+                WHERE allocation_id = $1 AND sender_address = $2 AND request_key = $3
+            "#,
+            self.allocation_id.encode_hex(),
+            self.sender.encode_hex(),
+            request_key,
+            serde_json::to_value(rav)?,
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_rav_request(&self) -> Result<()> {
+        if !self.persist_rav_requests {
+            return Ok(());
+        }
+        sqlx::query!(
+            r#"
+                DELETE FROM scalar_tap_rav_requests
+                WHERE allocation_id = $1 AND sender_address = $2
+            "#,
+            self.allocation_id.encode_hex(),
+            self.sender.encode_hex(),
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
     pub async fn mark_rav_last(&self) -> Result<()> {
         tracing::info!(
             sender = %self.sender,
@@ -750,7 +1003,8 @@ impl SenderAllocationState {
                     anyhow!(e)
                 })?;
             debug!(
-                "Receipt for allocation {} and signer {} failed reason: {}",
+                "Receipt {} for allocation {} and signer {} failed reason: {}",
+                canonical_receipt_hash(receipt).0.encode_hex(),
                 allocation_id.encode_hex(),
                 receipt_signer.encode_hex(),
                 receipt_error
@@ -855,10 +1109,21 @@ impl SenderAllocationState {
     }
 }
 
+/// Identifies the aggregation window of a RAV request, two requests for the
+/// same receipts on top of the same previous RAV expecting the same RAV
+fn rav_request_key(expected_rav: &ReceiptAggregateVoucher) -> String {
+    let mut bytes = Vec::with_capacity(20 + 8 + 16);
+    bytes.extend_from_slice(expected_rav.allocationId.as_slice());
+    bytes.extend_from_slice(&expected_rav.timestampNs.to_be_bytes());
+    bytes.extend_from_slice(&expected_rav.valueAggregate.to_be_bytes());
+    keccak256(bytes).encode_hex()
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::{
-        SenderAllocation, SenderAllocationArgs, SenderAllocationMessage, SenderAllocationState,
+        rav_request_key, SenderAllocation, SenderAllocationArgs, SenderAllocationMessage,
+        SenderAllocationState,
     };
     use crate::{
         agent::{
@@ -866,6 +1131,7 @@ pub mod tests {
             sender_accounts_manager::NewReceiptNotification,
             unaggregated_receipts::UnaggregatedReceipts,
         },
+        tap::{canonical_receipt_hash, context::checks::QueryAppraisals},
         test::{
             actors::{create_mock_sender_account, TestableActor},
             create_rav, create_received_receipt, store_invalid_receipt, store_rav, store_receipt,
@@ -879,17 +1145,20 @@ pub mod tests {
     use ractor::{call, cast, Actor, ActorRef, ActorStatus};
     use ruint::aliases::U256;
     use serde_json::json;
-    use sqlx::PgPool;
+    use sqlx::{types::BigDecimal, PgPool};
     use std::{
         collections::HashMap,
         sync::Arc,
         time::{Duration, SystemTime, UNIX_EPOCH},
     };
     use tap_aggregator::{jsonrpsee_helpers::JsonRpcResponse, server::run_server};
-    use tap_core::receipt::{
-        checks::{Check, CheckError, CheckList, CheckResult},
-        state::Checking,
-        Context, ReceiptWithState,
+    use tap_core::{
+        rav::RAVRequest,
+        receipt::{
+            checks::{Check, CheckError, CheckList, CheckResult},
+            state::Checking,
+            Context, ReceiptWithState,
+        },
     };
     use test_assets::{
         flush_messages, ALLOCATION_ID_0, TAP_EIP712_DOMAIN as TAP_EIP712_DOMAIN_SEPARATOR,
@@ -961,8 +1230,12 @@ pub mod tests {
             config: super::AllocationConfig {
                 timestamp_buffer_ns: 1,
                 rav_request_receipt_limit: 1000,
+                persist_rav_requests: true,
+                max_rav_request_attempts: 3,
+                rav_request_retry_backoff: Duration::from_millis(10),
                 indexer_address: INDEXER.1,
                 escrow_polling_interval: Duration::from_millis(1000),
+                query_appraisals: None,
             },
         }
     }
@@ -1252,6 +1525,125 @@ pub mod tests {
         handle.stopped().await;
     }
 
+    /// RAV requests go by the signed timestamps, also for receipts stored with
+    /// the time the service received them
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_rav_round_trip_by_signed_timestamp(pgpool: PgPool) {
+        let (handle, aggregator_endpoint) = run_server(
+            0,
+            SIGNER.0.clone(),
+            vec![SIGNER.1].into_iter().collect(),
+            TAP_EIP712_DOMAIN_SEPARATOR.clone(),
+            100 * 1024,
+            100 * 1024,
+            1,
+        )
+        .await
+        .unwrap();
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("transactions"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "data": { "transactions": []}})),
+                    ),
+            )
+            .await;
+
+        let store = |nonce, timestamp_ns, received_at_ns: u64| {
+            let pgpool = pgpool.clone();
+            async move {
+                let receipt =
+                    create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, timestamp_ns, 10);
+                let id = store_receipt(&pgpool, receipt.signed_receipt())
+                    .await
+                    .unwrap();
+                sqlx::query("UPDATE scalar_tap_receipts SET received_at_ns = $1 WHERE id = $2")
+                    .bind(BigDecimal::from(received_at_ns))
+                    .bind(id as i64)
+                    .execute(&pgpool)
+                    .await
+                    .unwrap();
+                NewReceiptNotification {
+                    id,
+                    allocation_id: *ALLOCATION_ID_0,
+                    signer_address: SIGNER.1,
+                    timestamp_ns,
+                    value: 10,
+                }
+            }
+        };
+        // signed at 1 to 5, received long after, as stored with `server_receive`
+        for i in 1..=5 {
+            store(i, i, 1_000 + i).await;
+        }
+
+        let (mut message_receiver, sender_account) = create_mock_sender_account().await;
+        let (sender_allocation, notify) = create_sender_allocation(
+            pgpool.clone(),
+            "http://".to_owned() + &aggregator_endpoint.to_string(),
+            &mock_server.uri(),
+            Some(sender_account),
+        )
+        .await;
+
+        sender_allocation
+            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .unwrap();
+        flush_messages(&notify).await;
+        let rav = loop {
+            if let SenderAccountMessage::UpdateReceiptFees(
+                _,
+                ReceiptFees::RavRequestResponse((_, rav)),
+            ) = message_receiver.recv().await.unwrap()
+            {
+                break rav.unwrap().unwrap();
+            }
+        };
+        assert_eq!(rav.message.timestampNs, 5);
+        assert_eq!(rav.message.valueAggregate, 50);
+
+        // signed before the RAV but received after it: left out, instead of
+        // being sent to the aggregator, which refuses receipts older than the
+        // previous RAV
+        let late = store(6, 3, 2_000).await;
+        let on_time = store(7, 6, 2_001).await;
+        for notification in [late, on_time] {
+            sender_allocation
+                .cast(SenderAllocationMessage::NewReceipt(notification))
+                .unwrap();
+        }
+        sender_allocation
+            .cast(SenderAllocationMessage::TriggerRAVRequest)
+            .unwrap();
+        flush_messages(&notify).await;
+        let rav = loop {
+            if let SenderAccountMessage::UpdateReceiptFees(
+                _,
+                ReceiptFees::RavRequestResponse((_, rav)),
+            ) = message_receiver.recv().await.unwrap()
+            {
+                break rav.unwrap().unwrap();
+            }
+        };
+        assert_eq!(rav.message.timestampNs, 6);
+        assert_eq!(rav.message.valueAggregate, 60);
+
+        // nothing is left to aggregate, the late receipt is dropped as obsolete
+        // like any receipt older than the last RAV
+        let total_unaggregated_fees = call!(
+            sender_allocation,
+            SenderAllocationMessage::GetUnaggregatedReceipts
+        )
+        .unwrap();
+        assert_eq!(total_unaggregated_fees.value, 0u128);
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_close_allocation_no_pending_fees(pgpool: PgPool) {
         let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
@@ -1422,6 +1814,53 @@ pub mod tests {
         assert_eq!(total_invalid_receipts.value, 45u128);
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_receipts_checked_against_appraisals(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let mut args = create_sender_allocation_args(
+            pgpool.clone(),
+            DUMMY_URL.to_string(),
+            &mock_escrow_subgraph_server.uri(),
+            None,
+        )
+        .await;
+        let query_appraisals = QueryAppraisals::default();
+        args.config.query_appraisals = Some(query_appraisals.clone());
+        let state = SenderAllocationState::new(args).await.unwrap();
+
+        let appraised = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, 1, 10);
+        let unappraised = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 2, 2, 20);
+        for receipt in [&appraised, &unappraised] {
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // set after the state is created, as the admin routes would
+        query_appraisals.extend(
+            Some(SENDER.1),
+            [(canonical_receipt_hash(appraised.signed_receipt()), 10)],
+        );
+
+        let RAVRequest {
+            valid_receipts,
+            invalid_receipts,
+            ..
+        } = state
+            .tap_manager
+            .create_rav_request(&Context::new(), 0, None)
+            .await
+            .unwrap();
+        let valid: Vec<u64> = valid_receipts
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.nonce)
+            .collect();
+        let invalid: Vec<u64> = invalid_receipts
+            .iter()
+            .map(|receipt| receipt.signed_receipt().message.nonce)
+            .collect();
+        assert_eq!((valid, invalid), (vec![1], vec![2]));
+    }
+
     /// Test that the sender_allocation correctly updates the unaggregated fees from the
     /// database when there is a RAV in the database as well as receipts which timestamp are lesser
     /// and greater than the RAV's timestamp.
@@ -1753,4 +2192,210 @@ pub mod tests {
         // Invalid receipts should be found inside the table
         assert!(all_receipts.is_empty());
     }
+
+    async fn rav_requests(pgpool: &PgPool) -> usize {
+        sqlx::query!(
+            r#"
+                SELECT * FROM scalar_tap_rav_requests;
+            "#,
+        )
+        .fetch_all(pgpool)
+        .await
+        .expect("Should not fail to fetch from scalar_tap_rav_requests")
+        .len()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_rav_request_interrupted_after_response(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        // the aggregator already answered, it must not be asked again
+        let aggregator_server = MockServer::start().await;
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .respond_with(ResponseTemplate::new(500))
+                    .expect(0),
+            )
+            .await;
+
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 45);
+        let request_key = rav_request_key(&rav.message);
+
+        // the previous process recorded the request and the aggregator's
+        // answer, then stopped before storing the RAV
+        let state = SenderAllocationState::new(
+            create_sender_allocation_args(
+                pgpool.clone(),
+                aggregator_server.uri(),
+                &mock_escrow_subgraph_server.uri(),
+                None,
+            )
+            .await,
+        )
+        .await
+        .unwrap();
+        state.record_rav_request(&request_key).await.unwrap();
+        state.record_rav_response(&request_key, &rav).await.unwrap();
+        drop(state);
+
+        let mut state = SenderAllocationState::new(
+            create_sender_allocation_args(
+                pgpool.clone(),
+                aggregator_server.uri(),
+                &mock_escrow_subgraph_server.uri(),
+                None,
+            )
+            .await,
+        )
+        .await
+        .unwrap();
+        state.request_rav().await.unwrap();
+
+        assert_eq!(state.latest_rav, Some(rav));
+        assert_eq!(state.unaggregated_fees.value, 0);
+        assert_eq!(rav_requests(&pgpool).await, 0);
+        assert!(aggregator_server
+            .received_requests()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_rav_request_interrupted_before_response(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 45);
+        let aggregator_server = MockServer::start().await;
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("aggregate_receipts"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "id": 0,
+                        "jsonrpc": "2.0",
+                        "result": JsonRpcResponse {
+                            data: rav.clone(),
+                            warnings: None,
+                        }
+                    })))
+                    .expect(1),
+            )
+            .await;
+
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let mut state = SenderAllocationState::new(
+            create_sender_allocation_args(
+                pgpool.clone(),
+                aggregator_server.uri(),
+                &mock_escrow_subgraph_server.uri(),
+                None,
+            )
+            .await,
+        )
+        .await
+        .unwrap();
+        // the previous process stopped after sending the request, without
+        // recording an answer: the same request is sent again
+        state
+            .record_rav_request(&rav_request_key(&rav.message))
+            .await
+            .unwrap();
+        assert_eq!(rav_requests(&pgpool).await, 1);
+
+        state.request_rav().await.unwrap();
+
+        assert_eq!(state.latest_rav, Some(rav));
+        assert_eq!(rav_requests(&pgpool).await, 0);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_rav_request_retried_after_transient_failures(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let rav = create_rav(*ALLOCATION_ID_0, SIGNER.0.clone(), 10, 45);
+        let aggregator_server = MockServer::start().await;
+        // the aggregator is down for the first two attempts
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .respond_with(ResponseTemplate::new(503))
+                    .up_to_n_times(2)
+                    .expect(2),
+            )
+            .await;
+        aggregator_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("aggregate_receipts"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "id": 0,
+                        "jsonrpc": "2.0",
+                        "result": JsonRpcResponse {
+                            data: rav.clone(),
+                            warnings: None,
+                        }
+                    })))
+                    .expect(1),
+            )
+            .await;
+
+        for i in 0..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i + 1, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let (mut message_receiver, sender_account) = create_mock_sender_account().await;
+        let new_state = || async {
+            SenderAllocationState::new(
+                create_sender_allocation_args(
+                    pgpool.clone(),
+                    aggregator_server.uri(),
+                    &mock_escrow_subgraph_server.uri(),
+                    Some(sender_account.clone()),
+                )
+                .await,
+            )
+            .await
+            .unwrap()
+        };
+
+        let mut state = new_state().await;
+        state.request_rav().await.unwrap_err();
+        assert_eq!(state.rav_request_attempts, 1);
+        // the sender account is asked to retry once the backoff is over
+        assert_eq!(
+            message_receiver.recv().await.unwrap(),
+            SenderAccountMessage::RetryRavRequest(*ALLOCATION_ID_0)
+        );
+
+        // the failed attempts survive a restart
+        drop(state);
+        let mut state = new_state().await;
+        assert_eq!(state.rav_request_attempts, 1);
+
+        state.request_rav().await.unwrap_err();
+        assert_eq!(state.rav_request_attempts, 2);
+        assert_eq!(
+            message_receiver.recv().await.unwrap(),
+            SenderAccountMessage::RetryRavRequest(*ALLOCATION_ID_0)
+        );
+
+        state.request_rav().await.unwrap();
+        assert_eq!(state.latest_rav, Some(rav));
+        assert_eq!(state.rav_request_attempts, 0);
+        assert_eq!(rav_requests(&pgpool).await, 0);
+    }
 }
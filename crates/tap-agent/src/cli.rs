@@ -16,6 +16,10 @@ pub struct Cli {
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/tap-agent for examples.
     #[arg(long, value_name = "FILE", verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+    /// Name of the `[profiles.<name>]` table of the configuration file to merge on top of it.
+    /// Defaults to the `INDEXER_PROFILE` environment variable.
+    #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+    pub profile: Option<String>,
 }
 
 /// Sets up tracing, allows log level to be set from the environment variables
@@ -38,7 +42,12 @@ fn init_tracing(format: String) -> Result<(), SetGlobalDefaultError> {
 
 pub fn get_config() -> Result<IndexerConfig> {
     let cli = Cli::parse();
-    let config = IndexerConfig::parse(ConfigPrefix::Tap, cli.config.as_ref()).map_err(|e| {
+    let config = IndexerConfig::parse_with_profile(
+        ConfigPrefix::Tap,
+        cli.config.as_ref(),
+        cli.profile.as_deref(),
+    )
+    .map_err(|e| {
         error!(
             "Invalid configuration file `{}`: {}, if a value is missing you can also use \
                 --config to fill the rest of the values",
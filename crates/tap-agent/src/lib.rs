@@ -15,6 +15,7 @@ lazy_static! {
 }
 
 pub mod adaptative_concurrency;
+pub mod admin;
 pub mod agent;
 pub mod backoff;
 pub mod cli;
@@ -6,17 +6,35 @@ use ractor::ActorStatus;
 use tokio::signal::unix::{signal, SignalKind};
 use tracing::{debug, error, info};
 
-use indexer_tap_agent::{agent, metrics, CONFIG};
+use axum::Router;
+use indexer_tap_agent::{admin, agent, metrics, tap::context::checks::QueryAppraisals, CONFIG};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse basic configurations, also initializes logging.
     lazy_static::initialize(&CONFIG);
 
-    let (manager, handler) = agent::start_agent().await;
+    // the appraisals set through the admin routes are those the receipts are checked against
+    let query_appraisals = QueryAppraisals::new(CONFIG.tap.max_query_appraisals);
+    let (manager, handler) = agent::start_agent(
+        CONFIG
+            .tap
+            .check_appraised_values
+            .then(|| query_appraisals.clone()),
+    )
+    .await;
     info!("TAP Agent started.");
 
-    tokio::spawn(metrics::run_server(CONFIG.metrics.port));
+    let admin_routes = match (&CONFIG.tap.admin_auth_token, &CONFIG.tap.admin_signature) {
+        (None, None) => Router::new(),
+        (admin_auth_token, admin_signature) => admin::admin_routes(
+            query_appraisals,
+            CONFIG.tap.max_appraisal_batch_size,
+            admin_auth_token.clone(),
+            admin_signature.clone(),
+        ),
+    };
+    tokio::spawn(metrics::run_server(CONFIG.metrics.port, admin_routes));
     info!("Metrics port opened");
 
     // Have tokio wait for SIGTERM or SIGINT.
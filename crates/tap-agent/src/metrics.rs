@@ -28,9 +28,10 @@ async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found")
 }
 
-async fn _run_server(port: u16) {
+async fn _run_server(port: u16, admin_routes: Router) {
     let app = Router::new()
         .route("/metrics", get(handler_metrics))
+        .merge(admin_routes)
         .fallback(handler_404);
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let listener = tokio::net::TcpListener::bind(addr)
@@ -49,10 +50,11 @@ async fn _run_server(port: u16) {
     };
 }
 
-pub async fn run_server(port: u16) {
+/// Serves the metrics, and the admin routes if any, on the port
+pub async fn run_server(port: u16, admin_routes: Router) {
     // Code here is to abort program if there is a panic in _run_server
     // Otherwise, when spawning the task, the panic will be silently ignored
-    let res = panic::AssertUnwindSafe(_run_server(port))
+    let res = panic::AssertUnwindSafe(_run_server(port, admin_routes))
         .catch_unwind()
         .await;
     if res.is_err() {
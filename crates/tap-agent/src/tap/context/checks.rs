@@ -7,3 +7,4 @@ mod value;
 
 pub use allocation_id::AllocationId;
 pub use signature::Signature;
+pub use value::{QueryAppraisals, Value};
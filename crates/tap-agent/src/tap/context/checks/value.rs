@@ -2,11 +2,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, PoisonError},
 };
 
+use alloy::primitives::Address;
 use anyhow::anyhow;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
 use tap_core::{
     receipt::{
         checks::{Check, CheckError, CheckResult},
@@ -16,10 +19,146 @@ use tap_core::{
     signed_message::MessageId,
 };
 
-use crate::tap::context::error::AdapterError;
+use crate::tap::{canonical_receipt_hash, context::error::AdapterError};
+
+lazy_static! {
+    static ref QUERY_APPRAISALS: IntGauge = register_int_gauge!(
+        "tap_query_appraisals",
+        "Appraisals currently held in memory"
+    )
+    .unwrap();
+    static ref QUERY_APPRAISALS_EVICTED: IntCounter = register_int_counter!(
+        "tap_query_appraisals_evicted_total",
+        "Appraisals evicted from memory, the least recently used first"
+    )
+    .unwrap();
+}
+
+type AppraisalKey = (Option<Address>, MessageId);
+
+/// Appraisals set at once while holding the lock
+const EXTEND_CHUNK_SIZE: usize = 1000;
+
+/// Appraisals along with when they were last used, to evict the least
+/// recently used ones past the capacity
+#[derive(Default)]
+struct Appraisals {
+    values: HashMap<AppraisalKey, (u128, u64)>,
+    by_last_use: BTreeMap<u64, AppraisalKey>,
+    uses: u64,
+    capacity: Option<usize>,
+}
+
+impl Appraisals {
+    fn touch(&mut self, key: AppraisalKey) -> Option<u128> {
+        let (value, last_use) = self.values.get_mut(&key)?;
+        self.by_last_use.remove(last_use);
+        self.uses += 1;
+        *last_use = self.uses;
+        self.by_last_use.insert(self.uses, key);
+        Some(*value)
+    }
+
+    fn insert(&mut self, key: AppraisalKey, value: u128) {
+        self.uses += 1;
+        if let Some((_, last_use)) = self.values.insert(key, (value, self.uses)) {
+            self.by_last_use.remove(&last_use);
+        }
+        self.by_last_use.insert(self.uses, key);
+
+        let capacity = self.capacity.unwrap_or(usize::MAX);
+        while self.values.len() > capacity {
+            let Some((_, key)) = self.by_last_use.pop_first() else {
+                break;
+            };
+            self.values.remove(&key);
+            QUERY_APPRAISALS_EVICTED.inc();
+        }
+    }
+}
+
+/// Values receipts are expected to be worth, by sender and [canonical_receipt_hash]
+///
+/// Appraisals set without a sender are global, they hold for the senders
+/// with no appraisal of their own for the query. Past the capacity, the least
+/// recently set or read appraisals are evicted.
+#[derive(Clone, Default)]
+pub struct QueryAppraisals(Arc<Mutex<Appraisals>>);
+
+impl QueryAppraisals {
+    /// Appraisals holding at most `capacity` values
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(Appraisals {
+            capacity: Some(capacity),
+            ..Default::default()
+        })))
+    }
+
+    /// Appraisal of the query for `sender`, falling back to the global one,
+    /// along with the sender it was set for
+    pub fn get(
+        &self,
+        sender: Option<Address>,
+        query_id: &MessageId,
+    ) -> Option<(Option<Address>, u128)> {
+        let mut appraisals = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        sender
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .find_map(|sender| {
+                appraisals
+                    .touch((sender, *query_id))
+                    .map(|value| (sender, value))
+            })
+    }
+
+    /// Sets the appraisals of `sender`, global ones if `None`
+    ///
+    /// They are set [EXTEND_CHUNK_SIZE] at a time, releasing the lock in
+    /// between for the checks reading appraisals, which may see a part of
+    /// them set.
+    pub fn extend(
+        &self,
+        sender: Option<Address>,
+        appraisals: impl IntoIterator<Item = (MessageId, u128)>,
+    ) {
+        let mut appraisals = appraisals.into_iter().peekable();
+        while appraisals.peek().is_some() {
+            let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            for (query_id, value) in appraisals.by_ref().take(EXTEND_CHUNK_SIZE) {
+                inner.insert((sender, query_id), value);
+            }
+            QUERY_APPRAISALS.set(inner.values.len() as i64);
+        }
+    }
+
+    /// Appraisals currently held
+    pub fn len(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
 
 pub struct Value {
-    query_appraisals: Option<Arc<RwLock<HashMap<MessageId, u128>>>>,
+    query_appraisals: Option<QueryAppraisals>,
+    sender: Address,
+}
+
+impl Value {
+    pub fn new(query_appraisals: QueryAppraisals, sender: Address) -> Self {
+        Self {
+            query_appraisals: Some(query_appraisals),
+            sender,
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -30,26 +169,59 @@ impl Check for Value {
         receipt: &ReceiptWithState<Checking>,
     ) -> CheckResult {
         let value = receipt.signed_receipt().message.value;
-        let query_id = receipt.signed_receipt().unique_hash();
+        let query_id = canonical_receipt_hash(receipt.signed_receipt());
 
         let query_appraisals = self.query_appraisals.as_ref().expect(
             "Query appraisals should be initialized. The opposite should never happen when \
             receipts value checking is enabled.",
         );
-        let query_appraisals_read = query_appraisals.read().unwrap();
-        let appraised_value = query_appraisals_read
-            .get(&query_id)
+        let (_, appraised_value) = query_appraisals
+            .get(Some(self.sender), &query_id)
             .ok_or(AdapterError::ValidationError {
                 error: "No appraised value found for query".to_string(),
             })
             .map_err(|e| CheckError::Failed(e.into()))?;
-        if value != *appraised_value {
+        if value != appraised_value {
             return Err(CheckError::Failed(anyhow!(
                 "Value different from appraised_value. value: {}, appraised_value: {}",
                 value,
-                *appraised_value
+                appraised_value
             )));
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tap_core::signed_message::MessageId;
+    use test_assets::TAP_SENDER;
+
+    use super::QueryAppraisals;
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let query = |i: u8| MessageId([i; 32]);
+        let appraisals = QueryAppraisals::new(3);
+        appraisals.extend(None, [(query(1), 1), (query(2), 2)]);
+        appraisals.extend(Some(TAP_SENDER.1), [(query(3), 3)]);
+
+        // reading the first appraisal makes the second the least recently used
+        assert_eq!(appraisals.get(None, &query(1)), Some((None, 1)));
+        appraisals.extend(None, [(query(4), 4)]);
+        assert_eq!(appraisals.len(), 3);
+        assert_eq!(appraisals.get(None, &query(2)), None);
+        assert_eq!(appraisals.get(None, &query(1)), Some((None, 1)));
+
+        // falling back to a global appraisal uses it too
+        assert_eq!(
+            appraisals.get(Some(TAP_SENDER.1), &query(4)),
+            Some((None, 4))
+        );
+        appraisals.extend(None, [(query(5), 5), (query(6), 6)]);
+        assert_eq!(appraisals.get(Some(TAP_SENDER.1), &query(3)), None);
+        assert_eq!(appraisals.get(None, &query(1)), None);
+        assert_eq!(appraisals.get(None, &query(4)), Some((None, 4)));
+        assert_eq!(appraisals.len(), 3);
+    }
+}
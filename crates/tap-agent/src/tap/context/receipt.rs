@@ -193,6 +193,7 @@ impl ReceiptDelete for TapAgentContext {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::tap::canonical_receipt_hash;
     use crate::test::{create_received_receipt, store_receipt, SENDER_2};
     use alloy::{primitives::U256, signers::local::PrivateKeySigner};
     use anyhow::Result;
@@ -239,8 +240,8 @@ mod test {
             .clone();
 
         assert_eq!(
-            received_receipt.signed_receipt().unique_hash(),
-            retrieved_receipt.signed_receipt().unique_hash(),
+            canonical_receipt_hash(received_receipt.signed_receipt()),
+            canonical_receipt_hash(retrieved_receipt.signed_receipt()),
         );
     }
 
@@ -279,7 +280,7 @@ mod test {
             .retrieve_receipts_in_timestamp_range(range, None)
             .await?
             .into_iter()
-            .map(|r| r.signed_receipt().unique_hash())
+            .map(|r| canonical_receipt_hash(r.signed_receipt()))
             .collect::<Vec<_>>();
 
         // Check length
@@ -292,7 +293,7 @@ mod test {
         // the ones in received_receipt_vec
         assert!(received_receipt_vec.iter().all(|received_receipt| {
             recovered_received_receipt_vec
-                .contains(&received_receipt.signed_receipt().unique_hash())
+                .contains(&canonical_receipt_hash(received_receipt.signed_receipt()))
         }));
         Ok(())
     }
@@ -390,14 +391,14 @@ mod test {
                     },
                     signature,
                 };
-                signed_receipt.unique_hash()
+                canonical_receipt_hash(&signed_receipt)
             })
             .collect();
 
         // Check values recovered_received_receipt_set contains values received_receipt_vec
         assert!(received_receipt_vec.iter().all(|(_, received_receipt)| {
             recovered_received_receipt_set
-                .contains(&received_receipt.signed_receipt().unique_hash())
+                .contains(&canonical_receipt_hash(received_receipt.signed_receipt()))
         }));
 
         // Removing all the receipts in the DB
@@ -424,6 +425,70 @@ mod test {
         Ok(())
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn retrieve_receipts_by_signed_timestamp(pgpool: PgPool) {
+        let escrow_accounts = watch::channel(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ))
+        .1;
+        let storage_adapter = TapAgentContext::new(
+            pgpool.clone(),
+            *ALLOCATION_ID_0,
+            SENDER.1,
+            escrow_accounts.clone(),
+        );
+
+        // signed at 10, 20 and 30 but received at 1010 and 1020, as stored with
+        // `server_receive`. The last one has no receive time
+        for (nonce, timestamp_ns, received_at_ns) in
+            [(1, 10, Some(1010)), (2, 20, Some(1020)), (3, 30, None)]
+        {
+            let receipt =
+                create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, timestamp_ns, 10);
+            let id = store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+            sqlx::query("UPDATE scalar_tap_receipts SET received_at_ns = $1 WHERE id = $2")
+                .bind(received_at_ns.map(BigDecimal::from))
+                .bind(id as i64)
+                .execute(&pgpool)
+                .await
+                .unwrap();
+        }
+        let nonces = |receipts: Vec<ReceiptWithState<Checking>>| {
+            receipts
+                .iter()
+                .map(|receipt| receipt.signed_receipt().message.nonce)
+                .collect::<Vec<_>>()
+        };
+
+        // windowed by the signed time RAVs cover, whatever the receive time
+        assert_eq!(
+            nonces(
+                storage_adapter
+                    .retrieve_receipts_in_timestamp_range(0..=25, None)
+                    .await
+                    .unwrap()
+            ),
+            vec![1, 2]
+        );
+        assert_eq!(
+            nonces(
+                storage_adapter
+                    .retrieve_receipts_in_timestamp_range(25..=35, None)
+                    .await
+                    .unwrap()
+            ),
+            vec![3]
+        );
+        assert!(storage_adapter
+            .retrieve_receipts_in_timestamp_range(1000..=1025, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn retrieve_receipts_with_limit(pgpool: PgPool) {
         let escrow_accounts = watch::channel(EscrowAccounts::new(
@@ -3,11 +3,32 @@
 
 use alloy::hex::ToHexExt;
 use alloy::primitives::Address;
+use alloy::sol_types::SolStruct;
 use indexer_monitor::EscrowAccounts;
+use tap_core::{receipt::SignedReceipt, signed_message::MessageId};
 use tokio::sync::watch::Receiver;
 
 pub mod context;
 
+/// Identity of a receipt, the key of its appraisal and what identical receipts
+/// are matched on
+///
+/// The hash is the EIP-712 `hashStruct` of the receipt message:
+///
+/// ```text
+/// keccak256(
+///     keccak256("Receipt(address allocation_id,uint64 timestamp_ns,uint64 nonce,uint128 value)")
+///     || allocation_id || timestamp_ns || nonce || value
+/// )
+/// ```
+///
+/// with each field left padded to 32 bytes. Neither the signature nor the
+/// EIP-712 domain are part of it, so a gateway can compute it before signing
+/// and the same receipt signed by two signers has the same hash.
+pub fn canonical_receipt_hash(receipt: &SignedReceipt) -> MessageId {
+    MessageId(receipt.message.eip712_hash_struct().into())
+}
+
 pub async fn signers_trimmed(
     escrow_accounts_rx: Receiver<EscrowAccounts>,
     sender: Address,
@@ -20,3 +41,45 @@ pub async fn signers_trimmed(
         .collect::<Vec<String>>();
     Ok(signers)
 }
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{address, b256};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+
+    use super::canonical_receipt_hash;
+
+    #[tokio::test]
+    async fn test_canonical_receipt_hash() {
+        let request = || {
+            SignedReceiptRequest::builder()
+                .allocation_id(address!("fa44c72b753a66591f241c7dc04e8178c30e13af"))
+                .timestamp_ns(1_700_000_000_000_000_000)
+                .nonce(42)
+                .value(1000)
+                .build()
+        };
+        let receipt = create_signed_receipt(request()).await;
+        assert_eq!(
+            canonical_receipt_hash(&receipt).0,
+            b256!("70637a32fe63f2914ebaeda62c459d8af06f6c5cd206b501d4413ca4bf394d53").0
+        );
+
+        // the signature is not part of the hash
+        let mut resigned = create_signed_receipt(request()).await;
+        resigned.signature = create_signed_receipt(SignedReceiptRequest::builder().build())
+            .await
+            .signature;
+        assert_eq!(
+            canonical_receipt_hash(&resigned),
+            canonical_receipt_hash(&receipt)
+        );
+
+        let mut other = receipt.clone();
+        other.message.nonce = 43;
+        assert_ne!(
+            canonical_receipt_hash(&other),
+            canonical_receipt_hash(&receipt)
+        );
+    }
+}
@@ -6,11 +6,14 @@
 //! usually carry like initializing things without initializing
 //! its values
 
-use std::{future::Future, time::Duration};
+use std::{future::Future, sync::Arc, time::Duration};
 
 use tokio::{
     select,
-    sync::watch::{self, Ref},
+    sync::{
+        watch::{self, Ref},
+        Notify,
+    },
     task::JoinHandle,
     time::{self, sleep},
 };
@@ -22,6 +25,21 @@ pub async fn new_watcher<T, F, Fut>(
     interval: Duration,
     function: F,
 ) -> anyhow::Result<watch::Receiver<T>>
+where
+    F: Fn() -> Fut + Send + 'static,
+    T: Sync + Send + 'static,
+    Fut: Future<Output = anyhow::Result<T>> + Send,
+{
+    new_watcher_with_trigger(interval, Arc::new(Notify::new()), function).await
+}
+
+/// Same as [new_watcher], also updating it out of cycle each time `trigger`
+/// is notified
+pub async fn new_watcher_with_trigger<T, F, Fut>(
+    interval: Duration,
+    trigger: Arc<Notify>,
+    function: F,
+) -> anyhow::Result<watch::Receiver<T>>
 where
     F: Fn() -> Fut + Send + 'static,
     T: Sync + Send + 'static,
@@ -35,7 +53,10 @@ where
         let mut time_interval = time::interval(interval);
         time_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
         loop {
-            time_interval.tick().await;
+            select! {
+                _ = time_interval.tick() => {},
+                _ = trigger.notified() => time_interval.reset(),
+            }
             let result = function().await;
             match result {
                 Ok(value) => tx.send(value).expect("Failed to update channel"),
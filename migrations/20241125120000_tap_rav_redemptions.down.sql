@@ -0,0 +1,3 @@
+ALTER TABLE scalar_tap_ravs
+    DROP COLUMN IF EXISTS redemption_block_number,
+    DROP COLUMN IF EXISTS redeemed_at;
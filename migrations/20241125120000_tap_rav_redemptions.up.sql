@@ -0,0 +1,9 @@
+-- Track the on-chain redemption status of the last RAV of each allocation.
+--
+-- `redemption_block_number` is the escrow subgraph block at which the redeem
+-- transaction was first observed. `redeemed_at` is only set once the
+-- redemption is buried under the configured confirmation depth, so a re-org
+-- can still roll back an observed (but not yet confirmed) redemption.
+ALTER TABLE scalar_tap_ravs
+    ADD COLUMN IF NOT EXISTS redemption_block_number BIGINT,
+    ADD COLUMN IF NOT EXISTS redeemed_at TIMESTAMP WITH TIME ZONE;
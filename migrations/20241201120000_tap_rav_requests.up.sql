@@ -0,0 +1,16 @@
+-- RAV requests that were sent to a sender's aggregator but whose RAV is not
+-- stored yet, at most one per allocation and sender.
+--
+-- `request_key` identifies the aggregation window (allocation, last receipt
+-- timestamp and value aggregate of the expected RAV). `rav` holds the
+-- aggregator's answer as soon as it is received, so a restart between the
+-- answer and storing the RAV doesn't need to request it again. The row is
+-- removed once the RAV is stored.
+CREATE TABLE IF NOT EXISTS scalar_tap_rav_requests (
+    allocation_id CHAR(40) NOT NULL,
+    sender_address CHAR(40) NOT NULL,
+    request_key CHAR(64) NOT NULL,
+    rav JSONB,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (allocation_id, sender_address)
+);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS scalar_tap_dead_letter_receipts;
@@ -0,0 +1,15 @@
+-- Receipts that passed the checks but paid for a query that can never be
+-- served. They are stored in scalar_tap_receipts all the same, this only keeps
+-- track of them and why their query failed.
+CREATE TABLE IF NOT EXISTS scalar_tap_dead_letter_receipts (
+    id BIGSERIAL PRIMARY KEY,
+    allocation_id CHAR(40) NOT NULL,
+    sender_address CHAR(40),
+    signature BYTEA NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    deployment_id VARCHAR(255),
+    failure TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
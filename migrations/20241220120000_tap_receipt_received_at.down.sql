@@ -0,0 +1 @@
+ALTER TABLE scalar_tap_receipts DROP COLUMN IF EXISTS received_at_ns;
@@ -0,0 +1,4 @@
+-- Time the service received a receipt, in nanoseconds since the epoch, next to
+-- the `timestamp_ns` the sender signed. Only set when `tap.receipt_time_source`
+-- is `server_receive`, to audit receipts. RAVs are windowed by `timestamp_ns`.
+ALTER TABLE scalar_tap_receipts ADD COLUMN IF NOT EXISTS received_at_ns NUMERIC(20);
@@ -0,0 +1 @@
+ALTER TABLE scalar_tap_rav_requests DROP COLUMN IF EXISTS attempts;
@@ -0,0 +1,5 @@
+-- Failed attempts at sending the pending RAV request of an allocation and
+-- sender to the aggregator, so the retries with backoff resume after a restart
+-- and give up after `tap.rav_request.max_retry_attempts` in total.
+ALTER TABLE scalar_tap_rav_requests
+    ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,19 @@
+-- Sample of the paid queries served, kept for audits apart from
+-- scalar_tap_receipts, whose receipts are deleted once redeemed.
+CREATE TABLE IF NOT EXISTS scalar_tap_receipt_archive (
+    id BIGSERIAL PRIMARY KEY,
+    allocation_id CHAR(40) NOT NULL,
+    sender_address CHAR(40),
+    signature BYTEA NOT NULL,
+    timestamp_ns NUMERIC(20) NOT NULL,
+    nonce NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    deployment_id VARCHAR(255),
+    request TEXT NOT NULL,
+    response TEXT NOT NULL,
+    attestation JSONB,
+    archived_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS scalar_tap_receipt_archive_archived_at_idx
+    ON scalar_tap_receipt_archive (archived_at);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS tap_escrow_top_up_grace;
@@ -0,0 +1,8 @@
+-- Grace windows of the senders whose escrow balance ran out, so a restart
+-- doesn't give them a new one. `value` is the total of the receipts accepted
+-- in the window.
+CREATE TABLE IF NOT EXISTS tap_escrow_top_up_grace (
+    sender_address CHAR(40) PRIMARY KEY,
+    opened_at_ns NUMERIC(20) NOT NULL,
+    value NUMERIC(39) NOT NULL
+);